@@ -0,0 +1,140 @@
+//! Benchmarks of reading a request and sending the response header, which
+//! happens for every request. Run with `cargo bench`.
+//!
+//! Before the benchmarks run, sending the header of a successful response is
+//! checked not to allocate memory, which is what the formatted MIME types are
+//! reused for.
+
+use {
+    agate::bench::{configure, Connection},
+    criterion::{criterion_group, Criterion},
+    std::{
+        alloc::{GlobalAlloc, Layout, System},
+        future::Future,
+        pin::pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering::Relaxed},
+            Arc,
+        },
+        task::{Context, Poll, Wake, Waker},
+    },
+};
+
+/// Counts allocations, so tests can check that something does not allocate.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// A waker for futures that never have to wait, so it is never woken.
+struct Noop;
+
+impl Wake for Noop {
+    fn wake(self: Arc<Self>) {}
+}
+
+thread_local! {
+    static WAKER: Waker = Waker::from(Arc::new(Noop));
+}
+
+/// Runs a future of a `Connection`, which never has to wait.
+fn ready<F: Future>(future: F) -> F::Output {
+    let poll = WAKER.with(|waker| pin!(future).poll(&mut Context::from_waker(waker)));
+    match poll {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("the connection should never have to wait"),
+    }
+}
+
+fn header_allocations() {
+    let mut connection = Connection::new(b"");
+    // the MIME type is formatted the first time
+    ready(connection.send_file_header("text/gemini"));
+
+    let before = ALLOCATIONS.load(Relaxed);
+    for _ in 0..100 {
+        ready(connection.send_file_header("text/gemini"));
+    }
+    let allocations = ALLOCATIONS.load(Relaxed) - before;
+    assert_eq!(
+        allocations, 0,
+        "sending 100 headers of successful responses allocated memory {} times",
+        allocations
+    );
+    assert_eq!(connection.sent(), 101 * "20 text/gemini;lang=en\r\n".len());
+}
+
+/// Prints how often handling `request` allocates memory, after the first
+/// time, when MIME types were formatted and caches filled.
+fn report_allocations(name: &str, request: &'static [u8]) {
+    ready(Connection::new(request).respond("text/gemini"));
+    let before = ALLOCATIONS.load(Relaxed);
+    ready(Connection::new(request).respond("text/gemini"));
+    println!(
+        "{}: {} allocations",
+        name,
+        ALLOCATIONS.load(Relaxed) - before
+    );
+}
+
+fn requests(c: &mut Criterion) {
+    c.bench_function("valid request", |b| {
+        b.iter(|| {
+            let mut connection = Connection::new(b"gemini://localhost/test.gmi\r\n");
+            ready(connection.respond("text/gemini"));
+            connection.sent()
+        })
+    });
+    c.bench_function("invalid URL", |b| {
+        b.iter(|| {
+            let mut connection = Connection::new(b"not a URL\r\n");
+            ready(connection.respond("text/gemini"));
+            connection.sent()
+        })
+    });
+    c.bench_function("successful header", |b| {
+        let mut connection = Connection::new(b"");
+        b.iter(|| ready(connection.send_file_header("text/gemini")))
+    });
+}
+
+criterion_group!(benches, requests);
+
+fn main() {
+    let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+    configure(&[
+        "--content",
+        &format!("{}/content", data),
+        "--certs",
+        &format!("{}/.certificates", data),
+        "--hostname",
+        "localhost",
+        "--lang",
+        "en",
+        "--loose-ports",
+    ]);
+    header_allocations();
+    report_allocations("valid request", b"gemini://localhost/test.gmi\r\n");
+    report_allocations("invalid URL", b"not a URL\r\n");
+
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -0,0 +1,297 @@
+//! Serving content from a zip archive instead of a directory.
+
+use {
+    glob::{MatchOptions, Pattern, PatternError},
+    std::{
+        collections::{BTreeSet, HashMap},
+        ffi::OsString,
+        fs::File,
+        io::{self, Read, Seek, SeekFrom},
+        path::{Path, PathBuf},
+        pin::Pin,
+        sync::Arc,
+        task::{ready, Context, Poll},
+    },
+    tokio::{
+        io::{AsyncRead, ReadBuf},
+        sync::mpsc,
+    },
+    zip::ZipArchive,
+};
+
+/// How much of a file is decompressed at once while streaming it.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file or directory in the archive.
+pub(crate) enum Entry {
+    File {
+        /// The index of the file in the archive.
+        index: usize,
+        /// The uncompressed size.
+        size: u64,
+    },
+    /// A directory with the names of its children.
+    Dir(BTreeSet<OsString>),
+}
+
+pub(crate) struct Archive {
+    /// The path of the archive file. Content paths start with it as if it was
+    /// the content directory.
+    path: PathBuf,
+    zip: ZipArchive<SharedFile>,
+    /// All files and directories by their path relative to the archive root.
+    /// Directories that are not in the archive themselves but only contain
+    /// files are included too.
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Archive {
+    /// Opens the archive and indexes its central directory.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let error = |e: &dyn std::fmt::Display| format!("Could not open archive {:?}: {}", path, e);
+        let file = File::open(path).map_err(|e| error(&e))?;
+        let len = file.metadata().map_err(|e| error(&e))?.len();
+        let mut zip = ZipArchive::new(SharedFile {
+            file: Arc::new(file),
+            len,
+            pos: 0,
+        })
+        .map_err(|e| error(&e))?;
+
+        let mut archive = Self {
+            path: path.to_path_buf(),
+            zip: zip.clone(),
+            entries: HashMap::new(),
+        };
+        archive
+            .entries
+            .insert(PathBuf::new(), Entry::Dir(BTreeSet::new()));
+        for index in 0..zip.len() {
+            let file = zip.by_index_raw(index).map_err(|e| error(&e))?;
+            let name = match file.enclosed_name() {
+                Some(name) => name.to_path_buf(),
+                None => {
+                    tracing::warn!("Ignoring archive entry with unsafe name {:?}", file.name());
+                    continue;
+                }
+            };
+            let entry = if file.is_dir() {
+                Entry::Dir(BTreeSet::new())
+            } else {
+                Entry::File {
+                    index,
+                    size: file.size(),
+                }
+            };
+            archive.insert(name, entry);
+        }
+        Ok(archive)
+    }
+
+    /// Adds an entry and makes sure all its parent directories exist.
+    fn insert(&mut self, path: PathBuf, entry: Entry) {
+        match (self.entries.get(&path), &entry) {
+            // a directory may have been created for a file in it before
+            (Some(Entry::Dir(_)), Entry::Dir(_)) => return,
+            (Some(_), _) => {
+                tracing::warn!("Ignoring duplicate archive entry {:?}", path);
+                return;
+            }
+            (None, _) => (),
+        }
+        let mut child = path.as_path();
+        while let (Some(parent), Some(name)) = (child.parent(), child.file_name()) {
+            let parent_entry = self
+                .entries
+                .entry(parent.to_path_buf())
+                .or_insert_with(|| Entry::Dir(BTreeSet::new()));
+            match parent_entry {
+                Entry::Dir(children) => {
+                    // the other ancestors already exist if the parent had other children
+                    if !children.insert(name.to_os_string()) {
+                        break;
+                    }
+                }
+                Entry::File { .. } => {
+                    tracing::warn!("Ignoring archive entry {:?} inside a file", path);
+                    return;
+                }
+            }
+            child = parent;
+        }
+        self.entries.insert(path, entry);
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Looks up a content path, i.e. one starting with the archive path.
+    pub fn get(&self, path: &Path) -> Option<&Entry> {
+        self.entries.get(path.strip_prefix(&self.path).ok()?)
+    }
+
+    pub fn is_file(&self, path: &Path) -> bool {
+        matches!(self.get(path), Some(Entry::File { .. }))
+    }
+
+    /// Returns the file names in a directory and whether each is a directory.
+    pub fn read_dir(&self, path: &Path) -> Option<Vec<(OsString, bool)>> {
+        match self.get(path)? {
+            Entry::Dir(children) => Some(
+                children
+                    .iter()
+                    .map(|name| {
+                        let is_dir = matches!(self.get(&path.join(name)), Some(Entry::Dir(_)));
+                        (name.clone(), is_dir)
+                    })
+                    .collect(),
+            ),
+            Entry::File { .. } => None,
+        }
+    }
+
+    /// Returns the content paths and sizes of all files.
+    pub fn files(&self) -> impl Iterator<Item = (PathBuf, u64)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(move |(path, entry)| match entry {
+                Entry::File { size, .. } => Some((self.path.join(path), *size)),
+                Entry::Dir(_) => None,
+            })
+    }
+
+    /// Returns the content paths of all files matching the glob pattern.
+    pub fn glob(&self, pattern: &str, options: MatchOptions) -> Result<Vec<PathBuf>, PatternError> {
+        let pattern = Pattern::new(pattern)?;
+        Ok(self
+            .files()
+            .map(|(path, _)| path)
+            .filter(|path| pattern.matches_path_with(path, options))
+            .collect())
+    }
+
+    /// Reads a whole file, which should be small, e.g. a sidecar file.
+    pub fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        let index = self.index(path)?;
+        let mut text = String::new();
+        self.zip
+            .clone()
+            .by_index(index)?
+            .read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    /// Opens a file for streaming, starting at `offset`. It is decompressed
+    /// on a blocking thread in chunks, so it does not have to fit into
+    /// memory. Compressed files can not seek, so everything before `offset`
+    /// is decompressed and skipped.
+    pub fn open_file(&self, path: &Path, offset: u64) -> io::Result<Reader> {
+        let index = self.index(path)?;
+        let mut zip = self.zip.clone();
+        // a small buffer so decompressing does not get far ahead of sending
+        let (tx, rx) = mpsc::channel(2);
+        tokio::task::spawn_blocking(move || {
+            let result = zip
+                .by_index(index)
+                .map_err(io::Error::from)
+                .and_then(|mut file| {
+                    io::copy(&mut (&mut file).take(offset), &mut io::sink())?;
+                    Ok(file)
+                })
+                .and_then(|mut file| loop {
+                    let mut chunk = vec![0; CHUNK_SIZE];
+                    let len = file.read(&mut chunk)?;
+                    if len == 0 {
+                        return Ok(());
+                    }
+                    chunk.truncate(len);
+                    if tx.blocking_send(Ok(chunk)).is_err() {
+                        // the reader was dropped, e.g. because the client went away
+                        return Ok(());
+                    }
+                });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        Ok(Reader {
+            rx,
+            chunk: vec![],
+            pos: 0,
+        })
+    }
+
+    fn index(&self, path: &Path) -> io::Result<usize> {
+        match self.get(path) {
+            Some(Entry::File { index, .. }) => Ok(*index),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+/// The archive file, shared between all readers. Each clone has its own
+/// position, so files can be read concurrently.
+#[derive(Clone)]
+struct SharedFile {
+    file: Arc<File>,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let len = std::os::unix::fs::FileExt::read_at(&*self.file, buf, self.pos)?;
+        #[cfg(windows)]
+        let len = std::os::windows::fs::FileExt::seek_read(&*self.file, buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// A file in the archive that is being decompressed.
+pub(crate) struct Reader {
+    rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+    /// The chunk that is currently being read.
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for Reader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.pos == self.chunk.len() {
+            match ready!(self.rx.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                // end of file
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = buf.remaining().min(self.chunk.len() - self.pos);
+        buf.put_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,120 @@
+//! Entry points for the benchmarks in `benches/`, which can not reach the
+//! request handling otherwise. Nothing else should use this module.
+
+use {
+    crate::{
+        default_mime_params, default_params, interned, metadata::FileOptions, PresetMeta,
+        RequestHandle, ARGS,
+    },
+    std::{
+        io,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+    },
+    tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        sync::Mutex,
+    },
+};
+
+/// Sets up the configuration from command line arguments, without the name
+/// of the program. Has to be called once before anything else.
+pub fn configure(args: &[&str]) {
+    let args = std::iter::once("agate")
+        .chain(args.iter().copied())
+        .map(String::from)
+        .collect();
+    let args = crate::args(args).unwrap_or_else(|e| panic!("{}", e));
+    ARGS.0
+        .set(args)
+        .unwrap_or_else(|_| panic!("configured twice"));
+}
+
+/// A plaintext connection that receives a fixed request and throws away
+/// everything sent to it. It never has to wait, so its futures are ready
+/// the first time they are polled.
+pub struct Connection {
+    handle: RequestHandle<Memory>,
+    /// The MIME type parameters of files without a `.meta` entry.
+    params: String,
+}
+
+impl Connection {
+    pub fn new(request: &'static [u8]) -> Self {
+        let params = default_params();
+        let default = PresetMeta::Parameters(params.clone());
+        let metadata = Arc::new(Mutex::new(FileOptions::new(default)));
+        let memory = Memory { request, sent: 0 };
+        Self {
+            handle: RequestHandle::plaintext(memory, metadata, "bench".into(), None),
+            params,
+        }
+    }
+
+    /// Reads the request and sends the header of the response. A valid
+    /// request is answered like one for a file of type `mime`.
+    pub async fn respond(&mut self, mime: &str) {
+        match self.handle.parse_request().await {
+            Ok(_) => self.send_file_header(mime).await,
+            Err(response) => self
+                .handle
+                .write_response(response)
+                .await
+                .expect("could not send response"),
+        }
+    }
+
+    /// Sends the header of a successful response for a file of type `mime`
+    /// with the default parameters, like for a file without a `.meta` entry.
+    pub async fn send_file_header(&mut self, mime: &str) {
+        let defaults = default_mime_params(mime).unwrap_or_default();
+        let meta = interned::meta(&[mime, &self.params, "", defaults]);
+        self.handle
+            .write_header(20, meta)
+            .await
+            .expect("could not send header");
+    }
+
+    /// The number of bytes sent so far.
+    pub fn sent(&self) -> usize {
+        self.handle.stream.sent
+    }
+}
+
+struct Memory {
+    request: &'static [u8],
+    sent: usize,
+}
+
+impl AsyncRead for Memory {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let len = self.request.len().min(buf.remaining());
+        buf.put_slice(&self.request[..len]);
+        self.request = &self.request[len..];
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Memory {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.sent += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
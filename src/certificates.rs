@@ -1,19 +1,28 @@
 use {
+    ring::digest::{digest, SHA256},
     rustls::{
-        sign::{any_supported_type, CertifiedKey},
-        ResolvesServerCert,
+        internal::pemfile,
+        sign::{any_supported_type, CertifiedKey, SigningKey},
+        ResolvesServerCert, SignatureScheme,
     },
     std::{
+        collections::BTreeSet,
         ffi::OsStr,
         fmt::{Display, Formatter},
-        path::Path,
-        sync::Arc,
+        num::NonZeroUsize,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering::Relaxed},
+            Arc,
+        },
+        time::Instant,
     },
     webpki::DNSNameRef,
 };
 
 /// A struct that holds all loaded certificates and the respective domain
 /// names.
+#[derive(Default)]
 pub(crate) struct CertStore {
     /// Stores the certificates and the domains they apply to, sorted by domain
     /// names, longest matches first
@@ -22,6 +31,16 @@ pub(crate) struct CertStore {
 
 pub static CERT_FILE_NAME: &str = "cert.der";
 pub static KEY_FILE_NAME: &str = "key.der";
+/// The directory of a certificate for all host names generated with
+/// `--single-cert`. It can not be confused with a domain because host names
+/// can not contain underscores.
+pub static SINGLE_CERT_DIR: &str = "_all-hostnames";
+/// The directory unused certificates are moved to with `--prune-delete`.
+/// It is never loaded.
+pub static RETIRED_DIR: &str = "retired";
+
+/// The most threads used to load the certificates of domains at the same time.
+const MAX_LOAD_THREADS: usize = 16;
 
 #[derive(Debug)]
 pub enum CertLoadError {
@@ -46,39 +65,84 @@ pub enum CertLoadError {
     /// neither a key file nor a certificate file were present for the given
     /// domain (but a folder was present)
     EmptyDomain(String),
+    /// the key file for the specified domain does not contain the private key
+    /// belonging to the certificate file
+    KeyMismatch(String),
+}
+
+impl CertLoadError {
+    /// Returns the domain whose certificate could not be loaded, which is
+    /// empty for the fallback certificate.
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Self::NoReadCertDir | Self::Empty => None,
+            Self::BadDomain(domain)
+            | Self::BadKey(domain)
+            | Self::BadCert(domain, _)
+            | Self::MissingKey(domain)
+            | Self::MissingCert(domain)
+            | Self::EmptyDomain(domain)
+            | Self::KeyMismatch(domain) => Some(domain),
+        }
+    }
 }
 
 impl Display for CertLoadError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // the fallback certificate has no domain
+        let domain = match self.domain() {
+            Some("") => "fallback",
+            Some(domain) => domain,
+            None => "",
+        };
         match self {
             Self::NoReadCertDir => write!(f, "Could not read from certificate directory."),
             Self::Empty => write!(f, "No keys or certificates were found in the given directory.\nSpecify the --hostname option to generate these automatically."),
-            Self::BadDomain(domain) if !domain.is_ascii() => write!(
+            Self::BadDomain(_) if !domain.is_ascii() => write!(
                 f,
                 "The domain name {} cannot be processed, it must be punycoded.",
                 domain
             ),
-            Self::BadDomain(domain) => write!(f, "The domain name {} cannot be processed.", domain),
-            Self::BadKey(domain) => write!(f, "The key file for {} is malformed.", domain),
-            Self::BadCert(domain, e) => {
+            Self::BadDomain(_) => write!(f, "The domain name {} cannot be processed.", domain),
+            Self::BadKey(_) => write!(f, "The key file for {} is malformed.", domain),
+            Self::BadCert(_, e) => {
                 write!(f, "The certificate file for {} is malformed: {}", domain, e)
             }
-            Self::MissingKey(domain) => write!(f, "The key file for {} is missing.", domain),
-            Self::MissingCert(domain) => {
+            Self::MissingKey(_) => write!(f, "The key file for {} is missing.", domain),
+            Self::MissingCert(_) => {
                 write!(f, "The certificate file for {} is missing.", domain)
             }
-            Self::EmptyDomain(domain) => write!(
+            Self::EmptyDomain(_) => write!(
                 f,
                 "A folder for {} exists, but there is no certificate or key file.",
                 domain
             ),
+            Self::KeyMismatch(_) => write!(
+                f,
+                "The key file for {} does not belong to the certificate file in the same folder, one of them was probably replaced without the other.",
+                domain
+            ),
         }
     }
 }
 
 impl std::error::Error for CertLoadError {}
 
-fn load_domain(certs_dir: &Path, domain: String) -> Result<CertifiedKey, CertLoadError> {
+/// Returns the SHA-256 fingerprint of a DER encoded certificate as lowercase
+/// hexadecimal digits.
+pub fn fingerprint(cert: &[u8]) -> String {
+    digest(&SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn load_domain(
+    certs_dir: &Path,
+    domain: String,
+    check_key: bool,
+) -> Result<CertifiedKey, CertLoadError> {
     let mut path = certs_dir.to_path_buf();
     path.push(&domain);
     // load certificate from file
@@ -99,16 +163,430 @@ fn load_domain(certs_dir: &Path, domain: String) -> Result<CertifiedKey, CertLoa
     if !path.is_file() {
         return Err(CertLoadError::MissingKey(domain));
     }
+    warn_if_readable(&path);
     let key = rustls::PrivateKey(
         std::fs::read(&path).map_err(|_| CertLoadError::MissingKey(domain.clone()))?,
     );
 
+    certified_key(vec![cert], key, domain, check_key)
+}
+
+/// Loads a certificate and key to present as a client certificate from a
+/// directory laid out like the one of a domain, i.e. containing `cert.der`
+/// and `key.der`. Errors name the directory instead of a domain.
+pub fn load_identity(dir: &Path) -> Result<CertifiedKey, CertLoadError> {
+    let name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    load_domain(dir.parent().unwrap_or(dir), name, true)
+}
+
+/// Loads the certificate and key for a domain from files named after it
+/// directly in the certificate directory. Either `<domain>.pem` contains both
+/// in PEM format, or they are in `<domain>.crt` and `<domain>.key`, each in
+/// PEM or DER format.
+fn load_flat(
+    certs_dir: &Path,
+    domain: String,
+    check_key: bool,
+) -> Result<CertifiedKey, CertLoadError> {
+    let path = |extension: &str| certs_dir.join(format!("{}.{}", domain, extension));
+
+    let (certs, key) = if path("pem").is_file() {
+        warn_if_readable(&path("pem"));
+        let data =
+            std::fs::read(path("pem")).map_err(|_| CertLoadError::MissingCert(domain.clone()))?;
+        let certs = pem_certs(&data).ok_or_else(|| CertLoadError::MissingCert(domain.clone()))?;
+        let key = pem_key(&data).ok_or_else(|| CertLoadError::MissingKey(domain.clone()))?;
+        (certs, key)
+    } else {
+        if !path("crt").is_file() {
+            return Err(CertLoadError::MissingCert(domain));
+        }
+        let data =
+            std::fs::read(path("crt")).map_err(|_| CertLoadError::MissingCert(domain.clone()))?;
+        let certs = if is_pem(&data) {
+            pem_certs(&data).ok_or_else(|| {
+                CertLoadError::BadCert(domain.clone(), "no certificate in PEM file".to_string())
+            })?
+        } else {
+            vec![rustls::Certificate(data)]
+        };
+
+        if !path("key").is_file() {
+            return Err(CertLoadError::MissingKey(domain));
+        }
+        warn_if_readable(&path("key"));
+        let data =
+            std::fs::read(path("key")).map_err(|_| CertLoadError::MissingKey(domain.clone()))?;
+        let key = if is_pem(&data) {
+            pem_key(&data).ok_or_else(|| CertLoadError::BadKey(domain.clone()))?
+        } else {
+            rustls::PrivateKey(data)
+        };
+        (certs, key)
+    };
+
+    certified_key(certs, key, domain, check_key)
+}
+
+/// The domains with certificate files in the certificate directory.
+struct StoredDomains {
+    /// The domains with a directory.
+    dirs: BTreeSet<String>,
+    /// The domains of files directly in the certificate directory.
+    flat: BTreeSet<String>,
+    /// Whether there is a certificate for all host names.
+    single: bool,
+    /// Directories whose names can not be domain names.
+    errors: Vec<CertLoadError>,
+}
+
+impl StoredDomains {
+    fn list(certs_dir: &Path) -> Result<Self, CertLoadError> {
+        let entries = certs_dir
+            .read_dir()
+            .or(Err(CertLoadError::NoReadCertDir))?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect::<Vec<_>>();
+        let mut domains = Self {
+            dirs: BTreeSet::new(),
+            flat: BTreeSet::new(),
+            single: false,
+            errors: vec![],
+        };
+        for path in entries {
+            if path.file_name() == Some(OsStr::new(SINGLE_CERT_DIR)) {
+                domains.single = true;
+            } else if path.file_name() == Some(OsStr::new(RETIRED_DIR)) {
+                continue;
+            } else if path.is_dir() {
+                // the filename should be the domain name
+                let filename = path.file_name().unwrap_or_default();
+                match filename.to_str() {
+                    Some(domain) => {
+                        domains.dirs.insert(domain.to_string());
+                    }
+                    None => domains
+                        .errors
+                        .push(CertLoadError::BadDomain(filename.to_string_lossy().into())),
+                }
+            } else if path
+                .extension()
+                .is_some_and(|ext| ext == "crt" || ext == "key" || ext == "pem")
+            {
+                // everything before the last extension is the domain name
+                match path.file_stem().and_then(OsStr::to_str) {
+                    Some(domain) if !domain.is_empty() => {
+                        domains.flat.insert(domain.to_string());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(domains)
+    }
+}
+
+/// Returns the domains that have a certificate directory or files in the
+/// certificate directory, not including the fallback certificate and the
+/// certificate for all host names.
+pub fn stored_domains(certs_dir: &Path) -> Result<BTreeSet<String>, CertLoadError> {
+    let StoredDomains { mut dirs, flat, .. } = StoredDomains::list(certs_dir)?;
+    dirs.extend(flat);
+    Ok(dirs)
+}
+
+/// Moves the certificate directory or files of a domain into the `retired`
+/// directory. Returns the paths they were moved to. Files that already exist
+/// there are not overwritten.
+pub fn retire(certs_dir: &Path, domain: &str) -> std::io::Result<Vec<PathBuf>> {
+    let retired = certs_dir.join(RETIRED_DIR);
+    std::fs::create_dir_all(&retired)?;
+    let names = std::iter::once(domain.to_string())
+        .chain(["crt", "key", "pem"].map(|extension| format!("{}.{}", domain, extension)));
+    let moves = names
+        .map(|name| (certs_dir.join(&name), retired.join(&name)))
+        .filter(|(from, _)| from.exists())
+        .collect::<Vec<_>>();
+    // nothing is moved if anything is in the way
+    if let Some((_, to)) = moves.iter().find(|(_, to)| to.exists()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists", to),
+        ));
+    }
+    let mut moved = vec![];
+    for (from, to) in moves {
+        std::fs::rename(&from, &to)?;
+        moved.push(to);
+    }
+    Ok(moved)
+}
+
+/// Checks if there is a directory or are files for a domain, even if they
+/// could not be loaded.
+pub fn has_files(certs_dir: &Path, domain: &str) -> bool {
+    certs_dir.join(domain).exists()
+        || ["crt", "key", "pem"]
+            .iter()
+            .any(|extension| certs_dir.join(format!("{}.{}", domain, extension)).exists())
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.starts_with(b"-----BEGIN ")
+}
+
+/// Returns the certificates in PEM data, the end entity certificate first.
+fn pem_certs(data: &[u8]) -> Option<Vec<rustls::Certificate>> {
+    pemfile::certs(&mut &data[..])
+        .ok()
+        .filter(|certs| !certs.is_empty())
+}
+
+/// Returns the first PKCS#8 or RSA private key in PEM data.
+fn pem_key(data: &[u8]) -> Option<rustls::PrivateKey> {
+    pemfile::pkcs8_private_keys(&mut &data[..])
+        .ok()
+        .and_then(|keys| keys.into_iter().next())
+        .or_else(|| {
+            pemfile::rsa_private_keys(&mut &data[..])
+                .ok()
+                .and_then(|keys| keys.into_iter().next())
+        })
+}
+
+/// Combines a certificate chain with its private key, checking that they
+/// belong together unless disabled.
+fn certified_key(
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+    domain: String,
+    check_key: bool,
+) -> Result<CertifiedKey, CertLoadError> {
     // transform key to correct format
     let key = match any_supported_type(&key) {
         Ok(key) => key,
         Err(()) => return Err(CertLoadError::BadKey(domain)),
     };
-    Ok(CertifiedKey::new(vec![cert], Arc::new(key)))
+    if check_key {
+        check_key_matches(&certs[0].0, key.as_ref(), domain)?;
+    }
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Checks that a private key belongs to a certificate by signing a message
+/// with the key and verifying the signature with the public key in the
+/// certificate.
+fn check_key_matches(
+    cert: &[u8],
+    key: &dyn SigningKey,
+    domain: String,
+) -> Result<(), CertLoadError> {
+    const MESSAGE: &[u8] = b"agate key check";
+
+    let end_entity = webpki::EndEntityCert::from(cert)
+        .map_err(|e| CertLoadError::BadCert(domain.clone(), format!("{:?}", e)))?;
+    let signer = match key.choose_scheme(&[
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256,
+    ]) {
+        Some(signer) => signer,
+        // the key type can not be checked, leave it to the TLS handshake
+        None => return Ok(()),
+    };
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        _ => return Ok(()),
+    };
+    let signature = signer
+        .sign(MESSAGE)
+        .map_err(|_| CertLoadError::BadKey(domain.clone()))?;
+    end_entity
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| CertLoadError::KeyMismatch(domain))
+}
+
+/// Warns if a key file can be read by users other than its owner. On other
+/// platforms than Unix, permissions are not checked.
+fn warn_if_readable(path: &Path) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if metadata.permissions().mode() & 0o044 != 0 {
+                tracing::warn!(
+                    "The private key {:?} is readable by other users, consider restricting its permissions with `chmod 600`.",
+                    path
+                );
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Reads a DER encoded tag-length-value triple, returning the tag, the value
+/// and the remaining data.
+fn der_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&len, data) = data.split_first()?;
+    let (len, data) = if len & 0x80 == 0 {
+        (len as usize, data)
+    } else {
+        // long form, the lower bits are the number of length bytes
+        let octets = (len & 0x7f) as usize;
+        if octets == 0 || octets > 4 || data.len() < octets {
+            return None;
+        }
+        let (len, data) = data.split_at(octets);
+        (
+            len.iter().fold(0, |acc, &byte| acc << 8 | byte as usize),
+            data,
+        )
+    };
+    if data.len() < len {
+        return None;
+    }
+    let (value, rest) = data.split_at(len);
+    Some((tag, value, rest))
+}
+
+/// Returns the end of the validity period of a DER encoded X.509 certificate
+/// in the form `YYYY-MM-DD hh:mm:ss UTC`.
+pub fn expiry(cert: &[u8]) -> Option<String> {
+    let (_, cert, _) = der_tlv(cert)?;
+    let (_, mut tbs, _) = der_tlv(cert)?;
+    // skip the optional version
+    let (tag, _, rest) = der_tlv(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    // skip serial number, signature algorithm and issuer
+    for _ in 0..3 {
+        tbs = der_tlv(tbs)?.2;
+    }
+    let (_, validity, _) = der_tlv(tbs)?;
+    // skip the start of the validity period
+    let (_, _, validity) = der_tlv(validity)?;
+    let (tag, time, _) = der_tlv(validity)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let time = match tag {
+        // UTCTime, the two digit year means 1950 to 2049
+        0x17 if time.get(..2)? < "50" => format!("20{}", time),
+        0x17 => format!("19{}", time),
+        // GeneralizedTime
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    if time.len() < 14 || !time.bytes().take(14).all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{} {}:{}:{} UTC",
+        &time[0..4],
+        &time[4..6],
+        &time[6..8],
+        &time[8..10],
+        &time[10..12],
+        &time[12..14]
+    ))
+}
+
+/// Returns the DNS names in the subject alternative name extension of a DER
+/// encoded X.509 certificate.
+pub fn dns_names(cert: &[u8]) -> Option<Vec<String>> {
+    let (_, cert, _) = der_tlv(cert)?;
+    let (_, mut tbs, _) = der_tlv(cert)?;
+    // skip the optional version
+    let (tag, _, rest) = der_tlv(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    // skip serial number, signature algorithm, issuer, validity, subject and
+    // subject public key info
+    for _ in 0..6 {
+        tbs = der_tlv(tbs)?.2;
+    }
+    // skip the optional unique identifiers until the extensions
+    let mut extensions = loop {
+        let (tag, value, rest) = der_tlv(tbs)?;
+        if tag == 0xa3 {
+            break der_tlv(value)?.1;
+        }
+        tbs = rest;
+    };
+    while !extensions.is_empty() {
+        let (_, extension, rest) = der_tlv(extensions)?;
+        extensions = rest;
+        let (_, oid, mut extension) = der_tlv(extension)?;
+        // 2.5.29.17 is the subject alternative name
+        if oid != [0x55, 0x1d, 0x11] {
+            continue;
+        }
+        // skip the optional critical flag
+        let (tag, _, rest) = der_tlv(extension)?;
+        if tag == 0x01 {
+            extension = rest;
+        }
+        let (_, names, _) = der_tlv(extension)?;
+        let (_, mut names, _) = der_tlv(names)?;
+        let mut dns_names = vec![];
+        while !names.is_empty() {
+            let (tag, name, rest) = der_tlv(names)?;
+            names = rest;
+            // dNSName is the context specific tag 2
+            if tag == 0x82 {
+                dns_names.push(std::str::from_utf8(name).ok()?.to_string());
+            }
+        }
+        return Some(dns_names);
+    }
+    None
+}
+
+/// Calls `load` for each item on several threads. The results are returned in
+/// the order of the items, so errors are always reported in the same order.
+fn load_parallel<T: Sync, R: Send>(items: &[T], load: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(MAX_LOAD_THREADS)
+        .min(items.len());
+    if threads <= 1 {
+        return items.iter().map(load).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Relaxed);
+                        match items.get(index) {
+                            Some(item) => results.push((index, load(item))),
+                            None => break results,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    results.sort_unstable_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
 impl CertStore {
@@ -119,67 +597,128 @@ impl CertStore {
     ///
     /// If there are `cert.pem` and `key.rsa` directly in certs_dir, these will be
     /// loaded as default certificates.
-    pub fn load_from(certs_dir: &Path) -> Result<Self, CertLoadError> {
+    ///
+    /// Alternatively, the files can be directly in certs_dir, named after the
+    /// domain, see `load_flat`. If both exist, the folder is used.
+    ///
+    /// Domains that can not be loaded are skipped, so that one broken
+    /// directory does not keep the others from being served. Their errors are
+    /// returned together with the store. Loading only fails if no certificate
+    /// could be loaded at all.
+    ///
+    /// The domains are loaded on several threads, which are joined before
+    /// returning, so this can be called from synchronous code as well as from
+    /// a blocking task.
+    pub fn load_from(
+        certs_dir: &Path,
+        check_keys: bool,
+    ) -> Result<(Self, Vec<CertLoadError>), CertLoadError> {
         // load all certificates from directories
         let mut certs = vec![];
+        let mut errors = vec![];
 
         // Try to load fallback certificate and key directly from the top level
         // certificate directory.
-        match load_domain(certs_dir, String::new()) {
+        match load_domain(certs_dir, String::new(), check_keys) {
             Err(CertLoadError::EmptyDomain(_)) => { /* there are no fallback keys */ }
-            Err(CertLoadError::Empty)
-            | Err(CertLoadError::NoReadCertDir)
-            | Err(CertLoadError::BadDomain(_)) => unreachable!(),
-            Err(CertLoadError::BadKey(_)) => {
-                return Err(CertLoadError::BadKey("fallback".to_string()))
-            }
-            Err(CertLoadError::BadCert(_, e)) => {
-                return Err(CertLoadError::BadCert("fallback".to_string(), e))
-            }
-            Err(CertLoadError::MissingKey(_)) => {
-                return Err(CertLoadError::MissingKey("fallback".to_string()))
-            }
-            Err(CertLoadError::MissingCert(_)) => {
-                return Err(CertLoadError::MissingCert("fallback".to_string()))
-            }
+            Err(e) => errors.push(e),
             // For the fallback keys there is no domain name to verify them
             // against, so we can skip that step and only have to do it for the
             // other keys below.
             Ok(key) => certs.push((String::new(), key)),
         }
 
-        for file in certs_dir
-            .read_dir()
-            .or(Err(CertLoadError::NoReadCertDir))?
-            .filter_map(Result::ok)
-            .filter(|x| x.path().is_dir())
-        {
-            let path = file.path();
-
-            // the filename should be the domain name
-            let filename = path
-                .file_name()
-                .and_then(OsStr::to_str)
-                .unwrap()
-                .to_string();
-
-            let dns_name = match DNSNameRef::try_from_ascii_str(&filename) {
-                Ok(name) => name,
-                Err(_) => return Err(CertLoadError::BadDomain(filename)),
-            };
-
-            let key = load_domain(certs_dir, filename.clone())?;
+        let StoredDomains {
+            dirs: dir_domains,
+            flat: flat_domains,
+            single,
+            errors: list_errors,
+        } = StoredDomains::list(certs_dir)?;
+        errors.extend(list_errors);
+
+        for domain in &flat_domains {
+            if dir_domains.contains(domain) {
+                tracing::warn!(
+                    "There is a directory and files named after {}, using the directory.",
+                    domain
+                );
+            }
+        }
+        let domains = dir_domains
+            .iter()
+            .map(|domain| (domain, true))
+            .chain(
+                flat_domains
+                    .iter()
+                    .filter(|domain| !dir_domains.contains(*domain))
+                    .map(|domain| (domain, false)),
+            )
+            .collect::<Vec<_>>();
+        let started = Instant::now();
+        let loaded = load_parallel(&domains, |&(domain, is_dir)| {
+            let dns_name = DNSNameRef::try_from_ascii_str(domain)
+                .map_err(|_| CertLoadError::BadDomain(domain.clone()))?;
+            let key = if is_dir {
+                load_domain(certs_dir, domain.clone(), check_keys)
+            } else {
+                load_flat(certs_dir, domain.clone(), check_keys)
+            }?;
             key.cross_check_end_entity_cert(Some(dns_name))
-                .map_err(|e| CertLoadError::BadCert(filename.clone(), e.to_string()))?;
+                .map_err(|e| CertLoadError::BadCert(domain.clone(), e.to_string()))?;
+            Ok(key)
+        });
+        for ((domain, _), key) in domains.into_iter().zip(loaded) {
+            match key {
+                Ok(key) => certs.push((domain.clone(), key)),
+                Err(e) => errors.push(e),
+            }
+        }
+        tracing::info!(
+            "Loaded certificates for {} of {} domains in {:?}",
+            certs
+                .iter()
+                .filter(|(domain, _)| !domain.is_empty())
+                .count(),
+            dir_domains.len() + flat_domains.difference(&dir_domains).count(),
+            started.elapsed()
+        );
 
-            certs.push((filename, key));
+        // The certificate for all host names is used for each name it
+        // contains, unless there is a separate certificate for that domain.
+        if single {
+            match load_domain(certs_dir, SINGLE_CERT_DIR.to_string(), check_keys) {
+                Ok(key) => {
+                    for domain in dns_names(&key.cert[0].0).unwrap_or_default() {
+                        if !certs.iter().any(|(d, _)| *d == domain) {
+                            certs.push((domain, key.clone()));
+                        }
+                    }
+                }
+                Err(e) => errors.push(e),
+            }
         }
 
         if certs.is_empty() {
-            return Err(CertLoadError::Empty);
+            // the first error is more helpful than saying there is nothing
+            return Err(if errors.is_empty() {
+                CertLoadError::Empty
+            } else {
+                errors.remove(0)
+            });
         }
 
-        certs.sort_unstable_by(|(a, _), (b, _)| {
+        let mut store = Self { certs };
+        store.sort();
+        tracing::debug!(
+            "certs loaded for {:?}",
+            store.certs.iter().map(|t| &t.0).collect::<Vec<_>>()
+        );
+        Ok((store, errors))
+    }
+
+    /// Sorts the certificates so the longest matching domain comes first.
+    fn sort(&mut self) {
+        self.certs.sort_unstable_by(|(a, _), (b, _)| {
             // Try to match as many domain segments as possible. If one is a
             // substring of the other, the `zip` will only compare the smaller
             // length of either a or b and the for loop will not decide.
@@ -194,13 +733,28 @@ impl CertStore {
             // Sort longer domains first.
             a.len().cmp(&b.len()).reverse()
         });
+    }
 
-        log::debug!(
-            "certs loaded for {:?}",
-            certs.iter().map(|t| &t.0).collect::<Vec<_>>()
-        );
+    /// Uses the certificate of a domain from another store, e.g. the previous
+    /// one if the domain could not be loaded again. Returns false if the other
+    /// store does not have a certificate for exactly that domain either.
+    pub fn keep(&mut self, old: &Self, domain: &str) -> bool {
+        match old.certs.iter().find(|(d, _)| d == domain) {
+            Some(cert) if !self.certs.iter().any(|(d, _)| d == domain) => {
+                self.certs.push(cert.clone());
+                self.sort();
+                true
+            }
+            _ => false,
+        }
+    }
 
-        Ok(Self { certs })
+    /// Returns the domain names and DER encoded certificates of all loaded
+    /// certificates. The domain name of the fallback certificate is empty.
+    pub fn certificates(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.certs
+            .iter()
+            .map(|(domain, key)| (domain.as_str(), key.cert[0].0.as_slice()))
     }
 
     /// Checks if a certificate fitting a specific domain has been loaded.
@@ -0,0 +1,291 @@
+//! A minimal Gemini client, used for fetching from upstream servers, and the
+//! creation of outgoing connections.
+
+use {
+    crate::certificates::fingerprint,
+    rustls::{
+        sign::CertifiedKey, Certificate, ClientConfig, ResolvesClientCert, RootCertStore,
+        ServerCertVerified, ServerCertVerifier, SignatureScheme, TLSError,
+    },
+    std::{
+        fmt::{self, Display, Formatter},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        sync::Arc,
+    },
+    tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpSocket, TcpStream},
+    },
+    tokio_rustls::{client::TlsStream, TlsConnector},
+    url::Url,
+    webpki::DNSNameRef,
+};
+
+/// The maximum length of a response header including the CRLF, i.e. two
+/// digits of status code, a space, 1024 bytes of meta, CR and LF.
+pub(crate) const MAX_HEADER_LEN: u64 = 2 + 1 + 1024 + 2;
+
+/// Since Gemini servers commonly use self-signed certificates, this verifier
+/// either accepts any certificate or only a certificate with a specific
+/// SHA-256 fingerprint.
+struct FingerprintVerifier(Option<String>);
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let expected = match self.0 {
+            Some(ref expected) => expected,
+            None => return Ok(ServerCertVerified::assertion()),
+        };
+        match presented_certs.first() {
+            Some(cert) if &fingerprint(&cert.0) == expected => Ok(ServerCertVerified::assertion()),
+            Some(cert) => Err(TLSError::General(format!(
+                "certificate fingerprint {} does not match the pinned fingerprint",
+                fingerprint(&cert.0)
+            ))),
+            None => Err(TLSError::NoCertificatesPresented),
+        }
+    }
+}
+
+/// Presents the same client certificate to every server that asks for one.
+struct Identity(CertifiedKey);
+
+impl ResolvesClientCert for Identity {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        Some(self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// The response to a Gemini request. The body can be read from `body` until
+/// the end of the stream.
+pub(crate) struct Response {
+    pub status: u8,
+    pub meta: String,
+    pub body: BufReader<TlsStream<TcpStream>>,
+}
+
+/// Normalizes a user supplied SHA-256 fingerprint to the format returned by
+/// [`fingerprint`], i.e. lowercase hexadecimal without separators.
+pub(crate) fn parse_fingerprint(s: &str) -> Result<String, String> {
+    let hex = s.replace(':', "").to_ascii_lowercase();
+    if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(hex)
+    } else {
+        Err(format!("{:?} is not a SHA-256 fingerprint", s))
+    }
+}
+
+/// The local addresses outgoing connections are made from, set with
+/// `--outbound-addr`. There can be one for each address family.
+#[derive(Default)]
+pub(crate) struct OutboundAddrs {
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+}
+
+impl OutboundAddrs {
+    /// Parses the values of `--outbound-addr`, at most one IPv4 and one IPv6
+    /// address.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut addrs = Self::default();
+        for arg in args {
+            match arg.parse() {
+                Ok(IpAddr::V4(ip)) if addrs.v4.is_none() => addrs.v4 = Some(ip),
+                Ok(IpAddr::V6(ip)) if addrs.v6.is_none() => addrs.v6 = Some(ip),
+                Ok(_) => {
+                    return Err(format!(
+                        "--outbound-addr can only be given once for IPv4 and once for IPv6, {} is the second one",
+                        arg
+                    ))
+                }
+                Err(e) => return Err(format!("Invalid --outbound-addr {:?}: {}", arg, e)),
+            }
+        }
+        Ok(addrs)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_none() && self.v6.is_none()
+    }
+
+    /// The local address to connect to `remote` from, or `None` if any may
+    /// be used. If there is only an address of the other family, connecting
+    /// is not possible.
+    fn local_for(&self, remote: &SocketAddr) -> Result<Option<IpAddr>, String> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        match remote {
+            SocketAddr::V4(_) => self.v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+        .map(Some)
+        .ok_or_else(|| {
+            format!(
+                "can not connect to {} from --outbound-addr {}, which has no {} address",
+                remote,
+                self,
+                if remote.is_ipv4() { "IPv4" } else { "IPv6" }
+            )
+        })
+    }
+}
+
+impl Display for OutboundAddrs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (self.v4, self.v6) {
+            (Some(v4), Some(v6)) => write!(f, "{} and {}", v4, v6),
+            (Some(v4), None) => write!(f, "{}", v4),
+            (None, Some(v6)) => write!(f, "{}", v6),
+            (None, None) => f.write_str("none"),
+        }
+    }
+}
+
+/// Opens an outgoing connection to `remote`, from the address given with
+/// `--outbound-addr` if there is one. All outgoing connections have to be
+/// made with this or `connect_host`.
+pub(crate) async fn connect(remote: SocketAddr) -> Result<TcpStream, String> {
+    // the health check does not parse the server arguments
+    let local = match crate::ARGS.0.get() {
+        Some(args) => args.outbound_addrs.local_for(&remote)?,
+        None => None,
+    };
+    let local = match local {
+        Some(local) => local,
+        None => {
+            return TcpStream::connect(remote)
+                .await
+                .map_err(|e| format!("could not connect to {}: {}", remote, e))
+        }
+    };
+    let connect = async {
+        let socket = match remote {
+            SocketAddr::V4(_) => TcpSocket::new_v4(),
+            SocketAddr::V6(_) => TcpSocket::new_v6(),
+        }?;
+        socket.bind(SocketAddr::new(local, 0))?;
+        socket.connect(remote).await
+    };
+    connect
+        .await
+        .map_err(|e| format!("could not connect to {} from {}: {}", remote, local, e))
+}
+
+/// Like `connect`, but looks up the addresses of `host` first and tries each
+/// of them that can be connected to from `--outbound-addr`.
+pub(crate) async fn connect_host(host: &str, port: u16) -> Result<TcpStream, String> {
+    let addrs = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("could not look up {}: {}", host, e))?;
+    let mut error = format!("{} has no addresses", host);
+    for addr in addrs {
+        match connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Sends a request for `url` to the server at `addr`, or to the host and port
+/// of `url` if no address is given. The host of `url` is used for SNI.
+///
+/// If `fingerprint` is given, only a server certificate with that fingerprint
+/// is accepted. Otherwise any certificate is accepted. If `identity` is given,
+/// it is presented as the client certificate when the server asks for one.
+pub(crate) async fn request(
+    url: &Url,
+    addr: Option<SocketAddr>,
+    fingerprint: Option<String>,
+    identity: Option<CertifiedKey>,
+) -> Result<Response, String> {
+    let host = url.host_str().ok_or("URL does not contain a host")?;
+    let tcp = match addr {
+        Some(addr) => connect(addr).await,
+        None => connect_host(host, url.port().unwrap_or(1965)).await,
+    }?;
+    request_on(tcp, url, fingerprint, identity).await
+}
+
+/// Like `request`, but over a connection that was already established.
+pub(crate) async fn request_on(
+    tcp: TcpStream,
+    url: &Url,
+    fingerprint: Option<String>,
+    identity: Option<CertifiedKey>,
+) -> Result<Response, String> {
+    let host = url.host_str().ok_or("URL does not contain a host")?;
+    let mut stream = connect_tls(tcp, host, fingerprint, identity).await?;
+
+    stream
+        .write_all(format!("{}\r\n", url).as_bytes())
+        .await
+        .map_err(|e| format!("could not send request: {}", e))?;
+
+    let mut body = BufReader::new(stream);
+    let mut header = vec![];
+    (&mut body)
+        .take(MAX_HEADER_LEN)
+        .read_until(b'\n', &mut header)
+        .await
+        .map_err(|e| format!("could not read response header: {}", e))?;
+    let (status, meta) = parse_header(&header)?;
+
+    Ok(Response { status, meta, body })
+}
+
+/// Establishes a TLS session over `tcp` with `host` as the server name,
+/// checking the server certificate and presenting the client certificate
+/// like `request`.
+pub(crate) async fn connect_tls(
+    tcp: TcpStream,
+    host: &str,
+    fingerprint: Option<String>,
+    identity: Option<CertifiedKey>,
+) -> Result<TlsStream<TcpStream>, String> {
+    let dns_name = DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| format!("{} cannot be used as a TLS server name", host))?;
+
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(FingerprintVerifier(fingerprint)));
+    if let Some(identity) = identity {
+        config.client_auth_cert_resolver = Arc::new(Identity(identity));
+    }
+    TlsConnector::from(Arc::new(config))
+        .connect(dns_name, tcp)
+        .await
+        .map_err(|e| format!("TLS error: {}", e))
+}
+
+/// Parses a response header line including the terminating CRLF.
+pub(crate) fn parse_header(header: &[u8]) -> Result<(u8, String), String> {
+    let header = header
+        .strip_suffix(b"\r\n")
+        .ok_or("response header is not terminated by CRLF")?;
+    let header = std::str::from_utf8(header).or(Err("response header is not UTF-8"))?;
+    let (status, meta) = match header.find(' ') {
+        Some(i) => (&header[..i], &header[i + 1..]),
+        None => (header, ""),
+    };
+    match status.as_bytes() {
+        [b'1'..=b'6', b'0'..=b'9'] => Ok((status.parse().unwrap(), meta.to_string())),
+        _ => Err(format!("invalid status code {:?}", status)),
+    }
+}
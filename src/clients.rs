@@ -0,0 +1,196 @@
+//! Remembering client certificates across restarts, trusting each one on
+//! first use, enabled with `--client-registry`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+/// The number of client certificates remembered. Once it is reached, new
+/// certificates are not recorded anymore, since forgetting one would let it be
+/// seen for the first time again.
+const MAX_CLIENTS: usize = 100_000;
+
+/// The maximum length of a petname in bytes.
+const MAX_PETNAME_LEN: usize = 64;
+
+/// What is known about a client certificate.
+#[derive(Clone)]
+pub(crate) struct Client {
+    /// When the certificate was first seen.
+    pub first_seen: SystemTime,
+    /// The name given with a `petname=` query when it was first seen.
+    pub petname: Option<String>,
+    /// Whether the certificate was seen before the current request.
+    pub known: bool,
+}
+
+pub(crate) struct ClientRegistry {
+    /// The file the clients are saved to, as lines of a fingerprint, the time
+    /// it was first seen and a petname, separated by tabs.
+    file: PathBuf,
+    /// The clients by the SHA-256 fingerprint of their certificate.
+    clients: Mutex<HashMap<String, Client>>,
+    /// Whether there are clients that were not written to the file yet.
+    dirty: AtomicBool,
+    /// Held while writing, so two flushes do not write the temporary file at
+    /// the same time.
+    writing: tokio::sync::Mutex<()>,
+    /// Set once the warning about a full registry was logged.
+    full: AtomicBool,
+}
+
+impl ClientRegistry {
+    /// Reads the clients saved before. A missing file is not an error, so
+    /// the registry can start empty.
+    pub fn load(file: PathBuf) -> Result<Self, String> {
+        let mut clients = HashMap::new();
+        match fs::read_to_string(&file) {
+            Ok(text) => {
+                for (number, line) in text.lines().enumerate() {
+                    let mut fields = line.split('\t');
+                    let parsed = (|| {
+                        let fingerprint = fields.next()?;
+                        let first_seen = humantime::parse_rfc3339(fields.next()?).ok()?;
+                        let petname = fields.next().filter(|name| !name.is_empty());
+                        Some((fingerprint, first_seen, petname))
+                    })();
+                    match parsed {
+                        Some((fingerprint, first_seen, petname)) => {
+                            clients.insert(
+                                fingerprint.to_string(),
+                                Client {
+                                    first_seen,
+                                    petname: petname.map(str::to_string),
+                                    known: true,
+                                },
+                            );
+                        }
+                        None => tracing::warn!(
+                            "Ignoring invalid line {} in client registry {:?}",
+                            number + 1,
+                            file
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("Could not read client registry {:?}: {}", file, e)),
+        }
+        if clients.len() > MAX_CLIENTS {
+            tracing::warn!(
+                "Client registry {:?} contains more than {} clients, new clients will not be recorded",
+                file,
+                MAX_CLIENTS
+            );
+        }
+        Ok(Self {
+            file,
+            clients: Mutex::new(clients),
+            dirty: AtomicBool::new(false),
+            writing: tokio::sync::Mutex::new(()),
+            full: AtomicBool::new(false),
+        })
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Looks up a client certificate by its fingerprint, recording it if it
+    /// was not seen before. `petname` is only used for new certificates.
+    /// Returns `None` if the certificate is new but the registry is full.
+    pub fn see(&'static self, fingerprint: &str, petname: Option<&str>) -> Option<Client> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(fingerprint) {
+            return Some(client.clone());
+        }
+        if clients.len() >= MAX_CLIENTS {
+            if !self.full.swap(true, Relaxed) {
+                tracing::warn!(
+                    "Client registry {:?} is full, new clients are not recorded",
+                    self.file
+                );
+            }
+            return None;
+        }
+
+        // such names could not be read back from the file
+        let petname = petname
+            .filter(|name| !name.is_empty() && !name.contains(char::is_control))
+            .map(|name| {
+                let mut end = name.len().min(MAX_PETNAME_LEN);
+                while !name.is_char_boundary(end) {
+                    end -= 1;
+                }
+                name[..end].to_string()
+            });
+        tracing::info!("Recording new client certificate {}", fingerprint);
+        let client = Client {
+            first_seen: SystemTime::now(),
+            petname,
+            known: true,
+        };
+        clients.insert(fingerprint.to_string(), client.clone());
+        self.dirty.store(true, Relaxed);
+        tokio::spawn(self.flush());
+        Some(Client {
+            known: false,
+            ..client
+        })
+    }
+
+    /// Writes the clients to the file if they changed. The file is replaced
+    /// atomically, so it is never left half-written.
+    pub async fn flush(&'static self) {
+        let _writing = self.writing.lock().await;
+        if !self.dirty.swap(false, Relaxed) {
+            return;
+        }
+        let mut sorted = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fingerprint, client)| (fingerprint.clone(), client.clone()))
+            .collect::<Vec<_>>();
+        sorted.sort_by(|(a_fpr, a), (b_fpr, b)| {
+            a.first_seen
+                .cmp(&b.first_seen)
+                .then_with(|| a_fpr.cmp(b_fpr))
+        });
+
+        let result = tokio::task::spawn_blocking(move || self.write(&sorted))
+            .await
+            .unwrap();
+        if let Err(e) = result {
+            tracing::error!("Could not write client registry {:?}: {}", self.file, e);
+            // try again with the next change or on shutdown
+            self.dirty.store(true, Relaxed);
+        }
+    }
+
+    fn write(&self, sorted: &[(String, Client)]) -> io::Result<()> {
+        let mut temp = self.file.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&temp)?);
+        for (fingerprint, client) in sorted {
+            writeln!(
+                file,
+                "{}\t{}\t{}",
+                fingerprint,
+                humantime::format_rfc3339_seconds(client.first_seen),
+                client.petname.as_deref().unwrap_or_default()
+            )?;
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&temp, &self.file)
+    }
+}
@@ -0,0 +1,472 @@
+//! The `agate conformance` subcommand, which checks that a running Gemini
+//! server follows the protocol in edge cases. It only uses what any Gemini
+//! server has to do, so it can be run against other servers too.
+
+use {
+    crate::client,
+    rustls::{ProtocolVersion, Session},
+    std::{future::Future, net::SocketAddr, time::Duration},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        runtime::Runtime,
+    },
+    url::Url,
+};
+
+/// The maximum length of a request URL in bytes, without the CRLF.
+const MAX_URL_LEN: usize = 1024;
+
+/// The maximum length of the meta of a response header in bytes.
+const MAX_META_LEN: usize = 1024;
+
+/// The server to check and how to reach it.
+struct Target {
+    addr: SocketAddr,
+    /// The host name used for SNI and in the requests.
+    host: String,
+    timeout: Duration,
+}
+
+/// A response whose header was checked against the grammar.
+struct Exchange {
+    status: u8,
+    meta: String,
+}
+
+impl std::fmt::Display for Exchange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.meta)
+    }
+}
+
+/// The outcome of a check: `Ok` with what was observed if it passed, `Err`
+/// with the reason if it failed, or `None` if it was skipped.
+type Outcome = Option<Result<String, String>>;
+
+/// Runs the checks with the given command line arguments (without the program
+/// name and subcommand), prints a report and returns the exit code.
+pub(crate) fn run(program: &str, args: &[String]) -> u8 {
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "",
+        "addr",
+        "Address of the server to check (default 127.0.0.1:1965)",
+        "IP:PORT",
+    );
+    opts.optopt(
+        "",
+        "hostname",
+        "Host name the server serves, used for SNI and in the requests (default localhost)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "path",
+        "Path that is answered with a 2x status (default /)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "directory",
+        "Path of a directory, to check the redirect to its path with a trailing slash",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "timeout",
+        "Seconds to wait for each response (default 10)",
+        "SECONDS",
+    );
+    opts.optflag("h", "help", "Print this help text and exit.");
+
+    let matches = match opts.parse(args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    if matches.opt_present("h") {
+        eprintln!(
+            "{}",
+            opts.usage(&format!(
+                "Usage: {} conformance [options]\n\nChecks that a running Gemini server follows the protocol.",
+                program
+            ))
+        );
+        return 0;
+    }
+
+    let addr: SocketAddr = match matches.opt_get_default("addr", "127.0.0.1:1965".parse().unwrap())
+    {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("invalid address: {}", e);
+            return 2;
+        }
+    };
+    let timeout = match matches.opt_get_default("timeout", 10) {
+        Ok(timeout) => Duration::from_secs(timeout),
+        Err(e) => {
+            eprintln!("invalid timeout: {}", e);
+            return 2;
+        }
+    };
+    let target = Target {
+        addr,
+        host: matches
+            .opt_str("hostname")
+            .unwrap_or_else(|| "localhost".into()),
+        timeout,
+    };
+    let path = matches.opt_str("path").unwrap_or_else(|| "/".into());
+    let directory = matches.opt_str("directory");
+    for path in std::iter::once(&path).chain(&directory) {
+        if let Err(e) = Url::parse(&target.url(path)) {
+            eprintln!("invalid hostname or path: {}", e);
+            return 2;
+        }
+    }
+
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let results = runtime.block_on(target.check_all(&path, directory.as_deref()));
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, outcome) in results {
+        match outcome {
+            Some(Ok(observed)) => {
+                passed += 1;
+                println!("PASS {}: {}", name, observed);
+            }
+            Some(Err(reason)) => {
+                failed += 1;
+                println!("FAIL {}: {}", name, reason);
+            }
+            None => {
+                skipped += 1;
+                println!("SKIP {}", name);
+            }
+        }
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+    if failed == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+impl Target {
+    /// Returns the URL for `path` on the target, with the port if it is not
+    /// the default one.
+    fn url(&self, path: &str) -> String {
+        self.url_with_port(path, self.addr.port())
+    }
+
+    fn url_with_port(&self, path: &str, port: u16) -> String {
+        match port {
+            1965 => format!("gemini://{}{}", self.host, path),
+            port => format!("gemini://{}:{}{}", self.host, port, path),
+        }
+    }
+
+    /// Runs all checks in order and returns their names and outcomes.
+    async fn check_all(&self, path: &str, directory: Option<&str>) -> Vec<(&'static str, Outcome)> {
+        let long_url = |len: usize| {
+            let url = self.url("/?");
+            let padding = len.saturating_sub(url.len());
+            format!("{}{}", url, "a".repeat(padding))
+        };
+        let wrong_port = self.addr.port().checked_add(1).unwrap_or(1);
+
+        vec![
+            ("tls", Some(self.tls_version().await)),
+            ("plaintext", Some(self.plaintext().await)),
+            ("header", Some(self.success(path).await)),
+            (
+                "url-length-limit",
+                Some(
+                    self.status(long_url(MAX_URL_LEN), |s| s != 59, "not 59")
+                        .await,
+                ),
+            ),
+            (
+                "url-too-long",
+                Some(
+                    self.status(long_url(MAX_URL_LEN + 1), |s| s == 59, "59")
+                        .await,
+                ),
+            ),
+            (
+                "empty-request",
+                Some(self.status("", |s| s == 59, "59").await),
+            ),
+            (
+                "relative-url",
+                Some(self.status(path, |s| s == 59, "59").await),
+            ),
+            (
+                "non-utf8-request",
+                Some(
+                    self.raw_status(
+                        [self.url("/").as_bytes(), b"\xff\r\n"].concat(),
+                        |s| s == 59,
+                        "59",
+                    )
+                    .await,
+                ),
+            ),
+            (
+                "percent-encoded-utf8",
+                Some(
+                    self.status(self.url("/%C3%A9-conformance"), |s| s != 59, "not 59")
+                        .await,
+                ),
+            ),
+            (
+                "userinfo",
+                Some(
+                    self.status(
+                        self.url(path).replacen("://", "://user@", 1),
+                        |s| s == 59,
+                        "59",
+                    )
+                    .await,
+                ),
+            ),
+            (
+                "fragment",
+                Some(
+                    self.status(format!("{}#fragment", self.url(path)), |s| s == 59, "59")
+                        .await,
+                ),
+            ),
+            (
+                "other-scheme",
+                Some(
+                    self.status(format!("https://{}{}", self.host, path), |s| s == 53, "53")
+                        .await,
+                ),
+            ),
+            (
+                "foreign-host",
+                Some(
+                    self.status(
+                        format!("gemini://conformance.invalid{}", path),
+                        |s| s == 53,
+                        "53",
+                    )
+                    .await,
+                ),
+            ),
+            (
+                "wrong-port",
+                Some(
+                    self.status(self.url_with_port(path, wrong_port), |s| s == 53, "53")
+                        .await,
+                ),
+            ),
+            (
+                "directory-redirect",
+                match directory {
+                    Some(directory) => Some(self.directory_redirect(directory).await),
+                    None => None,
+                },
+            ),
+        ]
+    }
+
+    /// Sends `request` over TLS and reads the response, checking the header
+    /// grammar and that only successful responses have a body.
+    async fn exchange(&self, request: &[u8]) -> Result<Exchange, String> {
+        self.with_timeout(async {
+            let tcp = client::connect(self.addr).await?;
+            let mut stream = client::connect_tls(tcp, &self.host, None, None).await?;
+            stream
+                .write_all(request)
+                .await
+                .map_err(|e| format!("could not send request: {}", e))?;
+
+            let mut stream = BufReader::new(stream);
+            let mut header = vec![];
+            (&mut stream)
+                .take(client::MAX_HEADER_LEN)
+                .read_until(b'\n', &mut header)
+                .await
+                .map_err(|e| format!("could not read response header: {}", e))?;
+            let (status, meta) = check_header(&header)?;
+
+            let body = tokio::io::copy(&mut stream, &mut tokio::io::sink())
+                .await
+                .map_err(|e| format!("could not read the body: {}", e))?;
+            if status / 10 != 2 && body > 0 {
+                return Err(format!(
+                    "response {} {:?} has a body of {} bytes",
+                    status, meta, body
+                ));
+            }
+            Ok(Exchange { status, meta })
+        })
+        .await
+    }
+
+    async fn with_timeout<T>(
+        &self,
+        future: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        tokio::time::timeout(self.timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(format!("no response within {:?}", self.timeout)))
+    }
+
+    /// Sends `url` as the request and checks the status of the response.
+    async fn status(
+        &self,
+        url: impl AsRef<str>,
+        expected: impl Fn(u8) -> bool,
+        description: &str,
+    ) -> Result<String, String> {
+        let request = format!("{}\r\n", url.as_ref()).into_bytes();
+        self.raw_status(request, expected, description).await
+    }
+
+    /// Like `status`, but sends the bytes of `request` as they are.
+    async fn raw_status(
+        &self,
+        request: Vec<u8>,
+        expected: impl Fn(u8) -> bool,
+        description: &str,
+    ) -> Result<String, String> {
+        let exchange = self.exchange(&request).await?;
+        if expected(exchange.status) {
+            Ok(exchange.to_string())
+        } else {
+            Err(format!("expected status {}, got {}", description, exchange))
+        }
+    }
+
+    /// Checks that a TLS session with TLS 1.2 or later can be established.
+    async fn tls_version(&self) -> Result<String, String> {
+        self.with_timeout(async {
+            let tcp = client::connect(self.addr).await?;
+            let stream = client::connect_tls(tcp, &self.host, None, None).await?;
+            match stream.get_ref().1.get_protocol_version() {
+                Some(ProtocolVersion::TLSv1_3) => Ok("TLS 1.3".into()),
+                Some(ProtocolVersion::TLSv1_2) => Ok("TLS 1.2".into()),
+                version => Err(format!("unexpected TLS version {:?}", version)),
+            }
+        })
+        .await
+    }
+
+    /// Checks that a request without TLS is not answered with a Gemini
+    /// response header.
+    async fn plaintext(&self) -> Result<String, String> {
+        let request = async {
+            let mut tcp = client::connect(self.addr).await?;
+            // errors are expected, since the request is not a TLS handshake
+            let _ = tcp
+                .write_all(format!("{}\r\n", self.url("/")).as_bytes())
+                .await;
+            let mut received = vec![];
+            let _ = tcp.take(3).read_to_end(&mut received).await;
+            Ok::<_, String>(received)
+        };
+        let received = match tokio::time::timeout(self.timeout, request).await {
+            Ok(received) => received?,
+            Err(_) => return Ok("no response".into()),
+        };
+        match &received[..] {
+            [b'1'..=b'6', b'0'..=b'9', b' ' | b'\r'] => {
+                Err("the request was answered without TLS".into())
+            }
+            [] => Ok("connection closed without a response".into()),
+            _ => Ok("no Gemini response".into()),
+        }
+    }
+
+    /// Checks that `path` is answered with a 2x status, and with a MIME type
+    /// for status 20.
+    async fn success(&self, path: &str) -> Result<String, String> {
+        let exchange = self
+            .exchange(format!("{}\r\n", self.url(path)).as_bytes())
+            .await?;
+        if exchange.status / 10 != 2 {
+            return Err(format!("expected status 2x, got {}", exchange));
+        }
+        if exchange.status == 20 && !exchange.meta.is_empty() {
+            let essence = exchange.meta.split(';').next().unwrap().trim();
+            let valid = matches!(
+                essence.split_once('/'),
+                Some((kind, subtype)) if !kind.is_empty() && !subtype.is_empty() && !subtype.contains('/')
+            );
+            if !valid {
+                return Err(format!("{:?} is not a MIME type", exchange.meta));
+            }
+        }
+        Ok(exchange.to_string())
+    }
+
+    /// Checks that `directory` is redirected to the same path with a
+    /// trailing slash, and that this is not redirected again.
+    async fn directory_redirect(&self, directory: &str) -> Result<String, String> {
+        let directory = directory.trim_end_matches('/');
+        let url = Url::parse(&self.url(directory)).unwrap();
+        let expected = Url::parse(&self.url(&format!("{}/", directory))).unwrap();
+
+        let exchange = self.exchange(format!("{}\r\n", url).as_bytes()).await?;
+        if exchange.status / 10 != 3 {
+            return Err(format!("expected status 3x, got {}", exchange));
+        }
+        let target = url
+            .join(&exchange.meta)
+            .map_err(|e| format!("invalid redirect target {:?}: {}", exchange.meta, e))?;
+        if target != expected {
+            return Err(format!("redirected to {}, expected {}", target, expected));
+        }
+
+        let followed = self.exchange(format!("{}\r\n", target).as_bytes()).await?;
+        if followed.status / 10 == 3 {
+            return Err(format!("{} is redirected again with {}", target, followed));
+        }
+        Ok(format!("{}, then {}", exchange, followed))
+    }
+}
+
+/// Checks a response header including its CRLF against the grammar
+/// `<STATUS><SPACE><META><CR><LF>` and returns the status and meta.
+fn check_header(header: &[u8]) -> Result<(u8, String), String> {
+    let line = match header.strip_suffix(b"\r\n") {
+        Some(line) => line,
+        None if header.ends_with(b"\n") => {
+            return Err("header ends with a bare LF instead of CRLF".into())
+        }
+        None => {
+            return Err(format!(
+                "header is not terminated by CRLF within {} bytes",
+                client::MAX_HEADER_LEN
+            ))
+        }
+    };
+    if line.contains(&b'\r') {
+        return Err("header contains a CR before its end".into());
+    }
+    if line.starts_with("\u{feff}".as_bytes()) {
+        return Err("header starts with a byte order mark".into());
+    }
+    let (status, meta) = client::parse_header(header)?;
+    if meta.len() > MAX_META_LEN {
+        return Err(format!(
+            "meta is {} bytes long, at most {} are allowed",
+            meta.len(),
+            MAX_META_LEN
+        ));
+    }
+    Ok((status, meta))
+}
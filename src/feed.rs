@@ -0,0 +1,371 @@
+//! An Atom feed of the most recently changed gemtext files, and generated
+//! indexes of gemlog directories.
+
+use {
+    crate::metadata::{Availability, FileOptions},
+    once_cell::sync::Lazy,
+    percent_encoding::percent_encode,
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fmt::Write as _,
+        fs, io,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, Instant, SystemTime},
+    },
+    tokio::sync::Mutex,
+    url::Url,
+};
+
+/// The number of files in the feed.
+const MAX_ENTRIES: usize = 30;
+
+/// How long the list of recently changed files is reused before the content
+/// directory is walked again.
+const MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// A recently changed file.
+struct Entry {
+    /// The path relative to the content root of the feed.
+    path: PathBuf,
+    title: Option<String>,
+    modified: SystemTime,
+}
+
+/// The recently changed files below a content root and when they were
+/// collected.
+type Snapshot = (Instant, Arc<Vec<Entry>>);
+
+pub(crate) struct Feed {
+    /// The URL path of the feed.
+    path: String,
+    /// The snapshots by content root, i.e. the content directory or the
+    /// directory of a virtual host.
+    cache: Mutex<HashMap<PathBuf, Snapshot>>,
+}
+
+impl Feed {
+    pub fn new(path: String) -> Result<Self, String> {
+        if !path.starts_with('/') {
+            return Err(format!("feed path {:?} does not start with /", path));
+        }
+        Ok(Self {
+            path,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Generates the feed for the files below `root`, with links relative to
+    /// the URL of the feed.
+    pub async fn generate(
+        &self,
+        url: &Url,
+        root: &Path,
+        metadata: Arc<Mutex<FileOptions>>,
+    ) -> String {
+        let entries = {
+            // holding the lock makes concurrent requests wait for one walk
+            let mut cache = self.cache.lock().await;
+            match cache.get(root) {
+                Some((collected, entries)) if collected.elapsed() < MAX_AGE => entries.clone(),
+                _ => {
+                    let dir = root.to_path_buf();
+                    let entries = Arc::new(
+                        tokio::task::spawn_blocking(move || collect(&dir, metadata))
+                            .await
+                            .unwrap(),
+                    );
+                    cache.insert(root.to_path_buf(), (Instant::now(), entries.clone()));
+                    entries
+                }
+            }
+        };
+        render(url, &entries)
+    }
+}
+
+/// Walks the content below `root` and returns the most recently modified
+/// gemtext files that are published.
+fn collect(root: &Path, metadata: Arc<Mutex<FileOptions>>) -> Vec<Entry> {
+    let mut files = vec![];
+    crate::walk_content(root, 0, &mut |path, file_metadata| {
+        if path.extension().is_some_and(|ext| ext == "gmi") {
+            if let Ok(modified) = file_metadata.modified() {
+                files.push((path, modified));
+            }
+        }
+    });
+    files.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+
+    let mut metadata = metadata.blocking_lock();
+    let now = SystemTime::now();
+    files
+        .into_iter()
+        .filter(|(path, _)| is_published(&mut metadata, path, now))
+        .take(MAX_ENTRIES)
+        .map(|(path, modified)| Entry {
+            title: read_title(&path),
+            path: path.strip_prefix(root).unwrap().to_path_buf(),
+            modified,
+        })
+        .collect()
+}
+
+/// Checks that a file is not outside of its publication window.
+fn is_published(metadata: &mut FileOptions, path: &Path, now: SystemTime) -> bool {
+    metadata
+        .schedule(path)
+        .is_none_or(|schedule| schedule.availability(now) == Availability::Published)
+}
+
+/// Returns the first heading of a gemtext file.
+fn read_title(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|text| crate::first_heading(&text))
+}
+
+fn render(url: &Url, entries: &[Entry]) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    let host = url.host_str().unwrap_or_default();
+    let updated = entries
+        .iter()
+        .map(|entry| entry.modified)
+        .max()
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut feed = String::new();
+    // writing to a string can not fail
+    let _ = write!(
+        feed,
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         \x20 <title>Recent changes on {host}</title>\n\
+         \x20 <author><name>{host}</name></author>\n\
+         \x20 <id>{url}</id>\n\
+         \x20 <link rel=\"self\" href=\"{url}\"/>\n\
+         \x20 <link href=\"{root}\"/>\n\
+         \x20 <updated>{updated}</updated>\n",
+        host = escape(host),
+        url = escape(url.as_str()),
+        root = escape(url.join("/").map_or(String::new(), String::from).as_str()),
+        updated = humantime::format_rfc3339_seconds(updated),
+    );
+    for entry in entries {
+        let link = entry
+            .path
+            .components()
+            .map(|c| {
+                crate::file_name_bytes(c.as_os_str())
+                    .map(|name| format!("/{}", percent_encode(name, &crate::ENCODE_SET)))
+            })
+            .collect::<Option<String>>()
+            .and_then(|path| url.join(&path).ok());
+        // such a file could not be requested anyway
+        let link = match link {
+            Some(link) => link,
+            None => continue,
+        };
+        let _ = write!(
+            feed,
+            "  <entry>\n\
+             \x20   <title>{title}</title>\n\
+             \x20   <link href=\"{link}\"/>\n\
+             \x20   <id>{link}</id>\n\
+             \x20   <updated>{updated}</updated>\n\
+             \x20 </entry>\n",
+            title = escape(
+                entry
+                    .title
+                    .as_deref()
+                    .unwrap_or(&entry.path.to_string_lossy())
+            ),
+            link = escape(link.as_str()),
+            updated = humantime::format_rfc3339_seconds(entry.modified),
+        );
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+/// Escapes text for use in XML content and attribute values. Control
+/// characters are not allowed in XML and are removed.
+fn escape(text: &str) -> String {
+    text.replace(|c: char| c.is_control(), "")
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A file in a gemlog directory.
+struct Post {
+    file_name: OsString,
+    /// The date at the start of the file name, if it has one.
+    date: Option<String>,
+    title: Option<String>,
+    is_dir: bool,
+}
+
+/// The files in a gemlog directory and the preamble, with the modification
+/// times of the directory and the marker file they were read at.
+type Gemlog = ((SystemTime, SystemTime), Arc<(String, Vec<Post>)>);
+
+/// The gemlogs read so far, by directory.
+static GEMLOGS: Lazy<Mutex<HashMap<PathBuf, Gemlog>>> = Lazy::new(Default::default);
+
+/// Generates the index of a directory containing a `.gemlog` marker file. The
+/// contents of the marker file come first, then links to the files whose names
+/// start with a date, newest first, and then links to all other files.
+///
+/// The directory is only read again when it or the marker file changed, so
+/// changed headings in existing posts show up once a file is added or removed.
+pub(crate) async fn gemlog_index(
+    dir: &Path,
+    metadata: Arc<Mutex<FileOptions>>,
+) -> io::Result<String> {
+    let marker = dir.join(".gemlog");
+    let modified = (
+        tokio::fs::metadata(dir).await?.modified()?,
+        tokio::fs::metadata(&marker).await?.modified()?,
+    );
+    let gemlog = {
+        // holding the lock makes concurrent requests wait for one read
+        let mut cache = GEMLOGS.lock().await;
+        match cache.get(dir) {
+            Some((cached, gemlog)) if *cached == modified => gemlog.clone(),
+            _ => {
+                let path = dir.to_path_buf();
+                let gemlog = Arc::new(
+                    tokio::task::spawn_blocking(move || read_gemlog(&path))
+                        .await
+                        .unwrap()?,
+                );
+                cache.insert(dir.to_path_buf(), (modified, gemlog.clone()));
+                gemlog
+            }
+        }
+    };
+
+    // publication windows are checked on every request, they do not change
+    // the modification time of the directory
+    let (preamble, posts) = &*gemlog;
+    let mut metadata = metadata.lock().await;
+    let now = SystemTime::now();
+    let posts = posts
+        .iter()
+        .filter(|post| is_published(&mut metadata, &dir.join(&post.file_name), now))
+        .collect::<Vec<_>>();
+    drop(metadata);
+
+    let mut index = preamble.clone();
+    if !index.is_empty() && !index.ends_with('\n') {
+        index.push('\n');
+    }
+    let (dated, other): (Vec<_>, Vec<_>) = posts.into_iter().partition(|post| post.date.is_some());
+    if !index.is_empty() && !dated.is_empty() {
+        index.push('\n');
+    }
+    for post in dated {
+        if let Some(link) = gemlog_link(post) {
+            let title = post.title.as_deref().map_or_else(
+                || post.file_name.to_string_lossy(),
+                std::borrow::Cow::Borrowed,
+            );
+            let _ = writeln!(
+                index,
+                "=> {} {} \u{2014} {}",
+                link,
+                post.date.as_deref().unwrap_or_default(),
+                crate::link_label(&title)
+            );
+        }
+    }
+    if !other.is_empty() {
+        if !index.is_empty() {
+            index.push('\n');
+        }
+        index.push_str("## Other pages\n\n");
+        for post in other {
+            if let Some(link) = gemlog_link(post) {
+                let mut name = post.file_name.to_string_lossy().into_owned();
+                if post.is_dir {
+                    name.push('/');
+                }
+                let _ = match post.title {
+                    Some(ref title) => writeln!(index, "=> {} {}", link, crate::link_label(title)),
+                    // the link and the name are identical
+                    None if link == name => writeln!(index, "=> {}", link),
+                    None => writeln!(index, "=> {} {}", link, crate::link_label(&name)),
+                };
+            }
+        }
+    }
+    Ok(index)
+}
+
+/// Returns the relative link to a file in a gemlog directory.
+fn gemlog_link(post: &Post) -> Option<String> {
+    let name = crate::file_name_bytes(&post.file_name)?;
+    let mut link = percent_encode(name, &crate::ENCODE_SET).to_string();
+    if post.is_dir {
+        link.push('/');
+    }
+    Some(link)
+}
+
+/// Reads the marker file and the files of a gemlog directory, except hidden
+/// ones and the index.
+fn read_gemlog(dir: &Path) -> io::Result<(String, Vec<Post>)> {
+    let preamble = fs::read_to_string(dir.join(".gemlog"))?;
+    let mut posts = vec![];
+    for (count, entry) in fs::read_dir(dir)?.flatten().enumerate() {
+        if count >= crate::ARGS.listing_max_entries {
+            return Err(crate::TooManyEntries::error());
+        }
+        let file_name = entry.file_name();
+        let name = file_name.to_string_lossy();
+        if name.starts_with('.') || name == "index.gmi" {
+            continue;
+        }
+        // symlinks are followed, dangling ones are left out
+        let is_dir = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata.is_dir(),
+            Err(_) => continue,
+        };
+        let is_gemtext = !is_dir && name.ends_with(".gmi");
+        posts.push(Post {
+            date: is_gemtext.then(|| post_date(&name)).flatten(),
+            title: is_gemtext.then(|| read_title(&entry.path())).flatten(),
+            file_name,
+            is_dir,
+        });
+    }
+    // dated posts newest first, the other files by name
+    posts.sort_by(|a, b| {
+        b.date.cmp(&a.date).then_with(|| match a.date {
+            Some(_) => b.file_name.cmp(&a.file_name),
+            None => a.file_name.cmp(&b.file_name),
+        })
+    });
+    Ok((preamble, posts))
+}
+
+/// Returns the date a file name starts with, like `2024-05-01-title.gmi`.
+fn post_date(name: &str) -> Option<String> {
+    let date = name.get(..10)?;
+    let rest = &name[10..];
+    let bytes = date.as_bytes();
+    let is_date = bytes.iter().enumerate().all(|(i, b)| match i {
+        4 | 7 => *b == b'-',
+        _ => b.is_ascii_digit(),
+    }) && matches!(date[5..7].parse(), Ok(1..=12))
+        && matches!(date[8..].parse(), Ok(1..=31));
+    (is_date && (rest.starts_with('-') || rest.starts_with('.'))).then(|| date.to_string())
+}
@@ -0,0 +1,412 @@
+//! A minimal HTTP/1.1 listener for web browsers following links to the
+//! capsule, enabled with `--http-gateway` and the `http-gateway` feature.
+//!
+//! Each HTTP request is turned into a Gemini request for the same host and
+//! path, which is answered by the same code as requests over TLS. The Gemini
+//! response is then converted to HTTP, with gemtext rendered as HTML.
+
+use {
+    crate::{metadata::FileOptions, RequestHandle, ARGS},
+    std::{fmt::Write as _, io, net::SocketAddr, sync::Arc},
+    tokio::{
+        io::{
+            AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+            BufReader,
+        },
+        net::TcpStream,
+        sync::Mutex,
+    },
+    url::Url,
+};
+
+/// The maximum length of the request line and headers. Browsers send more
+/// headers than needed, but not nearly this much.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// The maximum length of a Gemini response header, including CR and LF.
+const MAX_HEADER_LEN: usize = 1024 + 5;
+
+/// The maximum length of a gemtext page that is rendered as HTML. The whole
+/// page is kept in memory while rendering it.
+const MAX_PAGE_LEN: u64 = 1024 * 1024;
+
+/// Answers a single HTTP request on the connection and closes it. Returns the
+/// access log line of the Gemini request, if there was one.
+pub(crate) async fn serve(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    local: SocketAddr,
+    metadata: Arc<Mutex<FileOptions>>,
+) -> Option<crate::AccessLog> {
+    let request = match read_request(&mut stream).await {
+        Ok(request) => request,
+        Err((status, reason)) => {
+            let _ = send_error(&mut stream, status, reason, reason).await;
+            return None;
+        }
+    };
+
+    let (gemini, relay) = tokio::io::duplex(crate::transfer::DEFAULT_BUFFER_SIZE);
+    let handle =
+        RequestHandle::plaintext(gemini, metadata, format!("http:{}", local), Some(peer.ip()));
+    let forward = async move {
+        let mut relay = BufReader::new(relay);
+        relay.get_mut().write_all(request.url.as_bytes()).await?;
+        relay.get_mut().write_all(b"\r\n").await?;
+        respond(&mut stream, &mut relay, &request).await?;
+        stream.shutdown().await
+    };
+    let (log, result) = tokio::join!(handle.handle(), forward);
+    if let Err(e) = result {
+        tracing::debug!("could not send HTTP response: {}", e);
+    }
+    Some(log)
+}
+
+/// The parts of an HTTP request that are needed to answer it.
+struct Request {
+    /// Whether only the headers should be sent.
+    head: bool,
+    /// The Gemini URL with the same host and path.
+    url: String,
+}
+
+/// Reads the request line and headers. Only `GET` and `HEAD` requests in
+/// origin form, i.e. for a path on this server, are accepted.
+async fn read_request(stream: &mut TcpStream) -> std::result::Result<Request, (u16, &'static str)> {
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_LEN as u64);
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line).await {
+            Ok(_) if line.ends_with('\n') => (),
+            Ok(_) if reader.limit() == 0 => return Err((431, "Request Header Fields Too Large")),
+            Ok(_) => return Err((400, "Bad Request")),
+            Err(_) => return Err((400, "Bad Request")),
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().ok_or((400, "Bad Request"))?;
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err((400, "Bad Request")),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err((505, "HTTP Version Not Supported"));
+    }
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return Err((405, "Method Not Allowed")),
+    };
+    // absolute URLs would be a request to act as a proxy
+    if !target.starts_with('/') || target.contains('#') {
+        return Err((400, "Bad Request"));
+    }
+
+    let host = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("host")
+                .then(|| value.trim().to_string())
+        })
+        .next()
+        .ok_or((400, "Bad Request"))?;
+    // only keep the host name of the header, without a port
+    let host = Url::parse(&format!("http://{}/", host))
+        .ok()
+        .filter(|url| url.path() == "/" && url.username().is_empty() && url.password().is_none())
+        .and_then(|url| url.host_str().map(str::to_string))
+        .ok_or((400, "Bad Request"))?;
+
+    Ok(Request {
+        head,
+        url: format!("gemini://{}{}", host, target),
+    })
+}
+
+/// Reads the Gemini response for the request and sends it as an HTTP response.
+async fn respond<W, R>(stream: &mut W, relay: &mut R, request: &Request) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncBufRead + AsyncRead + Unpin,
+{
+    let mut header = Vec::new();
+    (&mut *relay)
+        .take(MAX_HEADER_LEN as u64)
+        .read_until(b'\n', &mut header)
+        .await?;
+    let header = String::from_utf8_lossy(&header);
+    let header = header.trim_end_matches(['\r', '\n']);
+    let (status, meta) = header.split_once(' ').unwrap_or((header, ""));
+    let status = status.parse::<u8>().unwrap_or(0);
+
+    match status {
+        20..=29 if meta.starts_with("text/gemini") => {
+            let mut body = Vec::new();
+            (&mut *relay)
+                .take(MAX_PAGE_LEN + 1)
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() as u64 > MAX_PAGE_LEN {
+                return send_error(
+                    stream,
+                    502,
+                    "Bad Gateway",
+                    "This page is too large to be shown here, please open it with a Gemini client.",
+                )
+                .await;
+            }
+            let html = render(&String::from_utf8_lossy(&body), meta, &request.url);
+            send_head(stream, 200, "OK", "text/html; charset=utf-8").await?;
+            if !request.head {
+                stream.write_all(html.as_bytes()).await?;
+            }
+            Ok(())
+        }
+        20..=29 => {
+            send_head(stream, 200, "OK", meta).await?;
+            if !request.head {
+                crate::transfer::copy(relay, stream, &mut 0, None, None).await?;
+            }
+            Ok(())
+        }
+        30..=39 => {
+            // only redirects within the capsule are followed by the browser,
+            // for anything else the Gemini URL is shown
+            let base = Url::parse(&request.url).ok();
+            match base.as_ref().and_then(|base| base.join(meta).ok()) {
+                Some(target)
+                    if target.scheme() == "gemini"
+                        && target.host_str() == base.as_ref().and_then(Url::host_str) =>
+                {
+                    let location = match target.query() {
+                        Some(query) => format!("{}?{}", target.path(), query),
+                        None => target.path().to_string(),
+                    };
+                    let code = if status == 31 { 301 } else { 302 };
+                    let reason = if status == 31 {
+                        "Moved Permanently"
+                    } else {
+                        "Found"
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {} {}\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        code, reason, location
+                    );
+                    stream.write_all(head.as_bytes()).await
+                }
+                _ => {
+                    send_error(
+                        stream,
+                        502,
+                        "Bad Gateway",
+                        "The page has moved to another capsule.",
+                    )
+                    .await
+                }
+            }
+        }
+        10..=19 => {
+            send_error(
+                stream,
+                501,
+                "Not Implemented",
+                "This page asks for input, please open it with a Gemini client.",
+            )
+            .await
+        }
+        44 => send_error(stream, 429, "Too Many Requests", meta).await,
+        51 => send_error(stream, 404, "Not Found", meta).await,
+        52 => send_error(stream, 410, "Gone", meta).await,
+        53 => send_error(stream, 421, "Misdirected Request", meta).await,
+        59 => send_error(stream, 400, "Bad Request", meta).await,
+        60..=69 => {
+            send_error(
+                stream,
+                403,
+                "Forbidden",
+                "This page requires a client certificate, please open it with a Gemini client.",
+            )
+            .await
+        }
+        _ => send_error(stream, 502, "Bad Gateway", meta).await,
+    }
+}
+
+async fn send_head<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    code: u16,
+    reason: &str,
+    content_type: &str,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+        code, reason, content_type
+    );
+    stream.write_all(head.as_bytes()).await
+}
+
+async fn send_error<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    code: u16,
+    reason: &str,
+    message: &str,
+) -> io::Result<()> {
+    send_head(stream, code, reason, "text/plain; charset=utf-8").await?;
+    stream.write_all(message.as_bytes()).await?;
+    stream.write_all(b"\n").await
+}
+
+/// The URL a Gemini client would use for the page, with the port of the TLS
+/// listener if it is not the default one.
+fn native_url(url: &str) -> String {
+    let port = match (ARGS.public_port, &ARGS.insecure_listener) {
+        (Some(port), _) => port,
+        (None, Some(_)) => 1965,
+        (None, None) => ARGS.addrs.first().map_or(1965, SocketAddr::port),
+    };
+    match Url::parse(url) {
+        Ok(mut url) if port != 1965 => {
+            let _ = url.set_port(Some(port));
+            url.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Renders a gemtext document as HTML, with a banner linking to the page in
+/// Gemini space.
+fn render(gemtext: &str, meta: &str, url: &str) -> String {
+    let lang = meta
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.trim().strip_prefix("lang="))
+        .next();
+    let native = native_url(url);
+    let title = gemtext
+        .lines()
+        .find_map(|line| line.strip_prefix('#').filter(|rest| !rest.starts_with('#')))
+        .map_or(native.as_str(), str::trim);
+
+    let mut html = String::with_capacity(gemtext.len() * 2);
+    html.push_str("<!DOCTYPE html>\n");
+    match lang {
+        Some(lang) => {
+            let _ = writeln!(html, "<html lang=\"{}\">", escape(lang));
+        }
+        None => html.push_str("<html>\n"),
+    }
+    let _ = writeln!(
+        html,
+        "<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" content=\"width=device-width\">\n<title>{}</title>\n</head>\n<body>",
+        escape(title)
+    );
+    let _ = writeln!(
+        html,
+        "<p class=\"gateway\">This page is served for the web from Gemini space. Open <a href=\"{0}\">{0}</a> with a Gemini client to view it natively.</p>",
+        escape(&native)
+    );
+
+    let mut preformatted = false;
+    let mut list = false;
+    for line in gemtext.lines() {
+        if preformatted {
+            if line.starts_with("```") {
+                html.push_str("</pre>\n");
+                preformatted = false;
+            } else {
+                html.push_str(&escape(line));
+                html.push('\n');
+            }
+            continue;
+        }
+        let item = line.strip_prefix("* ");
+        if list && item.is_none() {
+            html.push_str("</ul>\n");
+            list = false;
+        }
+        if let Some(alt) = line.strip_prefix("```") {
+            let _ = write!(html, "<pre aria-label=\"{}\">", escape(alt.trim()));
+            preformatted = true;
+        } else if let Some(item) = item {
+            if !list {
+                html.push_str("<ul>\n");
+                list = true;
+            }
+            let _ = writeln!(html, "<li>{}</li>", escape(item));
+        } else if let Some(link) = line.strip_prefix("=>") {
+            let link = link.trim_start();
+            let (target, label) = link
+                .split_once(char::is_whitespace)
+                .map_or((link, ""), |(target, label)| (target, label.trim()));
+            let label = if label.is_empty() { target } else { label };
+            if safe_link(target) {
+                let _ = writeln!(
+                    html,
+                    "<p><a href=\"{}\">{}</a></p>",
+                    escape(target),
+                    escape(label)
+                );
+            } else {
+                let _ = writeln!(html, "<p>{}</p>", escape(label));
+            }
+        } else if let Some(heading) = line.strip_prefix("###") {
+            let _ = writeln!(html, "<h3>{}</h3>", escape(heading.trim()));
+        } else if let Some(heading) = line.strip_prefix("##") {
+            let _ = writeln!(html, "<h2>{}</h2>", escape(heading.trim()));
+        } else if let Some(heading) = line.strip_prefix('#') {
+            let _ = writeln!(html, "<h1>{}</h1>", escape(heading.trim()));
+        } else if let Some(quote) = line.strip_prefix('>') {
+            let _ = writeln!(html, "<blockquote>{}</blockquote>", escape(quote.trim()));
+        } else if line.trim().is_empty() {
+            html.push_str("<br>\n");
+        } else {
+            let _ = writeln!(html, "<p>{}</p>", escape(line));
+        }
+    }
+    if preformatted {
+        html.push_str("</pre>\n");
+    }
+    if list {
+        html.push_str("</ul>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Checks that a link can not run scripts in the browser, i.e. it is relative
+/// or uses a scheme that only navigates somewhere else.
+fn safe_link(target: &str) -> bool {
+    match Url::parse(target) {
+        Ok(url) => matches!(
+            url.scheme(),
+            "gemini" | "http" | "https" | "gopher" | "finger" | "spartan" | "mailto"
+        ),
+        Err(url::ParseError::RelativeUrlWithoutBase) => true,
+        Err(_) => false,
+    }
+}
+
+/// Escapes text for use in HTML, also in attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
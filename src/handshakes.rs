@@ -0,0 +1,146 @@
+//! Dropping connections from IP addresses that failed many TLS handshakes,
+//! e.g. scanners speaking plain HTTP, before attempting another handshake.
+
+use {
+    crate::{stats::HandshakeFailure, tracked::Tracked},
+    std::{
+        net::IpAddr,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+/// The maximum number of IP addresses that are tracked at the same time, so
+/// that many clients cannot make agate use too much memory.
+const MAX_TRACKED: usize = 64 * 1024;
+
+/// What is known about the handshakes of one IP address.
+struct Client {
+    /// When the current period of counting failures started.
+    start: Instant,
+    failures: u32,
+    /// Until when connections are dropped.
+    blocked_until: Option<Instant>,
+    /// Whether a handshake succeeded, e.g. because the address is shared by
+    /// many clients behind NAT. Such addresses are never blocked.
+    succeeded: bool,
+}
+
+pub(crate) struct HandshakeLimit {
+    failures: u32,
+    period: Duration,
+    cooldown: Duration,
+    /// The tracked IP addresses.
+    clients: Mutex<Tracked<IpAddr, Client>>,
+}
+
+impl HandshakeLimit {
+    /// Creates a limit from `FAILURES/PERIOD`, e.g. `10/1m`, and the time
+    /// connections are dropped for. The period is parsed by [`humantime`].
+    pub fn new(limit: &str, cooldown: Duration) -> Result<Self, String> {
+        let (failures, period) = limit.split_once('/').ok_or_else(|| {
+            format!(
+                "handshake limit {:?} is not in the form FAILURES/PERIOD",
+                limit
+            )
+        })?;
+        let failures = failures.parse().map_err(|e| {
+            format!(
+                "invalid number of failures in handshake limit {:?}: {}",
+                limit, e
+            )
+        })?;
+        let period = humantime::parse_duration(period)
+            .map_err(|e| format!("invalid period in handshake limit {:?}: {}", limit, e))?;
+        if failures == 0 || period.is_zero() {
+            return Err(format!(
+                "the handshake limit {:?} has to allow at least one failure in a period longer than zero",
+                limit
+            ));
+        }
+        Ok(Self {
+            failures,
+            period,
+            cooldown,
+            clients: Mutex::new(Tracked::new(MAX_TRACKED)),
+        })
+    }
+
+    /// Checks if a new connection from `ip` should be handled.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        match clients.get_mut(&ip) {
+            Some(client) => match client.blocked_until {
+                Some(until) if until > now => false,
+                Some(_) => {
+                    client.blocked_until = None;
+                    client.failures = 0;
+                    client.start = now;
+                    true
+                }
+                None => true,
+            },
+            None => true,
+        }
+    }
+
+    /// Counts a failed handshake, and starts dropping connections from `ip`
+    /// if there were too many.
+    pub fn record_failure(&self, ip: IpAddr, failure: HandshakeFailure) {
+        // clients may close the connection on purpose, e.g. when the user
+        // does not trust the certificate
+        if failure == HandshakeFailure::PeerReset {
+            return;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let client = Self::entry(&mut clients, ip, now);
+        if client.succeeded {
+            return;
+        }
+        if now.duration_since(client.start) >= self.period {
+            client.start = now;
+            client.failures = 0;
+        }
+        client.failures += 1;
+        if client.failures >= self.failures && client.blocked_until.is_none() {
+            client.blocked_until = Some(now + self.cooldown);
+            tracing::info!(
+                "Dropping connections from {} for {} after {} failed TLS handshakes",
+                if crate::ARGS.log_ips {
+                    ip.to_string()
+                } else {
+                    "a client".to_string()
+                },
+                humantime::format_duration(self.cooldown),
+                client.failures
+            );
+        }
+    }
+
+    /// Remembers that a handshake from `ip` succeeded.
+    pub fn record_success(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let client = Self::entry(&mut clients, ip, now);
+        client.succeeded = true;
+        client.failures = 0;
+        client.blocked_until = None;
+    }
+
+    /// Returns the entry of `ip`. If the table is full, blocked clients are
+    /// the last ones to stop being tracked.
+    fn entry(clients: &mut Tracked<IpAddr, Client>, ip: IpAddr, now: Instant) -> &mut Client {
+        clients.entry(
+            ip,
+            |_, client| client.blocked_until.is_some_and(|until| until > now),
+            || Client {
+                start: now,
+                failures: 0,
+                blocked_until: None,
+                succeeded: false,
+            },
+        )
+    }
+}
@@ -0,0 +1,149 @@
+//! Counting successful responses per file, kept across restarts in a file.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// The number of different files counted. When it is reached, the file with
+/// the fewest hits is forgotten to make room for a new one.
+const MAX_PATHS: usize = 10_000;
+
+/// How often the counts are written to the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) struct HitCounts {
+    /// The file the counts are saved to, as lines of a count, a tab and a path.
+    file: PathBuf,
+    /// The number of hits by path relative to the content directory.
+    counts: Mutex<HashMap<String, u64>>,
+    /// Whether there are hits that were not written to the file yet.
+    dirty: AtomicBool,
+    /// Held while writing, so the periodic flush and the one on shutdown do
+    /// not write the temporary file at the same time.
+    writing: tokio::sync::Mutex<()>,
+}
+
+impl HitCounts {
+    /// Reads the counts saved before. A missing file is not an error, so
+    /// counting can start from zero.
+    pub fn load(file: PathBuf) -> Result<Self, String> {
+        let mut counts = vec![];
+        match fs::read_to_string(&file) {
+            Ok(text) => {
+                for (number, line) in text.lines().enumerate() {
+                    let parsed = line
+                        .split_once('\t')
+                        .and_then(|(count, path)| Some((path, count.parse::<u64>().ok()?)));
+                    match parsed {
+                        Some((path, count)) => counts.push((path.to_string(), count)),
+                        None => tracing::warn!(
+                            "Ignoring invalid line {} in stats file {:?}",
+                            number + 1,
+                            file
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(format!("Could not read stats file {:?}: {}", file, e)),
+        }
+        // the file might have been written with a larger limit
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        counts.truncate(MAX_PATHS);
+        Ok(Self {
+            file,
+            counts: Mutex::new(counts.into_iter().collect()),
+            dirty: AtomicBool::new(false),
+            writing: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Counts a successful response for a file, given by its content path.
+    pub fn record(&self, path: &Path) {
+        let path = match path.strip_prefix(&crate::ARGS.content_dir) {
+            Ok(path) => path.to_string_lossy(),
+            Err(_) => return,
+        };
+        // such paths could not be read back from the file
+        if path.contains(char::is_control) {
+            return;
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(path.as_ref()) {
+            *count += 1;
+        } else {
+            if counts.len() >= MAX_PATHS {
+                let least = counts
+                    .iter()
+                    .min_by_key(|(_, count)| **count)
+                    .map(|(path, _)| path.clone());
+                if let Some(least) = least {
+                    counts.remove(&least);
+                }
+            }
+            counts.insert(path.into_owned(), 1);
+        }
+        self.dirty.store(true, Relaxed);
+    }
+
+    /// Writes the counts to the file if they changed. The file is replaced
+    /// atomically, so it is never left half-written.
+    pub async fn flush(&'static self) {
+        let _writing = self.writing.lock().await;
+        if !self.dirty.swap(false, Relaxed) {
+            return;
+        }
+        let mut sorted = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, count)| (path.clone(), *count))
+            .collect::<Vec<_>>();
+        sorted.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+
+        let result = tokio::task::spawn_blocking(move || self.write(&sorted))
+            .await
+            .unwrap();
+        if let Err(e) = result {
+            tracing::error!("Could not write stats file {:?}: {}", self.file, e);
+            // try again next time
+            self.dirty.store(true, Relaxed);
+        }
+    }
+
+    fn write(&self, sorted: &[(String, u64)]) -> io::Result<()> {
+        let mut temp = self.file.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = io::BufWriter::new(fs::File::create(&temp)?);
+        for (path, count) in sorted {
+            writeln!(file, "{}\t{}", count, path)?;
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&temp, &self.file)
+    }
+
+    /// Writes the counts to the file regularly, never returns.
+    pub async fn flush_periodically(&'static self) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Finding out how a request for a directory is answered, while looking at
+//! the file system as little as possible. The index file is always looked
+//! for, but how directories without one are served is remembered for a short
+//! time.
+
+use {
+    crate::watch::{self, Invalidate},
+    once_cell::sync::Lazy,
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of directories remembered. When it is reached, all of them are
+/// forgotten.
+const MAX_ENTRIES: usize = 1024;
+
+/// How long it is remembered how a directory is served, so that adding or
+/// removing a marker file takes effect soon after. This is longer if changes
+/// are watched.
+const TTL: Duration = Duration::from_secs(2);
+
+pub(crate) static FALLBACKS: Lazy<Fallbacks> = Lazy::new(Fallbacks::default);
+
+/// How directories without an index file are served, and when that was
+/// checked.
+#[derive(Default)]
+pub(crate) struct Fallbacks(Mutex<HashMap<PathBuf, (DirIndex, Instant)>>);
+
+impl Invalidate for Fallbacks {
+    fn invalidate(&self, path: &Path) {
+        // a marker file changes how its directory is served
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|dir, _| !dir.starts_with(path) && path.parent() != Some(dir));
+    }
+
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// How a request for a directory is answered.
+#[derive(Clone, Copy)]
+pub(crate) enum DirIndex {
+    /// The `index.gmi` file with the given size is served.
+    File(u64),
+    /// A gemlog index is generated because there is a `.gemlog` file.
+    Gemlog,
+    /// The directory is listed because there is a `.directory-listing-ok`
+    /// file, or listing is enabled for the requested host name.
+    Listing,
+    /// There is no index file and the directory may not be listed.
+    Disabled,
+}
+
+/// Looks for the index file of `dir`, or how `dir` was served recently if
+/// it has none. Returns `None` if neither is known, e.g. because `dir` does
+/// not exist or is not a directory.
+pub(crate) async fn find(dir: &Path) -> Option<DirIndex> {
+    if let Some((_, len)) = crate::content_metadata(&dir.join("index.gmi")).await {
+        return Some(DirIndex::File(len));
+    }
+    match FALLBACKS.0.lock().unwrap().get(dir) {
+        Some(&(index, created)) if created.elapsed() < watch::ttl(TTL) => Some(index),
+        _ => None,
+    }
+}
+
+/// Checks how the directory `dir` is served when it has no index file, and
+/// remembers it.
+pub(crate) async fn fallback(dir: &Path) -> DirIndex {
+    let index = if crate::ARGS.archive.is_none()
+        && crate::content_metadata(&dir.join(".gemlog"))
+            .await
+            .is_some()
+    {
+        DirIndex::Gemlog
+    } else if crate::content_metadata(&dir.join(".directory-listing-ok"))
+        .await
+        .is_some()
+    {
+        DirIndex::Listing
+    } else {
+        DirIndex::Disabled
+    };
+
+    let mut fallbacks = FALLBACKS.0.lock().unwrap();
+    if fallbacks.len() >= MAX_ENTRIES {
+        fallbacks.clear();
+    }
+    fallbacks.insert(dir.to_path_buf(), (index, Instant::now()));
+    index
+}
+
+/// Forgets how directories are served, e.g. because of a reload.
+pub(crate) fn clear() {
+    FALLBACKS.clear();
+}
@@ -0,0 +1,84 @@
+//! The meta strings of successful responses, i.e. MIME types with their
+//! parameters. Most capsules only send a handful of different ones, so each is
+//! formatted once and reused instead of allocating a new string for every
+//! response.
+
+use {
+    once_cell::sync::Lazy,
+    std::{
+        borrow::Cow,
+        collections::{hash_map::DefaultHasher, HashMap},
+        hash::{Hash, Hasher},
+        sync::{PoisonError, RwLock},
+    },
+};
+
+/// The number of different meta strings that are kept. Once it is reached,
+/// other meta strings are formatted for each response, so unusual
+/// combinations can not make the memory use grow without bounds.
+const MAX_INTERNED: usize = 1024;
+
+#[derive(Default)]
+struct Interned {
+    /// The meta strings by the hash of the parts they were made of. They are
+    /// leaked, so they can be sent and logged like string literals, but there
+    /// are at most `MAX_INTERNED` of them.
+    by_hash: HashMap<u64, Vec<&'static str>>,
+    count: usize,
+}
+
+impl Interned {
+    fn find(&self, hash: u64, parts: &[&str]) -> Option<&'static str> {
+        self.by_hash
+            .get(&hash)?
+            .iter()
+            .copied()
+            .find(|meta| is_concat(meta, parts))
+    }
+}
+
+/// Almost all calls find their meta string, so they only need to share a read
+/// lock with the other connections.
+static INTERNED: Lazy<RwLock<Interned>> = Lazy::new(Default::default);
+
+/// Returns the concatenation of `parts`, without allocating if it was
+/// returned before.
+pub(crate) fn meta(parts: &[&str]) -> Cow<'static, str> {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    let interned = INTERNED.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some(meta) = interned.find(hash, parts) {
+        return Cow::Borrowed(meta);
+    }
+    drop(interned);
+
+    let mut interned = INTERNED.write().unwrap_or_else(PoisonError::into_inner);
+    // another connection may have added it while the lock was released
+    if let Some(meta) = interned.find(hash, parts) {
+        return Cow::Borrowed(meta);
+    }
+    let meta = parts.concat();
+    if interned.count >= MAX_INTERNED {
+        return Cow::Owned(meta);
+    }
+    let meta: &'static str = Box::leak(meta.into_boxed_str());
+    interned.by_hash.entry(hash).or_default().push(meta);
+    interned.count += 1;
+    Cow::Borrowed(meta)
+}
+
+/// Checks whether `s` consists of exactly `parts`, one after the other.
+fn is_concat(s: &str, parts: &[&str]) -> bool {
+    // compared as bytes, str::strip_prefix goes through the pattern matching
+    // machinery, which takes several times as long for these short strings
+    let mut rest = s.as_bytes();
+    for part in parts {
+        match rest.strip_prefix(part.as_bytes()) {
+            Some(after) => rest = after,
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
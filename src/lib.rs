@@ -0,0 +1,4814 @@
+#![forbid(unsafe_code)]
+
+mod archive;
+#[doc(hidden)]
+pub mod bench;
+mod certificates;
+mod client;
+mod clients;
+mod conformance;
+mod feed;
+#[cfg(feature = "http-gateway")]
+mod gateway;
+mod handshakes;
+mod hits;
+mod indexes;
+mod interned;
+mod logging;
+mod metadata;
+mod mirror;
+mod misses;
+mod offload;
+mod ping;
+mod ratelimit;
+mod resolve;
+mod search;
+mod selftest;
+mod sniff;
+mod stats;
+mod status;
+mod tracked;
+mod transfer;
+mod validate;
+mod variants;
+mod warmup;
+mod watch;
+mod welcome;
+use archive::Archive;
+use certificates::CertStore;
+use clients::{Client, ClientRegistry};
+use feed::Feed;
+use handshakes::HandshakeLimit;
+use hits::HitCounts;
+use indexes::DirIndex;
+use logging::{AccessLog, AccessLogTarget, Dedup, LogTime};
+use metadata::{Availability, FileOptions, PresetMeta};
+use mirror::Mirror;
+use ratelimit::RateLimit;
+use search::Search;
+
+use {
+    arc_swap::ArcSwap,
+    once_cell::sync::{Lazy, OnceCell},
+    percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS},
+    rcgen::{Certificate, CertificateParams, DnType},
+    rustls::{
+        sign::CertifiedKey, ClientCertVerified, ClientCertVerifier, ClientHello,
+        DistinguishedNames, NoClientAuth, ResolvesServerCert, ServerConfig, TLSError,
+    },
+    std::{
+        borrow::Cow,
+        cell::Cell,
+        collections::{BTreeMap, HashMap, HashSet},
+        error::Error,
+        ffi::{OsStr, OsString},
+        fmt::{Display, Formatter},
+        fs::{self, File},
+        io::Write as _,
+        net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+        ops::Deref,
+        path::{self, Component, Path, PathBuf},
+        process::ExitCode,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        io::{
+            AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+            BufReader,
+        },
+        net::{TcpListener, TcpStream},
+        runtime::Runtime,
+        sync::{Mutex, Semaphore, SemaphorePermit},
+    },
+    tokio_rustls::{server::TlsStream, TlsAcceptor},
+    tracing::{field::display, Instrument, Span},
+    url::{Host, Position, Url},
+};
+
+/// Runs agate with the command line arguments of the process, which is all
+/// the binary does.
+pub fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("ping") {
+        // the health check does not need any of the server configuration
+        return ExitCode::from(ping::run(&args[0], &args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("conformance") {
+        return ExitCode::from(conformance::run(&args[0], &args[2..]));
+    }
+
+    logging::init();
+
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run() -> Result<(), StartupError> {
+    ARGS.0
+        .set(args(std::env::args().collect())?)
+        .unwrap_or_else(|_| unreachable!("arguments parsed twice"));
+
+    if ARGS.validate_content {
+        return validate::run().map_err(StartupError::Content);
+    }
+
+    if let Some(ref url) = ARGS.resolve_url {
+        resolve::run(url);
+        return Ok(());
+    }
+
+    if !ARGS.debug_hosts.is_empty() || !ARGS.debug_paths.is_empty() {
+        logging::debug_requests();
+    }
+
+    if let Some(ref search) = ARGS.search {
+        search.rebuild();
+    }
+
+    let runtime = Runtime::new().map_err(|e| StartupError::Other(e.to_string()))?;
+    runtime.block_on(async {
+        let mimetypes = Arc::new(Mutex::new(FileOptions::new(PresetMeta::Parameters(
+            default_params(),
+        ))));
+        let listener = TcpListener::bind(&ARGS.addrs[..]).await.map_err(|e| {
+            StartupError::Bind(format!("Could not listen on {:?}: {}", ARGS.addrs, e))
+        })?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|e| StartupError::Bind(e.to_string()))?;
+        let offload =
+            match ARGS.insecure_listener {
+                Some(ref addr) => Some(addr.bind().await.map_err(|e| {
+                    StartupError::Bind(format!("Could not listen on {}: {}", addr, e))
+                })?),
+                None => None,
+            };
+        #[cfg(feature = "http-gateway")]
+        let gateway =
+            match ARGS.http_gateway {
+                Some(addr) => Some(TcpListener::bind(addr).await.map_err(|e| {
+                    StartupError::Bind(format!("Could not listen on {}: {}", addr, e))
+                })?),
+                None => None,
+            };
+        log_summary();
+        welcome::check();
+        watch::subscribe(&*misses::MISSES);
+        watch::subscribe(&*indexes::FALLBACKS);
+        watch::start();
+        if ARGS.warm_up {
+            warmup::run(&mimetypes).await;
+        }
+        stats::STATS.start();
+        if let Some(ref addr) = ARGS.insecure_listener {
+            tracing::warn!("Accepting plaintext requests from a TLS relay on {}", addr);
+        }
+        // the signals have to be handled before telling anyone that agate
+        // is running, since they would terminate it otherwise
+        #[cfg(unix)]
+        let (mut usr2, mut hangup) = {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            (
+                signal(SignalKind::user_defined2()).expect("could not listen for SIGUSR2"),
+                signal(SignalKind::hangup()).expect("could not listen for SIGHUP"),
+            )
+        };
+
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            while usr2.recv().await.is_some() {
+                tracing::info!("{}", stats::STATS);
+                transfer::log_active();
+            }
+        });
+
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                // a panic while reloading must not end this task, or later
+                // signals would not be handled anymore
+                match tokio::task::spawn_blocking(reload).await {
+                    Ok(Ok(())) => tracing::info!("Reloaded host names and certificates."),
+                    Ok(Err(e)) => {
+                        tracing::error!("Could not reload: {}; keeping the old configuration.", e)
+                    }
+                    Err(e) => {
+                        tracing::error!("Reloading failed: {}; keeping the old configuration.", e)
+                    }
+                }
+                if let Some(ref search) = ARGS.search {
+                    if let Err(e) = tokio::task::spawn_blocking(move || search.rebuild()).await {
+                        tracing::error!("Rebuilding the search index failed: {}", e);
+                    }
+                }
+            }
+        });
+
+        let serve_offload = async {
+            let listener = match offload {
+                Some(listener) => listener,
+                None => return std::future::pending().await,
+            };
+            loop {
+                let stream = listener
+                    .accept()
+                    .await
+                    .map_err(|e| StartupError::Other(e.to_string()))?;
+                let arc = mimetypes.clone();
+                let span = logging::connection_span();
+                tokio::spawn(
+                    async move {
+                        let _open = stats::STATS.open_connection();
+                        let local_addr =
+                            format!("offload:{}", ARGS.insecure_listener.as_ref().unwrap());
+                        let log = RequestHandle::plaintext(stream, arc, local_addr, None)
+                            .handle()
+                            .await;
+                        match ARGS.log_dedup {
+                            // all requests come from the relay
+                            Some(ref dedup) => dedup.emit(&log, Ipv6Addr::LOCALHOST.into()),
+                            None => log.emit(),
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        };
+        let serve = async {
+            loop {
+                let (stream, peer) = listener
+                    .accept()
+                    .await
+                    .map_err(|e| StartupError::Other(e.to_string()))?;
+                if let Some(ref limit) = ARGS.handshake_limit {
+                    if !limit.allows(peer.ip()) {
+                        // without a handshake, so it costs as little as possible
+                        stats::STATS.record_dropped_connection();
+                        continue;
+                    }
+                }
+                let arc = mimetypes.clone();
+                let span = logging::connection_span();
+                tokio::spawn(
+                    async move {
+                        let _open = stats::STATS.open_connection();
+                        let log = match RequestHandle::new(stream, arc).await {
+                            Ok(handle) => handle.handle().await,
+                            Err(log) => log,
+                        };
+                        match ARGS.log_dedup {
+                            Some(ref dedup) => dedup.emit(&log, peer.ip()),
+                            None => log.emit(),
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        };
+
+        #[cfg(feature = "http-gateway")]
+        let serve_gateway = async {
+            let listener = match gateway {
+                Some(listener) => listener,
+                None => return std::future::pending().await,
+            };
+            loop {
+                let (stream, peer) = listener
+                    .accept()
+                    .await
+                    .map_err(|e| StartupError::Other(e.to_string()))?;
+                let local = stream
+                    .local_addr()
+                    .map_err(|e| StartupError::Other(e.to_string()))?;
+                let arc = mimetypes.clone();
+                let span = logging::connection_span();
+                tokio::spawn(
+                    async move {
+                        let _open = stats::STATS.open_connection();
+                        let log = match gateway::serve(stream, peer, local, arc).await {
+                            Some(log) => log,
+                            None => return,
+                        };
+                        match ARGS.log_dedup {
+                            Some(ref dedup) => dedup.emit(&log, peer.ip()),
+                            None => log.emit(),
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        };
+        #[cfg(not(feature = "http-gateway"))]
+        let serve_gateway = std::future::pending();
+
+        // the self-test needs connections to be accepted already, but agate
+        // is only announced as running once it passed
+        let ready = async {
+            if let Some(ref path) = ARGS.self_test {
+                match selftest::run(local_addr, path).await {
+                    Ok(header) => tracing::info!("Self-test passed: {}", header),
+                    Err(e) if ARGS.self_test_warn => tracing::error!("SELF-TEST FAILED: {}", e),
+                    Err(e) => {
+                        return Err(StartupError::SelfTest(format!("Self-test failed: {}", e)))
+                    }
+                }
+            }
+            tracing::info!("Listening on {}...", local_addr);
+            std::future::pending().await
+        };
+
+        let serve = async {
+            tokio::select! {
+                result = serve => result,
+                result = serve_offload => result,
+                result = serve_gateway => result,
+                result = ready => result,
+            }
+        };
+
+        if let Some(ref dedup) = ARGS.log_dedup {
+            tokio::spawn(dedup.summarize_periodically());
+        }
+
+        if let Some(ref hit_counts) = ARGS.hit_counts {
+            tokio::spawn(hit_counts.flush_periodically());
+        }
+        // the hit counts since the last flush would be lost when being killed
+        if ARGS.hit_counts.is_none()
+            && ARGS.client_registry.is_none()
+            && ARGS.shutdown_grace.is_zero()
+        {
+            return serve.await;
+        }
+        tokio::select! {
+            result = serve => result,
+            () = shutdown_signal() => {
+                // no more connections are accepted from here on
+                drain().await;
+                if let Some(ref hit_counts) = ARGS.hit_counts {
+                    tracing::info!("Shutting down, saving hit counts...");
+                    hit_counts.flush().await;
+                }
+                if let Some(ref registry) = ARGS.client_registry {
+                    registry.flush().await;
+                }
+                Ok(())
+            }
+        }
+    })
+}
+
+/// Completes when agate is asked to terminate, i.e. on `SIGINT` or `SIGTERM`.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// How often the transfers in progress are logged while shutting down.
+const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(2);
+/// How often to check if all transfers finished while shutting down.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits for the transfers in progress to finish, but at most for the grace
+/// period from `--shutdown-grace`. Until then, the remaining transfers are
+/// logged every few seconds.
+async fn drain() {
+    if ARGS.shutdown_grace.is_zero() || transfer::active() == 0 {
+        return;
+    }
+    tracing::info!(
+        "Shutting down, waiting up to {}s for transfers to finish...",
+        ARGS.shutdown_grace.as_secs()
+    );
+    let deadline = tokio::time::Instant::now() + ARGS.shutdown_grace;
+    let mut interval = tokio::time::interval(DRAIN_LOG_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => transfer::log_active(),
+            () = tokio::time::sleep_until(deadline) => {
+                tracing::warn!("Grace period expired, aborting {} transfers", transfer::active());
+                return;
+            }
+            () = tokio::time::sleep(DRAIN_POLL_INTERVAL) => (),
+        }
+        if transfer::active() == 0 {
+            tracing::info!("All transfers finished");
+            return;
+        }
+    }
+}
+
+type Result<T = (), E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
+
+/// Errors that prevent agate from starting or keep it from running. Each kind
+/// has its own exit code so that scripts can tell them apart.
+#[derive(Debug)]
+enum StartupError {
+    /// Any other error, e.g. while running.
+    Other(String),
+    /// The command line arguments are invalid.
+    Args(String),
+    /// Certificates could not be loaded or generated.
+    Certs(String),
+    /// Agate could not listen on the specified addresses.
+    Bind(String),
+    /// The content directory does not exist.
+    Content(String),
+    /// The request of `--self-test` failed.
+    SelfTest(String),
+}
+
+impl StartupError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::Other(_) => 1,
+            Self::Args(_) => 2,
+            Self::Certs(_) => 3,
+            Self::Bind(_) => 4,
+            Self::Content(_) => 5,
+            Self::SelfTest(_) => 6,
+        }
+    }
+
+    fn args(e: impl Display) -> Self {
+        Self::Args(e.to_string())
+    }
+
+    fn certs(e: impl Display) -> Self {
+        Self::Certs(e.to_string())
+    }
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Other(e)
+            | Self::Args(e)
+            | Self::Certs(e)
+            | Self::Bind(e)
+            | Self::Content(e)
+            | Self::SelfTest(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// The effective configuration, set once at startup by `main`.
+static ARGS: Config = Config(OnceCell::new());
+
+struct Config(OnceCell<Args>);
+
+impl Deref for Config {
+    type Target = Args;
+
+    fn deref(&self) -> &Args {
+        self.0.get().expect("arguments were not parsed yet")
+    }
+}
+
+struct Args {
+    addrs: Vec<SocketAddr>,
+    /// The plaintext listener for a relay terminating TLS.
+    insecure_listener: Option<offload::Address>,
+    /// The port in URLs of requests, if given explicitly. Otherwise it is
+    /// 1965 for the plaintext listener and the local port for TLS listeners.
+    public_port: Option<u16>,
+    /// The address of the HTTP listener for web browsers.
+    #[cfg(feature = "http-gateway")]
+    http_gateway: Option<SocketAddr>,
+    /// The content directory, or the archive file if there is one.
+    content_dir: PathBuf,
+    archive: Option<Archive>,
+    /// The loaded certificates, replaced when reloading.
+    certs: ArcSwap<CertStore>,
+    certs_path: PathBuf,
+    /// Whether to generate certificates using Ed25519 instead of ECDSA.
+    ed25519: bool,
+    /// The host names from the command line and the hostnames file, replaced
+    /// when reloading.
+    hostnames: ArcSwap<Vec<Host>>,
+    /// The host names given on the command line.
+    cli_hostnames: Vec<Host>,
+    hostnames_file: Option<PathBuf>,
+    language: Option<String>,
+    serve_secret: bool,
+    /// List directories without an index file even without a
+    /// `.directory-listing-ok` file.
+    list_directories: bool,
+    /// Settings from `--host-option` that replace the global ones for a host
+    /// name, which has no trailing dot.
+    host_options: Vec<(String, HostOptions)>,
+    /// Serve agate's own configuration files like other secret files.
+    serve_config_files: bool,
+    /// Serve a welcome page if the content directory is empty.
+    welcome: bool,
+    log_ips: bool,
+    /// The time zone of log timestamps, `None` for the old format.
+    log_time: Option<LogTime>,
+    /// Where access log lines are written.
+    access_log_target: AccessLogTarget,
+    /// Collapses identical log lines of failed requests if enabled.
+    log_dedup: Option<Dedup>,
+    /// How long to wait for transfers in progress when shutting down.
+    shutdown_grace: Duration,
+    /// How long a connection may stay open from accepting it, if limited.
+    max_connection_time: Option<Duration>,
+    only_tls13: bool,
+    skip_cert_validation: bool,
+    ignore_broken_certs: bool,
+    single_cert: bool,
+    /// Logs certificates that are not used by any host name.
+    prune_certs: bool,
+    /// Moves the certificates found by `prune_certs` out of the way.
+    prune_delete: bool,
+    central_config: bool,
+    /// Only check the content directory instead of serving it.
+    validate_content: bool,
+    /// The URL whose resolution `agate resolve` shows instead of serving.
+    resolve_url: Option<String>,
+    /// Preload the `.meta` files before accepting connections.
+    warm_up: bool,
+    /// The path requested from the server itself before it is ready.
+    self_test: Option<String>,
+    /// Only log an error if the self-test fails, instead of exiting.
+    self_test_warn: bool,
+    /// Host names whose requests are logged at debug level, without a
+    /// trailing dot.
+    debug_hosts: Vec<String>,
+    /// Path prefixes whose requests are logged at debug level.
+    debug_paths: Vec<String>,
+    strict_sni: bool,
+    /// Accept URLs without a port on listeners that are not on port 1965.
+    loose_ports: bool,
+    /// Serve all host names from the content root instead of a directory
+    /// for each.
+    shared_content: bool,
+    /// Whether to redirect requests to the canonical form of their URL.
+    canonical_urls: bool,
+    /// The host name other host names are redirected to, without a trailing dot.
+    canonical_host: Option<String>,
+    /// Host names whose requests are redirected to another host, from
+    /// `--redirect-host`. The old names have no trailing dot, the new ones
+    /// include the port if one was given.
+    redirect_hosts: Vec<(String, String)>,
+    ip_requests: IpRequests,
+    /// The meta string of responses to requests for a host that is not served.
+    unknown_host_meta: String,
+    redact_queries: bool,
+    /// Remove fragments from requests instead of refusing them.
+    strip_fragments: bool,
+    /// Accept requests ending with a bare LF.
+    lenient_termination: bool,
+    sniff_mime: bool,
+    /// Serve a slice of a file for a `bytes=START-END` query.
+    byte_ranges: bool,
+    /// The query that selects the gzip compressed companion of a file.
+    precompressed: Option<String>,
+    lang_all_text: bool,
+    /// Serve language variants like `page.de.gmi` for `page.gmi?lang=de`.
+    lang_variants: bool,
+    /// Parameters added to guessed MIME types, by a MIME type, `type/*` or `*`.
+    mime_params: HashMap<String, String>,
+    /// The maximum number of path segments in a request.
+    max_path_segments: usize,
+    /// The maximum length of a single decoded path segment in bytes.
+    max_segment_length: usize,
+    /// Larger files are not sent unless allowed by a sidecar file.
+    max_response_size: Option<u64>,
+    /// The size of the buffers response bodies are copied with.
+    io_buffer: usize,
+    /// The number of directory listings and gemlog indexes generated at the
+    /// same time.
+    max_generated: usize,
+    /// The largest number of entries of a directory that is listed.
+    listing_max_entries: usize,
+    mirror: Option<Mirror>,
+    /// The local addresses of outgoing connections.
+    outbound_addrs: client::OutboundAddrs,
+    rate_limit: Option<RateLimit>,
+    /// Drops connections from clients that failed too many TLS handshakes.
+    handshake_limit: Option<HandshakeLimit>,
+    search: Option<Search>,
+    feed: Option<Feed>,
+    hit_counts: Option<HitCounts>,
+    /// Remembers client certificates across restarts.
+    client_registry: Option<ClientRegistry>,
+    /// The path of the status page for administrators.
+    status_page: Option<String>,
+    /// The path of the endpoint showing what was received, from
+    /// `--debug-endpoint`.
+    debug_endpoint: Option<String>,
+    /// The SHA-256 fingerprints of the client certificates allowed to see
+    /// the status page.
+    admin_certs: Vec<String>,
+}
+
+fn args(args: Vec<String>) -> Result<Args, StartupError> {
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "",
+        "content",
+        "Root of the content directory (default ./content/)",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "content-archive",
+        "Serve the content from this zip archive instead of a directory",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "certs",
+        "Root of the certificate directory (default ./.certificates/)",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "validate-content",
+        "Check the content directory for problems like dangling symlinks and unreadable files, print them and exit.",
+    );
+    opts.optflagopt(
+        "",
+        "create-content",
+        "Create the content directory with a placeholder index.gmi if it does not exist. With \"recursive\", missing parent directories are created too.",
+        "recursive",
+    );
+    opts.optmulti(
+        "",
+        "addr",
+        "Address to listen on, IPv6 addresses may have a zone like [fe80::1%eth0]:1965 (default 0.0.0.0:1965 and [::]:1965; muliple occurences means listening on multiple interfaces)",
+        "IP:PORT",
+    );
+    opts.optopt(
+        "",
+        "hostnames-file",
+        "File with one host name per line, used in addition to --hostname. Reread when receiving SIGHUP.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "insecure-listener",
+        "Also accept plaintext requests from a relay that terminates TLS, only on a loopback address or unix:PATH",
+        "IP:PORT",
+    );
+    opts.optopt(
+        "",
+        "public-port",
+        "The port clients connect to, used instead of the local port in request URLs, e.g. behind port forwarding or a relay for --insecure-listener (default: 1965 for --insecure-listener, the local port otherwise)",
+        "PORT",
+    );
+    #[cfg(feature = "http-gateway")]
+    opts.optopt(
+        "",
+        "http-gateway",
+        "Also answer HTTP requests from web browsers on this address, rendering gemtext as HTML",
+        "IP:PORT",
+    );
+    opts.optmulti(
+        "",
+        "hostname",
+        "Domain name of this Gemini server, enables checking hostname and port in requests. (multiple occurences means basic vhosts)",
+        "NAME",
+    );
+    opts.optflag(
+        "",
+        "shared-content",
+        "With multiple host names, serve all of them from the content root instead of a directory for each.",
+    );
+    opts.optopt(
+        "",
+        "lang",
+        "RFC 4646 Language code for text/gemini documents",
+        "LANG",
+    );
+    opts.optflag(
+        "",
+        "lang-all-text",
+        "Also add the language code to all other text/* MIME types, not only text/gemini.",
+    );
+    opts.optopt(
+        "",
+        "shutdown-grace",
+        "When asked to terminate, wait up to this long for responses in progress to finish, logging them every 2 seconds (default 0)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "max-connection-time",
+        "Close connections this long after accepting them, whatever they are doing, e.g. 5m (default no limit)",
+        "DURATION",
+    );
+    opts.optflag(
+        "",
+        "lang-variants",
+        "Serve language variants like page.de.gmi for requests like page.gmi?lang=de.",
+    );
+    opts.optmulti(
+        "",
+        "mime-params",
+        "Add parameters to guessed MIME types matching a pattern, e.g. \"text/*=;charset=utf-8\". The pattern is a MIME type, type/* or *; the most specific one is used.",
+        "PATTERN=PARAMS",
+    );
+    opts.optflag("h", "help", "Print this help text and exit.");
+    opts.optflag("V", "version", "Print version information and exit.");
+    opts.optflag(
+        "3",
+        "only-tls13",
+        "Only use TLSv1.3 (default also allows TLSv1.2)",
+    );
+    opts.optflag(
+        "",
+        "skip-cert-validation",
+        "Do not check that each private key belongs to its certificate when loading them.",
+    );
+    opts.optflag(
+        "",
+        "ignore-broken-certs",
+        "Start even if the certificate for one of the host names can not be loaded (default only other certificates may be broken)",
+    );
+    opts.optopt(
+        "",
+        "max-path-segments",
+        "Refuse requests with more path segments than this with status 59 (default 32)",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "max-segment-length",
+        "Refuse requests with a decoded path segment longer than this with status 59 (default 255)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-response-size",
+        "Refuse to send files and directory listings larger than this with status 59, can be overridden in .meta files (default unlimited)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "io-buffer",
+        "Size of the buffer used for reading files and sending them (default 65536)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-generated",
+        "Number of directory listings and gemlog indexes generated at the same time, further requests for them wait briefly and are then told to retry (default 4)",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "listing-max-entries",
+        "Refuse to list directories with more entries than this, also for gemlog indexes, feeds and search (default 5000)",
+        "NUMBER",
+    );
+    opts.optflag(
+        "",
+        "serve-secret",
+        "Enable serving secret files (files/directories starting with a dot)",
+    );
+    opts.optflag(
+        "",
+        "list-directories",
+        "List directories without an index file, even if they do not contain a .directory-listing-ok file.",
+    );
+    opts.optflag(
+        "",
+        "warm-up",
+        "Read the .meta files and look for the index files of the top-level directories before accepting connections",
+    );
+    opts.optflagopt(
+        "",
+        "self-test",
+        "Request this path from the server itself at startup and exit if the response is not a 2x or 3x (default /)",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "self-test-warn",
+        "Only log an error if the self-test fails instead of exiting.",
+    );
+    opts.optmulti(
+        "",
+        "debug-host",
+        "Log requests for this host name at debug level, including how they are resolved. Can be given multiple times.",
+        "NAME",
+    );
+    opts.optmulti(
+        "",
+        "debug-path",
+        "Log requests for paths starting with this prefix at debug level, including how they are resolved. Can be given multiple times.",
+        "PREFIX",
+    );
+    opts.optflag(
+        "",
+        "serve-config-files",
+        "Serve configuration files like .meta like other secret files instead of always refusing them",
+    );
+    opts.optflag(
+        "",
+        "no-welcome",
+        "Do not serve a welcome page if the content directory is empty.",
+    );
+    opts.optflag("", "log-ip", "Output the remote IP address when logging.");
+    opts.optopt(
+        "",
+        "log-time",
+        "Log timestamps with milliseconds in UTC or in the local time zone (default UTC with seconds)",
+        "utc|local",
+    );
+    opts.optopt(
+        "",
+        "access-log-target",
+        "Where to write access log lines, other messages are always written to stderr (default stderr)",
+        "stdout|stderr|file:PATH",
+    );
+    opts.optflagopt(
+        "",
+        "log-dedup",
+        "Log identical failed requests from the same network only once per time window and how often they were repeated (default disabled, window 60 seconds)",
+        "SECONDS",
+    );
+    opts.optflag(
+        "",
+        "redact-queries",
+        "Do not log the query part of requests.",
+    );
+    opts.optflag(
+        "",
+        "strip-fragments",
+        "Ignore fragments in request URLs instead of refusing the requests.",
+    );
+    opts.optflag(
+        "",
+        "lenient-termination",
+        "Also accept requests ending with LF instead of CRLF.",
+    );
+    opts.optflagopt(
+        "",
+        "precompressed",
+        "Serve FILE.gz as application/gzip for requests for FILE with this query, if it exists (default disabled, query gz)",
+        "QUERY",
+    );
+    opts.optflag(
+        "",
+        "sniff-mime",
+        "Guess the MIME type of files with an unknown extension from their content instead of using application/octet-stream.",
+    );
+    opts.optflag(
+        "",
+        "byte-ranges",
+        "Serve only the bytes START to END of a file for requests with a bytes=START-END query.",
+    );
+    opts.optflag(
+        "C",
+        "central-conf",
+        "Use a central .meta file in the content root directory. Decentral config files will be ignored.",
+    );
+    opts.optflag(
+        "",
+        "strict-sni",
+        "Refuse requests for a different host than the one sent by the client when establishing the TLS connection.",
+    );
+    opts.optflag(
+        "",
+        "loose-ports",
+        "Accept URLs without a port, which are for port 1965, on any port.",
+    );
+    opts.optflag(
+        "",
+        "canonical-urls",
+        "Redirect requests for paths with empty or dot segments, files with a trailing slash and index.gmi files to a single URL per resource.",
+    );
+    opts.optopt(
+        "",
+        "canonical-host",
+        "Redirect requests for any other accepted host name or IP address to the same path on this host name.",
+        "NAME",
+    );
+    opts.optmulti(
+        "",
+        "redirect-host",
+        "Redirect all requests for the host name OLD to the same path on NEW, which may include a port. OLD has to be one of the host names, if they are given.",
+        "OLD=NEW",
+    );
+    opts.optmulti(
+        "",
+        "host-option",
+        "Use other settings than the global ones for requests for NAME, separated by commas: serve-secret, no-serve-secret, list-directories or no-list-directories",
+        "NAME=OPTIONS",
+    );
+    opts.optopt(
+        "",
+        "ip-requests",
+        "How to answer requests for an IP address instead of a host name: \"refuse\" them unless the address is one of the host names (default), \"redirect\" them to the canonical or first host name, or \"serve\" them like the first host name",
+        "redirect|refuse|serve",
+    );
+    opts.optopt(
+        "",
+        "unknown-host-meta",
+        "Meta string of the status 53 response to requests for a host name that is not served (default \"Proxy request refused\")",
+        "TEXT",
+    );
+    opts.optflag(
+        "e",
+        "ed25519",
+        "Generate keys using the Ed25519 signature algorithm instead of the default ECDSA.",
+    );
+    opts.optflag(
+        "",
+        "single-cert",
+        "Generate one certificate for all host names instead of one for each.",
+    );
+    opts.optflag(
+        "",
+        "prune-unused-certs",
+        "Log certificates that are not used for any host name at startup and when reloading.",
+    );
+    opts.optflag(
+        "",
+        "prune-delete",
+        "Move the certificates found by --prune-unused-certs to the retired directory in the certificate directory.",
+    );
+    opts.optmulti(
+        "",
+        "rate-limit",
+        "Limit the requests per client IP address, e.g. 100/1m. Prefix with a path to only limit requests for it and below, e.g. /search=5/60s. For each request the rule with the longest matching path applies.",
+        "[PATH=]REQUESTS/PERIOD",
+    );
+    opts.optopt(
+        "",
+        "handshake-limit",
+        "Drop connections from a client IP address that failed this many TLS handshakes within the period, unless a handshake from it succeeded before (default 10/1m)",
+        "FAILURES/PERIOD",
+    );
+    opts.optopt(
+        "",
+        "handshake-cooldown",
+        "How long to drop connections for after too many failed TLS handshakes (default 10m)",
+        "DURATION",
+    );
+    opts.optflag(
+        "",
+        "no-handshake-limit",
+        "Never drop connections because of failed TLS handshakes",
+    );
+    opts.optopt(
+        "",
+        "search",
+        "Answer requests for this path with a search of the .gmi and .txt files (default disabled)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "feed",
+        "Answer requests for this path with an Atom feed of the most recently changed .gmi files (default disabled)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "status-page",
+        "Answer requests for this path with a status page for administrators (default disabled, requires --admin-cert)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "debug-endpoint",
+        "Answer requests for this path with what was received, e.g. the query and the TLS version, for testing clients (default disabled)",
+        "PATH",
+    );
+    opts.optmulti(
+        "",
+        "admin-cert",
+        "SHA-256 fingerprint of a client certificate allowed to see the status page (repeatable)",
+        "FPR",
+    );
+    opts.optopt(
+        "",
+        "stats-file",
+        "Count successful responses per file and save the counts to this file (default disabled)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "client-registry",
+        "Remember client certificates the first time they are seen and save them to this file (default disabled)",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "mirror",
+        "Fetch files that do not exist in the content directory from this Gemini capsule and save them.",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "mirror-fingerprint",
+        "SHA-256 fingerprint of the certificate of the mirrored capsule (required with --mirror)",
+        "HEX",
+    );
+    opts.optopt(
+        "",
+        "mirror-identity",
+        "Directory with cert.der and key.der to present as client certificate to the mirrored capsule (default none)",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "mirror-max-size",
+        "Largest mirrored file that will be saved, larger ones are only passed through (default 64 MiB)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "mirror-max-age",
+        "Fetch saved files again from the mirrored capsule if they are older than this (default never)",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "outbound-addr",
+        "Local address to make outgoing connections from, e.g. to the mirrored capsule. Can be given once for IPv4 and once for IPv6 (default any)",
+        "IP",
+    );
+
+    // `agate resolve URL` takes the same options as the server
+    let (resolve_url, options) = match args.get(1).map(String::as_str) {
+        Some("resolve") => match args.get(2) {
+            Some(url) => (Some(url.clone()), &args[3..]),
+            None => {
+                return Err(StartupError::Args(
+                    "the resolve subcommand needs a URL".into(),
+                ))
+            }
+        },
+        _ => (None, &args[1..]),
+    };
+    let matches = opts.parse(options).map_err(StartupError::args)?;
+
+    if matches.opt_present("h") {
+        eprintln!(
+            "{}",
+            opts.usage(&format!(
+                "Usage: {0} [options]\n       {0} ping [options]\n       {0} resolve URL [options]\n       {0} conformance --addr HOST:PORT --hostname NAME",
+                &args[0]
+            ))
+        );
+        std::process::exit(0);
+    }
+
+    if matches.opt_present("V") {
+        eprintln!("agate {}", env!("CARGO_PKG_VERSION"));
+        std::process::exit(0);
+    }
+
+    // try to open the certificate directory
+    let certs_path = matches
+        .opt_get_default("certs", ".certificates".to_string())
+        .map_err(StartupError::args)?;
+    let mut cert_errors = vec![];
+    // certificates are not needed to check the content or resolve a URL
+    let validate_content = matches.opt_present("validate-content");
+    let offline = validate_content || resolve_url.is_some();
+    let (certs, certs_path) = match check_path(certs_path.clone()) {
+        _ if offline => (Some(CertStore::default()), PathBuf::from(certs_path)),
+        // the directory exists, try to load certificates
+        Ok(certs_path) => match certificates::CertStore::load_from(
+            &certs_path,
+            !matches.opt_present("skip-cert-validation"),
+        ) {
+            // all is good, at least for some domains
+            Ok((certs, errors)) => {
+                cert_errors = errors;
+                (Some(certs), certs_path)
+            }
+            // the certificate directory did not contain certificates, but we can generate some
+            // because the hostname option was given
+            Err(certificates::CertLoadError::Empty)
+                if matches.opt_present("hostname") || matches.opt_present("hostnames-file") =>
+            {
+                (None, certs_path)
+            }
+            // failed loading certificates or missing hostname to generate them
+            Err(e) => return Err(StartupError::certs(e)),
+        },
+        // the directory does not exist
+        Err(_) => {
+            // since certificate management should be automated, we are going to create the directory too
+            tracing::info!(
+                "The certificate directory {:?} does not exist, creating it.",
+                certs_path
+            );
+            std::fs::create_dir(&certs_path).map_err(|e| {
+                StartupError::Certs(format!(
+                    "Could not create certificate directory {:?}: {}",
+                    certs_path, e
+                ))
+            })?;
+            // we just created the directory, skip loading from it
+            (None, PathBuf::from(certs_path))
+        }
+    };
+
+    // If we have not loaded any certificates yet, we have to try to reload them later.
+    // This ensures we get the right error message.
+    let mut reload_certs = certs.is_none();
+
+    let mut cli_hostnames = vec![];
+    for s in matches.opt_strs("hostname") {
+        let hostname = parse_hostname(&s).map_err(StartupError::args)?;
+        add_hostnames(&mut cli_hostnames, vec![hostname]);
+    }
+    let hostnames_file = matches.opt_str("hostnames-file").map(PathBuf::from);
+    let mut hostnames = cli_hostnames.clone();
+    if let Some(ref file) = hostnames_file {
+        add_hostnames(
+            &mut hostnames,
+            read_hostnames_file(file).map_err(StartupError::Args)?,
+        );
+    }
+
+    let canonical_host = match matches.opt_str("canonical-host") {
+        Some(name) => {
+            let host = parse_hostname(name.trim_end_matches('.')).map_err(|e| {
+                StartupError::Args(format!("Invalid canonical host name {:?}: {}", name, e))
+            })?;
+            if !hostnames.is_empty() && !hostnames.contains(&host) {
+                return Err(StartupError::Args(format!(
+                    "The canonical host name {} has to be one of the host names",
+                    host
+                )));
+            }
+            Some(host.to_string())
+        }
+        None => None,
+    };
+
+    let ip_requests = match matches.opt_str("ip-requests").as_deref() {
+        None | Some("refuse") => IpRequests::Refuse,
+        Some("redirect") => {
+            if canonical_host.is_none() && !hostnames.iter().any(|h| matches!(h, Host::Domain(_))) {
+                return Err(StartupError::args(
+                    "--ip-requests redirect requires --canonical-host or a --hostname",
+                ));
+            }
+            IpRequests::Redirect
+        }
+        Some("serve") => IpRequests::Serve,
+        Some(other) => {
+            return Err(StartupError::Args(format!(
+            "Invalid value {:?} for --ip-requests, expected \"redirect\", \"refuse\" or \"serve\"",
+            other
+        )))
+        }
+    };
+
+    let redirect_hosts = matches
+        .opt_strs("redirect-host")
+        .iter()
+        .map(|arg| parse_redirect_host(arg, &hostnames))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(StartupError::Args)?;
+
+    let mut host_options: Vec<(String, HostOptions)> = vec![];
+    for arg in matches.opt_strs("host-option") {
+        let (host, options) = parse_host_option(&arg, &hostnames).map_err(StartupError::Args)?;
+        match host_options.iter_mut().find(|(name, _)| *name == host) {
+            Some((_, existing)) => existing.merge(options),
+            None => host_options.push((host, options)),
+        }
+    }
+
+    let io_buffer = matches
+        .opt_get_default("io-buffer", transfer::DEFAULT_BUFFER_SIZE)
+        .map_err(StartupError::args)?;
+    if !(MIN_IO_BUFFER..=MAX_IO_BUFFER).contains(&io_buffer) {
+        return Err(StartupError::Args(format!(
+            "The I/O buffer size has to be between {} and {} bytes.",
+            MIN_IO_BUFFER, MAX_IO_BUFFER
+        )));
+    }
+
+    let max_generated = matches
+        .opt_get_default("max-generated", DEFAULT_MAX_GENERATED)
+        .map_err(StartupError::args)?;
+    if max_generated == 0 {
+        return Err(StartupError::Args(
+            "The number of generated responses has to be at least 1.".into(),
+        ));
+    }
+
+    let listing_max_entries = matches
+        .opt_get_default("listing-max-entries", DEFAULT_LISTING_MAX_ENTRIES)
+        .map_err(StartupError::args)?;
+    if listing_max_entries == 0 {
+        return Err(StartupError::Args(
+            "The maximum number of directory entries has to be at least 1.".into(),
+        ));
+    }
+
+    let mut mime_params = HashMap::new();
+    let lang_len = matches
+        .opt_str("lang")
+        .map_or(0, |lang| ";lang=".len() + lang.len());
+    for rule in matches.opt_strs("mime-params") {
+        let (pattern, params) = parse_mime_params(&rule, lang_len)
+            .map_err(|e| StartupError::Args(format!("Invalid --mime-params {:?}: {}", rule, e)))?;
+        mime_params.insert(pattern, params);
+    }
+
+    let unknown_host_meta = matches
+        .opt_str("unknown-host-meta")
+        .unwrap_or_else(|| "Proxy request refused".into());
+    if unknown_host_meta.contains(&['\r', '\n'][..]) {
+        return Err(StartupError::args(
+            "--unknown-host-meta must not contain line breaks",
+        ));
+    }
+    if unknown_host_meta.len() > metadata::MAX_META_LEN {
+        return Err(StartupError::Args(format!(
+            "--unknown-host-meta must not be longer than {} bytes",
+            metadata::MAX_META_LEN
+        )));
+    }
+
+    check_cert_errors(
+        &cert_errors,
+        &hostnames,
+        matches.opt_present("ignore-broken-certs"),
+    )
+    .map_err(StartupError::Certs)?;
+
+    let ed25519 = matches.opt_present("e");
+    if matches.opt_present("prune-delete") && !matches.opt_present("prune-unused-certs") {
+        return Err(StartupError::args(
+            "--prune-delete requires --prune-unused-certs",
+        ));
+    }
+    if !offline
+        && matches.opt_present("prune-unused-certs")
+        && prune_certificates(&certs_path, &hostnames, matches.opt_present("prune-delete"))
+    {
+        reload_certs = true;
+    }
+
+    if !offline
+        && generate_certificates(
+            certs.as_ref(),
+            &certs_path,
+            &hostnames,
+            ed25519,
+            matches.opt_present("single-cert"),
+        )
+        .map_err(StartupError::Certs)?
+    {
+        reload_certs = true;
+    }
+
+    // if new certificates were generated, reload the certificate store
+    let certs = if reload_certs {
+        // errors were already reported when loading the first time
+        certificates::CertStore::load_from(
+            &certs_path,
+            !matches.opt_present("skip-cert-validation"),
+        )
+        .map_err(StartupError::certs)?
+        .0
+    } else {
+        // there must already have been certificates loaded
+        certs.unwrap()
+    };
+
+    // parse listening addresses
+    let mut addrs = vec![];
+    for i in matches.opt_strs("addr") {
+        addrs.push(
+            parse_addr(&i)
+                .map_err(|e| StartupError::Args(format!("Invalid address {:?}: {}", i, e)))?,
+        );
+    }
+    if addrs.is_empty() {
+        addrs = vec![
+            "[::]:1965".parse().unwrap(),
+            "0.0.0.0:1965".parse().unwrap(),
+        ];
+    }
+
+    let insecure_listener = matches
+        .opt_str("insecure-listener")
+        .map(|addr| {
+            offload::Address::parse(&addr).map_err(|e| {
+                StartupError::Args(format!("Invalid --insecure-listener {:?}: {}", addr, e))
+            })
+        })
+        .transpose()?;
+
+    let outbound_addrs = client::OutboundAddrs::parse(&matches.opt_strs("outbound-addr"))
+        .map_err(StartupError::Args)?;
+
+    let mirror = match matches.opt_str("mirror") {
+        Some(upstream) => {
+            let fingerprint = matches
+                .opt_str("mirror-fingerprint")
+                .ok_or_else(|| StartupError::args("--mirror requires --mirror-fingerprint"))?;
+            // a broken identity would make every upstream request fail
+            let identity = match matches.opt_str("mirror-identity") {
+                Some(dir) => Some(certificates::load_identity(Path::new(&dir)).map_err(|e| {
+                    StartupError::Certs(format!("Could not load --mirror-identity: {}", e))
+                })?),
+                None => None,
+            };
+            Some(
+                Mirror::new(
+                    &upstream,
+                    &fingerprint,
+                    identity,
+                    matches
+                        .opt_get_default("mirror-max-size", 64 * 1024 * 1024)
+                        .map_err(StartupError::args)?,
+                    matches
+                        .opt_get("mirror-max-age")
+                        .map_err(StartupError::args)?
+                        .map(Duration::from_secs),
+                )
+                .map_err(StartupError::args)?,
+            )
+        }
+        None => None,
+    };
+
+    let admin_certs = matches
+        .opt_strs("admin-cert")
+        .iter()
+        .map(|fingerprint| client::parse_fingerprint(fingerprint))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(StartupError::Args)?;
+    let status_page = matches.opt_str("status-page");
+    match status_page {
+        Some(ref path) if !path.starts_with('/') => {
+            return Err(StartupError::Args(format!(
+                "status page path {:?} does not start with /",
+                path
+            )));
+        }
+        Some(_) if admin_certs.is_empty() => {
+            return Err(StartupError::args("--status-page requires --admin-cert"));
+        }
+        None if !admin_certs.is_empty() => {
+            return Err(StartupError::args("--admin-cert requires --status-page"));
+        }
+        _ => (),
+    }
+    let debug_endpoint = matches.opt_str("debug-endpoint");
+    if let Some(ref path) = debug_endpoint {
+        if !path.starts_with('/') {
+            return Err(StartupError::Args(format!(
+                "debug endpoint path {:?} does not start with /",
+                path
+            )));
+        }
+    }
+
+    let self_test = match matches.opt_present("self-test") {
+        true => Some(matches.opt_str("self-test").unwrap_or_else(|| "/".into())),
+        false => None,
+    };
+    match self_test {
+        Some(ref path) if !path.starts_with('/') => {
+            return Err(StartupError::Args(format!(
+                "self-test path {:?} does not start with /",
+                path
+            )));
+        }
+        None if matches.opt_present("self-test-warn") => {
+            return Err(StartupError::args("--self-test-warn requires --self-test"));
+        }
+        _ => (),
+    }
+
+    if let Some(path) = matches
+        .opt_strs("debug-path")
+        .into_iter()
+        .find(|path| !path.starts_with('/'))
+    {
+        return Err(StartupError::Args(format!(
+            "debug path prefix {:?} does not start with /",
+            path
+        )));
+    }
+
+    let rate_limit = match matches.opt_strs("rate-limit") {
+        rules if rules.is_empty() => None,
+        rules => Some(RateLimit::new(&rules).map_err(StartupError::Args)?),
+    };
+
+    let handshake_limit = if matches.opt_present("no-handshake-limit") {
+        None
+    } else {
+        let cooldown = match matches.opt_str("handshake-cooldown") {
+            Some(cooldown) => humantime::parse_duration(&cooldown).map_err(|e| {
+                StartupError::Args(format!("invalid handshake cooldown {:?}: {}", cooldown, e))
+            })?,
+            None => DEFAULT_HANDSHAKE_COOLDOWN,
+        };
+        let limit = matches
+            .opt_str("handshake-limit")
+            .unwrap_or_else(|| DEFAULT_HANDSHAKE_LIMIT.to_string());
+        Some(HandshakeLimit::new(&limit, cooldown).map_err(StartupError::Args)?)
+    };
+
+    let max_connection_time = match matches.opt_str("max-connection-time") {
+        Some(time) => {
+            let time = humantime::parse_duration(&time).map_err(|e| {
+                StartupError::Args(format!("invalid maximum connection time {:?}: {}", time, e))
+            })?;
+            if time.is_zero() {
+                return Err(StartupError::Args(
+                    "the maximum connection time has to be longer than zero".into(),
+                ));
+            }
+            Some(time)
+        }
+        None => None,
+    };
+
+    let archive = match matches.opt_str("content-archive") {
+        Some(path) => {
+            for conflicting in [
+                "content",
+                "create-content",
+                "mirror",
+                "feed",
+                "validate-content",
+            ] {
+                if matches.opt_present(conflicting) {
+                    return Err(StartupError::Args(format!(
+                        "--{} can not be used with --content-archive",
+                        conflicting
+                    )));
+                }
+            }
+            Some(Archive::open(Path::new(&path)).map_err(StartupError::Content)?)
+        }
+        None => None,
+    };
+
+    let content_dir = if let Some(ref archive) = archive {
+        // paths in the archive are looked up relative to the archive path
+        archive.path().to_path_buf()
+    } else {
+        let content_dir: String = matches
+            .opt_get_default("content", "content".into())
+            .map_err(StartupError::args)?;
+        match (
+            check_path(content_dir.clone()),
+            matches.opt_present("create-content"),
+        ) {
+            (Ok(content_dir), _) => content_dir,
+            (Err(e), false) => return Err(StartupError::Content(e)),
+            (Err(_), true) => {
+                let recursive = match matches.opt_str("create-content").as_deref() {
+                    None => false,
+                    Some("recursive") => true,
+                    Some(s) => {
+                        return Err(StartupError::Args(format!(
+                            "Invalid value {:?} for --create-content, expected \"recursive\"",
+                            s
+                        )))
+                    }
+                };
+                tracing::info!(
+                    "The content directory {:?} does not exist, creating it.",
+                    content_dir
+                );
+                let content_dir = PathBuf::from(content_dir);
+                create_content_dir(&content_dir, recursive).map_err(StartupError::Content)?;
+                content_dir
+            }
+        }
+    };
+
+    Ok(Args {
+        addrs,
+        insecure_listener,
+        public_port: matches.opt_get("public-port").map_err(StartupError::args)?,
+        #[cfg(feature = "http-gateway")]
+        http_gateway: matches
+            .opt_str("http-gateway")
+            .map(|addr| {
+                parse_addr(&addr).map_err(|e| {
+                    StartupError::Args(format!("Invalid --http-gateway {:?}: {}", addr, e))
+                })
+            })
+            .transpose()?,
+        content_dir,
+        archive,
+        certs: ArcSwap::from_pointee(certs),
+        certs_path,
+        ed25519,
+        hostnames: ArcSwap::from_pointee(hostnames),
+        cli_hostnames,
+        hostnames_file,
+        language: matches.opt_str("lang"),
+        serve_secret: matches.opt_present("serve-secret"),
+        list_directories: matches.opt_present("list-directories"),
+        host_options,
+        serve_config_files: matches.opt_present("serve-config-files"),
+        welcome: !matches.opt_present("no-welcome"),
+        log_ips: matches.opt_present("log-ip"),
+        log_time: matches
+            .opt_str("log-time")
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(StartupError::Args)?,
+        access_log_target: matches
+            .opt_str("access-log-target")
+            .map_or(Ok(AccessLogTarget::Stderr), |s| AccessLogTarget::parse(&s))
+            .map_err(StartupError::Args)?,
+        shutdown_grace: Duration::from_secs(
+            matches
+                .opt_get_default("shutdown-grace", 0)
+                .map_err(StartupError::args)?,
+        ),
+        max_connection_time,
+        log_dedup: match matches.opt_present("log-dedup") {
+            true => Some(
+                Dedup::new(Duration::from_secs(
+                    matches
+                        .opt_get_default("log-dedup", 60)
+                        .map_err(StartupError::args)?,
+                ))
+                .map_err(StartupError::Args)?,
+            ),
+            false => None,
+        },
+        only_tls13: matches.opt_present("only-tls13"),
+        skip_cert_validation: matches.opt_present("skip-cert-validation"),
+        ignore_broken_certs: matches.opt_present("ignore-broken-certs"),
+        single_cert: matches.opt_present("single-cert"),
+        prune_certs: matches.opt_present("prune-unused-certs"),
+        prune_delete: matches.opt_present("prune-delete"),
+        central_config: matches.opt_present("central-conf"),
+        validate_content,
+        resolve_url,
+        warm_up: matches.opt_present("warm-up"),
+        self_test,
+        self_test_warn: matches.opt_present("self-test-warn"),
+        debug_hosts: matches
+            .opt_strs("debug-host")
+            .into_iter()
+            .map(|host| host.trim_end_matches('.').to_string())
+            .collect(),
+        debug_paths: matches.opt_strs("debug-path"),
+        strict_sni: matches.opt_present("strict-sni"),
+        loose_ports: matches.opt_present("loose-ports"),
+        shared_content: matches.opt_present("shared-content"),
+        canonical_urls: matches.opt_present("canonical-urls"),
+        canonical_host,
+        redirect_hosts,
+        ip_requests,
+        unknown_host_meta,
+        redact_queries: matches.opt_present("redact-queries"),
+        strip_fragments: matches.opt_present("strip-fragments"),
+        lenient_termination: matches.opt_present("lenient-termination"),
+        sniff_mime: matches.opt_present("sniff-mime"),
+        byte_ranges: matches.opt_present("byte-ranges"),
+        precompressed: match matches.opt_present("precompressed") {
+            true => Some(
+                matches
+                    .opt_str("precompressed")
+                    .unwrap_or_else(|| "gz".into()),
+            ),
+            false => None,
+        },
+        lang_all_text: matches.opt_present("lang-all-text"),
+        lang_variants: matches.opt_present("lang-variants"),
+        mime_params,
+        max_path_segments: matches
+            .opt_get_default("max-path-segments", 32)
+            .map_err(StartupError::args)?,
+        max_response_size: matches
+            .opt_get("max-response-size")
+            .map_err(StartupError::args)?,
+        max_segment_length: matches
+            .opt_get_default("max-segment-length", 255)
+            .map_err(StartupError::args)?,
+        io_buffer,
+        max_generated,
+        listing_max_entries,
+        mirror,
+        outbound_addrs,
+        rate_limit,
+        handshake_limit,
+        search: matches
+            .opt_str("search")
+            .map(Search::new)
+            .transpose()
+            .map_err(StartupError::Args)?,
+        feed: matches
+            .opt_str("feed")
+            .map(Feed::new)
+            .transpose()
+            .map_err(StartupError::Args)?,
+        hit_counts: matches
+            .opt_str("stats-file")
+            .map(|file| HitCounts::load(file.into()))
+            .transpose()
+            .map_err(StartupError::Other)?,
+        client_registry: matches
+            .opt_str("client-registry")
+            .map(|file| ClientRegistry::load(file.into()))
+            .transpose()
+            .map_err(StartupError::Other)?,
+        status_page,
+        admin_certs,
+        debug_endpoint,
+    })
+}
+
+/// The limits for `--io-buffer`.
+const MIN_IO_BUFFER: usize = 512;
+const MAX_IO_BUFFER: usize = 16 * 1024 * 1024;
+
+/// The longest MIME type without parameters, since RFC 6838 limits the type
+/// and subtype names to 127 characters each.
+const MAX_MIME_LEN: usize = 127 + 1 + 127;
+
+/// The MIME type parameters of files without a `.meta` entry.
+fn default_params() -> String {
+    ARGS.language
+        .as_ref()
+        .map_or(String::new(), |lang| format!(";lang={}", lang))
+}
+
+/// Parses a rule of `--mime-params`, i.e. a pattern, an equals sign and the
+/// parameters. The parameters have to fit into a response header together
+/// with any MIME type and language parameter.
+fn parse_mime_params(rule: &str, lang_len: usize) -> Result<(String, String), String> {
+    let (pattern, params) = rule
+        .split_once('=')
+        .ok_or("expected a pattern, \"=\" and parameters")?;
+    let pattern = pattern.trim().to_ascii_lowercase();
+    let valid = match pattern.split_once('/') {
+        None => pattern == "*",
+        Some((kind, subtype)) => {
+            let token = |s: &str| {
+                !s.is_empty()
+                    && s.chars()
+                        .all(|c| c.is_ascii_graphic() && !"/;=*".contains(c))
+            };
+            token(kind) && (subtype == "*" || token(subtype))
+        }
+    };
+    if !valid {
+        return Err(format!("{:?} is not a MIME type, type/* or *", pattern));
+    }
+    if !params.starts_with(';') {
+        return Err("the parameters have to start with \";\"".into());
+    }
+    if params.contains(&['\r', '\n'][..]) {
+        return Err("the parameters must not contain line breaks".into());
+    }
+    if MAX_MIME_LEN + lang_len + params.len() > metadata::MAX_META_LEN {
+        return Err("the parameters are too long to fit into a response header".into());
+    }
+    Ok((pattern, params.to_string()))
+}
+
+/// Returns the parameters from `--mime-params` for a MIME type without
+/// parameters. An exact match is preferred over `type/*`, which is preferred
+/// over `*`.
+fn default_mime_params(mime: &str) -> Option<&'static str> {
+    let kind = mime.split('/').next().unwrap();
+    ARGS.mime_params
+        .get(mime)
+        .or_else(|| {
+            ARGS.mime_params
+                .iter()
+                .find(|(pattern, _)| pattern.strip_suffix("/*") == Some(kind))
+                .map(|(_, params)| params)
+        })
+        .or_else(|| ARGS.mime_params.get("*"))
+        .map(String::as_str)
+}
+
+/// Parses a socket address. In addition to what the standard library accepts,
+/// the zone of an IPv6 address may be an interface name, e.g.
+/// `[fe80::1%eth0]:1965`.
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    let (ip, zone, port) = match s
+        .strip_prefix('[')
+        .and_then(|s| s.split_once("]:"))
+        .and_then(|(ip, port)| Some((ip.split_once('%')?, port)))
+    {
+        Some(((ip, zone), port)) => (ip, zone, port),
+        None => return s.parse().map_err(|e| format!("{}", e)),
+    };
+    let ip = ip.parse::<Ipv6Addr>().map_err(|e| format!("{}", e))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|e| format!("invalid port: {}", e))?;
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => interface_index(zone)?,
+    };
+    Ok(SocketAddrV6::new(ip, port, 0, scope_id).into())
+}
+
+/// Returns the index of a network interface for use as the zone of an IPv6
+/// address. Since unsafe code is not allowed, the index is read from sysfs
+/// instead of calling `if_nametoindex`.
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> Result<u32, String> {
+    let unknown = || format!("unknown network interface {:?}", name);
+    if name.is_empty() || name.contains(['/', '.']) {
+        return Err(unknown());
+    }
+    fs::read_to_string(Path::new("/sys/class/net").join(name).join("ifindex"))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+        .ok_or_else(unknown)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(name: &str) -> Result<u32, String> {
+    Err(format!(
+        "network interface names like {:?} are not supported on this platform, use the interface number",
+        name
+    ))
+}
+
+/// Parses a host name. IPv6 addresses may be given with or without brackets,
+/// so that `[::1]` and `::1` are the same host name. Zones are not allowed,
+/// because they can not be part of a URL.
+fn parse_hostname(s: &str) -> Result<Host, String> {
+    if s.contains('%') && s.contains(':') {
+        return Err(format!(
+            "{:?} contains a zone, which can not be used in a URL",
+            s
+        ));
+    }
+    match s.parse::<Ipv6Addr>() {
+        Ok(ip) => Ok(Host::Ipv6(ip)),
+        Err(_) => Host::parse(s).map_err(|e| e.to_string()),
+    }
+}
+
+/// Parses an `OLD=NEW` argument of `--redirect-host`. `NEW` is returned as
+/// it will appear in the redirects, i.e. the host name and an optional port.
+fn parse_redirect_host(arg: &str, hostnames: &[Host]) -> Result<(String, String), String> {
+    let (old, new) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid host redirect {:?}, expected OLD=NEW", arg))?;
+    let old = parse_hostname(old.trim_end_matches('.'))
+        .map_err(|e| format!("Invalid host name {:?} in host redirect: {}", old, e))?;
+    if !hostnames.is_empty() && !hostnames.contains(&old) {
+        return Err(format!(
+            "The redirected host name {} has to be one of the host names",
+            old
+        ));
+    }
+    let target = Url::parse(&format!("gemini://{}", new))
+        .ok()
+        .filter(|url| {
+            url.host().is_some()
+                && url.username().is_empty()
+                && url.password().is_none()
+                && matches!(url.path(), "" | "/")
+                && url.query().is_none()
+                && url.fragment().is_none()
+        })
+        .ok_or_else(|| {
+            format!(
+                "Invalid target {:?} in host redirect, expected a host name and an optional port",
+                new
+            )
+        })?;
+    Ok((
+        old.to_string(),
+        target[Position::BeforeHost..Position::AfterPort].to_string(),
+    ))
+}
+
+/// Settings for a single host name from `--host-option`. Those that are
+/// `None` are taken from the global options.
+#[derive(Clone, Copy, Default)]
+struct HostOptions {
+    serve_secret: Option<bool>,
+    list_directories: Option<bool>,
+}
+
+impl HostOptions {
+    /// Takes the settings that are given in `other`.
+    fn merge(&mut self, other: Self) {
+        self.serve_secret = other.serve_secret.or(self.serve_secret);
+        self.list_directories = other.list_directories.or(self.list_directories);
+    }
+}
+
+/// Parses a `--host-option` argument like `example.com=serve-secret`.
+fn parse_host_option(arg: &str, hostnames: &[Host]) -> Result<(String, HostOptions), String> {
+    let (name, list) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid host option {:?}, expected NAME=OPTIONS", arg))?;
+    let host = parse_hostname(name.trim_end_matches('.'))
+        .map_err(|e| format!("Invalid host name {:?} in host option: {}", name, e))?;
+    if !hostnames.is_empty() && !hostnames.contains(&host) {
+        return Err(format!(
+            "The host name {} of a host option has to be one of the host names",
+            host
+        ));
+    }
+    let mut options = HostOptions::default();
+    for option in list.split(',') {
+        match option {
+            "serve-secret" => options.serve_secret = Some(true),
+            "no-serve-secret" => options.serve_secret = Some(false),
+            "list-directories" => options.list_directories = Some(true),
+            "no-list-directories" => options.list_directories = Some(false),
+            _ => {
+                return Err(format!(
+                    "Invalid host option {:?}, expected serve-secret, no-serve-secret, list-directories or no-list-directories",
+                    option
+                ))
+            }
+        }
+    }
+    Ok((host.to_string(), options))
+}
+
+/// Returns the settings from `--host-option` for a requested host name.
+fn host_options(host: &str) -> HostOptions {
+    let host = host.trim_end_matches('.');
+    ARGS.host_options
+        .iter()
+        .find(|(name, _)| host.eq_ignore_ascii_case(name))
+        .map_or_else(HostOptions::default, |(_, options)| *options)
+}
+
+/// Adds host names to a list, leaving out duplicates.
+fn add_hostnames(hostnames: &mut Vec<Host>, new: Vec<Host>) {
+    for hostname in new {
+        if !hostnames.contains(&hostname) {
+            hostnames.push(hostname);
+        }
+    }
+}
+
+/// Reads a file with one host name per line. Everything after a `#` is a
+/// comment, empty lines are ignored.
+fn read_hostnames_file(path: &Path) -> Result<Vec<Host>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read hostnames file {:?}: {}", path, e))?;
+    let mut hostnames = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hostname = parse_hostname(line).map_err(|e| {
+            format!(
+                "{:?} line {}: invalid host name {:?}: {}",
+                path,
+                i + 1,
+                line,
+                e
+            )
+        })?;
+        add_hostnames(&mut hostnames, vec![hostname]);
+    }
+    Ok(hostnames)
+}
+
+/// Generates a certificate for each domain in `hostnames` that does not have
+/// one in `certs` yet, or a single one for all of them if `single` is set.
+/// Returns true if any certificates were generated.
+fn generate_certificates(
+    certs: Option<&CertStore>,
+    certs_path: &Path,
+    hostnames: &[Host],
+    ed25519: bool,
+    single: bool,
+) -> Result<bool, String> {
+    if single {
+        return generate_single_certificate(certs_path, hostnames, ed25519);
+    }
+    let mut generated = false;
+    for hostname in hostnames {
+        // check if we have a certificate for that domain
+        let domain = match hostname {
+            Host::Domain(domain) if !matches!(certs, Some(certs) if certs.has_domain(domain)) => {
+                domain
+            }
+            _ => continue,
+        };
+        // the certificate could not be loaded, do not overwrite it
+        if certificates::has_files(certs_path, domain) {
+            continue;
+        }
+        tracing::info!(
+            "No certificate or key found for {:?}, generating them.",
+            domain
+        );
+
+        let mut cert_params = CertificateParams::new(vec![domain.clone()]);
+        cert_params
+            .distinguished_name
+            .push(DnType::CommonName, domain);
+
+        // <CertificateParams as Default>::default() already implements a
+        // date in the far future from the time of writing: 4096-01-01
+
+        if ed25519 {
+            cert_params.alg = &rcgen::PKCS_ED25519;
+        }
+
+        let error =
+            |e: &dyn Display| format!("Could not generate certificate for {}: {}", domain, e);
+
+        // generate the certificate with the configuration
+        let cert = Certificate::from_params(cert_params).map_err(|e| error(&e))?;
+        write_certificate(&certs_path.join(domain), &cert).map_err(|e| error(&e))?;
+
+        generated = true;
+    }
+    Ok(generated)
+}
+
+/// Logs the certificates in the certificate directory that are not used for
+/// any of the host names, i.e. neither for the host name itself nor for a
+/// parent domain of it, and moves them to the retired directory if `delete`
+/// is set. Returns whether a certificate was moved. Without any host names,
+/// all certificates are in use.
+fn prune_certificates(certs_path: &Path, hostnames: &[Host], delete: bool) -> bool {
+    let domains = hostnames
+        .iter()
+        .filter_map(|host| match host {
+            Host::Domain(domain) => Some(domain.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if domains.is_empty() {
+        return false;
+    }
+    let stored = match certificates::stored_domains(certs_path) {
+        Ok(stored) => stored,
+        Err(e) => {
+            tracing::warn!("Could not look for unused certificates: {}", e);
+            return false;
+        }
+    };
+    let mut moved = false;
+    // the same rule as for choosing a certificate
+    for unused in stored
+        .iter()
+        .filter(|stored| !domains.iter().any(|d| d.ends_with(*stored)))
+    {
+        if !delete {
+            tracing::warn!(
+                "The certificate for {} is not used for any host name.",
+                unused
+            );
+            continue;
+        }
+        match certificates::retire(certs_path, unused) {
+            Ok(paths) => {
+                tracing::info!(
+                    "Moved the unused certificate for {} to {:?}.",
+                    unused,
+                    paths
+                );
+                moved = true;
+            }
+            Err(e) => tracing::warn!(
+                "Could not move the unused certificate for {}: {}",
+                unused,
+                e
+            ),
+        }
+    }
+    moved
+}
+
+/// Generates one certificate for all domains in `hostnames` if there is none
+/// yet or it does not include all of them. An existing key is reused, so
+/// adding a host name does not change the key clients may have pinned.
+fn generate_single_certificate(
+    certs_path: &Path,
+    hostnames: &[Host],
+    ed25519: bool,
+) -> Result<bool, String> {
+    let domains = hostnames
+        .iter()
+        .filter_map(|host| match host {
+            Host::Domain(domain) => Some(domain.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if domains.is_empty() {
+        return Ok(false);
+    }
+    let dir = certs_path.join(certificates::SINGLE_CERT_DIR);
+    let covered = fs::read(dir.join(certificates::CERT_FILE_NAME))
+        .ok()
+        .and_then(|cert| certificates::dns_names(&cert))
+        .unwrap_or_default();
+    if domains.iter().all(|domain| covered.contains(domain)) {
+        return Ok(false);
+    }
+    tracing::info!(
+        "Generating a certificate for all host names: {}",
+        domains.join(", ")
+    );
+
+    let error =
+        |e: &dyn Display| format!("Could not generate certificate for all host names: {}", e);
+    let mut cert_params = CertificateParams::new(domains.clone());
+    cert_params
+        .distinguished_name
+        .push(DnType::CommonName, &domains[0]);
+    match fs::read(dir.join(certificates::KEY_FILE_NAME)) {
+        Ok(key) => {
+            let key_pair = rcgen::KeyPair::from_der(&key).map_err(|e| error(&e))?;
+            // the algorithm has to match the existing key
+            cert_params.alg = key_pair.compatible_algs().next().unwrap();
+            cert_params.key_pair = Some(key_pair);
+        }
+        Err(_) if ed25519 => cert_params.alg = &rcgen::PKCS_ED25519,
+        Err(_) => (),
+    }
+    let cert = Certificate::from_params(cert_params).map_err(|e| error(&e))?;
+    write_certificate(&dir, &cert).map_err(|e| error(&e))?;
+    Ok(true)
+}
+
+/// Writes a generated certificate and its key to a directory, which is created
+/// if necessary.
+fn write_certificate(dir: &Path, cert: &Certificate) -> Result {
+    // make sure the certificate directory exists, only the key has to be
+    // secret but nobody else needs to access the directory either
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    match builder.create(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => (),
+    }
+    // write certificate data to disk
+    File::create(dir.join(certificates::CERT_FILE_NAME))?.write_all(&cert.serialize_der()?)?;
+    // write key data to disk, readable only by the owner
+    let mut key_options = fs::OpenOptions::new();
+    key_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut key_options, 0o600);
+    key_options
+        .open(dir.join(certificates::KEY_FILE_NAME))?
+        .write_all(&cert.serialize_private_key_der())?;
+    Ok(())
+}
+
+/// Reads the hostnames file and the certificates again and generates
+/// certificates for new host names. The new configuration is only used if
+/// all of this succeeds.
+///
+/// Reloads are done one at a time, so that two of them can not both find a
+/// certificate missing and write the files for the same domain at once.
+fn reload() -> Result<(), String> {
+    static RELOADING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // the lock guards no data, so a reload that panicked left nothing broken
+    let _reloading = RELOADING
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+    let mut hostnames = ARGS.cli_hostnames.clone();
+    if let Some(ref file) = ARGS.hostnames_file {
+        add_hostnames(&mut hostnames, read_hostnames_file(file)?);
+    }
+
+    if ARGS.prune_certs {
+        prune_certificates(&ARGS.certs_path, &hostnames, ARGS.prune_delete);
+    }
+    let (certs, errors) = match CertStore::load_from(&ARGS.certs_path, !ARGS.skip_cert_validation) {
+        Ok((certs, errors)) => (Some(certs), errors),
+        Err(certificates::CertLoadError::Empty) => (None, vec![]),
+        Err(e) => return Err(e.to_string()),
+    };
+    check_cert_errors(&errors, &hostnames, ARGS.ignore_broken_certs)?;
+    let generated = generate_certificates(
+        certs.as_ref(),
+        &ARGS.certs_path,
+        &hostnames,
+        ARGS.ed25519,
+        ARGS.single_cert,
+    )?;
+    let mut certs = match certs {
+        Some(certs) if !generated => certs,
+        // load the generated certificates too
+        _ => {
+            CertStore::load_from(&ARGS.certs_path, !ARGS.skip_cert_validation)
+                .map_err(|e| e.to_string())?
+                .0
+        }
+    };
+
+    // keep serving domains that were broken by an update
+    let old = ARGS.certs.load();
+    let kept = errors
+        .iter()
+        .filter_map(|e| e.domain())
+        .filter(|domain| certs.keep(&old, domain))
+        .collect::<Vec<_>>();
+    log_cert_changes(&old, &certs, &kept);
+
+    ARGS.hostnames.store(Arc::new(hostnames));
+    ARGS.certs.store(Arc::new(certs));
+    TLS.store(Arc::new(acceptor()));
+    misses::MISSES.clear();
+    indexes::clear();
+    welcome::check();
+    Ok(())
+}
+
+/// Reports certificates that could not be loaded. Fails if one of them is for
+/// one of the host names, unless this is allowed with `--ignore-broken-certs`.
+fn check_cert_errors(
+    errors: &[certificates::CertLoadError],
+    hostnames: &[Host],
+    ignore: bool,
+) -> Result<(), String> {
+    for e in errors {
+        let is_hostname = hostnames
+            .iter()
+            .any(|host| matches!((host, e.domain()), (Host::Domain(host), Some(domain)) if host == domain));
+        if is_hostname && !ignore {
+            return Err(e.to_string());
+        }
+        tracing::warn!("Skipping a certificate: {}", e);
+    }
+    Ok(())
+}
+
+/// Logs which certificates were added, replaced or removed by reloading, and
+/// which old ones were kept because the new ones could not be loaded.
+fn log_cert_changes(old: &CertStore, new: &CertStore, kept: &[&str]) {
+    let name = |domain: &str| {
+        if domain.is_empty() {
+            "fallback".to_string()
+        } else {
+            domain.to_string()
+        }
+    };
+    let old = old.certificates().collect::<BTreeMap<_, _>>();
+    let new = new.certificates().collect::<BTreeMap<_, _>>();
+    let mut added = vec![];
+    let mut replaced = vec![];
+    for (domain, cert) in &new {
+        match old.get(domain) {
+            None => added.push(name(domain)),
+            Some(old_cert) if old_cert != cert => replaced.push(name(domain)),
+            Some(_) => (),
+        }
+    }
+    let removed = old
+        .keys()
+        .filter(|domain| !new.contains_key(*domain))
+        .map(|domain| name(domain))
+        .collect::<Vec<_>>();
+    let kept = kept.iter().map(|domain| name(domain)).collect::<Vec<_>>();
+
+    let mut changes = vec![];
+    for (list, what) in [
+        (added, "added"),
+        (replaced, "replaced"),
+        (removed, "removed"),
+        (kept, "kept because of errors"),
+    ] {
+        if !list.is_empty() {
+            changes.push(format!("{} {}", what, list.join(", ")));
+        }
+    }
+    if changes.is_empty() {
+        changes.push("no changes".to_string());
+    }
+    tracing::info!("Certificates: {}", changes.join("; "));
+}
+
+fn check_path(s: String) -> Result<PathBuf, String> {
+    let p = PathBuf::from(s);
+    if p.as_path().exists() {
+        Ok(p)
+    } else {
+        Err(format!("No such file: {:?}", p))
+    }
+}
+
+/// Text of the index.gmi file put into a newly created content directory.
+const PLACEHOLDER_INDEX: &str = "\
+# It works!
+
+Agate is running and serving this file from its content directory. Replace it with your own content.
+";
+
+/// Creates a missing content directory and a placeholder index file in it.
+/// Unless `recursive` is set, the parent directory has to exist already.
+fn create_content_dir(path: &Path, recursive: bool) -> Result<(), String> {
+    let mut missing = vec![path];
+    if recursive {
+        missing.extend(
+            path.ancestors()
+                .skip(1)
+                .filter(|p| !p.as_os_str().is_empty())
+                .take_while(|p| !p.exists()),
+        );
+    }
+    for dir in missing.into_iter().rev() {
+        fs::create_dir(dir).map_err(|e| format!("Could not create directory {:?}: {}", dir, e))?;
+    }
+
+    let index = path.join("index.gmi");
+    fs::write(&index, PLACEHOLDER_INDEX)
+        .map_err(|e| format!("Could not create placeholder {:?}: {}", index, e))
+}
+
+/// Logs a summary of the effective configuration.
+fn log_summary() {
+    if ARGS.archive.is_some() {
+        tracing::info!("Content archive: {:?}", ARGS.content_dir);
+    } else {
+        tracing::info!("Content directory: {:?}", ARGS.content_dir);
+    }
+    let hostnames = ARGS.hostnames.load();
+    if hostnames.is_empty() {
+        tracing::info!("Hostnames: any (not checked)");
+    } else {
+        let hostnames = hostnames
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        tracing::info!("Hostnames: {}", hostnames.join(", "));
+    }
+    let certs = ARGS.certs.load();
+    for (domain, cert) in certs.certificates() {
+        tracing::info!(
+            "Certificate for {}: SHA-256 fingerprint {}, valid until {}",
+            if domain.is_empty() {
+                "other domains"
+            } else {
+                domain
+            },
+            certificates::fingerprint(cert),
+            certificates::expiry(cert).as_deref().unwrap_or("unknown"),
+        );
+    }
+    tracing::info!(
+        "TLS versions: {}",
+        if ARGS.only_tls13 { "1.3" } else { "1.2, 1.3" }
+    );
+
+    let mut features = vec![];
+    if let Some(ref language) = ARGS.language {
+        features.push(format!("default language {}", language));
+    }
+    if ARGS.central_config {
+        features.push("central configuration".to_string());
+    }
+    if ARGS.serve_secret {
+        features.push("serving secret files".to_string());
+    }
+    if ARGS.serve_config_files {
+        features.push("serving configuration files".to_string());
+    }
+    if ARGS.warm_up {
+        features.push("warm-up".to_string());
+    }
+    if let Some(ref path) = ARGS.self_test {
+        features.push(format!("self-test of {}", path));
+    }
+    if !ARGS.debug_hosts.is_empty() || !ARGS.debug_paths.is_empty() {
+        let filters = ARGS
+            .debug_hosts
+            .iter()
+            .chain(&ARGS.debug_paths)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        features.push(format!("debug logging for {}", filters.join(", ")));
+    }
+    if ARGS.prune_delete {
+        features.push("retiring unused certificates".to_string());
+    } else if ARGS.prune_certs {
+        features.push("reporting unused certificates".to_string());
+    }
+    if ARGS.log_ips {
+        features.push("logging IP addresses".to_string());
+    }
+    if ARGS.redact_queries {
+        features.push("redacting queries in logs".to_string());
+    }
+    if ARGS.strip_fragments {
+        features.push("stripping fragments from requests".to_string());
+    }
+    if ARGS.lenient_termination {
+        features.push("accepting requests ending with LF".to_string());
+    }
+    if let Some(ref dedup) = ARGS.log_dedup {
+        features.push(format!(
+            "collapsing repeated failures in logs for {}s",
+            dedup.window().as_secs()
+        ));
+    }
+    if let Some(ref query) = ARGS.precompressed {
+        features.push(format!("precompressed files with ?{}", query));
+    }
+    if ARGS.sniff_mime {
+        features.push("MIME type sniffing".to_string());
+    }
+    if ARGS.byte_ranges {
+        features.push("byte ranges".to_string());
+    }
+    if ARGS.lang_variants {
+        features.push("language variants".to_string());
+    }
+    if let Some(time) = ARGS.max_connection_time {
+        features.push(format!(
+            "closing connections after {}",
+            humantime::format_duration(time)
+        ));
+    }
+    if !ARGS.shutdown_grace.is_zero() {
+        features.push(format!(
+            "shutdown grace period of {}s",
+            ARGS.shutdown_grace.as_secs()
+        ));
+    }
+    if ARGS.strict_sni {
+        features.push("strict SNI checking".to_string());
+    }
+    if ARGS.loose_ports {
+        features.push("URLs without a port accepted on any port".to_string());
+    }
+    if ARGS.shared_content {
+        features.push("shared content for all host names".to_string());
+    }
+    if let Some(ref canonical) = ARGS.canonical_host {
+        features.push(format!("redirecting to {}", canonical));
+    }
+    for (old, new) in &ARGS.redirect_hosts {
+        features.push(format!("redirecting {} to {}", old, new));
+    }
+    if ARGS.list_directories {
+        features.push("listing directories".to_string());
+    }
+    for (host, _) in &ARGS.host_options {
+        features.push(format!("other options for {}", host));
+    }
+    if ARGS.rate_limit.is_some() {
+        features.push("rate limiting".to_string());
+    }
+    if let Some(ref mirror) = ARGS.mirror {
+        features.push(format!("mirroring {}", mirror.upstream()));
+    }
+    if !ARGS.outbound_addrs.is_empty() {
+        features.push(format!("outgoing connections from {}", ARGS.outbound_addrs));
+    }
+    if let Some(ref search) = ARGS.search {
+        features.push(format!("search at {}", search.path()));
+    }
+    if let Some(ref feed) = ARGS.feed {
+        features.push(format!("feed at {}", feed.path()));
+    }
+    if let Some(ref hit_counts) = ARGS.hit_counts {
+        features.push(format!("hit counts in {:?}", hit_counts.file()));
+    }
+    if !matches!(ARGS.access_log_target, AccessLogTarget::Stderr) {
+        features.push(format!("access log to {}", ARGS.access_log_target));
+    }
+    if let Some(ref registry) = ARGS.client_registry {
+        features.push(format!("client registry in {:?}", registry.file()));
+    }
+    if let Some(ref path) = ARGS.debug_endpoint {
+        features.push(format!("debug endpoint at {}", path));
+    }
+    if let Some(ref path) = ARGS.status_page {
+        features.push(format!(
+            "status page at {} for {} client certificates",
+            path,
+            ARGS.admin_certs.len()
+        ));
+    }
+    if let Some(ref addr) = ARGS.insecure_listener {
+        features.push(format!(
+            "plaintext offload listener on {} for public port {}",
+            addr,
+            ARGS.public_port.unwrap_or(1965)
+        ));
+    } else if let Some(port) = ARGS.public_port {
+        features.push(format!("public port {}", port));
+    }
+    #[cfg(feature = "http-gateway")]
+    if let Some(addr) = ARGS.http_gateway {
+        features.push(format!("HTTP gateway on {}", addr));
+    }
+    if features.is_empty() {
+        features.push("none".to_string());
+    }
+    tracing::info!("Optional features: {}", features.join(", "));
+}
+
+/// Percent-decodes a URL path segment to a file name. On Unix, file names can
+/// be arbitrary bytes, so the decoded segment does not have to be UTF-8.
+/// Segments without escapes are borrowed instead of copied.
+fn decode_segment(segment: &str) -> Option<Cow<'_, OsStr>> {
+    let decoded: Cow<'_, [u8]> = percent_decode_str(segment).into();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        Some(match decoded {
+            Cow::Borrowed(bytes) => Cow::Borrowed(OsStr::from_bytes(bytes)),
+            Cow::Owned(bytes) => Cow::Owned(OsString::from_vec(bytes)),
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        match decoded {
+            Cow::Borrowed(bytes) => std::str::from_utf8(bytes)
+                .ok()
+                .map(|s| Cow::Borrowed(s.as_ref())),
+            Cow::Owned(bytes) => String::from_utf8(bytes).ok().map(|s| Cow::Owned(s.into())),
+        }
+    }
+}
+
+/// Returns the bytes of a file name for use in a URL. On platforms other than
+/// Unix, this only works for file names that are valid Unicode.
+fn file_name_bytes(name: &OsStr) -> Option<&[u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(name.as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        name.to_str().map(str::as_bytes)
+    }
+}
+
+/// Returns the length of the request without the line break at the end of
+/// `received`, or `None` if it does not end with one yet. The line break is
+/// CRLF, or also a bare LF with `--lenient-termination`. A bare CR never ends
+/// a request.
+fn request_end(received: &[u8]) -> Option<usize> {
+    if let Some(request) = received.strip_suffix(b"\r\n") {
+        Some(request.len())
+    } else if ARGS.lenient_termination {
+        received.strip_suffix(b"\n").map(<[u8]>::len)
+    } else {
+        None
+    }
+}
+
+/// The beginnings of requests in other protocols that are sent to Gemini
+/// servers by scanners and misconfigured clients, with the name of the
+/// protocol. None of them can be the beginning of a URL, since that is a
+/// scheme, which can not contain spaces or control characters.
+const OTHER_PROTOCOLS: [(&[u8], &str); 10] = [
+    (b"GET ", "HTTP"),
+    (b"HEAD ", "HTTP"),
+    (b"POST ", "HTTP"),
+    (b"PUT ", "HTTP"),
+    (b"DELETE ", "HTTP"),
+    (b"OPTIONS ", "HTTP"),
+    (b"CONNECT ", "HTTP"),
+    (b"PRI * HTTP/2", "HTTP/2"),
+    (b"SSH-", "SSH"),
+    // a TLS handshake record, i.e. TLS inside of TLS
+    (b"\x16\x03", "TLS"),
+];
+
+/// Returns the protocol of a request that is obviously not a Gemini request,
+/// judging by the bytes received so far. If they are only the beginning of
+/// such a request, it can not be told yet.
+fn other_protocol(received: &[u8]) -> Option<&'static str> {
+    OTHER_PROTOCOLS
+        .iter()
+        .find(|(start, _)| received.starts_with(start))
+        .map(|(_, protocol)| *protocol)
+}
+
+/// Whether a request is logged in detail because of `--debug-host` or
+/// `--debug-path`. If both are given, the request has to match both.
+fn is_debugged(url: &Url) -> bool {
+    if ARGS.debug_hosts.is_empty() && ARGS.debug_paths.is_empty() {
+        return false;
+    }
+    let host = url.host_str().unwrap_or_default();
+    let host = host.strip_suffix('.').unwrap_or(host);
+    (ARGS.debug_hosts.is_empty()
+        || ARGS
+            .debug_hosts
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(host)))
+        && (ARGS.debug_paths.is_empty()
+            || ARGS
+                .debug_paths
+                .iter()
+                .any(|prefix| url.path().starts_with(prefix.as_str())))
+}
+
+/// Returns whether a path in the content directory or archive is a directory
+/// and the size of the file, or `None` if it does not exist.
+async fn content_metadata(path: &Path) -> Option<(bool, u64)> {
+    match ARGS.archive {
+        Some(ref archive) => match archive.get(path)? {
+            archive::Entry::Dir(_) => Some((true, 0)),
+            archive::Entry::File { size, .. } => Some((false, *size)),
+        },
+        None => {
+            let metadata = tokio::fs::metadata(path).await.ok()?;
+            Some((metadata.is_dir(), metadata.len()))
+        }
+    }
+}
+
+/// Opens a file in the content directory or archive, reading from `offset`
+/// on. The length of a file in the content directory is returned too, since
+/// the file might have changed since its metadata was read. Files in the
+/// archive can not change.
+async fn open_content(
+    path: &Path,
+    offset: u64,
+) -> std::io::Result<(Box<dyn AsyncRead + Send + Unpin>, Option<u64>)> {
+    match ARGS.archive {
+        Some(ref archive) => Ok((Box::new(archive.open_file(path, offset)?), None)),
+        None => {
+            let mut file = tokio::fs::File::open(path).await?;
+            let len = file.metadata().await?.len();
+            if offset > 0 {
+                file.seek(std::io::SeekFrom::Start(offset)).await?;
+            }
+            Ok((Box::new(file), Some(len)))
+        }
+    }
+}
+
+/// Guesses the MIME type of a file from its beginning.
+async fn sniff_start(path: &Path) -> Option<&'static str> {
+    let (file, _) = open_content(path, 0).await.ok()?;
+    BufReader::new(file)
+        .fill_buf()
+        .await
+        .ok()
+        .and_then(sniff::sniff)
+}
+
+/// Parses the `START-END` of a `bytes=START-END` query. Both are byte offsets
+/// and the end is included, an empty end means the end of the file.
+fn parse_byte_range(spec: &str) -> Option<(u64, Option<u64>)> {
+    let (start, end) = spec.split_once('-')?;
+    let number = |s: &str| match s.bytes().all(|b| b.is_ascii_digit()) {
+        true => s.parse::<u64>().ok(),
+        false => None,
+    };
+    let start = number(start)?;
+    match end {
+        "" => Some((start, None)),
+        end => Some((start, Some(number(end)?))).filter(|&(_, end)| end >= Some(start)),
+    }
+}
+
+/// Returns the path of `url` the way files are resolved for it, with decoded
+/// segments and without empty ones, so that rate limit rules can not be
+/// avoided by writing the same path differently.
+fn served_path(url: &Url) -> String {
+    let mut path = String::new();
+    for segment in url.path_segments().into_iter().flatten() {
+        if !segment.is_empty() {
+            path.push('/');
+            path.push_str(&percent_decode_str(segment).decode_utf8_lossy());
+        }
+    }
+    if path.is_empty() || url.path().ends_with('/') {
+        path.push('/');
+    }
+    path
+}
+
+/// Returns the number of bytes of a file of `size` bytes in `range`, or
+/// `None` if the range is not inside of the file.
+fn range_len((start, end): (u64, Option<u64>), size: u64) -> Option<u64> {
+    let end = end.unwrap_or(size.checked_sub(1)?);
+    (start <= end && end < size).then(|| end - start + 1)
+}
+
+/// Returns the file names in a directory and whether each is a directory.
+/// Symlinks are followed, dangling ones are left out.
+async fn read_dir(path: &Path) -> std::io::Result<Vec<(OsString, bool)>> {
+    let mut entries = tokio::fs::read_dir(path).await?;
+    let mut result = vec![];
+    let mut count = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        count += 1;
+        if count > ARGS.listing_max_entries {
+            return Err(TooManyEntries::error());
+        }
+        let file_type = entry.file_type().await?;
+        // only follow symlinks, so regular files do not need another stat
+        let is_dir = if file_type.is_symlink() {
+            match tokio::fs::metadata(entry.path()).await {
+                Ok(metadata) => metadata.is_dir(),
+                Err(_) => {
+                    tracing::debug!("Not listing dangling symlink {:?}", entry.path());
+                    continue;
+                }
+            }
+        } else {
+            file_type.is_dir()
+        };
+        result.push((entry.file_name(), is_dir));
+    }
+    Ok(result)
+}
+
+/// The error of reading a directory with more than `--listing-max-entries`
+/// entries, which is given up on before all of them were read.
+#[derive(Debug)]
+pub(crate) struct TooManyEntries;
+
+impl TooManyEntries {
+    pub fn error() -> std::io::Error {
+        std::io::Error::other(Self)
+    }
+
+    /// Whether reading a directory failed because of too many entries.
+    fn is(e: &std::io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<Self>())
+    }
+}
+
+impl Display for TooManyEntries {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "more than {} entries", ARGS.listing_max_entries)
+    }
+}
+
+impl Error for TooManyEntries {}
+
+/// Returns when a connection accepted now has to be closed, if there is a
+/// `--max-connection-time`.
+fn connection_deadline() -> Option<tokio::time::Instant> {
+    ARGS.max_connection_time
+        .map(|time| tokio::time::Instant::now() + time)
+}
+
+/// Calls `f` for every file below `dir` in the content directory. Hidden
+/// files are skipped unless secret files are served, configuration files
+/// always are. Symlinks are followed,
+/// but only as deep as paths in requests may be, so loops end.
+fn walk_content(dir: &Path, depth: usize, f: &mut dyn FnMut(PathBuf, fs::Metadata)) {
+    if depth >= ARGS.max_path_segments {
+        return;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::warn!("Could not read directory {:?}: {}", dir, e);
+            return;
+        }
+    };
+    // the whole directory is left out, not just some of its files
+    let entries = entries
+        .flatten()
+        .take(ARGS.listing_max_entries + 1)
+        .collect::<Vec<_>>();
+    if entries.len() > ARGS.listing_max_entries {
+        tracing::warn!("Skipping directory {:?}, it has {}", dir, TooManyEntries);
+        return;
+    }
+    for entry in entries {
+        if entry.file_name().to_string_lossy().starts_with('.') && !ARGS.serve_secret
+            || is_config_file(&entry.path())
+        {
+            continue;
+        }
+        match fs::metadata(entry.path()) {
+            Ok(metadata) if metadata.is_dir() => walk_content(&entry.path(), depth + 1, f),
+            Ok(metadata) => f(entry.path(), metadata),
+            Err(_) => (),
+        }
+    }
+}
+
+/// Returns whether `path` is one of the files agate reads its configuration
+/// from, which are refused even with `--serve-secret`.
+fn is_config_file(path: &Path) -> bool {
+    !ARGS.serve_config_files
+        && path
+            .file_name()
+            .is_some_and(|name| CONFIG_FILES.iter().any(|config| name == *config))
+}
+
+/// Reads the `.descriptions` file of a directory, which contains lines of a
+/// file name, a `|` and a description to use as the link label in directory
+/// listings. The file is optional, malformed lines are ignored.
+async fn read_descriptions(dir: &Path) -> HashMap<String, String> {
+    let path = dir.join(".descriptions");
+    let text = match ARGS.archive {
+        Some(ref archive) if archive.is_file(&path) => archive.read_to_string(&path),
+        Some(_) => return HashMap::new(),
+        None => tokio::fs::read_to_string(&path).await,
+    };
+    let text = match text {
+        Ok(text) => text,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::debug!("Could not read {:?}: {}", path, e);
+            }
+            return HashMap::new();
+        }
+    };
+
+    let mut descriptions = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        match line.split_once('|') {
+            // a trailing slash may mark a directory
+            Some((name, description)) if !name.trim_end_matches('/').is_empty() => {
+                let description = description.trim();
+                if !description.is_empty() {
+                    descriptions.insert(
+                        name.trim_end_matches('/').to_string(),
+                        description.to_string(),
+                    );
+                    continue;
+                }
+            }
+            _ if line.trim().is_empty() => continue,
+            _ => (),
+        }
+        tracing::debug!("Ignoring malformed line {} in {:?}", number + 1, path);
+    }
+    descriptions
+}
+
+/// Returns the URL without empty and dot segments in its path if the request
+/// contained any. The URL parser already removed dot segments, so they are
+/// looked for in the request itself. A trailing slash, the query and the
+/// percent-encoding of the remaining segments are kept.
+fn normalize_path(request: &str, url: &Url) -> Option<Url> {
+    let raw_path = request
+        .split_once("://")
+        .map(|(_, rest)| rest.split(['?', '#']).next().unwrap())
+        .and_then(|rest| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or_default();
+    let has_dots = raw_path.split('/').any(|segment| {
+        matches!(
+            segment.to_ascii_lowercase().replace("%2e", ".").as_str(),
+            "." | ".."
+        )
+    });
+
+    let segments = url
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let mut path = format!("/{}", segments.join("/"));
+    if url.path().ends_with('/') && !segments.is_empty() {
+        path.push('/');
+    }
+    if path == url.path() && !has_dots {
+        return None;
+    }
+    let mut normalized = url.clone();
+    normalized.set_path(&path);
+    Some(normalized)
+}
+
+/// Returns the canonical URL of a file if the requested URL is a different
+/// one, i.e. the URL without a trailing slash, or the URL of the directory for
+/// an `index.gmi` file.
+fn canonical_file_path(url: &Url) -> Option<&str> {
+    let path = url.path().trim_end_matches('/');
+    let path = path
+        .strip_suffix("/index.gmi")
+        .map_or(path, |dir| &path[..dir.len() + 1]);
+    (path != url.path()).then_some(path)
+}
+
+/// The host name and port of a redirect target, see `redirect`.
+enum TargetHost<'a> {
+    /// The host name and port of the request.
+    Same,
+    /// Another host name, with the port of the request.
+    Name(&'a str),
+    /// Another host name and optional port, replacing both.
+    Authority(&'a str),
+}
+
+/// Builds a redirect to the requested `url` with another host name and/or
+/// path. `path` has to be percent-encoded like `Url::path`; characters that
+/// are not allowed in a path are encoded, but existing escapes are kept. The
+/// parts of the URL that do not change are copied byte for byte, so clients
+/// get to the same resource after following the redirect.
+fn redirect(url: &Url, host: TargetHost<'_>, path: Option<&str>) -> Response {
+    let mut target = String::from("gemini://");
+    match host {
+        TargetHost::Same => target.push_str(&url[Position::BeforeHost..Position::AfterPort]),
+        TargetHost::Name(name) => {
+            target.push_str(name);
+            target.push_str(&url[Position::AfterHost..Position::AfterPort]);
+        }
+        TargetHost::Authority(authority) => target.push_str(authority),
+    }
+    match path {
+        Some(path) => target.extend(percent_encode(path.as_bytes(), &ENCODE_SET)),
+        None => target.push_str(&url[Position::BeforePath..Position::AfterPath]),
+    }
+    target.push_str(&url[Position::AfterPath..Position::AfterQuery]);
+    Response::header(31, target)
+}
+
+/// Returns the text of the first heading line of a gemtext document.
+fn first_heading(text: &str) -> Option<String> {
+    text.lines()
+        .find(|line| line.starts_with('#'))
+        .map(|line| line.trim_start_matches('#').trim().to_string())
+        .filter(|title| !title.is_empty())
+}
+
+/// Makes a file name safe for use as the label of a gemtext link line. Control
+/// characters are replaced so a file name cannot start a new line, and
+/// leading line type markers are removed.
+fn link_label(name: &str) -> Cow<'_, str> {
+    let name = name.trim_start_matches(|c: char| "=>#*`".contains(c) || c.is_whitespace());
+    if name.contains(char::is_control) {
+        Cow::Owned(name.replace(char::is_control, "\u{FFFD}"))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// How requests for an IP address instead of a host name are answered, from
+/// `--ip-requests`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IpRequests {
+    /// The address has to be one of the host names, like any other host.
+    Refuse,
+    /// Redirect to the canonical host name, or the first host name.
+    Redirect,
+    /// Serve the content of the first host name, unless the address is one
+    /// of the host names.
+    Serve,
+}
+
+/// Whether a requested host is an IP address. For `gemini` URLs, IPv4
+/// addresses are not parsed, so they look like domain names.
+fn is_ip_address(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(name) => name.parse::<std::net::Ipv4Addr>().is_ok(),
+        Host::Ipv4(_) | Host::Ipv6(_) => true,
+    }
+}
+
+/// The host name requests for an IP address are redirected to with
+/// `--ip-requests redirect`.
+fn ip_redirect_host() -> Option<String> {
+    ARGS.canonical_host.clone().or_else(|| {
+        ARGS.hostnames.load().iter().find_map(|host| match host {
+            Host::Domain(name) => Some(name.clone()),
+            _ => None,
+        })
+    })
+}
+
+/// The scheme of a requested URL. Only `gemini` URLs are served, but requests
+/// for other schemes are told apart so that probing for an open proxy can be
+/// recognized in the logs.
+enum Scheme<'a> {
+    Gemini,
+    /// The scheme of another protocol a proxy could fetch, e.g. `https`,
+    /// with the response refusing it.
+    Proxy(&'static FixedHeader),
+    /// Any other scheme, e.g. `mailto`.
+    Other(&'a str),
+}
+
+impl<'a> Scheme<'a> {
+    fn classify(scheme: &'a str) -> Self {
+        match scheme {
+            "gemini" => Self::Gemini,
+            "http" => Self::Proxy(&REFUSED_HTTP),
+            "https" => Self::Proxy(&REFUSED_HTTPS),
+            "gopher" => Self::Proxy(&REFUSED_GOPHER),
+            "titan" => Self::Proxy(&REFUSED_TITAN),
+            "spartan" => Self::Proxy(&REFUSED_SPARTAN),
+            "finger" => Self::Proxy(&REFUSED_FINGER),
+            _ => Self::Other(scheme),
+        }
+    }
+
+    /// The response refusing a request for this scheme.
+    fn refusal(&self) -> Response {
+        match self {
+            Self::Gemini => unreachable!("gemini requests are not refused"),
+            Self::Proxy(header) => Response::Fixed(header),
+            Self::Other(scheme) => {
+                Response::header(53, format!("Unsupported URL scheme {}", scheme))
+            }
+        }
+    }
+}
+
+/// The characters that are percent-encoded in links to files.
+/// https://url.spec.whatwg.org/#path-percent-encode-set
+/// CONTROLS also makes sure that line breaks are encoded
+const ENCODE_SET: AsciiSet = CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The maximum number of links on a page of search results.
+const MAX_SEARCH_RESULTS: usize = 50;
+
+/// The names of the files agate reads its configuration from, see
+/// `is_config_file`.
+const CONFIG_FILES: [&str; 4] = [".meta", ".directory-listing-ok", ".gemlog", ".descriptions"];
+
+/// The default for `--max-generated`.
+const DEFAULT_MAX_GENERATED: usize = 4;
+
+/// The default for `--listing-max-entries`.
+const DEFAULT_LISTING_MAX_ENTRIES: usize = 5000;
+
+/// The default for `--handshake-limit`.
+const DEFAULT_HANDSHAKE_LIMIT: &str = "10/1m";
+
+/// The default for `--handshake-cooldown`.
+const DEFAULT_HANDSHAKE_COOLDOWN: Duration = Duration::from_secs(600);
+
+/// How long a client whose connection reached `--max-connection-time` gets
+/// to receive the `41` response.
+const DEADLINE_GRACE: Duration = Duration::from_secs(1);
+
+/// How long a request waits for a directory listing or gemlog index to be
+/// generated for another one before it is told to retry.
+const GENERATION_WAIT: Duration = Duration::from_secs(2);
+
+/// Limits the number of directory listings and gemlog indexes generated at
+/// the same time. This is separate from other requests, so requests for
+/// files are never blocked by them.
+static GENERATING: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(ARGS.max_generated));
+
+/// Waits until a directory listing or gemlog index may be generated. Returns
+/// `None` if too many were generated for too long.
+async fn generation_permit() -> Option<SemaphorePermit<'static>> {
+    if let Ok(permit) = GENERATING.try_acquire() {
+        return Some(permit);
+    }
+    stats::STATS.record_generation_wait();
+    match tokio::time::timeout(GENERATION_WAIT, GENERATING.acquire()).await {
+        // the semaphore is never closed
+        Ok(permit) => Some(permit.unwrap()),
+        Err(_) => {
+            stats::STATS.record_generation_busy();
+            None
+        }
+    }
+}
+
+/// The maximum length of each value shown by the debug endpoint.
+const MAX_ECHO_LEN: usize = 1024;
+
+/// The maximum length of a response header including the status code, space,
+/// CR and LF.
+const MAX_HEADER_LEN: usize = metadata::MAX_META_LEN + 5;
+
+/// A response header that never changes. It is formatted at compile time, so
+/// sending it is a single write, which matters for the error responses sent
+/// to scanners flooding the server with malformed requests.
+struct FixedHeader {
+    status: u8,
+    meta: &'static str,
+    /// The whole header line, including CR and LF.
+    line: &'static [u8],
+}
+
+macro_rules! fixed_headers {
+    ($($name:ident = $status:literal $meta:literal;)*) => {
+        $(static $name: FixedHeader = FixedHeader {
+            status: $status,
+            meta: $meta,
+            line: concat!($status, " ", $meta, "\r\n").as_bytes(),
+        };)*
+    };
+}
+
+fixed_headers! {
+    SEARCH_PROMPT = 10 "Search this capsule";
+    ECHO_PROMPT = 10 "Type something";
+    MIRROR_UNREACHABLE = 43 "Could not reach the mirrored capsule.";
+    MIRROR_BUSY = 44 "2";
+    GENERATION_BUSY = 44 "2";
+    DIRECTORY_TOO_LARGE = 40 "Directory too large to list";
+    CONNECTION_TIME_EXCEEDED = 41 "Connection took too long.";
+    INTERNAL_ERROR = 42 "Internal server error.";
+    NOT_FOUND = 51 "Not found, sorry.";
+    INDEX_DISABLED = 51 "Directory index disabled.";
+    SECRET = 52 "If I told you, it would not be a secret.";
+    NO_LONGER_AVAILABLE = 52 "This file is no longer available.";
+    HOST_MISMATCH = 53 "Host mismatch";
+    PROXY_REFUSED = 53 "proxy request refused";
+    DEFAULT_PORT_REFUSED = 53 "proxy request refused, a URL without a port is for port 1965";
+    REFUSED_HTTP = 53 "Proxying http URLs is not supported";
+    REFUSED_HTTPS = 53 "Proxying https URLs is not supported";
+    REFUSED_GOPHER = 53 "Proxying gopher URLs is not supported";
+    REFUSED_TITAN = 53 "Proxying titan URLs is not supported";
+    REFUSED_SPARTAN = 53 "Proxying spartan URLs is not supported";
+    REFUSED_FINGER = 53 "Proxying finger URLs is not supported";
+    REQUEST_ENDED = 59 "Request ended unexpectedly";
+    REQUEST_TOO_LONG = 59 "Request too long";
+    NON_UTF8_REQUEST = 59 "Non-UTF-8 request";
+    INVALID_URL = 59 "Invalid URL";
+    OTHER_PROTOCOL = 59 "This is a Gemini server";
+    USERINFO = 59 "URL must not contain userinfo";
+    FRAGMENT = 59 "URL must not contain a fragment";
+    NO_HOST = 59 "URL does not contain a host";
+    TOO_MANY_SEGMENTS = 59 "Path has too many segments";
+    SEGMENT_TOO_LONG = 59 "Path segment too long";
+    TOO_LARGE = 59 "Resource too large";
+    INVALID_RANGE = 59 "Invalid byte range, expected bytes=START-END";
+    CERT_REQUIRED = 60 "Client certificate required";
+    CERT_NOT_AUTHORIZED = 61 "Certificate not authorized";
+}
+
+/// TLS configuration. It is replaced when reloading, connections keep the
+/// one their handshake started with.
+static TLS: Lazy<ArcSwap<TlsAcceptor>> = Lazy::new(|| ArcSwap::from_pointee(acceptor()));
+
+/// Builds the TLS configuration from the current options.
+fn acceptor() -> TlsAcceptor {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    if ARGS.only_tls13 {
+        config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+    }
+    config.cert_resolver = Arc::new(CurrentCerts);
+    // only ask for client certificates if they are used
+    if ARGS.status_page.is_some() || ARGS.debug_endpoint.is_some() || ARGS.client_registry.is_some()
+    {
+        config.set_client_certificate_verifier(Arc::new(AnyClientCert));
+    }
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Accepts any client certificate or none, since Gemini clients use
+/// self-signed certificates. Only the fingerprint is checked later, for the
+/// status page.
+struct AnyClientCert;
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _presented_certs: &[rustls::Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Resolves certificates using the current certificate store, which is
+/// replaced when reloading.
+struct CurrentCerts;
+
+impl ResolvesServerCert for CurrentCerts {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<CertifiedKey> {
+        if client_hello.server_name().is_none() {
+            let _ = NO_SNI.try_with(|no_sni| no_sni.set(true));
+        }
+        ARGS.certs.load().resolve(client_hello)
+    }
+}
+
+tokio::task_local! {
+    /// Set during a TLS handshake if the client did not send a host name, so
+    /// a failed handshake can be told apart from one for an unknown host name.
+    static NO_SNI: Cell<bool>;
+}
+
+/// The response to a request, as determined from the request and the content
+/// directory before anything is sent.
+enum Response {
+    /// A response without a body, e.g. a redirect or an error.
+    Header(u8, Cow<'static, str>),
+    /// A response without a body that never changes, e.g. a common error.
+    Fixed(&'static FixedHeader),
+    /// A successful response with the contents of a file and its MIME type.
+    File(FileBody, Cow<'static, str>),
+    /// A successful response with a generated body and its MIME type.
+    Bytes(Vec<u8>, Cow<'static, str>),
+    /// A successful response relayed from the mirrored capsule.
+    Mirrored(Box<mirror::Relay>, String),
+}
+
+impl Response {
+    fn header(status: u8, meta: impl Into<Cow<'static, str>>) -> Self {
+        Self::Header(status, meta.into())
+    }
+}
+
+/// A file that was opened to be sent as the body of a response.
+struct FileBody {
+    reader: BufReader<Box<dyn AsyncRead + Send + Unpin>>,
+    path: PathBuf,
+    /// The size of the file, if it is known.
+    size: Option<u64>,
+}
+
+/// Catches a panic while polling a future, so that a bug only ends the
+/// connection it happened in and can be logged with it.
+struct CatchUnwind<F>(F);
+
+impl<F: std::future::Future + Unpin> std::future::Future for CatchUnwind<F> {
+    type Output = std::thread::Result<F::Output>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let inner = &mut self.0;
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            std::pin::Pin::new(inner).poll(cx)
+        })) {
+            Ok(poll) => poll.map(Ok),
+            Err(panic) => std::task::Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+/// A connection to a client, usually over TLS, but in plain text when
+/// accepted by the offload listener.
+struct RequestHandle<S = TlsStream<TcpStream>> {
+    stream: S,
+    /// The port that has to be in request URLs, if they contain one.
+    port: u16,
+    /// The host name sent by the client during the TLS handshake.
+    sni: Option<String>,
+    /// The IP address of the client, used for rate limiting.
+    peer_ip: Option<IpAddr>,
+    /// The SHA-256 fingerprint of the client certificate, if there is one.
+    client_cert: Option<String>,
+    /// What `--client-registry` knows about the client certificate.
+    client: Option<Client>,
+    /// The negotiated TLS version, `None` for plaintext connections.
+    tls_version: Option<rustls::ProtocolVersion>,
+    log: AccessLog,
+    metadata: Arc<Mutex<FileOptions>>,
+    /// Set if the response could not be sent completely.
+    aborted: bool,
+    /// Number of response body bytes sent.
+    body_bytes: u64,
+    /// Set if the path of the requested URL was normalized because of
+    /// `--canonical-urls`, so the client has to be redirected.
+    non_canonical: bool,
+    /// The URL as requested by the client, for making redirects relative.
+    request_url: Option<Url>,
+    /// When the connection has to be closed because of `--max-connection-time`.
+    deadline: Option<tokio::time::Instant>,
+    /// The host name of the request once it was checked, for counting the
+    /// response by host name.
+    vhost: Option<String>,
+    /// The steps of resolving the request, only collected for `agate resolve`.
+    /// Nothing is fetched from a mirrored capsule then.
+    steps: Option<Vec<String>>,
+    /// The span debug events are logged in if the request matches
+    /// `--debug-host` or `--debug-path`.
+    debug_span: Option<Span>,
+}
+
+impl RequestHandle {
+    /// Creates a new request handle for the given stream. If establishing the TLS
+    /// session fails, returns a corresponding access log line.
+    async fn new(stream: TcpStream, metadata: Arc<Mutex<FileOptions>>) -> Result<Self, AccessLog> {
+        // the connection was just accepted
+        let deadline = connection_deadline();
+        let port = ARGS
+            .public_port
+            .unwrap_or_else(|| stream.local_addr().unwrap().port());
+        let local_addr = stream.local_addr().unwrap().to_string();
+        let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+
+        // try to get the remote IP address if desired
+        let peer_addr = if ARGS.log_ips {
+            match stream.peer_addr() {
+                Ok(addr) => addr.ip().to_string(),
+                Err(_) => {
+                    let mut log = AccessLog::new(local_addr, "-".into());
+                    log.request = Some(String::new());
+                    // use nonexistent status code 01 if peer IP is unknown
+                    log.status = Some((1, "IP error".into()));
+                    log.error = Some("could not get peer address".into());
+                    return Err(log);
+                }
+            }
+        } else {
+            // Do not log IP address, but something else so columns still line up.
+            "-".into()
+        };
+
+        Span::current().record("peer", display(&peer_addr));
+        let mut log = AccessLog::new(local_addr, peer_addr);
+
+        let accept = NO_SNI.scope(Cell::new(false), async {
+            let acceptor = TLS.load_full();
+            let result = acceptor.accept(stream).await;
+            (result, NO_SNI.with(Cell::get))
+        });
+        let (result, no_sni) = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, accept).await {
+                Ok(accepted) => accepted,
+                Err(_) => {
+                    log.request = Some(String::new());
+                    log.status = Some((0, "Connection time exceeded".into()));
+                    log.error = Some("connection time exceeded during the TLS handshake".into());
+                    return Err(log);
+                }
+            },
+            None => accept.await,
+        };
+        match result {
+            Ok(mut stream) => {
+                // without a limit, rustls buffers everything written to it,
+                // so a slow client would make the whole file stay in memory
+                rustls::Session::set_buffer_limit(stream.get_mut().1, ARGS.io_buffer);
+                let sni = stream.get_ref().1.get_sni_hostname().map(str::to_string);
+                let client_cert = rustls::Session::get_peer_certificates(stream.get_ref().1)
+                    .and_then(|certs| Some(certificates::fingerprint(&certs.first()?.0)));
+                let tls_version = rustls::Session::get_protocol_version(stream.get_ref().1);
+                Span::current().record("sni", display(sni.as_deref().unwrap_or("-")));
+                tracing::debug!("TLS handshake completed");
+                if let (Some(limit), Some(ip)) = (&ARGS.handshake_limit, peer_ip) {
+                    limit.record_success(ip);
+                }
+                Ok(Self {
+                    stream,
+                    port,
+                    sni,
+                    peer_ip,
+                    client_cert,
+                    client: None,
+                    tls_version,
+                    log,
+                    metadata,
+                    aborted: false,
+                    body_bytes: 0,
+                    non_canonical: false,
+                    request_url: None,
+                    deadline,
+                    vhost: None,
+                    steps: None,
+                    debug_span: None,
+                })
+            }
+            Err(e) => {
+                let failure = stats::HandshakeFailure::classify(&e, no_sni);
+                stats::STATS.record_handshake_failure(failure);
+                if let (Some(limit), Some(ip)) = (&ARGS.handshake_limit, peer_ip) {
+                    limit.record_failure(ip, failure);
+                }
+                log.request = Some(String::new());
+                // use nonexistent status code 00 if connection was not established
+                log.status = Some((0, format!("TLS error: {}", failure).into()));
+                log.error = Some(e.to_string());
+                Err(log)
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> RequestHandle<S> {
+    /// Creates a request handle for a plaintext connection, from the offload
+    /// listener or the HTTP gateway. `local_addr` is logged in place of the
+    /// address of a TLS listener. For the offload listener, the relay is the
+    /// peer, so there is no `peer_ip` to be logged or used for rate limiting.
+    fn plaintext(
+        stream: S,
+        metadata: Arc<Mutex<FileOptions>>,
+        local_addr: String,
+        peer_ip: Option<IpAddr>,
+    ) -> Self {
+        let kind = local_addr.split(':').next().unwrap_or_default();
+        Span::current().record("sni", display(kind));
+        let peer_addr = match peer_ip {
+            Some(ip) if ARGS.log_ips => ip.to_string(),
+            _ => "-".into(),
+        };
+        Span::current().record("peer", display(&peer_addr));
+        Self {
+            stream,
+            port: ARGS.public_port.unwrap_or(1965),
+            sni: None,
+            peer_ip,
+            client_cert: None,
+            client: None,
+            tls_version: None,
+            log: AccessLog::new(local_addr, peer_addr),
+            metadata,
+            aborted: false,
+            body_bytes: 0,
+            non_canonical: false,
+            request_url: None,
+            deadline: connection_deadline(),
+            vhost: None,
+            steps: None,
+            debug_span: None,
+        }
+    }
+
+    /// Records a step of resolving the request for `agate resolve`, or logs
+    /// it at debug level otherwise. The step is only formatted if it is used.
+    fn note(&mut self, step: impl FnOnce() -> String) {
+        match (&mut self.steps, &self.debug_span) {
+            (Some(steps), _) => steps.push(step()),
+            (None, Some(span)) => span.in_scope(|| tracing::debug!("{}", step())),
+            (None, None) => tracing::debug!("{}", step()),
+        }
+    }
+
+    /// Do the necessary actions to handle this request. Returns the
+    /// corresponding access log line, which contains an error if the request
+    /// did not finish without errors.
+    async fn handle(mut self) -> AccessLog {
+        let deadline = self.deadline;
+        let result = {
+            let respond = self.respond();
+            tokio::pin!(respond);
+            let respond = CatchUnwind(respond);
+            match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, respond).await.ok(),
+                None => Some(respond.await),
+            }
+        };
+        let result = match result {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => self.panicked(panic).await,
+            None => self.deadline_exceeded().await,
+        };
+
+        stats::STATS.record_response(
+            self.vhost.as_deref(),
+            self.log.status.as_ref().map(|(status, _)| *status),
+            !self.aborted,
+            self.body_bytes,
+            self.log.received,
+        );
+
+        if let Err(e) = result {
+            self.log.error = Some(e.to_string());
+        } else if self.log.error.is_some() {
+            // the request failed even though the client got a response
+        } else if let Err(e) = self.shutdown().await {
+            self.log.error = Some(e.to_string());
+        }
+        tracing::debug!("connection closed");
+        self.log
+    }
+
+    /// Reads the request and sends the response.
+    async fn respond(&mut self) -> Result {
+        // not already in error condition
+        let url = match self.parse_request().await {
+            Ok(url) => url,
+            Err(response) => return self.write_response(response).await,
+        };
+        let span = self.debug_span.clone().unwrap_or_else(Span::none);
+        async {
+            let response = match self.check_rate_limit(&url) {
+                Ok(()) => {
+                    self.see_client(&url);
+                    self.resolve(url).await
+                }
+                // Gemini clients expect the time to wait in whole seconds
+                Err(wait) => {
+                    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                    Ok(Response::header(44, seconds.to_string()))
+                }
+            };
+            match response {
+                Ok(response) => self.write_response(response).await,
+                Err(e) => Err(e),
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Ends a connection whose handling panicked, so the panic only affects
+    /// this one. If no header was sent yet, the client is still told about
+    /// the error, but only if that is quick.
+    async fn panicked(&mut self, panic: Box<dyn std::any::Any + Send>) -> Result {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        tracing::error!(
+            "Panic while handling request {:?}: {}",
+            self.log.request.as_deref().unwrap_or_default(),
+            message
+        );
+        stats::STATS.record_panic();
+        if self.log.status.is_none() {
+            self.log.status = Some((INTERNAL_ERROR.status, Cow::Borrowed(INTERNAL_ERROR.meta)));
+            let write = async {
+                self.stream.write_all(INTERNAL_ERROR.line).await?;
+                self.stream.shutdown().await
+            };
+            let _ = tokio::time::timeout(DEADLINE_GRACE, write).await;
+        } else {
+            self.aborted = true;
+        }
+        Err(format!("panicked: {}", message).into())
+    }
+
+    /// Gives up on a connection that reached its deadline in the middle of
+    /// `respond`. If no header was sent yet, the client is still told to come
+    /// back later, but only if that is quick.
+    async fn deadline_exceeded(&mut self) -> Result {
+        let stage = match (&self.log.request, &self.log.status) {
+            (None, _) => "reading the request",
+            (Some(_), None) => "preparing the response",
+            (Some(_), Some(_)) => "sending the response",
+        };
+        if self.log.status.is_none() {
+            self.log.status = Some((
+                CONNECTION_TIME_EXCEEDED.status,
+                Cow::Borrowed(CONNECTION_TIME_EXCEEDED.meta),
+            ));
+            let write = async {
+                self.stream.write_all(CONNECTION_TIME_EXCEEDED.line).await?;
+                self.stream.shutdown().await
+            };
+            let _ = tokio::time::timeout(DEADLINE_GRACE, write).await;
+        } else {
+            self.aborted = true;
+        }
+        Err(format!(
+            "connection time exceeded while {}, received {} bytes, sent {} body bytes",
+            stage, self.log.received, self.body_bytes
+        )
+        .into())
+    }
+
+    /// Closes the connection, but without waiting past the deadline for a
+    /// client that does not read the TLS close_notify.
+    async fn shutdown(&mut self) -> Result {
+        match self.deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, self.stream.shutdown()).await
+            {
+                Ok(result) => Ok(result?),
+                Err(_) => Err("connection time exceeded while closing the connection".into()),
+            },
+            None => Ok(self.stream.shutdown().await?),
+        }
+    }
+
+    /// Return the URL requested by the client.
+    async fn parse_request(&mut self) -> std::result::Result<Url, Response> {
+        // Because requests are limited to 1024 bytes (plus 2 bytes for CRLF), we
+        // can use a fixed-sized buffer on the stack, avoiding allocations and
+        // copying, and stopping bad clients from making us use too much memory.
+        let mut request = [0; 1026];
+        let mut buf = &mut request[..];
+        let mut len = 0;
+
+        // Read until CRLF, end-of-stream, or there's no buffer space left.
+        //
+        // Since neither CR nor LF can be part of a URI according to
+        // ISOC-RFC 3986, we could use BufRead::read_line here, but that does
+        // not allow us to cap the number of read bytes at 1024+2.
+        let result = loop {
+            let bytes_read = if let Ok(read) = self.stream.read(buf).await {
+                read
+            } else {
+                break Err(&REQUEST_ENDED);
+            };
+            len += bytes_read;
+            self.log.received += bytes_read as u64;
+            // no need to wait for the end of something that is not a request
+            if let Some(protocol) = other_protocol(&request[..len]) {
+                tracing::debug!("refusing {} request", protocol);
+                stats::STATS.record_other_protocol();
+                self.log.protocol = Some(protocol);
+                break Err(&OTHER_PROTOCOL);
+            }
+            if let Some(end) = request_end(&request[..len]) {
+                // with a bare LF, a full buffer holds one byte too many
+                if end > 1024 {
+                    stats::STATS.record_oversized_request();
+                    break Err(&REQUEST_TOO_LONG);
+                }
+                break Ok(end);
+            } else if len == request.len() {
+                stats::STATS.record_oversized_request();
+                break Err(&REQUEST_TOO_LONG);
+            } else if bytes_read == 0 {
+                break Err(&REQUEST_ENDED);
+            }
+            buf = &mut request[len..];
+        }
+        .and_then(|end| {
+            std::str::from_utf8(&request[..end]).map_err(|_| {
+                // keep the evidence, as far as it can be logged
+                let decoded = String::from_utf8_lossy(&request[..end]);
+                self.log.request = Some(logging::redact(&decoded).into_owned());
+                &NON_UTF8_REQUEST
+            })
+        });
+
+        let request = result.map_err(|header| {
+            // write empty request to log line for uniformity
+            self.log.request.get_or_insert_with(String::new);
+            Response::Fixed(header)
+        })?;
+
+        // log literal request (might be different from or not an actual URL)
+        let logged = logging::redact(request);
+        tracing::debug!("received request {:?}", logged);
+        self.log.request = Some(logged.into_owned());
+
+        let mut url = Url::parse(request).or(Err(Response::Fixed(&INVALID_URL)))?;
+        self.request_url = Some(url.clone());
+        if is_debugged(&url) {
+            self.debug_span = Some(logging::debug_span());
+        }
+
+        // Validate the URL:
+        // correct scheme
+        match Scheme::classify(url.scheme()) {
+            Scheme::Gemini => (),
+            scheme => {
+                tracing::debug!("refusing request for {} URL", url.scheme());
+                stats::STATS.record_refused_scheme();
+                self.log.scheme = Some(url.scheme().to_string());
+                return Err(scheme.refusal());
+            }
+        }
+
+        // no userinfo, which might be an attempt to send credentials
+        if url.password().is_some() || !url.username().is_empty() {
+            stats::STATS.record_userinfo();
+            return Err(Response::Fixed(&USERINFO));
+        }
+        // no fragment, which some clients send by mistake
+        if url.fragment().is_some() {
+            if ARGS.strip_fragments {
+                tracing::debug!("removing fragment from request");
+                url.set_fragment(None);
+            } else {
+                stats::STATS.record_fragment();
+                return Err(Response::Fixed(&FRAGMENT));
+            }
+        }
+
+        // correct host
+        if let Some(host) = url.host() {
+            // the host has to match the one the TLS session was established for
+            if let (true, Some(sni)) = (ARGS.strict_sni, &self.sni) {
+                if !url.host_str().unwrap().eq_ignore_ascii_case(sni) {
+                    return Err(Response::Fixed(&HOST_MISMATCH));
+                }
+            }
+            // do not use "contains" here since it requires the same type and does
+            // not allow to check for Host<&str> if the vec contains Hostname<String>
+            let hostnames = ARGS.hostnames.load();
+            // IP addresses are redirected or served later if configured
+            let ip_accepted = ARGS.ip_requests != IpRequests::Refuse && is_ip_address(&host);
+            if !hostnames.is_empty() && !ip_accepted && !hostnames.iter().any(|h| h == &host) {
+                self.note(|| format!("host name {} is not one of the --hostname", host));
+                stats::STATS.record_unknown_host();
+                return Err(Response::header(53, ARGS.unknown_host_meta.as_str()));
+            }
+        } else {
+            return Err(Response::Fixed(&NO_HOST));
+        }
+
+        // correct port
+        // Validate that the port in the URL is the same as for the stream
+        // this request came in on, or the public port if it was given or for
+        // the offload listener.
+        // A URL without a port is for the default port.
+        match url.port() {
+            Some(port) if port != self.port => return Err(Response::Fixed(&PROXY_REFUSED)),
+            None if self.port != 1965 && !ARGS.loose_ports => {
+                let port = self.port;
+                self.note(|| format!("URL without a port, but the port is {}", port));
+                return Err(Response::Fixed(&DEFAULT_PORT_REFUSED));
+            }
+            _ => (),
+        }
+
+        // the response is counted for the host name from now on, but only for
+        // one of the configured host names if there are any, so an IP address
+        // counts as unknown then
+        let hostnames = ARGS.hostnames.load();
+        let host = url.host().unwrap();
+        self.vhost = match hostnames.is_empty() {
+            true => Some(host.to_string().trim_end_matches('.').to_ascii_lowercase()),
+            false => hostnames
+                .iter()
+                .find(|h| *h == &host)
+                .map(ToString::to_string),
+        };
+        self.note(|| format!("host name {} accepted", host));
+
+        // send clients that used an IP address to the host name
+        if ARGS.ip_requests == IpRequests::Redirect && url.host().is_some_and(|h| is_ip_address(&h))
+        {
+            let name = match ip_redirect_host() {
+                Some(name) => name,
+                // the host names were reloaded without any names
+                None => {
+                    stats::STATS.record_unknown_host();
+                    return Err(Response::header(53, ARGS.unknown_host_meta.as_str()));
+                }
+            };
+            return Err(redirect(&url, TargetHost::Name(&name), None));
+        }
+
+        // redirect a host name that moved without looking at the content, with
+        // the path and query exactly as they were requested
+        let host = url.host_str().unwrap().trim_end_matches('.');
+        if let Some((_, new)) = ARGS
+            .redirect_hosts
+            .iter()
+            .find(|(old, _)| host.eq_ignore_ascii_case(old))
+        {
+            return Err(redirect(&url, TargetHost::Authority(new), None));
+        }
+
+        // Whether the normalized URL is the canonical one also depends on the
+        // content, e.g. if it is a directory, so the redirect is sent later.
+        // Otherwise the client might have to follow two redirects.
+        let url = match ARGS.canonical_urls.then(|| normalize_path(request, &url)) {
+            Some(Some(normalized)) => {
+                self.non_canonical = true;
+                normalized
+            }
+            _ => url,
+        };
+
+        // redirect other names of this capsule, instead of sending a response
+        if let Some(ref canonical) = ARGS.canonical_host {
+            let host = url.host_str().unwrap().trim_end_matches('.');
+            if !host.eq_ignore_ascii_case(canonical) {
+                return Err(redirect(&url, TargetHost::Name(canonical), None));
+            }
+        }
+        Ok(url)
+    }
+
+    /// Counts the request towards the rate limit. If the client made too many
+    /// requests, returns the time it has to wait.
+    fn check_rate_limit(&self, url: &Url) -> std::result::Result<(), Duration> {
+        match (&ARGS.rate_limit, self.peer_ip) {
+            (Some(rate_limit), Some(ip)) => rate_limit.check(ip, &served_path(url)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Determines the response to a request for the file at the requested
+    /// URL. Returns an error if the request should not be answered at all.
+    async fn resolve(&mut self, url: Url) -> Result<Response> {
+        // lets the tests check how panics are handled, never in release builds
+        #[cfg(debug_assertions)]
+        if std::env::var_os("AGATE_TEST_PANIC").is_some_and(|path| path == url.path()) {
+            panic!("test panic for {}", url);
+        }
+        // this does not depend on the content directory, so it also works
+        // when the content directory is broken
+        if ARGS.status_page.as_deref() == Some(url.path()) {
+            return Ok(self.status_page());
+        }
+        if ARGS.debug_endpoint.as_deref() == Some(url.path()) {
+            return Ok(self.echo(&url));
+        }
+
+        let mut path = std::path::PathBuf::from(&ARGS.content_dir);
+
+        let hostnames = ARGS.hostnames.load();
+        if hostnames.len() > 1 && !ARGS.shared_content {
+            // basic vhosts, existence of host_str was checked by parse_request already
+            let host = url.host_str().expect("no hostname");
+            // an IP address that is not one of the host names is served like
+            // the first host name
+            if ARGS.ip_requests == IpRequests::Serve
+                && is_ip_address(&url.host().unwrap())
+                && !hostnames.iter().any(|h| h.to_string() == host)
+            {
+                path.push(hostnames[0].to_string());
+            } else {
+                path.push(host);
+            }
+        }
+        self.note(|| format!("content root {:?}", path));
+
+        // a new capsule shows that it works even before there is content
+        if matches!(url.path(), "" | "/") {
+            if let Some(page) = welcome::page(&path).await {
+                self.note(|| "the content directory is empty, sending the welcome page".into());
+                return Ok(Response::Bytes(page.into(), "text/gemini".into()));
+            }
+        }
+
+        if let Some(ref search) = ARGS.search {
+            if url.path() == search.path() {
+                if self.non_canonical {
+                    return Ok(redirect(&url, TargetHost::Same, None));
+                }
+                return Ok(self.search(search, &url, &path).await);
+            }
+        }
+
+        if let Some(ref feed) = ARGS.feed {
+            if url.path() == feed.path() {
+                if self.non_canonical {
+                    return Ok(redirect(&url, TargetHost::Same, None));
+                }
+                let body = feed.generate(&url, &path, self.metadata.clone()).await;
+                return Ok(Response::Bytes(
+                    body.into_bytes(),
+                    "application/atom+xml".into(),
+                ));
+            }
+        }
+
+        if let Some(mut segments) = url.path_segments() {
+            // append percent-decoded path segments
+            // empty segments, e.g. after a trailing slash, do not add a component
+            for (i, segment) in segments
+                .clone()
+                .filter(|segment| !segment.is_empty())
+                .enumerate()
+            {
+                // limit the work done for pathological requests before
+                // touching the file system
+                if i >= ARGS.max_path_segments {
+                    self.note(|| format!("more than {} path segments", ARGS.max_path_segments));
+                    return Ok(Response::Fixed(&TOO_MANY_SEGMENTS));
+                }
+                // To prevent directory traversal attacks, we need to
+                // check that each filesystem path component in the URL
+                // path segment is a normal component (not the root
+                // directory, the parent directory, a drive label, or
+                // another special component). Furthermore, since path
+                // separators (e.g. the escaped forward slash %2F) in a
+                // single URL path segment are non-structural, the URL
+                // path segment should not contain multiple filesystem
+                // path components.
+                let decoded = decode_segment(segment).ok_or("Non-UTF-8 path")?;
+                self.note(|| format!("segment {:?} decoded to {:?}", segment, decoded));
+                if decoded.len() > ARGS.max_segment_length {
+                    self.note(|| format!("segment longer than {} bytes", ARGS.max_segment_length));
+                    return Ok(Response::Fixed(&SEGMENT_TOO_LONG));
+                }
+                let mut components = Path::new(&*decoded).components();
+                // the first component must be a normal component; if
+                // so, push it onto the PathBuf
+                match components.next() {
+                    None => (),
+                    Some(Component::Normal(c)) => path.push(c),
+                    Some(_) => {
+                        self.note(|| "segment is not a file name".into());
+                        return Ok(Response::Fixed(&NOT_FOUND));
+                    }
+                }
+                // there must not be more than one component
+                if components.next().is_some() {
+                    self.note(|| "segment contains a path separator".into());
+                    return Ok(Response::Fixed(&NOT_FOUND));
+                }
+                // even if it's one component, there may be trailing path
+                // separators at the end
+                if decoded.to_string_lossy().ends_with(path::is_separator) {
+                    self.note(|| "segment ends with a path separator".into());
+                    return Ok(Response::Fixed(&NOT_FOUND));
+                }
+            }
+            // check if hiding files is disabled
+            let serve_secret = host_options(url.host_str().unwrap_or_default())
+                .serve_secret
+                .unwrap_or(ARGS.serve_secret);
+            if !serve_secret
+                // there is a configuration for this file, assume it should be served
+                && !self.metadata.lock().await.exists(&path)
+                // check if file or directory is hidden
+                && segments.any(|segment| segment.starts_with('.'))
+            {
+                self.note(|| "path contains a hidden file or directory".into());
+                return Ok(Response::Fixed(&SECRET));
+            }
+        }
+
+        // repeated requests for a missing file are answered without looking
+        // for it again, unless a .meta file has an entry for it
+        let host = url.host_str().unwrap_or_default();
+        if !self.non_canonical
+            && misses::MISSES.contains(host, &path)
+            && !self.metadata.lock().await.exists(&path)
+        {
+            stats::STATS.record_cached_miss();
+            self.note(|| "file was recently found to be missing".into());
+            return Ok(Response::Fixed(&NOT_FOUND));
+        }
+
+        // the size of the file to be sent, if it is known
+        let mut size = None;
+
+        // if the path ends with a slash or the path is empty, the links will work the same
+        // without a redirect; an empty path only occurs for the content root, or
+        // the directory of a virtual host
+        let directory_url = url.path().ends_with('/') || url.path().is_empty();
+        // finding the index also shows that the path is a directory
+        let index = match directory_url {
+            true => indexes::find(&path).await,
+            false => None,
+        };
+        let found = match index {
+            Some(_) => Some((true, 0)),
+            None => content_metadata(&path).await,
+        };
+
+        // a language variant of a gemtext file may be served instead of it
+        let mut variant_lang = None;
+        if let Some((is_dir, len)) = found {
+            if is_dir {
+                if directory_url {
+                    if self.non_canonical {
+                        return Ok(redirect(&url, TargetHost::Same, None));
+                    }
+                    let has_index = matches!(index, Some(DirIndex::File(_)));
+                    let index_variant = match ARGS.lang_variants {
+                        true => variants::select_index(&path, url.query(), has_index).await,
+                        false => None,
+                    };
+                    let mut variant_path = None;
+                    let index = match index_variant {
+                        Some((variant, lang, len)) => {
+                            variant_path = Some(variant);
+                            variant_lang = Some(lang);
+                            DirIndex::File(len)
+                        }
+                        None => match index {
+                            Some(index) => index,
+                            None => indexes::fallback(&path).await,
+                        },
+                    };
+                    // without a .directory-listing-ok file, listing depends on
+                    // the host name
+                    let index = match index {
+                        DirIndex::Disabled
+                            if host_options(url.host_str().unwrap_or_default())
+                                .list_directories
+                                .unwrap_or(ARGS.list_directories) =>
+                        {
+                            DirIndex::Listing
+                        }
+                        index => index,
+                    };
+                    self.note(|| {
+                        let served = match index {
+                            DirIndex::File(_) => "index file",
+                            DirIndex::Gemlog => "gemlog index",
+                            DirIndex::Listing => "directory listing",
+                            DirIndex::Disabled => "no index file, listing disabled",
+                        };
+                        format!("directory {:?}, serving the {}", path, served)
+                    });
+                    match index {
+                        DirIndex::File(len) => {
+                            match variant_path {
+                                Some(variant) => {
+                                    self.note(|| format!("language variant {:?}", variant));
+                                    path = variant;
+                                }
+                                None => path.push("index.gmi"),
+                            }
+                            size = Some(len);
+                        }
+                        DirIndex::Gemlog => return Ok(self.gemlog_index(&path).await),
+                        DirIndex::Listing => return Ok(self.list_directory(&path).await),
+                        DirIndex::Disabled => {
+                            return Ok(Response::Fixed(&INDEX_DISABLED));
+                        }
+                    }
+                } else {
+                    // if client is not redirected, links may not work as expected without trailing slash
+                    let path = format!("{}/", url.path());
+                    return Ok(redirect(&url, TargetHost::Same, Some(&path)));
+                }
+            } else if let Some(path) = canonical_file_path(&url).filter(|_| ARGS.canonical_urls) {
+                return Ok(redirect(&url, TargetHost::Same, Some(path)));
+            } else if self.non_canonical {
+                return Ok(redirect(&url, TargetHost::Same, None));
+            } else {
+                size = Some(len);
+            }
+        } else if self.non_canonical {
+            return Ok(redirect(&url, TargetHost::Same, None));
+        }
+
+        if ARGS.lang_variants && variant_lang.is_none() {
+            if let Some((variant, lang, len)) =
+                variants::select(&path, url.query(), size.is_some()).await
+            {
+                self.note(|| format!("language variant {:?}", variant));
+                path = variant;
+                size = Some(len);
+                variant_lang = Some(lang);
+            }
+        }
+
+        tracing::debug!("resolved {:?} to {:?}", url.path(), path);
+        self.note(|| format!("file {:?}", path));
+        if is_config_file(&path) {
+            self.note(|| "configuration files are never served".into());
+            return Ok(Response::Fixed(&SECRET));
+        }
+        let schedule = self.metadata.lock().await.schedule(&path);
+        let availability = schedule.map(|s| s.availability(SystemTime::now()));
+        if let Some(availability) = availability {
+            self.note(|| format!("publication window: {:?}", availability));
+        }
+        match availability {
+            Some(Availability::Embargoed) => return Ok(Response::Fixed(&NOT_FOUND)),
+            Some(Availability::Expired) => return Ok(Response::Fixed(&NO_LONGER_AVAILABLE)),
+            Some(Availability::Published) | None => (),
+        }
+
+        // the compressed companion is served under the publication window of
+        // the file, but with its own options
+        if let Some(ref query) = ARGS.precompressed {
+            if size.is_some() && url.query() == Some(query.as_str()) {
+                let mut companion = path.clone().into_os_string();
+                companion.push(".gz");
+                let companion = PathBuf::from(companion);
+                if let Some((false, len)) = content_metadata(&companion).await {
+                    self.note(|| format!("compressed companion {:?}", companion));
+                    path = companion;
+                    size = Some(len);
+                }
+            }
+        }
+
+        let (data, explicit) = {
+            let mut metadata = self.metadata.lock().await;
+            (metadata.get(&path), metadata.exists(&path))
+        };
+        if explicit && (self.steps.is_some() || self.debug_span.is_some()) {
+            let source = self.metadata.lock().await.source(&path);
+            if let Some((db, line)) = source {
+                self.note(|| match line {
+                    Some(line) => format!("entry in {:?} line {}: {:?}", db, line, data),
+                    None => format!("entry in {:?}: {:?}", db, data),
+                });
+            }
+        }
+
+        if let PresetMeta::FullHeader(status, meta) = data {
+            // do not try to access the file
+            return Ok(Response::Header(status, meta.into()));
+        }
+
+        let range = match url.query().and_then(|query| query.strip_prefix("bytes=")) {
+            Some(spec) if ARGS.byte_ranges => match parse_byte_range(spec) {
+                Some(range) => Some(range),
+                None => {
+                    self.note(|| format!("invalid byte range {:?}", logging::redact_query(spec)));
+                    return Ok(Response::Fixed(&INVALID_RANGE));
+                }
+            },
+            _ => None,
+        };
+
+        // only the requested slice counts against the maximum size
+        if let Some(size) = size.map(|size| range.and_then(|r| range_len(r, size)).unwrap_or(size))
+        {
+            let max_size = self
+                .metadata
+                .lock()
+                .await
+                .max_size(&path)
+                .unwrap_or(ARGS.max_response_size);
+            if matches!(max_size, Some(max) if size > max) {
+                tracing::info!(
+                    "Not sending {:?}, it is larger than {:?} bytes",
+                    path,
+                    max_size
+                );
+                self.note(|| format!("{} bytes are more than the maximum size", size));
+                return Ok(Response::Fixed(&TOO_LARGE));
+            }
+        }
+
+        if let (Some(mirror), None) = (ARGS.mirror.as_ref(), range) {
+            if self.steps.is_some() {
+                self.note(|| {
+                    "would be fetched from the mirrored capsule if missing or outdated".into()
+                });
+            } else if let Some(response) = self.fetch_mirrored(mirror, &url, &path).await? {
+                return Ok(response);
+            }
+        }
+
+        // Make sure the file opens successfully before sending a success header.
+        let offset = range.map_or(0, |(start, _)| start);
+        let mut file = match open_content(&path, offset).await {
+            Ok((file, len)) => {
+                // exactly this many bytes are sent, even if the file changes
+                size = len.or(size);
+                BufReader::new(file)
+            }
+            Err(e) => {
+                // with language variants, the query can make a difference
+                if e.kind() == std::io::ErrorKind::NotFound
+                    && !explicit
+                    && !(ARGS.lang_variants && path.extension() == Some(OsStr::new("gmi")))
+                {
+                    misses::MISSES.insert(url.host_str().unwrap_or_default(), &path);
+                }
+                return Ok(self.failed(e, Response::Fixed(&NOT_FOUND)));
+            }
+        };
+        if let Some(range) = range {
+            let file_size = size.unwrap_or_default();
+            let Some(len) = range_len(range, file_size) else {
+                self.note(|| format!("byte range {:?} is outside of the file", range));
+                return Ok(Response::header(
+                    59,
+                    format!(
+                        "Byte range outside of the file, which has {} bytes",
+                        file_size
+                    ),
+                ));
+            };
+            let end = offset + len - 1;
+            self.note(|| format!("serving bytes {} to {} of {}", offset, end, file_size));
+            self.log.range = Some(format!("{}-{}", offset, end));
+            size = Some(len);
+        }
+
+        let mime = match data {
+            // this was already handled before opening the file
+            PresetMeta::FullHeader(..) => unreachable!(),
+            // treat this as the full MIME type
+            PresetMeta::FullMime(mime) => mime.into(),
+            // neither guessed nor with parameters like the language
+            PresetMeta::Download => "application/octet-stream".into(),
+            // guess the MIME type and add the parameters
+            PresetMeta::Parameters(params) => {
+                let guessed = mime_guess::from_path(&path).first();
+                let mime = if path.extension() == Some(OsStr::new("gmi")) {
+                    "text/gemini"
+                } else if ARGS.precompressed.is_some() && path.extension() == Some(OsStr::new("gz"))
+                {
+                    // not the type of the compressed content, e.g. for .tar.gz
+                    "application/gzip"
+                } else if let Some(ref mime) = guessed {
+                    mime.essence_str()
+                } else {
+                    // peek at the beginning of the file, the buffered data
+                    // will still be sent as part of the body
+                    let sniffed = if !ARGS.sniff_mime {
+                        None
+                    } else if offset == 0 {
+                        file.fill_buf().await.ok().and_then(sniff::sniff)
+                    } else {
+                        // the slice does not start with the beginning
+                        sniff_start(&path).await
+                    };
+                    sniffed.unwrap_or("application/octet-stream")
+                };
+                // the default language is only used for text/gemini, or for
+                // all text types if enabled, but never for binary types
+                let (params, lang) = if let (Some(lang), false) = (&variant_lang, explicit) {
+                    // the language of the variant replaces the default one
+                    (";lang=", lang.as_str())
+                } else if explicit
+                    || mime == "text/gemini"
+                    || (ARGS.lang_all_text && mime.starts_with("text/"))
+                {
+                    (params.as_str(), "")
+                } else {
+                    ("", "")
+                };
+                // parameters from a .meta file replace the default ones
+                let defaults = if explicit {
+                    ""
+                } else {
+                    default_mime_params(mime).unwrap_or_default()
+                };
+                interned::meta(&[mime, params, lang, defaults])
+            }
+        };
+        Ok(Response::File(
+            FileBody {
+                reader: file,
+                path,
+                size,
+            },
+            mime,
+        ))
+    }
+
+    /// Fetches the requested file from the mirrored capsule if it does not
+    /// exist locally or is outdated, and starts saving it if possible. Returns
+    /// `None` if the local file should be served instead.
+    ///
+    /// Requests with a query are always fetched and never saved, since the
+    /// saved file could only hold the response for one of the queries.
+    async fn fetch_mirrored(
+        &mut self,
+        mirror: &'static Mirror,
+        url: &Url,
+        path: &Path,
+    ) -> Result<Option<Response>> {
+        let query = url.query();
+        let local_metadata = || async {
+            match query {
+                None => tokio::fs::metadata(path).await.ok(),
+                Some(_) => None,
+            }
+        };
+        if !mirror.needs_fetch(local_metadata().await.as_ref()) {
+            return Ok(None);
+        }
+
+        // Only one request fetches a path at a time, others wait here and
+        // then either use the saved file or the response that was relayed.
+        let mut in_flight = mirror.wait(path, query).await;
+        if let Some(mirror::Fetched::Header(status, meta)) = in_flight.result.clone() {
+            return Ok(Some(Response::Header(status, meta.into())));
+        }
+        let local = local_metadata().await;
+        if !mirror.needs_fetch(local.as_ref()) {
+            return Ok(None);
+        }
+        if let Some(mirror::Fetched::Relayed) = *in_flight.result {
+            // the body was only sent to the request that fetched it, and
+            // fetching it again for each waiting request would send all of
+            // them upstream after all
+            self.note(|| "mirrored response was relayed, but not saved".into());
+            return Ok(Some(Response::Fixed(&MIRROR_BUSY)));
+        }
+
+        let response = match mirror.fetch(url).await {
+            Ok(response) => response,
+            Err(e) if local.is_some() => {
+                tracing::warn!("Could not refresh mirrored file {:?}: {}", path, e);
+                return Ok(None);
+            }
+            Err(e) => {
+                let response = Response::Fixed(&MIRROR_UNREACHABLE);
+                return Ok(Some(self.failed(e, response)));
+            }
+        };
+
+        // other responses are relayed as-is, but not saved
+        if response.status != 20 {
+            *in_flight.result = Some(mirror::Fetched::Header(
+                response.status,
+                response.meta.clone(),
+            ));
+            return Ok(Some(Response::Header(
+                response.status,
+                response.meta.into(),
+            )));
+        }
+
+        // directory indices and responses to queries are relayed, but there
+        // is no file to save them as
+        let download = if url.path().ends_with('/') || url.path().is_empty() || query.is_some() {
+            None
+        } else {
+            match mirror.save(path).await {
+                Ok(download) => Some(download),
+                Err(e) => {
+                    tracing::warn!("Could not save mirrored file {:?}: {}", path, e);
+                    None
+                }
+            }
+        };
+
+        // if the file is saved, the waiting requests find it instead
+        *in_flight.result = Some(mirror::Fetched::Relayed);
+        let relay = mirror::Relay::new(response.body, download, path, in_flight);
+        Ok(Some(Response::Mirrored(Box::new(relay), response.meta)))
+    }
+
+    /// Looks up the client certificate in the client registry, recording it
+    /// if it is new. A query of the form `petname=NAME` names a new client.
+    fn see_client(&mut self, url: &Url) {
+        let (Some(registry), Some(fingerprint)) = (&ARGS.client_registry, &self.client_cert) else {
+            return;
+        };
+        let petname = url
+            .query()
+            .and_then(|query| query.strip_prefix("petname="))
+            .map(|name| percent_decode_str(name).decode_utf8_lossy());
+        self.client = registry.see(fingerprint, petname.as_deref());
+    }
+
+    /// Answers a request for the status page if the client certificate is one
+    /// of those given with `--admin-cert`.
+    fn status_page(&self) -> Response {
+        match self.client_cert {
+            None => Response::Fixed(&CERT_REQUIRED),
+            Some(ref fingerprint) if !ARGS.admin_certs.contains(fingerprint) => {
+                tracing::info!("Refusing status page to certificate {}", fingerprint);
+                Response::Fixed(&CERT_NOT_AUTHORIZED)
+            }
+            Some(_) => Response::Bytes(status::render().into_bytes(), "text/gemini".into()),
+        }
+    }
+
+    /// Answers a request for the debug endpoint with what was received. The
+    /// values are quoted, so they can not add lines to the page, and cut off
+    /// after `MAX_ECHO_LEN` bytes. Nothing is logged, since the query might
+    /// have to be redacted.
+    fn echo(&self, url: &Url) -> Response {
+        let query = match url.query() {
+            Some(query) if !query.is_empty() => query,
+            _ => return Response::Fixed(&ECHO_PROMPT),
+        };
+        let show = |value: &dyn std::fmt::Debug| {
+            let mut value = format!("{:?}", value);
+            if value.len() > MAX_ECHO_LEN {
+                let mut end = MAX_ECHO_LEN;
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                value.truncate(end);
+                value.push('\u{2026}');
+            }
+            value
+        };
+        let tls_version = match self.tls_version {
+            Some(rustls::ProtocolVersion::TLSv1_2) => "TLS 1.2".to_string(),
+            Some(rustls::ProtocolVersion::TLSv1_3) => "TLS 1.3".to_string(),
+            Some(version) => format!("{:?}", version),
+            None => "none".to_string(),
+        };
+
+        let mut body = String::from("# Received request\n\n");
+        body.push_str(&format!("* Query: {}\n", show(&query)));
+        body.push_str(&format!(
+            "* Decoded query: {}\n",
+            show(&percent_decode_str(query).decode_utf8_lossy())
+        ));
+        // the request URL was parsed before anything else was checked
+        let parsed = self.request_url.as_ref().expect("request was parsed");
+        body.push_str(&format!("* Parsed URL: {}\n", show(&parsed.as_str())));
+        let sni = self.sni.as_ref().map(|sni| show(sni));
+        body.push_str(&format!("* SNI: {}\n", sni.as_deref().unwrap_or("none")));
+        body.push_str(&format!("* TLS version: {}\n", tls_version));
+        body.push_str(&format!(
+            "* Client certificate: {}\n",
+            self.client_cert.as_deref().unwrap_or("none")
+        ));
+        if let Some(ref client) = self.client {
+            body.push_str(&format!(
+                "* Known client: {}\n",
+                if client.known { "yes" } else { "no" }
+            ));
+            body.push_str(&format!(
+                "* First seen: {}\n",
+                humantime::format_rfc3339_seconds(client.first_seen)
+            ));
+            if let Some(ref petname) = client.petname {
+                body.push_str(&format!("* Petname: {}\n", show(petname)));
+            }
+        }
+        Response::Bytes(body.into_bytes(), "text/gemini".into())
+    }
+
+    /// Answers a request for the search endpoint. Without a query, the client
+    /// is asked for one, otherwise the matching files below `root` are listed.
+    async fn search(&mut self, search: &Search, url: &Url, root: &Path) -> Response {
+        let query = match url.query() {
+            Some(query) if !query.is_empty() => percent_decode_str(query).decode_utf8_lossy(),
+            _ => return Response::Fixed(&SEARCH_PROMPT),
+        };
+
+        let documents = {
+            let mut metadata = self.metadata.lock().await;
+            let now = SystemTime::now();
+            search.query(&query, MAX_SEARCH_RESULTS, |document| {
+                document.path.starts_with(root)
+                    && metadata.schedule(&document.path).is_none_or(|schedule| {
+                        schedule.availability(now) == Availability::Published
+                    })
+            })
+        };
+
+        let mut body = format!("# Search results for {}\n\n", link_label(&query));
+        if documents.is_empty() {
+            body.push_str("No matching pages found.\n");
+        }
+        for document in documents {
+            let relative = document.path.strip_prefix(root).unwrap();
+            let link = relative
+                .components()
+                .map(|c| {
+                    file_name_bytes(c.as_os_str())
+                        .map(|name| format!("/{}", percent_encode(name, &ENCODE_SET)))
+                })
+                .collect::<Option<String>>();
+            // such a file could not be requested anyway
+            let link = match link {
+                Some(link) => link,
+                None => continue,
+            };
+            let title = document
+                .title
+                .unwrap_or_else(|| relative.to_string_lossy().into_owned());
+            body.push_str(&format!("=> {} {}\n", link, link_label(&title)));
+        }
+
+        Response::Bytes(body.into_bytes(), "text/gemini".into())
+    }
+
+    async fn gemlog_index(&mut self, path: &Path) -> Response {
+        let _permit = match generation_permit().await {
+            Some(permit) => permit,
+            None => return Response::Fixed(&GENERATION_BUSY),
+        };
+        tracing::info!("Generating gemlog index for {:?}", path);
+        let index = match feed::gemlog_index(path, self.metadata.clone()).await {
+            Ok(index) => index,
+            Err(e) if TooManyEntries::is(&e) => {
+                tracing::warn!("Not generating gemlog index for {:?}, it has {}", path, e);
+                return self.failed(e, Response::Fixed(&DIRECTORY_TOO_LARGE));
+            }
+            Err(e) => return self.failed(e, Response::Fixed(&NOT_FOUND)),
+        };
+        // the index is generated, but may not be larger than a file
+        if matches!(ARGS.max_response_size, Some(max) if index.len() as u64 > max) {
+            return Response::Fixed(&TOO_LARGE);
+        }
+        Response::Bytes(index.into_bytes(), "text/gemini".into())
+    }
+
+    async fn list_directory(&mut self, path: &Path) -> Response {
+        let _permit = match generation_permit().await {
+            Some(permit) => permit,
+            None => return Response::Fixed(&GENERATION_BUSY),
+        };
+        tracing::info!("Listing directory {:?}", path);
+        let entries = match ARGS.archive {
+            Some(ref archive) => match archive.get(path) {
+                Some(archive::Entry::Dir(children))
+                    if children.len() > ARGS.listing_max_entries =>
+                {
+                    Err(TooManyEntries::error())
+                }
+                _ => archive
+                    .read_dir(path)
+                    .ok_or_else(|| std::io::ErrorKind::NotFound.into()),
+            },
+            None => read_dir(path).await,
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) if TooManyEntries::is(&e) => {
+                tracing::warn!("Not listing directory {:?}, it has {}", path, e);
+                return self.failed(e, Response::Fixed(&DIRECTORY_TOO_LARGE));
+            }
+            Err(e) => return self.failed(e, Response::Fixed(&NOT_FOUND)),
+        };
+        let descriptions = read_descriptions(path).await;
+        // files with a compressed companion, by name
+        let compressed = match ARGS.precompressed {
+            Some(_) => entries
+                .iter()
+                .filter(|(_, is_dir)| !is_dir)
+                .filter_map(|(name, _)| name.to_str()?.strip_suffix(".gz"))
+                .map(str::to_string)
+                .collect(),
+            None => HashSet::new(),
+        };
+        // the entries to be listed by their sort key, rendered after sorting
+        let mut listed = vec![];
+        let metadata = self.metadata.clone();
+        let mut metadata = metadata.lock().await;
+        let now = SystemTime::now();
+        for (file_name, is_dir) in entries {
+            let name = match file_name_bytes(&file_name) {
+                Some(name) => name.to_vec(),
+                None => {
+                    tracing::warn!("Not listing file with non-Unicode name {:?}", file_name);
+                    continue;
+                }
+            };
+            if name.starts_with(b".") {
+                continue;
+            }
+            // do not list files outside of their publication window
+            if let Some(schedule) = metadata.schedule(&path.join(&file_name)) {
+                if schedule.availability(now) != Availability::Published {
+                    continue;
+                }
+            }
+            let description = descriptions.get(&*String::from_utf8_lossy(&name));
+            let has_companion = !is_dir && compressed.contains(&*String::from_utf8_lossy(&name));
+            let download =
+                !is_dir && matches!(metadata.get(&path.join(&file_name)), PresetMeta::Download);
+            let variant = match std::str::from_utf8(&name) {
+                Ok(name) if ARGS.lang_variants && !is_dir && !has_companion => {
+                    variants::split(name).map(|(base, lang)| (base, lang.to_string()))
+                }
+                _ => None,
+            };
+            // sorted by the bytes of the file name, so the order does not
+            // depend on the file system or the locale; variants are sorted
+            // right after their base file
+            let key = match variant {
+                Some((ref base, ref lang)) => (base.as_bytes().to_vec(), Some(lang.clone())),
+                None => (name.clone(), None),
+            };
+            listed.push((
+                key,
+                name,
+                is_dir,
+                variant,
+                description,
+                has_companion,
+                download,
+            ));
+        }
+        drop(metadata);
+        listed.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+
+        // the links with their labels
+        let links = listed
+            .into_iter()
+            .map(
+                |(_, mut name, is_dir, variant, description, has_companion, download)| {
+                    if is_dir {
+                        name.push(b'/');
+                    }
+                    // variants are linked with their query
+                    let url = match variant {
+                        Some((ref base, ref lang)) => format!(
+                            "{}?lang={}",
+                            percent_encode(base.as_bytes(), &ENCODE_SET),
+                            lang
+                        ),
+                        None => percent_encode(&name, &ENCODE_SET).to_string(),
+                    };
+                    if let Some((base, lang)) = variant {
+                        name = format!("{} ({})", base, lang).into_bytes();
+                    }
+                    (url, name, description, has_companion, download)
+                },
+            )
+            .collect::<Vec<_>>();
+
+        let mut listing = String::with_capacity(
+            links
+                .iter()
+                .map(|(url, name, description, ..)| {
+                    "=> \n".len() + url.len() + 1 + description.map_or(name.len(), String::len)
+                })
+                .sum(),
+        );
+        for (url, name, description, has_companion, download) in links {
+            listing.push_str("=> ");
+            listing.push_str(&url);
+            match description {
+                Some(description) => {
+                    listing.push(' ');
+                    listing.push_str(&link_label(description));
+                }
+                // url and name are identical
+                None if url.as_bytes() == name && !has_companion && !download => (),
+                None => {
+                    listing.push(' ');
+                    listing.push_str(&link_label(&String::from_utf8_lossy(&name)));
+                }
+            }
+            if has_companion {
+                listing.push_str(" (also gzip compressed)");
+            }
+            if download {
+                listing.push_str(" (download)");
+            }
+            listing.push('\n');
+        }
+
+        // the listing is generated, but may not be larger than a file
+        if matches!(ARGS.max_response_size, Some(max) if listing.len() as u64 > max) {
+            return Response::Fixed(&TOO_LARGE);
+        }
+        Response::Bytes(listing.into_bytes(), "text/gemini".into())
+    }
+
+    /// Records an error that happened while resolving the request, which is
+    /// still answered with `response`. The connection is not closed cleanly
+    /// afterwards.
+    fn failed(&mut self, e: impl Display, response: Response) -> Response {
+        self.log.error = Some(e.to_string());
+        response
+    }
+
+    /// Sends the response to the client. This is the only place where
+    /// anything is written to the stream.
+    async fn write_response(&mut self, response: Response) -> Result {
+        match response {
+            Response::Header(status, meta) => self.write_header(status, meta).await,
+            Response::Fixed(header) => {
+                tracing::debug!("sending header {} {:?}", header.status, header.meta);
+                self.log.status = Some((header.status, Cow::Borrowed(header.meta)));
+                if let Err(e) = self.stream.write_all(header.line).await {
+                    self.aborted = true;
+                    return Err(e.into());
+                }
+                Ok(())
+            }
+            Response::Bytes(body, mime) => {
+                self.write_header(20, mime).await?;
+                if let Err(e) = self.stream.write_all(&body).await {
+                    self.aborted = true;
+                    return Err(e.into());
+                }
+                self.body_bytes = body.len() as u64;
+                Ok(())
+            }
+            Response::File(mut file, mime) => {
+                self.write_header(20, mime).await?;
+                if let Some(ref hit_counts) = ARGS.hit_counts {
+                    hit_counts.record(&file.path);
+                }
+                // nothing to copy, the header is the whole response
+                if file.size == Some(0) {
+                    return Ok(());
+                }
+
+                let transfer = transfer::Transfer::start(&file.path, file.size);
+                let progress = Some(transfer.progress());
+                let sent = &mut self.body_bytes;
+                let copied = transfer::copy(
+                    &mut file.reader,
+                    &mut self.stream,
+                    sent,
+                    file.size,
+                    progress,
+                )
+                .await;
+                match copied {
+                    Ok(transfer::Copied::Complete) => Ok(()),
+                    Ok(transfer::Copied::Short) => {
+                        let size = file.size.unwrap_or_default();
+                        tracing::warn!(
+                            "File {:?} was truncated during transfer, sent {} of {} bytes",
+                            file.path,
+                            self.body_bytes,
+                            size
+                        );
+                        self.log.file_changed = Some("truncated");
+                        // the client can tell the body is incomplete because
+                        // the connection is not closed cleanly
+                        self.aborted = true;
+                        Err(format!(
+                            "file truncated during transfer, sent {} of {} bytes",
+                            self.body_bytes, size
+                        )
+                        .into())
+                    }
+                    Ok(transfer::Copied::Long) => {
+                        tracing::warn!(
+                            "File {:?} grew during transfer, only sent the {} bytes it had when it was opened",
+                            file.path,
+                            self.body_bytes
+                        );
+                        self.log.file_changed = Some("grown");
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.aborted = true;
+                        Err(e.into())
+                    }
+                }
+            }
+            Response::Mirrored(mut relay, meta) => {
+                self.write_header(20, meta.into()).await?;
+
+                let mut buf = transfer::Buffer::take();
+                loop {
+                    let len = match relay.body.read(&mut buf).await {
+                        Ok(len) => len,
+                        // the upstream connection was closed without a TLS
+                        // close_notify, so the body might be incomplete
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            relay.download = None;
+                            0
+                        }
+                        Err(e) => {
+                            self.aborted = true;
+                            return Err(e.into());
+                        }
+                    };
+                    if len == 0 {
+                        break;
+                    }
+                    if let Err(e) = self.stream.write_all(&buf[..len]).await {
+                        self.aborted = true;
+                        return Err(e.into());
+                    }
+                    self.body_bytes += len as u64;
+                    // saving the file is only a side effect, the client
+                    // still gets the whole response if it fails
+                    if let Some(ref mut file) = relay.download {
+                        match file.write(&buf[..len]).await {
+                            Ok(true) => (),
+                            Ok(false) => relay.download = None,
+                            Err(e) => {
+                                tracing::warn!(
+                                    "Could not save mirrored file {:?}: {}",
+                                    relay.path,
+                                    e
+                                );
+                                relay.download = None;
+                            }
+                        }
+                    }
+                }
+
+                if let Some(download) = relay.download {
+                    match download.finish(&relay.path).await {
+                        Ok(()) => tracing::info!("Saved mirrored file {:?}", relay.path),
+                        Err(e) => {
+                            tracing::warn!("Could not save mirrored file {:?}: {}", relay.path, e)
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends the response header. Line breaks in `meta` are replaced, since
+    /// they would end the header early, and a header that is too long is
+    /// shortened or replaced by an error.
+    async fn write_header(&mut self, status: u8, meta: Cow<'static, str>) -> Result {
+        use std::io::Write as _;
+
+        let meta = if meta.contains(&['\r', '\n'][..]) {
+            tracing::warn!(
+                "Removing line breaks from response meta {:?}",
+                logging::redact_meta(status, meta.clone())
+            );
+            Cow::Owned(meta.replace(&['\r', '\n'][..], " "))
+        } else {
+            meta
+        };
+        let (status, meta) = self.fit_header(status, meta);
+
+        // the header is short enough to be formatted on the stack
+        let mut buffer = [0; MAX_HEADER_LEN];
+        let mut remaining = &mut buffer[..];
+        write!(remaining, "{} {}\r\n", status, meta).expect("header too long");
+        let len = MAX_HEADER_LEN - remaining.len();
+        let header = &buffer[..len];
+
+        // add response status and response meta, without the query of a
+        // redirect if queries are redacted
+        let meta = logging::redact_meta(status, meta);
+        tracing::debug!("sending header {} {:?}", status, meta);
+        self.log.status = Some((status, meta));
+
+        if let Err(e) = self.stream.write_all(header).await {
+            self.aborted = true;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    /// Makes sure that a header does not exceed the length allowed by the
+    /// protocol, which clients would handle unpredictably. A redirect is made
+    /// relative to the requested URL, a prompt or error message is cut off,
+    /// and anything else is replaced by an error.
+    fn fit_header<'a>(&self, status: u8, meta: Cow<'a, str>) -> (u8, Cow<'a, str>) {
+        if meta.len() <= metadata::MAX_META_LEN {
+            return (status, meta);
+        }
+        tracing::warn!(
+            "Response header is longer than {} bytes: {} {:?}",
+            MAX_HEADER_LEN,
+            status,
+            logging::redact_meta(status, Cow::Owned(meta.to_string()))
+        );
+        let fitted = match status / 10 {
+            3 => self
+                .request_url
+                .as_ref()
+                .zip(Url::parse(&meta).ok())
+                .and_then(|(base, target)| base.make_relative(&target))
+                .filter(|relative| relative.len() <= metadata::MAX_META_LEN)
+                .map(Cow::Owned),
+            1 | 4 | 5 | 6 => {
+                let mut end = metadata::MAX_META_LEN;
+                while !meta.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Some(Cow::Owned(meta[..end].to_string()))
+            }
+            _ => None,
+        };
+        match fitted {
+            Some(meta) => (status, meta),
+            None => (59, "Response header too long".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical_file_path, redirect, Response, TargetHost};
+    use url::Url;
+
+    fn target(url: &str, host: TargetHost<'_>, path: Option<&str>) -> String {
+        match redirect(&Url::parse(url).unwrap(), host, path) {
+            Response::Header(31, meta) => meta.into_owned(),
+            _ => panic!("not a redirect"),
+        }
+    }
+
+    #[test]
+    fn redirect_keeps_escapes_in_path_and_query() {
+        let url = "gemini://example.org:1966/a%2Fb/x+y/%F0%9F%98%80/?q=a%2Fb+c%20d";
+        assert_eq!(target(url, TargetHost::Same, None), url);
+        assert_eq!(
+            target(url, TargetHost::Name("example.com"), None),
+            "gemini://example.com:1966/a%2Fb/x+y/%F0%9F%98%80/?q=a%2Fb+c%20d"
+        );
+        assert_eq!(
+            target(url, TargetHost::Authority("example.com"), None),
+            "gemini://example.com/a%2Fb/x+y/%F0%9F%98%80/?q=a%2Fb+c%20d"
+        );
+    }
+
+    #[test]
+    fn redirect_to_canonical_path() {
+        // spaces and emoji are encoded when the URL is parsed
+        let url = Url::parse("gemini://example.org/a%2Fb/c d+😀/index.gmi?%2F+ 😀").unwrap();
+        let path = canonical_file_path(&url);
+        assert_eq!(path, Some("/a%2Fb/c%20d+%F0%9F%98%80/"));
+        assert_eq!(
+            target(url.as_str(), TargetHost::Same, path),
+            "gemini://example.org/a%2Fb/c%20d+%F0%9F%98%80/?%2F+%20%F0%9F%98%80"
+        );
+    }
+
+    #[test]
+    fn redirect_encodes_new_path() {
+        // characters not allowed in a path are encoded, escapes are kept
+        assert_eq!(
+            target(
+                "gemini://example.org/old?x+y",
+                TargetHost::Same,
+                Some("/a%2Fb/c d+😀?#")
+            ),
+            "gemini://example.org/a%2Fb/c%20d+%F0%9F%98%80%3F%23?x+y"
+        );
+    }
+}
@@ -0,0 +1,672 @@
+//! Setting up logging and the access log.
+
+use {
+    once_cell::sync::{Lazy, OnceCell},
+    ring::rand::{SecureRandom, SystemRandom},
+    std::{
+        borrow::Cow,
+        collections::HashMap,
+        fmt::{self, Display, Formatter},
+        fs,
+        io::{self, Write},
+        net::IpAddr,
+        path::PathBuf,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+            Mutex, MutexGuard,
+        },
+        time::{Duration, Instant, SystemTime},
+    },
+    tracing::{
+        field::{Empty, Field, Visit},
+        level_filters::LevelFilter,
+        Event, Level, Metadata, Span, Subscriber,
+    },
+    tracing_log::NormalizeEvent,
+    tracing_subscriber::{
+        filter::Directive,
+        fmt::{format::Writer, FmtContext, FormatEvent, FormatFields, MakeWriter},
+        registry::LookupSpan,
+        EnvFilter,
+    },
+};
+
+/// Adds a directive to the filter of the global subscriber, since some are
+/// only known once the arguments were parsed.
+static ADD_DIRECTIVE: OnceCell<Box<dyn Fn(Directive) + Send + Sync>> = OnceCell::new();
+
+/// Whether debug logging was enabled with `RUST_LOG`, and not only for some
+/// requests.
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Installs the global subscriber. The output is filtered according to the
+/// `RUST_LOG` environment variable, by default only agate logs at info level.
+pub(crate) fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("agate=info"));
+    VERBOSE.store(filter.max_level_hint() >= Some(LevelFilter::DEBUG), Relaxed);
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(Output)
+        .event_format(Format)
+        .with_filter_reloading();
+    let handle = builder.reload_handle();
+    builder.init();
+    let add_directive = move |directive| {
+        let _ = handle.modify(|filter| *filter = std::mem::take(filter).add_directive(directive));
+    };
+    let _ = ADD_DIRECTIVE.set(Box::new(add_directive));
+}
+
+/// Logs everything agate does at debug level in spans created by
+/// `debug_span`, for `--debug-host` and `--debug-path`.
+pub(crate) fn debug_requests() {
+    if let Some(add_directive) = ADD_DIRECTIVE.get() {
+        add_directive("agate[debug]=debug".parse().unwrap());
+    }
+}
+
+/// Creates the span for handling a request that matches `--debug-host` or
+/// `--debug-path`.
+pub(crate) fn debug_span() -> Span {
+    tracing::info_span!("debug")
+}
+
+/// Where access log lines are written, set with `--access-log-target`.
+/// Everything else is always written to stderr.
+pub(crate) enum AccessLogTarget {
+    Stderr,
+    Stdout,
+    File(PathBuf, Mutex<fs::File>),
+}
+
+impl AccessLogTarget {
+    /// Parses `stdout`, `stderr` or `file:PATH`, opening the file for
+    /// appending.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "stderr" => Ok(Self::Stderr),
+            "stdout" => Ok(Self::Stdout),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map(|file| Self::File(path.into(), Mutex::new(file)))
+                    .map_err(|e| format!("Could not open access log file {:?}: {}", path, e)),
+                _ => Err(format!(
+                    "Invalid value {:?} for --access-log-target, expected \"stdout\", \"stderr\" or \"file:PATH\"",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl Display for AccessLogTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stderr => f.write_str("stderr"),
+            Self::Stdout => f.write_str("stdout"),
+            Self::File(path, _) => write!(f, "{:?}", path),
+        }
+    }
+}
+
+/// Chooses the output for each event: access log lines and the summaries of
+/// collapsed ones go to the `--access-log-target`, everything else to stderr.
+/// Each line is written with a single call, so lines are not interleaved.
+struct Output;
+
+/// The output chosen by `Output` for an event.
+enum OutputWriter {
+    Stderr(io::Stderr),
+    Stdout(io::Stdout),
+    File(MutexGuard<'static, fs::File>),
+}
+
+impl MakeWriter<'_> for Output {
+    type Writer = OutputWriter;
+
+    fn make_writer(&self) -> OutputWriter {
+        OutputWriter::Stderr(io::stderr())
+    }
+
+    fn make_writer_for(&self, metadata: &Metadata<'_>) -> OutputWriter {
+        let fields = metadata.fields();
+        if fields.field("local_addr").is_none() && fields.field("repeated").is_none() {
+            return self.make_writer();
+        }
+        // the arguments are not parsed yet while parsing them
+        match crate::ARGS.0.get().map(|args| &args.access_log_target) {
+            Some(AccessLogTarget::Stdout) => OutputWriter::Stdout(io::stdout()),
+            Some(AccessLogTarget::File(_, file)) => {
+                OutputWriter::File(file.lock().unwrap_or_else(|e| e.into_inner()))
+            }
+            Some(AccessLogTarget::Stderr) | None => self.make_writer(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stderr(stderr) => stderr.write(buf),
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::File(file) => file.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Stderr(stderr) => stderr.write_all(buf),
+            Self::Stdout(stdout) => stdout.write_all(buf),
+            Self::File(file) => file.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stderr(stderr) => stderr.flush(),
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::File(file) => file.flush(),
+        }
+    }
+}
+
+/// The time zone of log timestamps, set with `--log-time`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LogTime {
+    Utc,
+    Local,
+}
+
+impl FromStr for LogTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "utc" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            _ => Err(format!(
+                "Invalid value {:?} for --log-time, expected \"utc\" or \"local\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Formats the current time for a log line. Without `--log-time`, this is
+/// UTC with second precision like in earlier versions of agate, otherwise an
+/// RFC 3339 timestamp with milliseconds and the offset of the time zone.
+fn timestamp() -> String {
+    // the arguments are not parsed yet while parsing them
+    match crate::ARGS.0.get().and_then(|args| args.log_time) {
+        None => humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        Some(LogTime::Utc) => humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        Some(LogTime::Local) => {
+            chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+        }
+    }
+}
+
+/// Formats events as `[timestamp LEVEL target] message`, the format used by
+/// earlier versions of agate. Events that happen while handling a connection
+/// are prefixed with its request ID, so the lines belonging to a request can
+/// be told apart. If debug logging is enabled, the other fields of the
+/// connection span are included too.
+struct Format;
+
+impl<S, N> FormatEvent<S, N> for Format
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        write!(
+            writer,
+            "[{} {:<5} {}] ",
+            timestamp(),
+            metadata.level(),
+            metadata.target()
+        )?;
+
+        if VERBOSE.load(Relaxed) {
+            if let Some(scope) = ctx.event_scope() {
+                for span in scope.from_root() {
+                    write!(writer, "{}", span.name())?;
+                    let extensions = span.extensions();
+                    if let Some(fields) =
+                        extensions.get::<tracing_subscriber::fmt::FormattedFields<N>>()
+                    {
+                        if !fields.is_empty() {
+                            write!(writer, "{{{}}}", fields)?;
+                        }
+                    }
+                    write!(writer, ": ")?;
+                }
+            }
+        } else if let Some(span) = ctx
+            .event_scope()
+            .and_then(|mut scope| scope.find(|span| span.name() == "conn"))
+        {
+            let extensions = span.extensions();
+            // the request ID is the first field of the connection span
+            if let Some(id) = extensions
+                .get::<tracing_subscriber::fmt::FormattedFields<N>>()
+                .and_then(|fields| fields.split(' ').next())
+            {
+                write!(writer, "conn{{{}}}: ", id)?;
+            }
+        }
+
+        // other fields are only for structured consumers
+        let mut message = Message(writer.by_ref(), Ok(()));
+        event.record(&mut message);
+        message.1?;
+        writeln!(writer)
+    }
+}
+
+/// Writes only the message of an event, without copying it first.
+struct Message<'a>(Writer<'a>, fmt::Result);
+
+impl Visit for Message<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.1 = write!(self.0, "{:?}", value);
+        }
+    }
+}
+
+/// Removes the query from a URL or request line if `--redact-queries` is used,
+/// so that user input is not logged. Everything after the first question mark
+/// is replaced by an ellipsis.
+pub(crate) fn redact(request: &str) -> Cow<'_, str> {
+    match request.find('?') {
+        Some(i) if crate::ARGS.redact_queries => Cow::Owned(format!("{}?\u{2026}", &request[..i])),
+        _ => Cow::Borrowed(request),
+    }
+}
+
+/// Like `redact`, for the meta of a response header. It is a URL for
+/// redirects, which may contain the query of the request.
+pub(crate) fn redact_meta(status: u8, meta: Cow<'static, str>) -> Cow<'static, str> {
+    if !crate::ARGS.redact_queries || (status / 10 != 3 && url::Url::parse(&meta).is_err()) {
+        return meta;
+    }
+    if let Cow::Owned(redacted) = redact(&meta) {
+        return Cow::Owned(redacted);
+    }
+    meta
+}
+
+/// Replaces a query, or a part of it, by an ellipsis if `--redact-queries`
+/// is used.
+pub(crate) fn redact_query(query: &str) -> &str {
+    if crate::ARGS.redact_queries {
+        "\u{2026}"
+    } else {
+        query
+    }
+}
+
+/// Returns a new request ID of 8 hexadecimal digits. The IDs are a counter
+/// scrambled with a random seed chosen at startup, so they do not repeat soon
+/// and differ between restarts.
+fn request_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    static SEED: Lazy<u64> = Lazy::new(|| {
+        let mut seed = [0; 8];
+        // without randomness, the IDs are still unique, but predictable
+        let _ = SystemRandom::new().fill(&mut seed);
+        u64::from_le_bytes(seed)
+    });
+
+    // the finalizer of SplitMix64, a bijection that mixes all bits
+    let mut x = NEXT.fetch_add(1, Relaxed) ^ *SEED;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    format!("{:08x}", x as u32)
+}
+
+/// Creates the span for handling a connection with a new request ID. The peer
+/// address and SNI are recorded when they are known.
+pub(crate) fn connection_span() -> Span {
+    tracing::info_span!(
+        "conn",
+        id = %request_id(),
+        peer = Empty,
+        sni = Empty,
+    )
+}
+
+/// The line logged for every connection, in the format
+/// `local_addr peer_addr "request" status "meta" [range:...] [error:...]`.
+pub(crate) struct AccessLog {
+    pub local_addr: String,
+    /// The remote IP address or `-` if it should not be logged.
+    pub peer_addr: String,
+    /// The literal request, might not be an actual URL.
+    pub request: Option<String>,
+    /// The scheme of a request that was refused because it is not `gemini`.
+    pub scheme: Option<String>,
+    /// The protocol of a request that was refused because it is obviously
+    /// not a Gemini request, e.g. `HTTP`.
+    pub protocol: Option<&'static str>,
+    pub status: Option<(u8, Cow<'static, str>)>,
+    pub error: Option<String>,
+    /// The number of request bytes read from the client.
+    pub received: u64,
+    /// How the served file changed while it was sent, `truncated` or
+    /// `grown`. Only the length it had when it was opened is ever sent.
+    pub file_changed: Option<&'static str>,
+    /// The slice of the file that was served for a `bytes=` query, as
+    /// `START-END`.
+    pub range: Option<String>,
+}
+
+impl AccessLog {
+    pub fn new(local_addr: String, peer_addr: String) -> Self {
+        Self {
+            local_addr,
+            peer_addr,
+            request: None,
+            scheme: None,
+            protocol: None,
+            status: None,
+            error: None,
+            received: 0,
+            file_changed: None,
+            range: None,
+        }
+    }
+
+    /// Whether the request failed, i.e. there was an error or no successful
+    /// or redirect response.
+    fn is_failure(&self) -> bool {
+        self.error.is_some() || self.status.as_ref().is_none_or(|(status, _)| *status >= 40)
+    }
+
+    /// Logs this line as an event with a field for each part, at warning
+    /// level if there was an error.
+    pub fn emit(&self) {
+        let (status, meta) = match self.status {
+            Some((status, ref meta)) => (Some(status), Some(meta.as_ref())),
+            None => (None, None),
+        };
+        macro_rules! access {
+            ($level:expr) => {
+                tracing::event!(
+                    target: "agate",
+                    $level,
+                    local_addr = %self.local_addr,
+                    peer_addr = %self.peer_addr,
+                    request = self.request.as_deref(),
+                    scheme = self.scheme.as_deref(),
+                    protocol = self.protocol,
+                    status,
+                    meta,
+                    error = self.error.as_deref(),
+                    received = self.received,
+                    file_changed = self.file_changed,
+                    range = self.range.as_deref(),
+                    "{}",
+                    self
+                )
+            };
+        }
+        if self.error.is_some() {
+            access!(Level::WARN);
+        } else {
+            access!(Level::INFO);
+        }
+    }
+}
+
+impl Display for AccessLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.local_addr, self.peer_addr)?;
+        if let Some(ref request) = self.request {
+            write!(f, " \"{}\"", Escaped(request))?;
+        }
+        if let Some((status, ref meta)) = self.status {
+            // with --mirror, the meta can be anything the mirrored capsule sent
+            write!(f, " {:02} \"{}\"", status, Escaped(meta))?;
+        }
+        if let Some(ref range) = self.range {
+            write!(f, " range:{}", range)?;
+        }
+        if let Some(ref error) = self.error {
+            write!(f, " error:{}", Escaped(error))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a string so it can be put between double quotes in a log line and
+/// read back unambiguously: double quotes and backslashes are escaped with a
+/// backslash, control characters are written as `\xNN`.
+struct Escaped<'a>(&'a str);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut rest = self.0;
+        while let Some(i) = rest.find(|c: char| c == '"' || c == '\\' || c.is_control()) {
+            f.write_str(&rest[..i])?;
+            let c = rest[i..].chars().next().unwrap();
+            match c {
+                '"' | '\\' => write!(f, "\\{}", c)?,
+                // C1 control characters take two bytes in UTF-8
+                _ => {
+                    for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                        write!(f, "\\x{:02x}", byte)?;
+                    }
+                }
+            }
+            rest = &rest[i + c.len_utf8()..];
+        }
+        f.write_str(rest)
+    }
+}
+
+/// The number of different failures that are collapsed at the same time.
+/// Further ones are logged as usual.
+const DEDUP_MAX_LINES: usize = 1000;
+
+/// What makes access log lines of failed requests identical for `--log-dedup`.
+#[derive(PartialEq, Eq, Hash)]
+struct DedupKey {
+    status: Option<(u8, Cow<'static, str>)>,
+    error: Option<String>,
+    /// The /24 network of an IPv4 client or the /64 network of an IPv6 client.
+    network: IpAddr,
+}
+
+/// A collapsed access log line.
+struct Repeats {
+    /// The first line, which was logged.
+    line: String,
+    warn: bool,
+    last_seen: Instant,
+    /// The number of identical lines since the last summary.
+    count: u64,
+}
+
+impl Repeats {
+    fn report(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        if self.warn {
+            tracing::warn!(
+                repeated = self.count,
+                "Last message repeated {} times: {}",
+                self.count,
+                self.line
+            );
+        } else {
+            tracing::info!(
+                repeated = self.count,
+                "Last message repeated {} times: {}",
+                self.count,
+                self.line
+            );
+        }
+        self.count = 0;
+    }
+}
+
+/// Collapses identical access log lines of failed requests from the same
+/// network, e.g. of a scanner sending malformed requests. The first line is
+/// logged immediately, later ones are only counted until none was seen for a
+/// whole window. The counts are logged once per window.
+pub(crate) struct Dedup {
+    window: Duration,
+    lines: Mutex<HashMap<DedupKey, Repeats>>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Result<Self, String> {
+        if window.is_zero() {
+            return Err("the --log-dedup window must not be zero".into());
+        }
+        Ok(Self {
+            window,
+            lines: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Logs an access log line, unless it is a failure identical to one that
+    /// was logged recently for a client in the same network.
+    pub fn emit(&self, log: &AccessLog, peer: IpAddr) {
+        if !log.is_failure() {
+            return log.emit();
+        }
+        let network = match peer {
+            IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & !0xff).into()),
+            IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !u128::from(u64::MAX)).into()),
+        };
+        let key = DedupKey {
+            status: log.status.clone(),
+            error: log.error.clone(),
+            network,
+        };
+
+        let now = Instant::now();
+        let mut lines = self.lines.lock().unwrap();
+        let full = lines.len() >= DEDUP_MAX_LINES;
+        match lines.get_mut(&key) {
+            Some(repeats) if now.duration_since(repeats.last_seen) < self.window => {
+                repeats.count += 1;
+                repeats.last_seen = now;
+                return;
+            }
+            // the summary of the previous run is not due yet
+            Some(repeats) => repeats.report(),
+            None if full => {
+                drop(lines);
+                return log.emit();
+            }
+            None => (),
+        }
+        lines.insert(
+            key,
+            Repeats {
+                line: log.to_string(),
+                warn: log.error.is_some(),
+                last_seen: now,
+                count: 0,
+            },
+        );
+        drop(lines);
+        log.emit();
+    }
+
+    /// Logs how often lines were collapsed since the last summary and forgets
+    /// the lines not seen for a whole window.
+    fn summarize(&self) {
+        let now = Instant::now();
+        self.lines.lock().unwrap().retain(|_, repeats| {
+            repeats.report();
+            now.duration_since(repeats.last_seen) < self.window
+        });
+    }
+
+    /// Logs the summaries once per window, never returns.
+    pub async fn summarize_periodically(&'static self) {
+        let mut interval = tokio::time::interval(self.window);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            self.summarize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessLog, Escaped};
+
+    fn escape(s: &str) -> String {
+        Escaped(s).to_string()
+    }
+
+    #[test]
+    fn escaped_plain() {
+        assert_eq!(
+            escape("gemini://example.org/ä?q=1"),
+            "gemini://example.org/ä?q=1"
+        );
+        assert_eq!(escape(""), "");
+    }
+
+    #[test]
+    fn escaped_quotes_and_backslashes() {
+        assert_eq!(escape(r#"a"b"#), r#"a\"b"#);
+        assert_eq!(escape(r"a\b"), r"a\\b");
+        // an escaped quote in the request can not end the quoted field
+        assert_eq!(escape(r#"\""#), r#"\\\""#);
+    }
+
+    #[test]
+    fn escaped_control_characters() {
+        assert_eq!(escape("a\nb"), r"a\x0ab");
+        assert_eq!(escape("a\tb\r"), r"a\x09b\x0d");
+        assert_eq!(escape("\0\x7f"), r"\x00\x7f");
+        // C1 control characters are escaped byte by byte
+        assert_eq!(escape("a\u{85}b"), r"a\xc2\x85b");
+    }
+
+    #[test]
+    fn escaped_invalid_utf8() {
+        // requests that are not UTF-8 are logged decoded like this
+        let decoded = String::from_utf8_lossy(b"a\xff\"\nb");
+        assert_eq!(escape(&decoded), "a\u{FFFD}\\\"\\x0ab");
+    }
+
+    #[test]
+    fn access_log_escapes_meta_and_error() {
+        let mut log = AccessLog::new("[::]:1965".into(), "-".into());
+        log.request = Some("gemini://example.org/".into());
+        log.status = Some((42, "upstream said \"no\"\r\n".into()));
+        log.error = Some("could not read \"C:\\x\"\n".into());
+        assert_eq!(
+            log.to_string(),
+            r#"[::]:1965 - "gemini://example.org/" 42 "upstream said \"no\"\x0d\x0a" error:could not read \"C:\\x\"\x0a"#
+        );
+    }
+}
@@ -6,6 +6,11 @@ use std::time::SystemTime;
 
 static SIDECAR_FILENAME: &str = ".meta";
 
+/// The maximum length of the meta string of a full header line, so that the
+/// whole response header including status code, space, CR and LF does not
+/// exceed 1024 bytes.
+pub(crate) const MAX_META_LEN: usize = 1024 - 5;
+
 /// A struct to store a string of metadata for each file retrieved from
 /// sidecar files called `.lang`.
 ///
@@ -25,6 +30,15 @@ pub(crate) struct FileOptions {
     databases_read: BTreeMap<PathBuf, SystemTime>,
     /// Stores the metadata for each file
     file_meta: BTreeMap<PathBuf, PresetMeta>,
+    /// Stores the sidecar file and the key of the line the metadata of each
+    /// file came from
+    file_meta_source: BTreeMap<PathBuf, (PathBuf, String)>,
+    /// Stores the publication window for each file that has one, and the
+    /// sidecar file it came from
+    file_schedule: BTreeMap<PathBuf, (Schedule, PathBuf)>,
+    /// Stores the maximum response size for each file that overrides the
+    /// default, `None` meaning unlimited, and the sidecar file it came from
+    file_max_size: BTreeMap<PathBuf, (Option<u64>, PathBuf)>,
     /// The default value to return
     default: PresetMeta,
 }
@@ -57,6 +71,89 @@ pub(crate) enum PresetMeta {
     /// Agate will send this header line, CR, LF, and nothing else. Agate will
     /// not try to access the requested file.
     FullHeader(u8, String),
+    /// A line that only contains `download`.
+    /// ```text
+    /// examples/*: download
+    /// ```
+    /// Agate will send `application/octet-stream` without any parameters as
+    /// the MIME type, so that clients save the file instead of displaying it.
+    Download,
+}
+
+/// The publication window of a file, set by lines in the sidecar file that
+/// start with `publish-after` or `expires` followed by an RFC 3339 timestamp
+/// in UTC. Both can be combined on the same line.
+/// ```text
+/// event.gmi: publish-after 2025-06-01T12:00:00Z expires 2025-07-01T00:00:00Z
+/// ```
+/// The timestamps are parsed when the sidecar file is read so checking them
+/// for a request is only a comparison.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Schedule {
+    /// Before this time, the file is treated as if it did not exist.
+    publish_after: Option<SystemTime>,
+    /// From this time on, the file is treated as gone.
+    expires: Option<SystemTime>,
+}
+
+/// Whether a file with a [`Schedule`] may be served at a given time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Availability {
+    /// The `publish-after` time has not been reached yet.
+    Embargoed,
+    /// The file may be served.
+    Published,
+    /// The `expires` time has passed.
+    Expired,
+}
+
+impl Schedule {
+    /// Parses the directives of a schedule line, e.g.
+    /// `publish-after 2025-06-01T12:00:00Z expires 2025-07-01T00:00:00Z`.
+    fn parse(line: &str) -> Result<Self, String> {
+        let mut schedule = Self::default();
+        let mut words = line.split_whitespace();
+        while let Some(directive) = words.next() {
+            let time = words
+                .next()
+                .ok_or_else(|| format!("missing timestamp after {:?}", directive))?;
+            let time = humantime::parse_rfc3339(time)
+                .map_err(|e| format!("invalid timestamp {:?}: {}", time, e))?;
+            match directive {
+                "publish-after" => schedule.publish_after = Some(time),
+                "expires" => schedule.expires = Some(time),
+                _ => return Err(format!("unknown directive {:?}", directive)),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Combines two schedules, the times set in `other` take precedence.
+    fn merge(self, other: Self) -> Self {
+        Self {
+            publish_after: other.publish_after.or(self.publish_after),
+            expires: other.expires.or(self.expires),
+        }
+    }
+
+    pub fn availability(&self, now: SystemTime) -> Availability {
+        if matches!(self.expires, Some(expires) if now >= expires) {
+            Availability::Expired
+        } else if matches!(self.publish_after, Some(publish) if now < publish) {
+            Availability::Embargoed
+        } else {
+            Availability::Published
+        }
+    }
+}
+
+/// A parsed line of the sidecar file.
+enum Directive {
+    Preset(PresetMeta),
+    Schedule(Schedule),
+    /// A line `max-size` followed by a number of bytes or `unlimited`, which
+    /// overrides `--max-response-size` for the file.
+    MaxSize(Option<u64>),
 }
 
 impl FileOptions {
@@ -64,6 +161,9 @@ impl FileOptions {
         Self {
             databases_read: BTreeMap::new(),
             file_meta: BTreeMap::new(),
+            file_meta_source: BTreeMap::new(),
+            file_schedule: BTreeMap::new(),
+            file_max_size: BTreeMap::new(),
             default,
         }
     }
@@ -78,7 +178,10 @@ impl FileOptions {
         };
         db.push(SIDECAR_FILENAME);
 
-        let should_read = if let Ok(metadata) = db.metadata() {
+        let should_read = if let Some(ref archive) = crate::ARGS.archive {
+            // archives do not change, so each sidecar file is only read once
+            archive.is_file(&db) && !self.databases_read.contains_key(&db)
+        } else if let Ok(metadata) = db.metadata() {
             if !metadata.is_file() {
                 // it exists, but it is a directory
                 false
@@ -107,13 +210,19 @@ impl FileOptions {
     /// (Re)reads a specified sidecar file.
     /// This function will allways try to read the file, even if it is current.
     fn read_database(&mut self, db: &Path) {
-        log::debug!("reading database {:?}", db);
+        tracing::debug!("reading database {:?}", db);
 
         let mut ini = Ini::new_cs();
         ini.set_default_section("mime");
         ini.set_comment_symbols(&['#']);
-        let map = ini
-            .load(db.to_str().expect("config path not UTF-8"))
+        let text = match crate::ARGS.archive {
+            Some(ref archive) => archive.read_to_string(db),
+            None => std::fs::read_to_string(db),
+        };
+        let map = text
+            .as_ref()
+            .map_err(ToString::to_string)
+            .and_then(|text| ini.read(text.clone()))
             .and_then(|mut sections| {
                 sections
                     .remove("mime")
@@ -121,13 +230,21 @@ impl FileOptions {
             });
         self.databases_read
             .insert(db.to_path_buf(), SystemTime::now());
+        // schedules are merged while reading, so the ones from the previous
+        // version of the file must not be merged into the new ones
+        self.file_schedule.retain(|_, (_, source)| source != db);
+        self.file_max_size.retain(|_, (_, source)| source != db);
+        // a new entry may be for a file that was remembered as missing
+        crate::misses::MISSES.clear();
         let files = match map {
             Ok(section) => section,
             Err(err) => {
-                log::error!("invalid config file {:?}: {}", db, err);
+                tracing::error!("invalid config file {:?}: {}", db, err);
                 return;
             }
         };
+        // only used for finding line numbers for error messages
+        let text = text.unwrap_or_default();
 
         for (rel_path, header) in files {
             // treat unassigned keys as if they had an empty value
@@ -136,40 +253,57 @@ impl FileOptions {
             // generate workspace-relative path
             let mut path = db.to_path_buf();
             path.pop();
-            path.push(rel_path);
+            path.push(&rel_path);
 
             // parse the preset
-            let preset = if header.is_empty() || header.starts_with(';') {
-                PresetMeta::Parameters(header.to_string())
-            } else if matches!(header.chars().next(), Some('1'..='6')) {
-                if header.len() < 3
-                    || !header.chars().nth(1).unwrap().is_ascii_digit()
-                    || !header.chars().nth(2).unwrap().is_whitespace()
-                {
-                    log::error!("Line for {:?} starts like a full header line, but it is incorrect; ignoring it.", path);
-                    return;
+            let directive = if header.starts_with("publish-after ")
+                || header.starts_with("expires ")
+            {
+                match Schedule::parse(&header) {
+                    Ok(schedule) => Directive::Schedule(schedule),
+                    Err(err) => {
+                        tracing::error!("Invalid schedule for {:?}: {}; ignoring it.", path, err);
+                        continue;
+                    }
+                }
+            } else if let Some(size) = header.strip_prefix("max-size ") {
+                match size.trim() {
+                    "unlimited" => Directive::MaxSize(None),
+                    size => match size.parse() {
+                        Ok(size) => Directive::MaxSize(Some(size)),
+                        Err(err) => {
+                            tracing::error!(
+                                "Invalid maximum size {:?} for {:?}: {}; ignoring it.",
+                                size,
+                                path,
+                                err
+                            );
+                            continue;
+                        }
+                    },
                 }
-                let separator = header.chars().nth(2).unwrap();
-                if separator != ' ' {
-                    // the Gemini specification says that the third
-                    // character has to be a space, so correct any
-                    // other whitespace to it (e.g. tabs)
-                    log::warn!("Full Header line for {:?} has an invalid character, treating {:?} as a space.", path, separator);
+            } else if header == "download" {
+                Directive::Preset(PresetMeta::Download)
+            } else if header.is_empty() || header.starts_with(';') {
+                Directive::Preset(PresetMeta::Parameters(header.to_string()))
+            } else if matches!(header.chars().next(), Some('1'..='6')) {
+                match parse_full_header(&header) {
+                    Ok((status, meta)) => Directive::Preset(PresetMeta::FullHeader(status, meta)),
+                    Err(err) => {
+                        tracing::error!(
+                            "{:?}{}: invalid full header line for {:?}: {}; ignoring it.",
+                            db,
+                            line_number(&text, &rel_path)
+                                .map_or(String::new(), |line| format!(" line {}", line)),
+                            rel_path,
+                            err
+                        );
+                        continue;
+                    }
                 }
-                let status = header
-                    .chars()
-                    .take(2)
-                    .collect::<String>()
-                    .parse::<u8>()
-                    // unwrap since we alread checked it's a number
-                    .unwrap();
-                // not taking a slice here because the separator
-                // might be a whitespace wider than a byte
-                let meta = header.chars().skip(3).collect::<String>();
-                PresetMeta::FullHeader(status, meta)
             } else {
                 // must be a MIME type, but without status code
-                PresetMeta::FullMime(header.to_string())
+                Directive::Preset(PresetMeta::FullMime(header.to_string()))
             };
 
             let glob_options = MatchOptions {
@@ -182,39 +316,78 @@ impl FileOptions {
             };
 
             // process filename as glob
-            let paths = if let Some(path) = path.to_str() {
-                match glob_with(path, glob_options) {
-                    Ok(paths) => paths.collect::<Vec<_>>(),
-                    Err(err) => {
-                        log::error!("incorrect glob pattern in {:?}: {}", path, err);
-                        continue;
-                    }
-                }
+            let path_str = if let Some(path) = path.to_str() {
+                path
             } else {
-                log::error!("path is not UTF-8: {:?}", path);
+                tracing::error!("path is not UTF-8: {:?}", path);
                 continue;
             };
+            let paths = match crate::ARGS.archive {
+                Some(ref archive) => archive.glob(path_str, glob_options),
+                None => glob_with(path_str, glob_options).map(|paths| {
+                    paths
+                        .filter_map(|glob_result| match glob_result {
+                            Ok(path) if path.is_dir() => None,
+                            Ok(path) => Some(path),
+                            Err(err) => {
+                                tracing::warn!("could not process glob path: {}", err);
+                                None
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                }),
+            };
+            let paths = match paths {
+                Ok(paths) => paths,
+                Err(err) => {
+                    tracing::error!("incorrect glob pattern in {:?}: {}", path, err);
+                    continue;
+                }
+            };
 
+            let source = (db.to_path_buf(), rel_path);
             if paths.is_empty() {
                 // probably an entry for a nonexistent file, glob only works for existing files
-                self.file_meta.insert(path, preset);
+                self.insert(path, &directive, &source);
             } else {
-                for glob_result in paths {
-                    match glob_result {
-                        Ok(path) if path.is_dir() => { /* ignore */ }
-                        Ok(path) => {
-                            self.file_meta.insert(path, preset.clone());
-                        }
-                        Err(err) => {
-                            log::warn!("could not process glob path: {}", err);
-                            continue;
-                        }
-                    };
+                for path in paths {
+                    self.insert(path, &directive, &source);
                 }
             }
         }
     }
 
+    /// Stores the result of a sidecar file line for the specified file.
+    fn insert(&mut self, path: PathBuf, directive: &Directive, source: &(PathBuf, String)) {
+        match directive {
+            Directive::Preset(preset) => {
+                self.file_meta_source.insert(path.clone(), source.clone());
+                self.file_meta.insert(path, preset.clone());
+            }
+            Directive::Schedule(schedule) => {
+                let merged = self
+                    .file_schedule
+                    .get(&path)
+                    .map_or(*schedule, |(old, _)| old.merge(*schedule));
+                self.file_schedule.insert(path, (merged, source.0.clone()));
+            }
+            Directive::MaxSize(size) => {
+                self.file_max_size.insert(path, (*size, source.0.clone()));
+            }
+        }
+    }
+
+    /// Reads the sidecar file for the files in `dir` if it was not read yet,
+    /// so that the first request for one of them does not have to.
+    pub fn preload(&mut self, dir: &Path) {
+        self.update(&dir.join(SIDECAR_FILENAME));
+    }
+
+    /// Returns the number of files there are entries for.
+    pub fn entries(&self) -> usize {
+        self.file_meta.len() + self.file_schedule.len() + self.file_max_size.len()
+    }
+
     /// Get the metadata for the specified file. This might need to (re)load a
     /// single sidecar file.
     /// The file path should consistenly be either absolute or relative to the
@@ -233,4 +406,80 @@ impl FileOptions {
 
         self.file_meta.contains_key(file)
     }
+
+    /// Returns the sidecar file the metadata of the specified file came from,
+    /// and the number of the line if it can still be found in it.
+    pub fn source(&mut self, file: &Path) -> Option<(PathBuf, Option<usize>)> {
+        self.update(file);
+
+        let (db, key) = self.file_meta_source.get(file)?;
+        let text = match crate::ARGS.archive {
+            Some(ref archive) => archive.read_to_string(db),
+            None => std::fs::read_to_string(db),
+        };
+        let line = text.ok().and_then(|text| line_number(&text, key));
+        Some((db.clone(), line))
+    }
+
+    /// Returns the publication window of the specified file, if it has one.
+    pub fn schedule(&mut self, file: &Path) -> Option<Schedule> {
+        self.update(file);
+
+        self.file_schedule.get(file).map(|(schedule, _)| *schedule)
+    }
+
+    /// Returns the maximum size of the specified file if it is overridden,
+    /// with `None` meaning unlimited.
+    pub fn max_size(&mut self, file: &Path) -> Option<Option<u64>> {
+        self.update(file);
+
+        self.file_max_size.get(file).map(|(size, _)| *size)
+    }
+}
+
+/// Parses a full header line, i.e. a status code, a space and a meta string.
+/// Any other whitespace separating the status code and meta string is treated
+/// as a space.
+fn parse_full_header(header: &str) -> Result<(u8, String), String> {
+    let mut chars = header.chars();
+    let status = chars.by_ref().take(2).collect::<String>();
+    if !matches!(status.as_bytes(), [b'1'..=b'6', b'0'..=b'9']) {
+        return Err(format!("{:?} is not a valid status code", status));
+    }
+    match chars.next() {
+        Some(' ') => (),
+        Some(separator) if separator.is_whitespace() => {
+            // the Gemini specification says that the third character has to
+            // be a space, so correct any other whitespace to it (e.g. tabs)
+            tracing::warn!(
+                "Full header line {:?} has an invalid character, treating {:?} as a space.",
+                header,
+                separator
+            );
+        }
+        _ => return Err("the status code has to be followed by a space".into()),
+    }
+    let meta = chars.as_str();
+    if meta.contains(&['\r', '\n'][..]) {
+        return Err("the meta string contains a line break".into());
+    }
+    if meta.len() > MAX_META_LEN {
+        return Err(format!(
+            "the meta string is longer than {} bytes",
+            MAX_META_LEN
+        ));
+    }
+    // unwrap since we already checked it's a number
+    Ok((status.parse().unwrap(), meta.to_string()))
+}
+
+/// Finds the line number of the entry for `key` in the text of a sidecar file.
+fn line_number(text: &str, key: &str) -> Option<usize> {
+    text.lines()
+        .position(|line| {
+            line.trim_start()
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.trim_start().starts_with(&['=', ':'][..]))
+        })
+        .map(|i| i + 1)
 }
@@ -0,0 +1,251 @@
+//! Fetching files that do not exist locally from an upstream capsule.
+
+use {
+    crate::{client, logging::redact},
+    rustls::sign::CertifiedKey,
+    std::{
+        collections::HashMap,
+        fs::Metadata,
+        path::{Path, PathBuf},
+        sync::Arc,
+        time::{Duration, SystemTime},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncWriteExt, BufReader},
+        net::TcpStream,
+        sync::{Mutex, OwnedMutexGuard},
+    },
+    tokio_rustls::client::TlsStream,
+    url::Url,
+};
+
+/// What an upstream request left for other requests waiting for the same
+/// path and query.
+type Slot = Arc<Mutex<Option<Fetched>>>;
+
+/// The outcome of a fetch, as far as the requests that waited for it need to
+/// know about it.
+#[derive(Clone)]
+pub(crate) enum Fetched {
+    /// A response without a body, which they are answered with as well.
+    Header(u8, String),
+    /// A successful response that was relayed. Unless it was saved, they
+    /// can not be answered with it.
+    Relayed,
+}
+
+/// A requested path and query, requests for the same key share one fetch.
+type Key = (PathBuf, Option<String>);
+
+pub(crate) struct Mirror {
+    /// The URL of the upstream capsule, request paths are appended to it.
+    upstream: Url,
+    /// The pinned SHA-256 fingerprint of the upstream certificate.
+    fingerprint: String,
+    /// The client certificate presented to the upstream capsule, if it asks
+    /// for one.
+    identity: Option<CertifiedKey>,
+    /// Responses with a body larger than this are relayed but not saved.
+    max_size: u64,
+    /// Saved copies older than this are fetched again.
+    max_age: Option<Duration>,
+    /// Paths and queries that are currently being fetched, so concurrent
+    /// requests for the same URL result in only one upstream request.
+    in_flight: std::sync::Mutex<HashMap<Key, Slot>>,
+}
+
+impl Mirror {
+    pub fn new(
+        upstream: &str,
+        fingerprint: &str,
+        identity: Option<CertifiedKey>,
+        max_size: u64,
+        max_age: Option<Duration>,
+    ) -> Result<Self, String> {
+        let upstream = Url::parse(upstream)
+            .map_err(|e| format!("invalid mirror URL {:?}: {}", upstream, e))?;
+        if upstream.scheme() != "gemini" {
+            return Err(format!("mirror URL {} is not a gemini URL", upstream));
+        }
+        Ok(Self {
+            upstream,
+            fingerprint: client::parse_fingerprint(fingerprint)?,
+            identity,
+            max_size,
+            max_age,
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn upstream(&self) -> &Url {
+        &self.upstream
+    }
+
+    /// Checks if the file with the given local metadata has to be fetched,
+    /// either because it does not exist or because it is outdated.
+    pub fn needs_fetch(&self, local: Option<&Metadata>) -> bool {
+        let metadata = match local {
+            Some(metadata) if metadata.is_file() => metadata,
+            _ => return true,
+        };
+        match (self.max_age, metadata.modified()) {
+            (Some(max_age), Ok(modified)) => SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age > max_age),
+            _ => false,
+        }
+    }
+
+    /// Waits until no other request is fetching the specified path with the
+    /// same query. The result tells about a fetch that happened in the
+    /// meantime.
+    pub async fn wait(&self, path: &Path, query: Option<&str>) -> InFlight<'_> {
+        let key = (path.to_path_buf(), query.map(str::to_string));
+        let slot = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = match slot.clone().try_lock_owned() {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::debug!("waiting for another request fetching {:?}", path);
+                slot.clone().lock_owned().await
+            }
+        };
+        InFlight {
+            mirror: self,
+            key,
+            result,
+            slot,
+        }
+    }
+
+    /// Requests the same path and query as in `url` from the upstream server.
+    pub async fn fetch(&self, url: &Url) -> Result<client::Response, String> {
+        let mut upstream = self.upstream.clone();
+        upstream.set_path(&format!(
+            "{}{}",
+            self.upstream.path().trim_end_matches('/'),
+            url.path()
+        ));
+        upstream.set_query(url.query());
+        tracing::debug!("fetching {} from upstream", redact(upstream.as_str()));
+        client::request(
+            &upstream,
+            None,
+            Some(self.fingerprint.clone()),
+            self.identity.clone(),
+        )
+        .await
+    }
+
+    /// Starts saving a fetched file to `path`. The file is written to a
+    /// hidden temporary file first and only renamed to `path` when complete.
+    pub async fn save(&self, path: &Path) -> std::io::Result<Download> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = path.with_file_name(format!(
+            ".{}.mirror",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        Ok(Download {
+            file: Some(File::create(&tmp).await?),
+            tmp,
+            size: 0,
+            max_size: self.max_size,
+        })
+    }
+}
+
+/// Marks a path as being fetched until dropped.
+pub(crate) struct InFlight<'a> {
+    mirror: &'a Mirror,
+    key: Key,
+    slot: Slot,
+    /// The outcome of a fetch by another request, if there was one.
+    pub result: OwnedMutexGuard<Option<Fetched>>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.mirror.in_flight.lock().unwrap();
+        // a new fetch might have started in the meantime, leave it alone
+        if matches!(in_flight.get(&self.key), Some(slot) if Arc::ptr_eq(slot, &self.slot)) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// A response from the upstream capsule that is relayed to a client and saved
+/// to `path` at the same time, unless `download` is `None`. Other requests for
+/// the same path wait until it is dropped.
+pub(crate) struct Relay {
+    pub body: BufReader<TlsStream<TcpStream>>,
+    pub download: Option<Download>,
+    pub path: PathBuf,
+    _in_flight: InFlight<'static>,
+}
+
+impl Relay {
+    pub fn new(
+        body: BufReader<TlsStream<TcpStream>>,
+        download: Option<Download>,
+        path: &Path,
+        in_flight: InFlight<'static>,
+    ) -> Self {
+        Self {
+            body,
+            download,
+            path: path.to_path_buf(),
+            _in_flight: in_flight,
+        }
+    }
+}
+
+/// A file that is being saved. If it is dropped before being finished, the
+/// temporary file is removed.
+pub(crate) struct Download {
+    file: Option<File>,
+    tmp: PathBuf,
+    size: u64,
+    max_size: u64,
+}
+
+impl Download {
+    /// Appends data to the file. Returns false if the file has become too
+    /// large and will not be saved.
+    pub async fn write(&mut self, data: &[u8]) -> std::io::Result<bool> {
+        self.size += data.len() as u64;
+        if self.size > self.max_size {
+            tracing::info!("not saving {:?}, it is too large", self.tmp);
+            return Ok(false);
+        }
+        self.file.as_mut().unwrap().write_all(data).await?;
+        Ok(true)
+    }
+
+    /// Moves the completed file to its final location.
+    /// If this fails, the temporary file is removed.
+    pub async fn finish(mut self, path: &Path) -> std::io::Result<()> {
+        let file = self.file.as_mut().unwrap();
+        file.flush().await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&self.tmp, path).await?;
+        // there is no temporary file left to remove
+        self.file = None;
+        Ok(())
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.tmp);
+        }
+    }
+}
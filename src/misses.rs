@@ -0,0 +1,100 @@
+//! Remembering recent requests for files that do not exist, so that clients
+//! requesting the same missing file again and again are answered without
+//! touching the file system.
+
+use {
+    crate::watch::{self, Invalidate},
+    once_cell::sync::Lazy,
+    std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of missing paths remembered. When it is reached, the least
+/// recently requested one is forgotten.
+const MAX_ENTRIES: usize = 1024;
+
+/// How long a path is remembered as missing, so that a file that is created
+/// is served soon after. This is longer if changes are watched.
+const TTL: Duration = Duration::from_secs(5);
+
+pub(crate) static MISSES: Lazy<Misses> = Lazy::new(Misses::default);
+
+struct Entry {
+    /// When the file was found to be missing.
+    created: Instant,
+    /// When the path was last requested, for finding the entry to forget.
+    used: Instant,
+}
+
+/// Missing files by host name and the path they would have in the content
+/// directory.
+#[derive(Default)]
+pub(crate) struct Misses(Mutex<HashMap<(String, PathBuf), Entry>>);
+
+impl Misses {
+    /// Checks if the file was found to be missing within the last few seconds.
+    pub fn contains(&self, host: &str, path: &Path) -> bool {
+        let mut entries = self.0.lock().unwrap();
+        let key = (host.to_string(), path.to_path_buf());
+        let now = Instant::now();
+        match entries.get_mut(&key) {
+            Some(entry) if now.duration_since(entry.created) < watch::ttl(TTL) => {
+                entry.used = now;
+                true
+            }
+            Some(_) => {
+                entries.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers that the file does not exist.
+    pub fn insert(&self, host: &str, path: &Path) {
+        let mut entries = self.0.lock().unwrap();
+        let now = Instant::now();
+        if entries.len() >= MAX_ENTRIES {
+            let ttl = watch::ttl(TTL);
+            entries.retain(|_, entry| now.duration_since(entry.created) < ttl);
+        }
+        if entries.len() >= MAX_ENTRIES {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            (host.to_string(), path.to_path_buf()),
+            Entry {
+                created: now,
+                used: now,
+            },
+        );
+    }
+
+    /// Forgets all missing files, e.g. because a `.meta` file changed.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+impl Invalidate for Misses {
+    fn invalidate(&self, path: &Path) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|(_, missing), _| !missing.starts_with(path));
+    }
+
+    fn clear(&self) {
+        Misses::clear(self);
+    }
+}
@@ -0,0 +1,103 @@
+//! A plaintext listener for use behind a relay that terminates TLS on the same
+//! host, enabled with `--insecure-listener`.
+
+use {
+    std::{fmt, io, net::SocketAddr, path::PathBuf},
+    tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpListener,
+    },
+};
+
+/// A connection accepted by the offload listener.
+pub(crate) trait Plaintext: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Plaintext for S {}
+
+/// Where the offload listener accepts connections.
+pub(crate) enum Address {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Parses the argument of `--insecure-listener`, either `unix:PATH` or an
+    /// IP address and port. Since requests arrive unencrypted, only loopback
+    /// addresses are allowed.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return match path {
+                "" => Err("the Unix socket path is empty".into()),
+                path => Ok(Self::Unix(PathBuf::from(path))),
+            };
+            #[cfg(not(unix))]
+            return Err(format!(
+                "Unix sockets like {:?} are not supported on this platform",
+                path
+            ));
+        }
+        let addr = crate::parse_addr(s)?;
+        let loopback = match addr {
+            SocketAddr::V4(addr) => addr.ip().is_loopback(),
+            SocketAddr::V6(addr) => {
+                addr.ip().is_loopback()
+                    || addr
+                        .ip()
+                        .to_ipv4_mapped()
+                        .is_some_and(|ip| ip.is_loopback())
+            }
+        };
+        if !loopback {
+            return Err(format!(
+                "{} is not a loopback address, plaintext requests could be sent from other hosts",
+                addr
+            ));
+        }
+        Ok(Self::Tcp(addr))
+    }
+
+    pub async fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Self::Tcp(addr) => TcpListener::bind(addr).await.map(Listener::Tcp),
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                // a socket file left over from a previous run can not be bound
+                // again, but anything else at that path is not replaced
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| {
+                    std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type())
+                }) {
+                    std::fs::remove_file(path)?;
+                }
+                tokio::net::UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    pub async fn accept(&self) -> io::Result<Box<dyn Plaintext>> {
+        Ok(match self {
+            Self::Tcp(listener) => Box::new(listener.accept().await?.0),
+            #[cfg(unix)]
+            Self::Unix(listener) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
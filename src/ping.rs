@@ -0,0 +1,112 @@
+//! The `agate ping` subcommand, a health check for a running server.
+
+use {
+    crate::client,
+    std::{net::SocketAddr, time::Duration},
+    tokio::runtime::Runtime,
+    url::Url,
+};
+
+/// Runs the health check with the given command line arguments (without the
+/// program name and subcommand) and returns the exit code.
+pub(crate) fn run(program: &str, args: &[String]) -> u8 {
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "",
+        "addr",
+        "Address of the server to check (default 127.0.0.1:1965)",
+        "IP:PORT",
+    );
+    opts.optopt(
+        "",
+        "hostname",
+        "Host name to use for SNI and in the request (default localhost)",
+        "NAME",
+    );
+    opts.optopt("", "path", "Path to request (default /)", "PATH");
+    opts.optopt(
+        "",
+        "timeout",
+        "Seconds to wait for a response (default 10)",
+        "SECONDS",
+    );
+    opts.optflag("h", "help", "Print this help text and exit.");
+
+    let matches = match opts.parse(args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    if matches.opt_present("h") {
+        eprintln!(
+            "{}",
+            opts.usage(&format!(
+                "Usage: {} ping [options]\n\nChecks that a Gemini server responds with a 2x or 3x status.",
+                program
+            ))
+        );
+        return 0;
+    }
+
+    let addr: SocketAddr = match matches.opt_get_default("addr", "127.0.0.1:1965".parse().unwrap())
+    {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("invalid address: {}", e);
+            return 2;
+        }
+    };
+    let timeout = match matches.opt_get_default("timeout", 10) {
+        Ok(timeout) => Duration::from_secs(timeout),
+        Err(e) => {
+            eprintln!("invalid timeout: {}", e);
+            return 2;
+        }
+    };
+    let hostname = matches
+        .opt_str("hostname")
+        .unwrap_or_else(|| "localhost".into());
+    let path = matches.opt_str("path").unwrap_or_else(|| "/".into());
+    // a URL without a port would be for port 1965
+    let url = match addr.port() {
+        1965 => format!("gemini://{}{}", hostname, path),
+        port => format!("gemini://{}:{}{}", hostname, port, path),
+    };
+    let url = match Url::parse(&url) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("invalid hostname or path: {}", e);
+            return 2;
+        }
+    };
+
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let result = runtime.block_on(async {
+        tokio::time::timeout(timeout, client::request(&url, Some(addr), None, None))
+            .await
+            .unwrap_or_else(|_| Err(format!("no response within {:?}", timeout)))
+    });
+
+    match result {
+        Ok(response) if matches!(response.status, 20..=39) => 0,
+        Ok(response) => {
+            eprintln!(
+                "{} responded with status {} {:?}",
+                url, response.status, response.meta
+            );
+            1
+        }
+        Err(e) => {
+            eprintln!("{}: {}", url, e);
+            1
+        }
+    }
+}
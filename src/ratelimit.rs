@@ -0,0 +1,130 @@
+//! Limiting the number of requests per client IP address.
+
+use {
+    crate::tracked::Tracked,
+    std::{
+        net::IpAddr,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+/// The maximum number of (rule, IP address) pairs that are tracked at the same
+/// time, so that many clients cannot make agate use too much memory.
+const MAX_TRACKED: usize = 64 * 1024;
+
+/// A limit of requests per time period for paths starting with a prefix.
+struct Rule {
+    /// The path prefix this rule applies to, empty for the default rule.
+    prefix: String,
+    requests: u32,
+    period: Duration,
+}
+
+impl Rule {
+    /// Parses a rule in the form `[PREFIX=]REQUESTS/PERIOD`, e.g.
+    /// `/search=5/60s`. The period is parsed by [`humantime`].
+    fn parse(s: &str) -> Result<Self, String> {
+        let (prefix, limit) = match s.rfind('=') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => ("", s),
+        };
+        if !prefix.is_empty() && !prefix.starts_with('/') {
+            return Err(format!(
+                "rate limit path {:?} does not start with /",
+                prefix
+            ));
+        }
+        let (requests, period) = limit
+            .split_once('/')
+            .ok_or_else(|| format!("rate limit {:?} is not in the form REQUESTS/PERIOD", s))?;
+        let requests = requests
+            .parse()
+            .map_err(|e| format!("invalid number of requests in rate limit {:?}: {}", s, e))?;
+        let period = humantime::parse_duration(period)
+            .map_err(|e| format!("invalid period in rate limit {:?}: {}", s, e))?;
+        if period.is_zero() {
+            return Err(format!("the period of rate limit {:?} is zero", s));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            requests,
+            period,
+        })
+    }
+
+    /// Checks if this rule applies to the path. A prefix only matches whole
+    /// path segments, i.e. `/search` matches `/search/foo` but not `/searching`.
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/') || self.prefix.ends_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// The requests a client made in the current period of a rule.
+struct Window {
+    start: Instant,
+    requests: u32,
+}
+
+pub(crate) struct RateLimit {
+    rules: Vec<Rule>,
+    /// Request counts by index of the rule and client address.
+    windows: Mutex<Tracked<(usize, IpAddr), Window>>,
+}
+
+impl RateLimit {
+    /// Creates a rate limit from the rules given on the command line.
+    pub fn new(rules: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            rules: rules
+                .iter()
+                .map(|rule| Rule::parse(rule))
+                .collect::<Result<_, _>>()?,
+            windows: Mutex::new(Tracked::new(MAX_TRACKED)),
+        })
+    }
+
+    /// Counts a request from `ip` for `path`. If the client has exceeded the
+    /// rule with the longest prefix matching the path, returns the time after
+    /// which it may make a request again.
+    pub fn check(&self, ip: IpAddr, path: &str) -> Result<(), Duration> {
+        let (index, rule) = match self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches(path))
+            .max_by_key(|(_, rule)| rule.prefix.len())
+        {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // windows of clients that are currently limited are the last ones to
+        // stop being tracked
+        let window = windows.entry(
+            (index, ip),
+            |&(index, _), window| {
+                let rule = &self.rules[index];
+                now.duration_since(window.start) < rule.period && window.requests >= rule.requests
+            },
+            || Window {
+                start: now,
+                requests: 0,
+            },
+        );
+        let elapsed = now.duration_since(window.start);
+        if elapsed >= rule.period {
+            window.start = now;
+            window.requests = 0;
+        } else if window.requests >= rule.requests {
+            return Err(rule.period - elapsed);
+        }
+        window.requests += 1;
+        Ok(())
+    }
+}
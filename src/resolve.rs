@@ -0,0 +1,60 @@
+//! The `agate resolve` subcommand, which shows how a request for a URL would
+//! be answered with the given options, without starting any listener.
+
+use {
+    crate::{default_params, metadata::FileOptions, PresetMeta, RequestHandle, Response},
+    std::sync::Arc,
+    tokio::{io::AsyncWriteExt, runtime::Runtime, sync::Mutex},
+};
+
+/// Resolves `url` like a request received by the server and prints each
+/// step, the response header and what the body would be.
+pub(crate) fn run(url: &str) {
+    let runtime = Runtime::new().expect("could not start runtime");
+    runtime.block_on(async {
+        let metadata = Arc::new(Mutex::new(FileOptions::new(PresetMeta::Parameters(
+            default_params(),
+        ))));
+
+        // the request is read like one from a plaintext connection, so the
+        // same checks apply to it
+        let (mut client, server) = tokio::io::duplex(2048);
+        client
+            .write_all(format!("{}\r\n", url).as_bytes())
+            .await
+            .expect("could not write request");
+        let mut handle = RequestHandle::plaintext(server, metadata, "resolve".into(), None);
+        handle.steps = Some(vec![]);
+
+        let response = match handle.parse_request().await {
+            Ok(url) => handle.resolve(url).await,
+            Err(response) => Ok(response),
+        };
+        for step in handle.steps.take().unwrap_or_default() {
+            println!("{}", step);
+        }
+        match response {
+            Ok(Response::Header(status, meta)) => {
+                let (status, meta) = handle.fit_header(status, meta);
+                println!("response: {} {}", status, meta);
+                if status / 10 == 3 {
+                    println!("redirect to {}", meta);
+                }
+            }
+            Ok(Response::Fixed(header)) => {
+                println!("response: {} {}", header.status, header.meta);
+            }
+            Ok(Response::File(file, mime)) => {
+                println!("response: 20 {}", mime);
+                println!("body: file {:?}", file.path);
+            }
+            Ok(Response::Bytes(body, mime)) => {
+                println!("response: 20 {}", mime);
+                println!("body: {} generated bytes", body.len());
+            }
+            // nothing is fetched while resolving
+            Ok(Response::Mirrored(_, meta)) => println!("response: 20 {}", meta),
+            Err(e) => println!("no response: {}", e),
+        }
+    });
+}
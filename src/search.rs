@@ -0,0 +1,201 @@
+//! A simple full text search over the text files in the content directory.
+
+use {
+    arc_swap::ArcSwap,
+    std::{
+        collections::HashMap,
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+};
+
+/// Files with these extensions are indexed.
+const EXTENSIONS: &[&str] = &["gmi", "txt"];
+
+/// Larger files are not indexed.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// The approximate memory the index may use. Once it is reached, no more
+/// files are indexed.
+const MAX_INDEX_SIZE: usize = 32 * 1024 * 1024;
+
+/// A file that can be found by searching.
+#[derive(Clone)]
+pub(crate) struct Document {
+    /// The content path of the file.
+    pub path: PathBuf,
+    /// The first heading of the file, if it has one.
+    pub title: Option<String>,
+}
+
+/// An inverted index from words to the documents containing them.
+#[derive(Default)]
+struct Index {
+    documents: Vec<Document>,
+    /// The documents containing each word, with the number of occurrences.
+    words: HashMap<String, Vec<(usize, u32)>>,
+}
+
+impl Index {
+    /// Builds the index for all text files in the content directory or archive.
+    fn build() -> Self {
+        let mut paths = vec![];
+        match crate::ARGS.archive {
+            Some(ref archive) => paths.extend(
+                archive
+                    .files()
+                    .filter(|(path, size)| {
+                        *size <= MAX_FILE_SIZE
+                            && !is_hidden(path.strip_prefix(archive.path()).unwrap())
+                    })
+                    .map(|(path, _)| path),
+            ),
+            None => crate::walk_content(&crate::ARGS.content_dir, 0, &mut |path, metadata| {
+                if metadata.len() <= MAX_FILE_SIZE {
+                    paths.push(path);
+                }
+            }),
+        }
+
+        let mut index = Self::default();
+        let mut size = 0;
+        for path in paths {
+            if !matches!(path.extension().and_then(|e| e.to_str()), Some(e) if EXTENSIONS.contains(&e))
+            {
+                continue;
+            }
+            let text = match read(&path) {
+                Some(text) => text,
+                None => continue,
+            };
+            if size > MAX_INDEX_SIZE {
+                tracing::warn!(
+                    "The search index is full, not indexing {:?} and further files.",
+                    path
+                );
+                break;
+            }
+
+            let id = index.documents.len();
+            let mut counts = HashMap::new();
+            for word in words(&text) {
+                *counts.entry(word).or_insert(0) += 1;
+            }
+            for (word, count) in counts {
+                let postings = index.words.entry(word).or_insert_with_key(|word| {
+                    size += word.len() + std::mem::size_of::<String>();
+                    vec![]
+                });
+                postings.push((id, count));
+                size += std::mem::size_of::<(usize, u32)>();
+            }
+            let title = crate::first_heading(&text);
+            index.documents.push(Document { path, title });
+        }
+        index
+    }
+
+    /// Finds the documents that contain all words of the query, the ones
+    /// with the most occurrences first.
+    fn query(&self, query: &str) -> Vec<&Document> {
+        let mut hits: Option<HashMap<usize, u32>> = None;
+        for word in words(query) {
+            let postings = self.words.get(&word).map_or(&[][..], Vec::as_slice);
+            hits = Some(match hits {
+                None => postings.iter().copied().collect(),
+                Some(hits) => postings
+                    .iter()
+                    .filter_map(|(id, count)| hits.get(id).map(|total| (*id, total + count)))
+                    .collect(),
+            });
+        }
+        let mut hits = hits.unwrap_or_default().into_iter().collect::<Vec<_>>();
+        hits.sort_by(|(a, a_count), (b, b_count)| {
+            b_count
+                .cmp(a_count)
+                .then_with(|| self.documents[*a].path.cmp(&self.documents[*b].path))
+        });
+        hits.into_iter()
+            .map(|(id, _)| &self.documents[id])
+            .collect()
+    }
+}
+
+/// Checks if a path relative to the content root contains a hidden file or
+/// directory that must not be found unless secret files are served.
+fn is_hidden(path: &Path) -> bool {
+    !crate::ARGS.serve_secret
+        && path
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+/// Reads a file to index from the content directory or archive. Files that
+/// are not UTF-8 are skipped.
+fn read(path: &Path) -> Option<String> {
+    match crate::ARGS.archive {
+        Some(ref archive) => archive.read_to_string(path).ok(),
+        None => fs::read_to_string(path).ok(),
+    }
+}
+
+/// Splits text into lowercase words.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+pub(crate) struct Search {
+    /// The URL path of the search endpoint.
+    path: String,
+    /// The index, replaced when rebuilding.
+    index: ArcSwap<Index>,
+}
+
+impl Search {
+    pub fn new(path: String) -> Result<Self, String> {
+        if !path.starts_with('/') {
+            return Err(format!("search path {:?} does not start with /", path));
+        }
+        Ok(Self {
+            path,
+            index: ArcSwap::from_pointee(Index::default()),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Indexes the content again. This reads all text files, so it should
+    /// not be called on an async task.
+    pub fn rebuild(&self) {
+        let index = Index::build();
+        tracing::info!(
+            "Indexed {} files with {} different words for searching.",
+            index.documents.len(),
+            index.words.len()
+        );
+        self.index.store(Arc::new(index));
+    }
+
+    /// Returns the documents matching the query that `filter` accepts, at
+    /// most `limit` of them.
+    pub fn query(
+        &self,
+        query: &str,
+        limit: usize,
+        mut filter: impl FnMut(&Document) -> bool,
+    ) -> Vec<Document> {
+        self.index
+            .load()
+            .query(query)
+            .into_iter()
+            .filter(|document| filter(document))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,73 @@
+//! The `--self-test` startup check: a request to the server itself, before it
+//! tells anyone that it is running.
+
+use {
+    crate::{client, ARGS},
+    std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        time::Duration,
+    },
+    tokio::net::TcpStream,
+    url::{Host, Url},
+};
+
+/// How long the server may take to answer the self-test request.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests `path` from the server listening on `local_addr` over TLS, for
+/// the first configured domain name or `localhost`. Returns the response
+/// header if it has a 2x or 3x status.
+pub(crate) async fn run(local_addr: SocketAddr, path: &str) -> Result<String, String> {
+    // a listener on all interfaces can be reached on the loopback interface
+    let ip = match local_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    let addr = SocketAddr::new(ip, local_addr.port());
+    // certificates are only selected by domain names
+    let host = ARGS
+        .hostnames
+        .load()
+        .iter()
+        .find_map(|host| match host {
+            Host::Domain(name) => Some(name.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| "localhost".into());
+    // a URL without a port would be for port 1965
+    let url = match local_addr.port() {
+        1965 => format!("gemini://{}{}", host, path),
+        port => format!("gemini://{}:{}{}", host, port, path),
+    };
+    let url = Url::parse(&url).map_err(|e| e.to_string())?;
+
+    let request = async {
+        // this is not an outgoing connection, so --outbound-addr does not
+        // apply to it
+        let tcp = TcpStream::connect(addr)
+            .await
+            .map_err(|e| format!("{}: could not connect to {}: {}", url, addr, e))?;
+        let mut response = client::request_on(tcp, &url, None, None)
+            .await
+            .map_err(|e| format!("{}: {}", url, e))?;
+        if !matches!(response.status, 20..=39) {
+            return Err(format!(
+                "{} responded with status {} {:?}",
+                url, response.status, response.meta
+            ));
+        }
+        tokio::io::copy(&mut response.body, &mut tokio::io::sink())
+            .await
+            .map_err(|e| format!("could not read the body of {}: {}", url, e))?;
+        Ok(format!("{} {}", response.status, response.meta))
+    };
+    tokio::time::timeout(SELF_TEST_TIMEOUT, request)
+        .await
+        .unwrap_or_else(|_| {
+            Err(format!(
+                "no response for {} within {:?}",
+                url, SELF_TEST_TIMEOUT
+            ))
+        })
+}
@@ -0,0 +1,48 @@
+//! Guessing the MIME type of a file from its first bytes.
+
+/// Magic numbers at the start of files and the corresponding MIME types.
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b", "application/gzip"),
+];
+
+/// The offset of the magic number in the header of POSIX tar archives.
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// Returns the MIME type of a file that starts with `data`, if it is one of a
+/// few well-known formats or UTF-8 text.
+pub(crate) fn sniff(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return Some(mime);
+    }
+    if data
+        .get(TAR_MAGIC_OFFSET..)
+        .is_some_and(|data| data.starts_with(b"ustar"))
+    {
+        return Some("application/x-tar");
+    }
+    if is_text(data) {
+        return Some("text/plain");
+    }
+    None
+}
+
+/// Checks if the data looks like UTF-8 text, i.e. it is valid UTF-8 (except
+/// for a character that might be cut off at the end) without control
+/// characters other than whitespace.
+fn is_text(data: &[u8]) -> bool {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        // only the last character is incomplete
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).unwrap(),
+        Err(_) => return false,
+    };
+    !text.is_empty()
+        && !text
+            .chars()
+            .any(|c| c.is_control() && !c.is_ascii_whitespace())
+}
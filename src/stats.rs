@@ -0,0 +1,432 @@
+//! Counters about the requests served since startup.
+
+use {
+    once_cell::sync::OnceCell,
+    std::{
+        collections::BTreeMap,
+        fmt::{Display, Formatter, Write},
+        io,
+        sync::{
+            atomic::{AtomicU64, Ordering::Relaxed},
+            Mutex,
+        },
+        time::{Duration, Instant},
+    },
+};
+
+/// The number of host names responses are counted for. Responses for further
+/// host names are counted like those for an unknown host.
+const MAX_HOSTS: usize = 256;
+
+/// Statistics collected while running, printed when receiving `SIGUSR2`.
+pub(crate) static STATS: Stats = Stats::new();
+
+pub(crate) struct Stats {
+    /// Responses that were sent completely, including header-only responses.
+    completed: AtomicU64,
+    /// Responses where the body could not be sent completely, e.g. because
+    /// the client disconnected.
+    aborted: AtomicU64,
+    /// Number of body bytes sent, including those of aborted responses.
+    bytes: AtomicU64,
+    /// Number of request bytes read from clients after the TLS handshake.
+    received: AtomicU64,
+    /// Requests that did not end within the maximum request length.
+    oversized_requests: AtomicU64,
+    /// Requests for URLs with userinfo, i.e. a user name or password.
+    userinfo: AtomicU64,
+    /// Requests for URLs with a fragment.
+    fragments: AtomicU64,
+    /// Requests for URLs with a scheme other than `gemini`.
+    refused_schemes: AtomicU64,
+    /// Requests in another protocol, e.g. HTTP or SSH.
+    other_protocols: AtomicU64,
+    /// Requests for host names that are not served.
+    unknown_hosts: AtomicU64,
+    /// Requests for missing files answered from the cache of recent misses.
+    cached_misses: AtomicU64,
+    /// Directory listings and gemlog indexes that had to wait for others to
+    /// be generated.
+    generation_waits: AtomicU64,
+    /// Directory listings and gemlog indexes that were refused because others
+    /// were generated for too long.
+    generation_busy: AtomicU64,
+    /// Connections that were dropped right away, because the client failed
+    /// too many TLS handshakes.
+    dropped_connections: AtomicU64,
+    /// Requests whose handling was ended by a panic.
+    panics: AtomicU64,
+    /// Failed TLS handshakes, indexed by `HandshakeFailure`.
+    handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    /// When the server started listening.
+    started: OnceCell<Instant>,
+    /// Responses by status code.
+    statuses: [AtomicU64; 100],
+    /// Connections that are currently open, including TLS handshakes.
+    open_connections: AtomicU64,
+    /// Counters by requested host name, `None` for requests that were refused
+    /// before their host name was checked or for a host name that is not
+    /// served.
+    hosts: Mutex<BTreeMap<Option<String>, HostStats>>,
+}
+
+/// The counters of the responses for one host name.
+#[derive(Default)]
+struct HostStats {
+    completed: u64,
+    aborted: u64,
+    /// Number of body bytes sent.
+    bytes: u64,
+    /// Number of request bytes read from clients.
+    received: u64,
+    /// Responses by status code.
+    statuses: BTreeMap<u8, u64>,
+}
+
+impl Display for HostStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} completed, {} aborted, {} body bytes sent, {} request bytes received",
+            self.completed, self.aborted, self.bytes, self.received
+        )?;
+        if !self.statuses.is_empty() {
+            f.write_str(", statuses")?;
+            for (status, count) in &self.statuses {
+                write!(f, " {:02}={}", status, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The label of the counters for requests without a known host name.
+fn host_label(host: &Option<String>) -> &str {
+    host.as_deref().unwrap_or("unknown")
+}
+
+/// The cause of a failed TLS handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HandshakeFailure {
+    /// The client only supports older TLS versions, e.g. a scanner.
+    ProtocolVersion,
+    NoCipherOverlap,
+    /// The client did not send a host name, which Gemini requires.
+    NoSni,
+    /// There is no certificate for the host name the client sent.
+    UnknownSni,
+    /// The client closed the connection during the handshake.
+    PeerReset,
+    Other,
+}
+
+impl HandshakeFailure {
+    const ALL: [Self; 6] = [
+        Self::ProtocolVersion,
+        Self::NoCipherOverlap,
+        Self::NoSni,
+        Self::UnknownSni,
+        Self::PeerReset,
+        Self::Other,
+    ];
+
+    /// Classifies the error returned by the TLS acceptor. `no_sni` tells if the
+    /// client sent no host name, which the error does not say.
+    pub fn classify(error: &io::Error, no_sni: bool) -> Self {
+        use rustls::TLSError;
+
+        match error.get_ref().and_then(|e| e.downcast_ref::<TLSError>()) {
+            Some(TLSError::PeerIncompatibleError(why)) if why.contains("TLS") => {
+                Self::ProtocolVersion
+            }
+            Some(TLSError::PeerIncompatibleError(why)) if why == "no ciphersuites in common" => {
+                Self::NoCipherOverlap
+            }
+            // the certificate resolver requires SNI
+            Some(TLSError::General(why)) if why == "no server certificate chain resolved" => {
+                if no_sni {
+                    Self::NoSni
+                } else {
+                    Self::UnknownSni
+                }
+            }
+            Some(_) => Self::Other,
+            None => match error.kind() {
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::BrokenPipe => Self::PeerReset,
+                _ => Self::Other,
+            },
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ProtocolVersion => "unsupported protocol version",
+            Self::NoCipherOverlap => "no cipher overlap",
+            Self::NoSni => "no SNI",
+            Self::UnknownSni => "unknown SNI",
+            Self::PeerReset => "peer reset",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl Display for HandshakeFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            completed: AtomicU64::new(0),
+            aborted: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            oversized_requests: AtomicU64::new(0),
+            userinfo: AtomicU64::new(0),
+            fragments: AtomicU64::new(0),
+            refused_schemes: AtomicU64::new(0),
+            other_protocols: AtomicU64::new(0),
+            unknown_hosts: AtomicU64::new(0),
+            cached_misses: AtomicU64::new(0),
+            generation_waits: AtomicU64::new(0),
+            generation_busy: AtomicU64::new(0),
+            dropped_connections: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            handshake_failures: [const { AtomicU64::new(0) }; HandshakeFailure::ALL.len()],
+            started: OnceCell::new(),
+            statuses: [const { AtomicU64::new(0) }; 100],
+            open_connections: AtomicU64::new(0),
+            hosts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records that the server started, for the uptime.
+    pub fn start(&self) {
+        let _ = self.started.set(Instant::now());
+    }
+
+    /// The time since the server started.
+    pub fn uptime(&self) -> Duration {
+        self.started.get().map_or(Duration::ZERO, Instant::elapsed)
+    }
+
+    /// Records a finished response for `host`, the validated host name of the
+    /// request, with its status code if a header was sent, the number of body
+    /// bytes sent and the number of request bytes read from the client.
+    pub fn record_response(
+        &self,
+        host: Option<&str>,
+        status: Option<u8>,
+        completed: bool,
+        bytes: u64,
+        received: u64,
+    ) {
+        if completed {
+            self.completed.fetch_add(1, Relaxed);
+        } else {
+            self.aborted.fetch_add(1, Relaxed);
+        }
+        self.bytes.fetch_add(bytes, Relaxed);
+        self.received.fetch_add(received, Relaxed);
+        if let Some(count) = status.and_then(|status| self.statuses.get(usize::from(status))) {
+            count.fetch_add(1, Relaxed);
+        }
+
+        let mut hosts = self.hosts.lock().unwrap();
+        let key = match host {
+            Some(host) if hosts.contains_key(&Some(host.to_string())) => Some(host.to_string()),
+            Some(host) if hosts.len() < MAX_HOSTS => Some(host.to_string()),
+            _ => None,
+        };
+        let counters = hosts.entry(key).or_default();
+        if completed {
+            counters.completed += 1;
+        } else {
+            counters.aborted += 1;
+        }
+        counters.bytes += bytes;
+        counters.received += received;
+        if let Some(status) = status {
+            *counters.statuses.entry(status).or_default() += 1;
+        }
+    }
+
+    /// Records a request that was longer than allowed.
+    pub fn record_oversized_request(&self) {
+        self.oversized_requests.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request that was refused because its URL contains userinfo.
+    pub fn record_userinfo(&self) {
+        self.userinfo.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request that was refused because its URL has a fragment.
+    pub fn record_fragment(&self) {
+        self.fragments.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request that was refused because of its URL scheme.
+    pub fn record_refused_scheme(&self) {
+        self.refused_schemes.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request that was refused because it is in another protocol.
+    pub fn record_other_protocol(&self) {
+        self.other_protocols.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request that was refused because its host is not served.
+    pub fn record_unknown_host(&self) {
+        self.unknown_hosts.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request for a file that was recently found to be missing.
+    pub fn record_cached_miss(&self) {
+        self.cached_misses.fetch_add(1, Relaxed);
+    }
+
+    /// Records a directory listing or gemlog index that had to wait.
+    pub fn record_generation_wait(&self) {
+        self.generation_waits.fetch_add(1, Relaxed);
+    }
+
+    /// Records a directory listing or gemlog index that was refused because
+    /// it waited too long.
+    pub fn record_generation_busy(&self) {
+        self.generation_busy.fetch_add(1, Relaxed);
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn open_connection(&'static self) -> OpenConnection {
+        self.open_connections.fetch_add(1, Relaxed);
+        OpenConnection(self)
+    }
+
+    /// Writes the counters as gemtext, for the status page.
+    pub fn write_gemtext(&self, page: &mut String) -> std::fmt::Result {
+        writeln!(
+            page,
+            "## Responses
+"
+        )?;
+        writeln!(
+            page,
+            "* {} completed, {} aborted",
+            self.completed.load(Relaxed),
+            self.aborted.load(Relaxed)
+        )?;
+        writeln!(page, "* {} body bytes sent", self.bytes.load(Relaxed))?;
+        writeln!(
+            page,
+            "* {} directory listings and gemlog indexes waited, {} of them refused",
+            self.generation_waits.load(Relaxed),
+            self.generation_busy.load(Relaxed)
+        )?;
+        writeln!(page, "* {} ended by a panic", self.panics.load(Relaxed))?;
+        for (status, count) in self.statuses.iter().enumerate() {
+            match count.load(Relaxed) {
+                0 => (),
+                count => writeln!(page, "* {} with status {}", count, status)?,
+            }
+        }
+
+        writeln!(page, "\n## Connections\n")?;
+        writeln!(page, "* {} open", self.open_connections.load(Relaxed))?;
+        let failures = HandshakeFailure::ALL
+            .iter()
+            .map(|failure| {
+                let count = self.handshake_failures[*failure as usize].load(Relaxed);
+                format!("{} {}", count, failure)
+            })
+            .collect::<Vec<_>>();
+        writeln!(page, "* TLS handshake failures: {}", failures.join(", "))?;
+        writeln!(
+            page,
+            "* {} dropped after too many failed handshakes",
+            self.dropped_connections.load(Relaxed)
+        )?;
+
+        writeln!(page, "\n## Responses by host name\n")?;
+        let hosts = self.hosts.lock().unwrap();
+        if hosts.is_empty() {
+            writeln!(page, "None yet.")?;
+        }
+        for (host, counters) in hosts.iter() {
+            writeln!(page, "* {}: {}", host_label(host), counters)?;
+        }
+        Ok(())
+    }
+
+    /// Records a failed TLS handshake.
+    pub fn record_handshake_failure(&self, failure: HandshakeFailure) {
+        self.handshake_failures[failure as usize].fetch_add(1, Relaxed);
+    }
+
+    /// Records a connection that was dropped without a TLS handshake.
+    pub fn record_dropped_connection(&self) {
+        self.dropped_connections.fetch_add(1, Relaxed);
+    }
+
+    /// Records a request whose handling panicked.
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Relaxed);
+    }
+}
+
+/// A connection counted as open, see [`Stats::open_connection`].
+pub(crate) struct OpenConnection(&'static Stats);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "statistics: {} responses completed, {} aborted, {} body bytes sent, {} request bytes received, {} requests too long, {} requests with userinfo refused, {} requests with a fragment refused, {} requests for other URL schemes refused, {} requests in other protocols refused, {} requests for unknown hosts refused, {} requests for missing files answered from cache, {} generated responses waited, {} refused as busy, {} ended by a panic",
+            self.completed.load(Relaxed),
+            self.aborted.load(Relaxed),
+            self.bytes.load(Relaxed),
+            self.received.load(Relaxed),
+            self.oversized_requests.load(Relaxed),
+            self.userinfo.load(Relaxed),
+            self.fragments.load(Relaxed),
+            self.refused_schemes.load(Relaxed),
+            self.other_protocols.load(Relaxed),
+            self.unknown_hosts.load(Relaxed),
+            self.cached_misses.load(Relaxed),
+            self.generation_waits.load(Relaxed),
+            self.generation_busy.load(Relaxed),
+            self.panics.load(Relaxed),
+        )?;
+        let failures = HandshakeFailure::ALL
+            .iter()
+            .map(|failure| {
+                format!(
+                    "{} {}",
+                    self.handshake_failures[*failure as usize].load(Relaxed),
+                    failure
+                )
+            })
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            ", TLS handshake failures: {}, {} connections dropped",
+            failures.join(", "),
+            self.dropped_connections.load(Relaxed)
+        )?;
+        for (host, counters) in self.hosts.lock().unwrap().iter() {
+            write!(f, "; {}: {}", host_label(host), counters)?;
+        }
+        Ok(())
+    }
+}
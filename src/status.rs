@@ -0,0 +1,48 @@
+//! The status page for administrators, enabled with `--status-page`. It is
+//! only shown to clients presenting a certificate given with `--admin-cert`,
+//! and does not depend on the content directory.
+
+use {
+    crate::{certificates, stats::STATS, transfer, ARGS},
+    std::{fmt::Write, time::Duration},
+};
+
+/// Renders the status page as gemtext, using the same counters as the
+/// statistics logged on `SIGUSR2`.
+pub(crate) fn render() -> String {
+    let mut page = String::new();
+    // writing to a string does not fail
+    write_page(&mut page).unwrap();
+    page
+}
+
+fn write_page(page: &mut String) -> std::fmt::Result {
+    let uptime = Duration::from_secs(STATS.uptime().as_secs());
+    writeln!(page, "# Server status\n")?;
+    writeln!(
+        page,
+        "Agate {}, running for {}.\n",
+        env!("CARGO_PKG_VERSION"),
+        humantime::format_duration(uptime)
+    )?;
+
+    STATS.write_gemtext(page)?;
+
+    writeln!(page, "\n## Transfers\n")?;
+    writeln!(page, "* {} in progress", transfer::active())?;
+
+    writeln!(page, "\n## Certificates\n")?;
+    for (domain, cert) in ARGS.certs.load().certificates() {
+        writeln!(
+            page,
+            "* {}: valid until {}",
+            if domain.is_empty() {
+                "other domains"
+            } else {
+                domain
+            },
+            certificates::expiry(cert).as_deref().unwrap_or("unknown"),
+        )?;
+    }
+    Ok(())
+}
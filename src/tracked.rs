@@ -0,0 +1,78 @@
+//! A table of what is known about clients, with a bounded number of entries
+//! so that many clients cannot make agate use too much memory.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+};
+
+/// How many entries are skipped at most when looking for one to stop
+/// tracking, so that a table full of entries worth keeping does not make this
+/// slow.
+const MAX_SKIPPED: usize = 16;
+
+pub(crate) struct Tracked<K, V> {
+    by_key: HashMap<K, V>,
+    /// The same keys as `by_key`, in the order they are considered for
+    /// eviction, i.e. mostly the order they were added in.
+    order: VecDeque<K>,
+    max: usize,
+}
+
+impl<K: Copy + Eq + Hash, V> Tracked<K, V> {
+    /// Creates a table of at most `max` entries, or of the maximum number of
+    /// entries the tests set, so that they can fill the table. The tests
+    /// cannot set it in release builds.
+    pub fn new(max: usize) -> Self {
+        #[cfg(debug_assertions)]
+        let max = std::env::var("AGATE_TEST_MAX_TRACKED")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(max);
+        Self {
+            by_key: HashMap::new(),
+            order: VecDeque::new(),
+            max,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.by_key.get_mut(key)
+    }
+
+    /// Returns the entry of `key`, adding the one `new` returns if there is
+    /// none. If the table is full, the entry that was added first is removed
+    /// to make room. Entries for which `keep` returns true are moved to the
+    /// back instead, unless there are too many of them in a row.
+    pub fn entry(
+        &mut self,
+        key: K,
+        keep: impl Fn(&K, &V) -> bool,
+        new: impl FnOnce() -> V,
+    ) -> &mut V {
+        if !self.by_key.contains_key(&key) {
+            if self.by_key.len() >= self.max {
+                self.evict(keep);
+            }
+            self.order.push_back(key);
+        }
+        self.by_key.entry(key).or_insert_with(new)
+    }
+
+    fn evict(&mut self, keep: impl Fn(&K, &V) -> bool) {
+        for _ in 0..MAX_SKIPPED {
+            let key = match self.order.pop_front() {
+                Some(key) => key,
+                None => return,
+            };
+            if !keep(&key, &self.by_key[&key]) {
+                self.by_key.remove(&key);
+                return;
+            }
+            self.order.push_back(key);
+        }
+        if let Some(key) = self.order.pop_front() {
+            self.by_key.remove(&key);
+        }
+    }
+}
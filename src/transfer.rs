@@ -0,0 +1,209 @@
+//! Copying response bodies to the client with buffers of a configurable size
+//! that are reused between requests. Everything that needs to look at the
+//! body while it is sent belongs into the copy loop here. The transfers that
+//! are in progress are kept in a registry, so they can be logged.
+
+use {
+    std::{
+        collections::BTreeMap,
+        fmt, io,
+        ops::{Deref, DerefMut},
+        sync::{
+            atomic::{AtomicU64, Ordering::Relaxed},
+            Arc, Mutex,
+        },
+        time::Instant,
+    },
+    tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+
+/// The default size of the buffers, large enough to fill several TLS records
+/// with one read.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The number of unused buffers kept for later requests. More buffers are
+/// allocated when there are more concurrent responses, but freed afterwards.
+const MAX_POOLED: usize = 16;
+
+static POOL: Mutex<Vec<Box<[u8]>>> = Mutex::new(Vec::new());
+
+/// A buffer of `--io-buffer` bytes taken from the pool, which is returned to
+/// the pool when dropped.
+pub(crate) struct Buffer(Box<[u8]>);
+
+impl Buffer {
+    pub fn take() -> Self {
+        let size = crate::ARGS.io_buffer;
+        let pooled = POOL.lock().unwrap().pop();
+        Self(pooled.unwrap_or_else(|| vec![0; size].into_boxed_slice()))
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let mut pool = POOL.lock().unwrap();
+        if pool.len() < MAX_POOLED {
+            pool.push(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// The transfers in progress by a number that is unique while running.
+static ACTIVE: Mutex<BTreeMap<u64, Arc<Progress>>> = Mutex::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The progress of a transfer in the registry, updated from the copy loop.
+pub(crate) struct Progress {
+    /// The file being sent, relative to the content directory.
+    path: String,
+    started: Instant,
+    sent: AtomicU64,
+    /// The size of the file when the transfer started, if it is known.
+    size: Option<u64>,
+}
+
+/// A transfer in the registry, which is removed when this is dropped, so it
+/// is also removed if the response fails or the connection task is cancelled.
+pub(crate) struct Transfer {
+    id: u64,
+    progress: Arc<Progress>,
+}
+
+impl Transfer {
+    /// Adds a transfer of the file at `path` to the registry.
+    pub fn start(path: &std::path::Path, size: Option<u64>) -> Self {
+        let path = path
+            .strip_prefix(&crate::ARGS.content_dir)
+            .unwrap_or(path)
+            .display()
+            .to_string();
+        let progress = Arc::new(Progress {
+            path,
+            started: Instant::now(),
+            sent: AtomicU64::new(0),
+            size,
+        });
+        let id = NEXT_ID.fetch_add(1, Relaxed);
+        ACTIVE.lock().unwrap().insert(id, progress.clone());
+        Self { id, progress }
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sent = self.sent.load(Relaxed);
+        write!(f, "{:?} ", self.path)?;
+        match self.size {
+            Some(size) => write!(
+                f,
+                "{} of {} bytes sent, {} remaining",
+                sent,
+                size,
+                size.saturating_sub(sent)
+            )?,
+            None => write!(f, "{} bytes sent", sent)?,
+        }
+        write!(f, " in {}s", self.started.elapsed().as_secs())
+    }
+}
+
+/// The number of transfers in progress.
+pub(crate) fn active() -> usize {
+    ACTIVE.lock().unwrap().len()
+}
+
+/// Logs the number of transfers in progress and one line for each of them.
+pub(crate) fn log_active() {
+    let active = ACTIVE.lock().unwrap();
+    tracing::info!("{} transfers in progress", active.len());
+    for progress in active.values() {
+        tracing::info!("in progress: {}", progress);
+    }
+}
+
+/// How copying a body ended.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Copied {
+    /// Everything was copied, which is exactly the expected length if there
+    /// was one.
+    Complete,
+    /// The reader ended before the expected length, e.g. because the file was
+    /// truncated while it was sent.
+    Short,
+    /// The reader had more than the expected length, e.g. because the file
+    /// grew while it was sent. Only the expected length was copied.
+    Long,
+}
+
+/// Copies everything from `reader` to `writer`, but at most `len` bytes if it
+/// is given. Reads may return fewer bytes than fit into the buffer, only a
+/// read of zero bytes ends the body. `sent` is increased after every write, so
+/// it also counts the bytes that were sent before an error. The `progress` of
+/// a registered transfer is updated too.
+pub(crate) async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    sent: &mut u64,
+    len: Option<u64>,
+    progress: Option<&Progress>,
+) -> io::Result<Copied>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buffer = Buffer::take();
+    let mut remaining = len;
+    loop {
+        let max = match remaining {
+            Some(remaining) => remaining.min(buffer.len() as u64) as usize,
+            None => buffer.len(),
+        };
+        if max == 0 {
+            // only check whether there is more, it is not sent
+            return Ok(match reader.read(&mut buffer[..1]).await {
+                Ok(1..) => Copied::Long,
+                _ => Copied::Complete,
+            });
+        }
+        let read = match reader.read(&mut buffer[..max]).await {
+            Ok(0) if remaining.is_some() => return Ok(Copied::Short),
+            Ok(0) => return Ok(Copied::Complete),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read]).await?;
+        *sent += read as u64;
+        if let Some(ref mut remaining) = remaining {
+            *remaining -= read as u64;
+        }
+        if let Some(progress) = progress {
+            progress.sent.store(*sent, Relaxed);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Checking the content directory for problems that would otherwise only show
+//! up as failed requests, enabled with `--validate-content`.
+
+use {
+    configparser::ini::Ini,
+    glob::{glob_with, MatchOptions},
+    std::{
+        fmt, fs, io,
+        path::{Path, PathBuf},
+    },
+};
+
+/// Names that look like they are meant as the index of a directory, but are
+/// not used as one.
+const INDEX_LOOKALIKES: &[&str] = &["index.gemini", "index.txt", "index.md", "index.html"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    /// The file can not be served.
+    Error,
+    /// The file can be served, but probably not as intended.
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        })
+    }
+}
+
+/// The findings so far. Each one is printed as soon as it is found.
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn add(&mut self, severity: Severity, path: &Path, message: fmt::Arguments<'_>) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        println!("{}: {:?}: {}", severity, path, message);
+    }
+}
+
+/// Walks the content directory and prints one line for each problem found.
+/// Returns an error if any of the problems keeps a file from being served.
+pub(crate) fn run() -> Result<(), String> {
+    let root = &crate::ARGS.content_dir;
+    let mut report = Report::default();
+    let mut ancestors = vec![];
+    match fs::canonicalize(root) {
+        Ok(canonical) => ancestors.push(canonical),
+        Err(e) => {
+            return Err(format!(
+                "Could not read content directory {:?}: {}",
+                root, e
+            ))
+        }
+    }
+    walk(root, &mut ancestors, &mut report);
+    if crate::ARGS.central_config {
+        check_meta(&root.join(".meta"), &mut report);
+    }
+
+    println!(
+        "{} errors, {} warnings in {:?}",
+        report.errors, report.warnings, root
+    );
+    match report.errors {
+        0 => Ok(()),
+        errors => Err(format!("Found {} errors in the content directory", errors)),
+    }
+}
+
+/// Checks the entries of a directory and the directories below it.
+/// `ancestors` are the canonical paths of the directories leading to `dir`
+/// including itself, so symlinks to them are not followed.
+fn walk(dir: &Path, ancestors: &mut Vec<PathBuf>, report: &mut Report) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report.add(
+                Severity::Error,
+                dir,
+                format_args!("directory can not be read: {}", e),
+            );
+            return;
+        }
+    };
+    let mut names = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        names.push(name.to_string_lossy().into_owned());
+
+        if name == ".meta" && !crate::ARGS.central_config {
+            check_meta(&path, report);
+        }
+        if name.to_string_lossy().starts_with('.') && !crate::ARGS.serve_secret {
+            continue;
+        }
+        match name.to_str() {
+            None => report.add(
+                Severity::Warning,
+                &path,
+                format_args!("name is not valid UTF-8"),
+            ),
+            Some(name) if name.contains(char::is_control) => report.add(
+                Severity::Warning,
+                &path,
+                format_args!("name contains control characters"),
+            ),
+            Some(_) => (),
+        }
+
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                report.add(Severity::Error, &path, format_args!("dangling symlink"));
+                continue;
+            }
+            Err(e) => {
+                report.add(
+                    Severity::Error,
+                    &path,
+                    format_args!("can not be accessed: {}", e),
+                );
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            if name == "index.gmi" {
+                report.add(
+                    Severity::Error,
+                    &path,
+                    format_args!("is a directory, so the directory index can not be served"),
+                );
+            }
+            let canonical = match fs::canonicalize(&path) {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    report.add(
+                        Severity::Error,
+                        &path,
+                        format_args!("can not be accessed: {}", e),
+                    );
+                    continue;
+                }
+            };
+            if ancestors.contains(&canonical) {
+                report.add(
+                    Severity::Warning,
+                    &path,
+                    format_args!("symlink loop to {:?}, not following it", canonical),
+                );
+                continue;
+            }
+            // deeper paths can not be requested
+            if ancestors.len() <= crate::ARGS.max_path_segments {
+                ancestors.push(canonical);
+                walk(&path, ancestors, report);
+                ancestors.pop();
+            }
+        } else if let Err(e) = fs::File::open(&path) {
+            report.add(
+                Severity::Error,
+                &path,
+                format_args!("can not be read: {}", e),
+            );
+        }
+    }
+
+    if !names.iter().any(|name| name == "index.gmi") {
+        for name in names.iter().filter(|name| {
+            INDEX_LOOKALIKES.contains(&name.as_str()) || name.eq_ignore_ascii_case("index.gmi")
+        }) {
+            report.add(
+                Severity::Warning,
+                &dir.join(name),
+                format_args!("is not used as directory index, only index.gmi is"),
+            );
+        }
+    }
+}
+
+/// Checks that the entries of a `.meta` file refer to existing files. Entries
+/// with a full header line are left out, since they are often used for files
+/// that are gone.
+fn check_meta(meta: &Path, report: &mut Report) {
+    let text = match fs::read_to_string(meta) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            report.add(
+                Severity::Error,
+                meta,
+                format_args!("can not be read: {}", e),
+            );
+            return;
+        }
+    };
+    let mut ini = Ini::new_cs();
+    ini.set_default_section("mime");
+    ini.set_comment_symbols(&['#']);
+    let section = match ini.read(text) {
+        Ok(mut sections) => sections.remove("mime").unwrap_or_default(),
+        Err(e) => {
+            report.add(Severity::Error, meta, format_args!("is invalid: {}", e));
+            return;
+        }
+    };
+    let dir = meta.parent().unwrap_or(Path::new(""));
+    let options = MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: !crate::ARGS.serve_secret,
+    };
+    for (key, value) in section {
+        if matches!(
+            value.as_deref().and_then(|v| v.chars().next()),
+            Some('1'..='6')
+        ) {
+            continue;
+        }
+        let pattern = dir.join(&key);
+        let matched = pattern
+            .to_str()
+            .and_then(|pattern| glob_with(pattern, options).ok())
+            .is_some_and(|mut paths| paths.any(|path| path.is_ok()));
+        if !matched {
+            report.add(
+                Severity::Warning,
+                meta,
+                format_args!("entry {:?} does not match any file", key),
+            );
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Language variants of gemtext files like `page.de.gmi` next to `page.gmi`,
+//! selected with a query like `?lang=de` if `--lang-variants` is enabled.
+
+use std::path::{Path, PathBuf};
+
+/// Checks if `tag` looks like a language tag, i.e. a two letter language code
+/// optionally followed by subtags like a region, e.g. `de` or `pt-BR`. Longer
+/// language codes are not accepted, so that names like `notes.old.gmi` are not
+/// mistaken for variants.
+pub(crate) fn is_lang_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let language = subtags.next().unwrap_or_default();
+    language.len() == 2
+        && language.bytes().all(|b| b.is_ascii_lowercase())
+        && subtags.all(|subtag| {
+            (2..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+/// Splits the name of a variant like `page.de.gmi` into the name of the base
+/// file and the language tag, i.e. `page.gmi` and `de`.
+pub(crate) fn split(name: &str) -> Option<(String, &str)> {
+    let (stem, lang) = name.strip_suffix(".gmi")?.rsplit_once('.')?;
+    if stem.is_empty() || !is_lang_tag(lang) {
+        return None;
+    }
+    Some((format!("{}.gmi", stem), lang))
+}
+
+/// The name of the variant of a base file name like `page.gmi`.
+fn variant_name(base: &str, lang: &str) -> Option<String> {
+    let stem = base.strip_suffix(".gmi").filter(|stem| !stem.is_empty())?;
+    Some(format!("{}.{}.gmi", stem, lang))
+}
+
+/// Finds the variant of the gemtext file at `path` to serve instead of it.
+/// This is the variant for the language requested with `lang` in the query if
+/// there is one, or the only variant if the file itself does not exist.
+/// Returns the path of the variant, its language tag and size.
+pub(crate) async fn select(
+    path: &Path,
+    query: Option<&str>,
+    exists: bool,
+) -> Option<(PathBuf, String, u64)> {
+    if let Some(found) = find(path, requested(query)).await {
+        return Some(found);
+    }
+    if exists {
+        return None;
+    }
+
+    let mut variants = languages(path).await.into_iter();
+    let lang = variants.next()?;
+    if variants.next().is_some() {
+        tracing::debug!("not choosing between the variants of missing {:?}", path);
+        return None;
+    }
+    find(path, Some(lang)).await
+}
+
+/// Finds the variant of the index file `index.gmi` in `dir` to serve for a
+/// request for the directory. In this order, this is the variant for the
+/// language requested with `lang` in the query, the variant for the default
+/// language set with `--lang`, the index file itself if it `exists`, or the
+/// first of the other variants by language tag. Returns the path of the
+/// variant, its language tag and size.
+pub(crate) async fn select_index(
+    dir: &Path,
+    query: Option<&str>,
+    exists: bool,
+) -> Option<(PathBuf, String, u64)> {
+    let path = dir.join("index.gmi");
+    let default = crate::ARGS
+        .language
+        .clone()
+        .filter(|lang| is_lang_tag(lang));
+    for lang in [requested(query), default] {
+        if let Some(found) = find(&path, lang).await {
+            return Some(found);
+        }
+    }
+    if exists {
+        return None;
+    }
+    for lang in languages(&path).await {
+        if let Some(found) = find(&path, Some(lang)).await {
+            return Some(found);
+        }
+    }
+    None
+}
+
+/// The language requested with `lang` in the query, if it is a language tag.
+fn requested(query: Option<&str>) -> Option<String> {
+    let query = query?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "lang")
+        .map(|(_, lang)| lang.into_owned())
+        .filter(|lang| is_lang_tag(lang))
+}
+
+/// Returns the path and size of the variant of the file at `path` for `lang`,
+/// if it exists.
+async fn find(path: &Path, lang: Option<String>) -> Option<(PathBuf, String, u64)> {
+    let lang = lang?;
+    let variant = path.with_file_name(variant_name(path.file_name()?.to_str()?, &lang)?);
+    match crate::content_metadata(&variant).await {
+        Some((false, len)) => Some((variant, lang, len)),
+        _ => None,
+    }
+}
+
+/// Returns the language tags of the variants of the file at `path` that
+/// exist, sorted.
+async fn languages(path: &Path) -> Vec<String> {
+    let (Some(dir), Some(base)) = (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) else {
+        return vec![];
+    };
+    let entries = match crate::ARGS.archive {
+        Some(ref archive) => archive.read_dir(dir),
+        None => crate::read_dir(dir).await.ok(),
+    };
+    let mut languages = entries
+        .unwrap_or_default()
+        .iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .filter_map(|(name, _)| split(name.to_str()?))
+        .filter(|(name, _)| name == base)
+        .map(|(_, lang)| lang.to_string())
+        .collect::<Vec<_>>();
+    languages.sort();
+    languages
+}
@@ -0,0 +1,85 @@
+//! Reading the `.meta` files and looking for the index files of the top-level
+//! directories before accepting connections, enabled with `--warm-up`, so the
+//! first requests after a start do not have to.
+
+use {
+    crate::{metadata::FileOptions, ARGS},
+    std::{path::PathBuf, time::Instant},
+    tokio::sync::Mutex,
+};
+
+/// Preloads the metadata cache and logs how long it took. Problems are only
+/// logged as warnings, the server starts anyway.
+pub(crate) async fn run(metadata: &Mutex<FileOptions>) {
+    let started = Instant::now();
+    let root = &ARGS.content_dir;
+    let mut metadata = metadata.lock().await;
+
+    // with a central configuration there is only one sidecar file, otherwise
+    // each directory may have one
+    let mut dirs = 0;
+    let mut stack = vec![(root.clone(), 0)];
+    while let Some((dir, depth)) = stack.pop() {
+        metadata.preload(&dir);
+        dirs += 1;
+        if ARGS.central_config || depth >= ARGS.max_path_segments {
+            continue;
+        }
+        let entries = match ARGS.archive {
+            Some(ref archive) => archive
+                .read_dir(&dir)
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into()),
+            None => crate::read_dir(&dir).await,
+        };
+        match entries {
+            Ok(entries) => stack.extend(
+                entries
+                    .into_iter()
+                    .filter(|(name, is_dir)| {
+                        *is_dir && (ARGS.serve_secret || !name.to_string_lossy().starts_with('.'))
+                    })
+                    .map(|(name, _)| (dir.join(name), depth + 1)),
+            ),
+            Err(e) => tracing::warn!("Could not read directory {:?} during warm-up: {}", dir, e),
+        }
+    }
+
+    // the top-level directories include those of virtual hosts
+    let mut top_level: Vec<PathBuf> = vec![root.clone()];
+    match ARGS.archive {
+        Some(ref archive) => top_level.extend(
+            archive
+                .read_dir(root)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(_, is_dir)| *is_dir)
+                .map(|(name, _)| root.join(name)),
+        ),
+        None => match crate::read_dir(root).await {
+            Ok(entries) => top_level.extend(
+                entries
+                    .into_iter()
+                    .filter(|(_, is_dir)| *is_dir)
+                    .map(|(name, _)| root.join(name)),
+            ),
+            Err(e) => tracing::warn!("Could not read directory {:?} during warm-up: {}", root, e),
+        },
+    }
+    let mut indexes = 0;
+    for dir in top_level {
+        if crate::content_metadata(&dir.join("index.gmi"))
+            .await
+            .is_some()
+        {
+            indexes += 1;
+        }
+    }
+
+    tracing::info!(
+        "Warmed up in {}ms: {} .meta entries loaded from {} directories, {} index files found",
+        started.elapsed().as_millis(),
+        metadata.entries(),
+        dirs,
+        indexes
+    );
+}
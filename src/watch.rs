@@ -0,0 +1,173 @@
+//! Watching the content directory for changes, so that caches can forget
+//! about changed files right away instead of expiring all entries after a
+//! few seconds. Linux, macOS and Windows are supported. Elsewhere, or if the
+//! directories can not be watched, the caches just expire their entries.
+
+use {
+    once_cell::sync::Lazy,
+    std::{
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering::Relaxed},
+            Mutex,
+        },
+        time::Duration,
+    },
+};
+
+/// How long caches keep entries while changes are watched. Changes are not
+/// reported for some file systems, e.g. network file systems, so entries
+/// still expire eventually.
+const WATCHED_TTL: Duration = Duration::from_secs(60);
+
+/// Whether all directories in the content directory are watched.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+static CACHES: Lazy<Mutex<Vec<&'static dyn Invalidate>>> = Lazy::new(Default::default);
+
+/// A cache of information about files in the content directory.
+pub(crate) trait Invalidate: Sync {
+    /// Forgets what is cached about `path` and the files below it, because
+    /// it was created, changed or removed.
+    fn invalidate(&self, path: &Path);
+    /// Forgets everything, because changes might have been missed.
+    fn clear(&self);
+}
+
+/// Makes `cache` forget about files when they change.
+pub(crate) fn subscribe(cache: &'static dyn Invalidate) {
+    CACHES.lock().unwrap().push(cache);
+}
+
+/// How long caches keep entries. Without watching, this is `unwatched`,
+/// which should be short so changes are noticed soon.
+pub(crate) fn ttl(unwatched: Duration) -> Duration {
+    if ACTIVE.load(Relaxed) {
+        WATCHED_TTL.max(unwatched)
+    } else {
+        unwatched
+    }
+}
+
+fn invalidate(path: &Path) {
+    for cache in CACHES.lock().unwrap().iter() {
+        cache.invalidate(path);
+    }
+}
+
+fn clear() {
+    for cache in CACHES.lock().unwrap().iter() {
+        cache.clear();
+    }
+}
+
+/// Starts watching the content directory in the background.
+pub(crate) fn start() {
+    if crate::ARGS.archive.is_some() {
+        // archives do not change while they are served
+        return;
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+    std::thread::spawn(|| {
+        if let Err(e) = notifier::watch(&crate::ARGS.content_dir) {
+            degrade(e);
+        }
+    });
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    tracing::debug!("Watching for changes is not supported, caches expire after a few seconds");
+}
+
+/// Goes back to expiring cache entries after a few seconds, because changes
+/// are not reported anymore.
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+fn degrade(e: impl std::fmt::Display) {
+    if ACTIVE.swap(false, Relaxed) {
+        clear();
+    }
+    tracing::warn!(
+        "Could not watch the content directory for changes, caches expire after a few seconds instead: {}",
+        e
+    );
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+mod notifier {
+    use {
+        super::{clear, degrade, invalidate, ACTIVE},
+        notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher},
+        std::{
+            io,
+            path::Path,
+            sync::{atomic::Ordering::Relaxed, Mutex},
+        },
+    };
+
+    /// The watcher stops watching when it is dropped, so it is kept here.
+    static WATCHER: Mutex<Option<RecommendedWatcher>> = Mutex::new(None);
+
+    /// Watches the directory `root` and everything below it. Changes are
+    /// reported on a thread of the watcher.
+    pub(super) fn watch(root: &Path) -> notify::Result<()> {
+        let mut watcher = RecommendedWatcher::new(
+            handle,
+            // symlinks are followed like when serving files
+            Config::default().with_follow_symlinks(true),
+        )?;
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(explain)?;
+        *WATCHER.lock().unwrap() = Some(watcher);
+        tracing::debug!("Watching the content directory for changes");
+        ACTIVE.store(true, Relaxed);
+        // changes before the directories were watched might have been missed
+        clear();
+        Ok(())
+    }
+
+    fn handle(event: notify::Result<Event>) {
+        match event {
+            // files are opened for every request, which changes nothing
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => (),
+            Ok(event) => {
+                if event.need_rescan() {
+                    tracing::debug!("Missed changes in the content directory");
+                    clear();
+                }
+                for path in &event.paths {
+                    tracing::trace!("{:?} changed", path);
+                    invalidate(path);
+                }
+            }
+            // new directories can not be watched, so their changes are missed
+            Err(e) if is_exhausted(&e) => {
+                if ACTIVE.load(Relaxed) {
+                    degrade(explain(e));
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Error while watching the content directory: {}", e);
+                clear();
+            }
+        }
+    }
+
+    /// Whether the system does not allow to watch any more directories.
+    fn is_exhausted(e: &notify::Error) -> bool {
+        match &e.kind {
+            notify::ErrorKind::MaxFilesWatch => true,
+            notify::ErrorKind::Io(e) => e.kind() == io::ErrorKind::StorageFull,
+            _ => false,
+        }
+    }
+
+    fn explain(e: notify::Error) -> notify::Error {
+        if is_exhausted(&e) {
+            notify::Error::generic(&format!(
+                "too many directories, see /proc/sys/fs/inotify/max_user_watches: {}",
+                e
+            ))
+        } else {
+            e
+        }
+    }
+}
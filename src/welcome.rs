@@ -0,0 +1,65 @@
+//! The welcome page served for an empty content directory, so that a new
+//! installation does not look broken. It can be disabled with `--no-welcome`.
+
+use {
+    crate::ARGS,
+    once_cell::sync::Lazy,
+    std::{
+        fs,
+        path::Path,
+        sync::atomic::{AtomicBool, Ordering::Relaxed},
+    },
+};
+
+/// Whether the content directory was empty when it was last checked.
+static EMPTY: AtomicBool = AtomicBool::new(false);
+
+static PAGE: Lazy<String> = Lazy::new(|| {
+    format!(
+        "\
+# Welcome to Agate
+
+Agate is running, but there is nothing to serve yet. The content directory is {}.
+
+This page disappears once you create an index.gmi file there. To list the files in it instead, create an empty file called .directory-listing-ok.
+",
+        ARGS.content_dir.display()
+    )
+});
+
+/// Checks whether the content directory is empty, at startup and when
+/// reloading. Hidden files like `.meta` do not count as content, unless they
+/// mark the directory to be listed or to get a gemlog index.
+pub(crate) fn check() {
+    let empty = ARGS.welcome
+        && ARGS.archive.is_none()
+        && match fs::read_dir(&ARGS.content_dir) {
+            Ok(entries) => entries.filter_map(Result::ok).all(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with('.')
+                    && !ARGS.serve_secret
+                    && name != ".directory-listing-ok"
+                    && name != ".gemlog"
+            }),
+            Err(_) => false,
+        };
+    if empty && !EMPTY.load(Relaxed) {
+        tracing::info!("The content directory is empty, serving a welcome page");
+    }
+    EMPTY.store(empty, Relaxed);
+}
+
+/// Returns the welcome page for a request for `root`, the content directory
+/// or the directory of a virtual host, if the content directory was empty
+/// and `root` still has no index file.
+pub(crate) async fn page(root: &Path) -> Option<&'static str> {
+    if !EMPTY.load(Relaxed)
+        || crate::content_metadata(&root.join("index.gmi"))
+            .await
+            .is_some()
+    {
+        return None;
+    }
+    Some(&PAGE)
+}
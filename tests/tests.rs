@@ -4,6 +4,8 @@ use std::io::{BufRead, BufReader, Read};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
 use url::Url;
 
 static BINARY_PATH: &str = env!("CARGO_BIN_EXE_agate");
@@ -20,31 +22,59 @@ fn addr(port: u16) -> SocketAddr {
 
 struct Server {
     server: std::process::Child,
+    /// The lines the server logs, read from its standard error by a thread.
+    log_lines: Receiver<String>,
+    /// The lines received by `wait_for_log`, which are still part of the
+    /// log returned by `stop_and_read_log`.
+    log: String,
     // is set when output is collected by stop()
     output: Option<Result<(), String>>,
 }
 
+/// How long `wait_for_log` waits for a line before failing the test.
+const LOG_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl Server {
+    /// Servers run in the default strict port mode, tests which leave out the
+    /// port of URLs have to pass `--loose-ports` themselves.
     pub fn new(args: &[&str]) -> Self {
+        Self::with_env(args, &[])
+    }
+
+    /// Like `new`, but with additional environment variables.
+    pub fn with_env(args: &[&str], env: &[(&str, &str)]) -> Self {
         // start the server
         let mut server = Command::new(BINARY_PATH)
             .stderr(Stdio::piped())
             .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
             .args(args)
             .env("RUST_LOG", "debug")
+            .envs(env.iter().copied())
             .spawn()
             .expect("failed to start binary");
 
+        // the log is read until the server exits, so it can not block on a
+        // full pipe
+        let (sender, log_lines) = channel();
+        let mut reader = BufReader::new(server.stderr.take().unwrap());
+        std::thread::spawn(move || loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
         // We can be sure that agate is listening because it logs a message saying so.
-        let mut reader = BufReader::new(server.stderr.as_mut().unwrap());
-        let mut buffer = String::new();
-        while matches!(reader.read_line(&mut buffer), Ok(i) if i>0) {
-            print!("log: {}", buffer);
-            if buffer.contains("Listening") {
+        for line in log_lines.iter() {
+            print!("log: {}", line);
+            if line.contains("Listening") {
                 break;
             }
-
-            buffer.clear();
         }
 
         if matches!(server.try_wait(), Ok(Some(_)) | Err(_)) {
@@ -53,10 +83,45 @@ impl Server {
 
         Self {
             server,
+            log_lines,
+            log: String::new(),
             output: None,
         }
     }
 
+    /// Waits until the server logs a line containing `pattern`, e.g. the
+    /// access log line of a request, which is only logged after the
+    /// connection was closed, or the reaction to a signal.
+    pub fn wait_for_log(&mut self, pattern: &str) {
+        loop {
+            match self.log_lines.recv_timeout(LOG_TIMEOUT) {
+                Ok(line) => {
+                    self.log.push_str(&line);
+                    if line.contains(pattern) {
+                        return;
+                    }
+                }
+                Err(e) => panic!(
+                    "server did not log {:?} ({}), log so far:\n{}",
+                    pattern, e, self.log
+                ),
+            }
+        }
+    }
+
+    /// Kills the server, unless it already exited, and returns everything
+    /// it logged after it started listening.
+    pub fn stop_and_read_log(&mut self) -> String {
+        if let Ok(None) = self.server.try_wait() {
+            self.server.kill().unwrap();
+        }
+        let mut log = std::mem::take(&mut self.log);
+        // the thread ends at the end of the log
+        log.extend(self.log_lines.iter());
+        self.output = Some(Ok(()));
+        log
+    }
+
     pub fn stop(&mut self) -> Result<(), String> {
         // try to stop the server
         if let Some(output) = self.output.as_ref() {
@@ -68,25 +133,16 @@ impl Server {
             Ok(None) => {
                 // everything fine, still running as expected, kill it now
                 self.server.kill().unwrap();
-
-                let mut reader = BufReader::new(self.server.stderr.as_mut().unwrap());
-                let mut buffer = String::new();
-                while matches!(reader.read_line(&mut buffer), Ok(i) if i>0) {
-                    print!("log: {}", buffer);
-                    if buffer.contains("Listening") {
-                        break;
-                    }
+                for line in self.log_lines.iter() {
+                    print!("log: {}", line);
                 }
                 Ok(())
             }
             Ok(Some(_)) => {
-                let mut reader = BufReader::new(self.server.stderr.as_mut().unwrap());
-                let mut buffer = String::new();
-                while matches!(reader.read_line(&mut buffer), Ok(i) if i>0) {
-                    print!("log: {}", buffer);
-                    if buffer.contains("Listening") {
-                        break;
-                    }
+                let mut buffer = std::mem::take(&mut self.log);
+                for line in self.log_lines.iter() {
+                    print!("log: {}", line);
+                    buffer.push_str(&line);
                 }
                 Err(buffer)
             }
@@ -120,6 +176,96 @@ fn get(args: &[&str], addr: SocketAddr, url: &str) -> Result<Page, anyhow::Error
     server.stop().map_err(|e| anyhow!(e)).and(page)
 }
 
+/// Sends a request to a running server without checking its certificate and
+/// returns the response header without the CRLF.
+fn header(addr: SocketAddr, url: &str) -> String {
+    let mut header = String::new();
+    request(addr, url).read_line(&mut header).unwrap();
+    header.trim_end().to_string()
+}
+
+/// Sends a request to a running server without checking its certificate and
+/// returns the connection to read the response from.
+fn request(
+    addr: SocketAddr,
+    url: &str,
+) -> BufReader<rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream>> {
+    request_with_cert(addr, url, None)
+}
+
+/// Like `request`, but presents a client certificate if one is given as DER
+/// encoded certificate and private key.
+fn request_with_cert(
+    addr: SocketAddr,
+    url: &str,
+    client_cert: Option<&(Vec<u8>, Vec<u8>)>,
+) -> BufReader<rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream>> {
+    use std::io::Write;
+
+    let host = match Url::parse(url).unwrap().host() {
+        // IP addresses can not be sent via SNI
+        Some(url::Host::Domain(host)) if host.parse::<std::net::Ipv4Addr>().is_err() => {
+            host.to_string()
+        }
+        _ => "localhost".to_string(),
+    };
+    let mut tls = BufReader::new(connect(addr, &host, client_cert));
+
+    write!(tls.get_mut(), "{}\r\n", url).unwrap();
+    tls
+}
+
+/// Opens a TLS connection to a running server without checking its
+/// certificate, so the request can be written in parts.
+fn connect(
+    addr: SocketAddr,
+    host: &str,
+    client_cert: Option<&(Vec<u8>, Vec<u8>)>,
+) -> rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream> {
+    use rustls::{
+        Certificate, ClientSession, PrivateKey, RootCertStore, ServerCertVerified,
+        ServerCertVerifier, TLSError,
+    };
+
+    struct AnyCert;
+
+    impl ServerCertVerifier for AnyCert {
+        fn verify_server_cert(
+            &self,
+            _: &RootCertStore,
+            _: &[Certificate],
+            _: webpki::DNSNameRef<'_>,
+            _: &[u8],
+        ) -> Result<ServerCertVerified, TLSError> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    let mut config = rustls::ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(std::sync::Arc::new(AnyCert));
+    if let Some((cert, key)) = client_cert {
+        let cert = Certificate(cert.clone());
+        let key = PrivateKey(key.clone());
+        config.set_single_client_cert(vec![cert], key).unwrap();
+    }
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(host).unwrap();
+    let session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+    let tcp = std::net::TcpStream::connect(addr).unwrap();
+    rustls::StreamOwned::new(session, tcp)
+}
+
+/// Reads the rest of a response. The close_notify alert at the end is
+/// reported as an error by rustls.
+fn read_to_end(mut reader: impl Read) -> Vec<u8> {
+    let mut data = vec![];
+    match reader.read_to_end(&mut data) {
+        Err(e) if e.kind() != std::io::ErrorKind::ConnectionAborted => panic!("{}", e),
+        _ => data,
+    }
+}
+
 #[test]
 /// - serves index page for a directory
 /// - serves the correct content
@@ -150,7 +296,7 @@ fn index_page() {
 /// - symlinked files are followed correctly
 fn symlink_page() {
     let page = get(
-        &["--addr", "[::]:1986"],
+        &["--loose-ports", "--addr", "[::]:1986"],
         addr(1986),
         "gemini://localhost/symlink.gmi",
     )
@@ -180,7 +326,7 @@ fn symlink_page() {
 /// - symlinked directories are followed correctly
 fn symlink_directory() {
     let page = get(
-        &["--addr", "[::]:1987"],
+        &["--loose-ports", "--addr", "[::]:1987"],
         addr(1987),
         "gemini://localhost/symlinked_dir/file.gmi",
     )
@@ -211,7 +357,7 @@ fn symlink_directory() {
 /// - MIME media types can be set in the configuration file
 fn meta() {
     let page = get(
-        &["--addr", "[::]:1966"],
+        &["--loose-ports", "--addr", "[::]:1966"],
         addr(1966),
         "gemini://localhost/test",
     )
@@ -231,7 +377,7 @@ fn meta() {
 /// - MIME media type parameters can be set in the configuration file
 fn meta_param() {
     let page = get(
-        &["--addr", "[::]:1967"],
+        &["--loose-ports", "--addr", "[::]:1967"],
         addr(1967),
         "gemini://localhost/test.gmi",
     )
@@ -246,12 +392,77 @@ fn meta_param() {
     );
 }
 
+#[test]
+/// - invalid full header lines in the configuration file are ignored
+/// - valid lines in the same configuration file still work
+fn meta_invalid_header() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:1998"]);
+    let fetch = |path: &str| {
+        let url = Url::parse(&format!("gemini://localhost/bad_meta/{}", path)).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(1998), None))
+            .expect("could not get page")
+            .header
+    };
+
+    let gemini = Header {
+        status: Status::Success,
+        meta: "text/gemini".to_string(),
+    };
+    assert_eq!(fetch("typo.gmi"), gemini);
+    assert_eq!(fetch("long.gmi"), gemini);
+    assert_eq!(
+        fetch("ok.gmi"),
+        Header {
+            status: Status::Success,
+            meta: "text/plain".to_string(),
+        }
+    );
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - the default language is only added to text/gemini by default
+/// - with --lang-all-text, it is added to all text types
+/// - it is never added to binary types
+fn lang_text_types() {
+    let meta = |port: u16, args: &[&str]| {
+        let mut server = Server::new(
+            &[
+                &["--loose-ports", "--addr", &format!("[::]:{}", port)],
+                args,
+            ]
+            .concat(),
+        );
+        // only the headers are read, the body of the image is not UTF-8
+        let meta = ["page.gmi", "notes.txt", "image.png"]
+            .map(|file| header(addr(port), &format!("gemini://localhost/lang/{}", file)));
+        server.stop().unwrap();
+        meta
+    };
+
+    assert_eq!(
+        meta(2005, &["--lang", "en"]),
+        ["20 text/gemini;lang=en", "20 text/plain", "20 image/png"]
+    );
+    assert_eq!(
+        meta(2006, &["--lang", "en", "--lang-all-text"]),
+        [
+            "20 text/gemini;lang=en",
+            "20 text/plain;lang=en",
+            "20 image/png"
+        ]
+    );
+}
+
 #[test]
 /// - globs in the configuration file work correctly
 /// - distributed configuration file is used when `-C` flag not used
 fn glob() {
     let page = get(
-        &["--addr", "[::]:1968"],
+        &["--loose-ports", "--addr", "[::]:1968"],
         addr(1968),
         "gemini://localhost/testdir/a.nl.gmi",
     )
@@ -271,7 +482,7 @@ fn glob() {
 /// - central configuration file is used when `-C` flag is used
 fn doubleglob() {
     let page = get(
-        &["--addr", "[::]:1969", "-C"],
+        &["--loose-ports", "--addr", "[::]:1969", "-C"],
         addr(1969),
         "gemini://localhost/testdir/a.nl.gmi",
     )
@@ -290,7 +501,7 @@ fn doubleglob() {
 /// - full header lines can be set in the configuration file
 fn full_header_preset() {
     let page = get(
-        &["--addr", "[::]:1970"],
+        &["--loose-ports", "--addr", "[::]:1970"],
         addr(1970),
         "gemini://localhost/gone.txt",
     )
@@ -305,6 +516,114 @@ fn full_header_preset() {
     );
 }
 
+#[test]
+/// - files are gone after their expiry date
+fn expired() {
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:1989"],
+        addr(1989),
+        "gemini://localhost/schedule/expired.gmi",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Gone);
+}
+
+#[test]
+/// - files are not found before their publication date
+fn embargoed() {
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:1990"],
+        addr(1990),
+        "gemini://localhost/schedule/embargoed.gmi",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::NotFound);
+}
+
+#[test]
+/// - files are served within their publication window
+/// - directory listings omit expired and embargoed files
+fn schedule_listing() {
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:1991"],
+        addr(1991),
+        "gemini://localhost/schedule/current.gmi",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Success);
+
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:1991"],
+        addr(1991),
+        "gemini://localhost/schedule/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Success);
+    assert_eq!(page.body, Some("=> current.gmi\n".to_string()));
+}
+
+#[test]
+/// - requests with up to the maximum number of path segments are served
+/// - requests with more path segments or a too long segment are rejected
+fn path_limits() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2010"]);
+    let fetch = |path: &str| {
+        let url = Url::parse(&format!("gemini://localhost{}", path)).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2010), None))
+            .expect("could not get page")
+            .header
+            .status
+    };
+
+    // the trailing slash does not count as a segment
+    assert_eq!(fetch(&"/a".repeat(32)), Status::NotFound);
+    assert_eq!(fetch(&format!("{}/", "/a".repeat(32))), Status::NotFound);
+    assert_eq!(fetch(&"/a".repeat(33)), Status::BadRequest);
+    assert_eq!(fetch(&format!("/{}", "a".repeat(255))), Status::NotFound);
+    assert_eq!(fetch(&format!("/{}", "a".repeat(256))), Status::BadRequest);
+    // the length of the decoded segment counts
+    assert_eq!(fetch(&format!("/{}", "%61".repeat(255))), Status::NotFound);
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - a schedule removed from a changed `.meta` file does not apply anymore
+fn schedule_removed() {
+    let content = std::env::temp_dir().join("agate-test-schedule-removed-2113");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join("event.gmi"), "# Event\n").unwrap();
+    std::fs::write(
+        content.join(".meta"),
+        "event.gmi: expires 2000-01-01T00:00:00Z\n",
+    )
+    .unwrap();
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2113",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+    assert!(header(addr(2113), "gemini://localhost/event.gmi").starts_with("52 "));
+
+    // make sure the modification time is after the file was read
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    std::fs::write(content.join(".meta"), "event.gmi: ;lang=en\n").unwrap();
+    assert_eq!(
+        header(addr(2113), "gemini://localhost/event.gmi"),
+        "20 text/gemini;lang=en"
+    );
+}
+
 #[test]
 /// - URLS with fragments are rejected
 fn fragment() {
@@ -316,6 +635,23 @@ fn fragment() {
     .expect("could not get page");
 
     assert_eq!(page.header.status, Status::BadRequest);
+    assert_eq!(page.header.meta, "URL must not contain a fragment");
+}
+
+#[test]
+/// - fragments are removed from requests with --strip-fragments
+/// - userinfo is still refused
+fn strip_fragments() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2044", "--strip-fragments"]);
+
+    assert_eq!(
+        header(addr(2044), "gemini://localhost/#fragment"),
+        "20 text/gemini"
+    );
+    assert_eq!(
+        header(addr(2044), "gemini://user@localhost/#fragment"),
+        "59 URL must not contain userinfo"
+    );
 }
 
 #[test]
@@ -329,6 +665,7 @@ fn username() {
     .expect("could not get page");
 
     assert_eq!(page.header.status, Status::BadRequest);
+    assert_eq!(page.header.meta, "URL must not contain userinfo");
 }
 
 #[test]
@@ -376,7 +713,7 @@ fn port_check() {
 /// - status for paths with hidden segments is "gone" if file does not exist
 fn secret_nonexistent() {
     let page = get(
-        &["--addr", "[::]:1973"],
+        &["--loose-ports", "--addr", "[::]:1973"],
         addr(1973),
         "gemini://localhost/.secret",
     )
@@ -389,7 +726,7 @@ fn secret_nonexistent() {
 /// - status for paths with hidden segments is "gone" if file exists
 fn secret_exists() {
     let page = get(
-        &["--addr", "[::]:1974"],
+        &["--loose-ports", "--addr", "[::]:1974"],
         addr(1974),
         "gemini://localhost/.meta",
     )
@@ -402,9 +739,9 @@ fn secret_exists() {
 /// - secret file served if `--serve-secret` is enabled
 fn serve_secret() {
     let page = get(
-        &["--addr", "[::]:1975", "--serve-secret"],
+        &["--loose-ports", "--addr", "[::]:1975", "--serve-secret"],
         addr(1975),
-        "gemini://localhost/.meta",
+        "gemini://localhost/searchable/.hidden.gmi",
     )
     .expect("could not get page");
 
@@ -421,7 +758,7 @@ fn directory_traversal_regression() {
     absolute
         .path_segments_mut()
         .unwrap()
-        .push(&env!("CARGO_MANIFEST_DIR")) // separators will be percent-encoded
+        .push(env!("CARGO_MANIFEST_DIR")) // separators will be percent-encoded
         .push("tests")
         .push("data")
         .push("directory_traversal.gmi");
@@ -439,8 +776,12 @@ fn directory_traversal_regression() {
 
     let urls = [absolute, relative];
     for url in urls.iter() {
-        let page =
-            get(&["--addr", "[::]:1988"], addr(1988), url.as_str()).expect("could not get page");
+        let page = get(
+            &["--loose-ports", "--addr", "[::]:1988"],
+            addr(1988),
+            url.as_str(),
+        )
+        .expect("could not get page");
         assert_eq!(page.header.status, Status::NotFound);
     }
 }
@@ -486,6 +827,7 @@ mod vhosts {
     fn example_com() {
         let page = get(
             &[
+                "--loose-ports",
                 "--addr",
                 "[::]:1977",
                 "--hostname",
@@ -517,6 +859,7 @@ mod vhosts {
     fn example_org() {
         let page = get(
             &[
+                "--loose-ports",
                 "--addr",
                 "[::]:1978",
                 "--hostname",
@@ -542,92 +885,4480 @@ mod vhosts {
             )
         );
     }
-}
-
-mod multicert {
-    use super::*;
 
     #[test]
-    #[should_panic]
-    fn cert_missing() {
-        let mut server = Server::new(&["--addr", "[::]:1979", "--certs", "cert_missing"]);
+    /// - with --shared-content, all host names serve the content root
+    /// - a certificate is still generated for each host name
+    /// - other host names are still refused
+    fn shared_content() {
+        let certs = std::env::temp_dir().join("agate-test-shared-content");
+        let _ = std::fs::remove_dir_all(&certs);
+        let _server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2090",
+            "--certs",
+            certs.to_str().unwrap(),
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "example.org",
+            "--shared-content",
+        ]);
 
-        // wait for the server to stop, it should crash
-        let _ = server.server.wait();
+        let index = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/content/index.gmi"
+        ))
+        .unwrap();
+        for host in ["example.com", "example.org"] {
+            let response = read_to_end(request(addr(2090), &format!("gemini://{}/", host)));
+            assert_eq!(response[..16], b"20 text/gemini\r\n"[..], "{}", host);
+            assert_eq!(response[16..], index[..], "{}", host);
+            assert!(certs.join(host).join("cert.der").is_file(), "{}", host);
+        }
+
+        // there is no certificate for the other host name
+        let mut tls = connect(addr(2090), "example.com", None);
+        std::io::Write::write_all(&mut tls, b"gemini://example.net/\r\n").unwrap();
+        assert!(read_to_end(tls).starts_with(b"53 "));
     }
 
-    #[test]
-    #[should_panic]
-    fn key_missing() {
-        let mut server = Server::new(&["--addr", "[::]:1980", "--certs", "key_missing"]);
+    /// Creates a content directory with a directory without an index file, one
+    /// with a `.directory-listing-ok` file and hidden files, one of which has
+    /// an entry in the `.meta` file.
+    fn host_options_content(name: &str) -> std::path::PathBuf {
+        let content = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&content);
+        std::fs::create_dir_all(content.join("dir")).unwrap();
+        std::fs::create_dir_all(content.join("marked")).unwrap();
+        std::fs::write(content.join("dir/a.gmi"), "# A\n").unwrap();
+        std::fs::write(content.join("marked/.directory-listing-ok"), "").unwrap();
+        std::fs::write(content.join(".secret.txt"), "secret\n").unwrap();
+        std::fs::write(content.join(".allowed.txt"), "allowed\n").unwrap();
+        std::fs::write(content.join(".meta"), ".allowed.txt: text/plain\n").unwrap();
+        content
+    }
 
-        // wait for the server to stop, it should crash
-        let _ = server.server.wait();
+    /// Starts a server for example.com and example.org with the given
+    /// additional arguments and returns the status of each of the requests.
+    fn host_options_statuses(port: u16, args: &[&str], requests: &[&str]) -> Vec<String> {
+        let content = host_options_content(&format!("agate-test-host-options-{}", port));
+        let certs = std::env::temp_dir().join(format!("agate-test-host-options-certs-{}", port));
+        let port_arg = format!("[::]:{}", port);
+        let mut all_args = vec![
+            "--loose-ports",
+            "--addr",
+            &port_arg,
+            "--content",
+            content.to_str().unwrap(),
+            "--certs",
+            certs.to_str().unwrap(),
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "example.org",
+            "--shared-content",
+        ];
+        all_args.extend_from_slice(args);
+        let _server = Server::new(&all_args);
+        requests
+            .iter()
+            .map(|url| header(addr(port), url)[..2].to_string())
+            .collect()
     }
 
     #[test]
-    fn example_com() {
-        use rustls::{Certificate, ClientSession};
-        use std::io::Write;
-        use std::net::TcpStream;
+    /// - `--host-option` enables listing directories and serving hidden files
+    ///   for one host name, while the global defaults apply to the others
+    /// - a `.directory-listing-ok` file and a `.meta` entry still apply
+    fn host_options_enable() {
+        let statuses = host_options_statuses(
+            2099,
+            &["--host-option", "example.com=serve-secret,list-directories"],
+            &[
+                "gemini://example.com/dir/",
+                "gemini://example.com/.secret.txt",
+                "gemini://example.org/dir/",
+                "gemini://example.org/.secret.txt",
+                "gemini://example.org/marked/",
+                "gemini://example.org/.allowed.txt",
+            ],
+        );
+        assert_eq!(statuses, ["20", "20", "51", "52", "20", "20"]);
+    }
 
-        let mut server = Server::new(&["--addr", "[::]:1981", "--certs", "multicert"]);
+    #[test]
+    /// - `--host-option` disables listing directories and serving hidden
+    ///   files for one host name, even if they are enabled globally
+    /// - a `.directory-listing-ok` file and a `.meta` entry take precedence
+    ///   over the host option
+    fn host_options_disable() {
+        let statuses = host_options_statuses(
+            2100,
+            &[
+                "--serve-secret",
+                "--list-directories",
+                "--host-option",
+                "example.org=no-serve-secret",
+                "--host-option",
+                "example.org=no-list-directories",
+            ],
+            &[
+                "gemini://example.com/dir/",
+                "gemini://example.com/.secret.txt",
+                "gemini://example.org/dir/",
+                "gemini://example.org/.secret.txt",
+                "gemini://example.org/marked/",
+                "gemini://example.org/.allowed.txt",
+            ],
+        );
+        assert_eq!(statuses, ["20", "20", "51", "52", "20", "20"]);
+    }
+}
 
-        let mut config = rustls::ClientConfig::new();
-        config
-            .root_store
-            .add(&Certificate(
-                include_bytes!(concat!(
-                    env!("CARGO_MANIFEST_DIR"),
-                    "/tests/data/multicert/example.com/cert.der"
-                ))
-                .to_vec(),
-            ))
-            .unwrap();
+#[test]
+/// - connections to the mirrored capsule are made from `--outbound-addr`
+/// - only one address per address family is accepted
+fn outbound_addr() {
+    let content = std::env::temp_dir().join("agate-test-outbound-addr");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
 
-        let dns_name = webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap();
-        let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
-        let mut tcp = TcpStream::connect(addr(1981)).unwrap();
-        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+    let mut upstream = Server::new(&["--addr", "[::]:2101", "--log-ip"]);
+    let mirror_args = [
+        "--loose-ports",
+        "--addr",
+        "[::]:2102",
+        "--content",
+        content.to_str().unwrap(),
+        "--mirror",
+        "gemini://localhost:2101",
+        // fingerprint of tests/data/.certificates/cert.der
+        "--mirror-fingerprint",
+        "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+        "--outbound-addr",
+        "127.0.0.2",
+    ];
+    let _mirror = Server::new(&mirror_args);
+    assert!(header(addr(2102), "gemini://localhost/test.gmi").starts_with("20 "));
 
-        write!(tls, "gemini://example.com/\r\n").unwrap();
+    upstream.wait_for_log("\"gemini://localhost:2101/test.gmi\" 20 ");
+    let log = upstream.stop_and_read_log();
+    assert!(
+        log.contains("127.0.0.2 \"gemini://localhost:2101/test.gmi\" 20 "),
+        "{}",
+        log
+    );
 
-        let mut buf = [0; 10];
-        let _ = tls.read(&mut buf);
+    let status = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args([
+            "--addr",
+            "[::]:2103",
+            "--outbound-addr",
+            "127.0.0.2",
+            "--outbound-addr",
+            "127.0.0.3",
+        ])
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to start binary");
+    assert_eq!(status.code(), Some(2));
+}
 
-        server.stop().unwrap();
+#[test]
+/// - directories with more than `--listing-max-entries` entries are not listed
+/// - the refused directory is named in the log
+/// - smaller directories are still listed
+fn listing_max_entries() {
+    let content = std::env::temp_dir().join("agate-test-listing-max-entries-2104");
+    let _ = std::fs::remove_dir_all(&content);
+    for dir in ["small", "large"] {
+        std::fs::create_dir_all(content.join(dir)).unwrap();
+        std::fs::write(content.join(dir).join(".directory-listing-ok"), "").unwrap();
+    }
+    for name in ["a.gmi", "b.gmi", "c.gmi"] {
+        std::fs::write(content.join("large").join(name), "").unwrap();
     }
 
-    #[test]
-    fn example_org() {
-        use rustls::{Certificate, ClientSession};
-        use std::io::Write;
-        use std::net::TcpStream;
+    let mut server = Server::with_env(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:2104",
+            "--content",
+            content.to_str().unwrap(),
+            "--listing-max-entries",
+            "3",
+        ],
+        &[("RUST_LOG", "agate=info")],
+    );
+    assert_eq!(
+        header(addr(2104), "gemini://localhost/large/"),
+        "40 Directory too large to list"
+    );
+    assert!(header(addr(2104), "gemini://localhost/small/").starts_with("20 "));
 
-        let mut server = Server::new(&["--addr", "[::]:1982", "--certs", "multicert"]);
+    server.wait_for_log("\"gemini://localhost/small/\" 20 ");
+    let log = server.stop_and_read_log();
+    assert!(
+        log.contains("Not listing directory")
+            && log.contains("large\", it has more than 3 entries"),
+        "{}",
+        log
+    );
+}
 
-        let mut config = rustls::ClientConfig::new();
-        config
-            .root_store
-            .add(&Certificate(
-                include_bytes!(concat!(
-                    env!("CARGO_MANIFEST_DIR"),
-                    "/tests/data/multicert/example.org/cert.der"
-                ))
-                .to_vec(),
-            ))
-            .unwrap();
+#[test]
+/// - a URL without a port is refused on a listener that is not on port 1965
+/// - a URL without a port is accepted from the offload listener if the public
+///   port is 1965, and refused for another public port
+/// - an explicit `--public-port` applies to the TLS listeners too
+/// - with `--loose-ports`, a URL without a port is accepted on any port
+fn default_port() {
+    use std::io::Write;
 
-        let dns_name = webpki::DNSNameRef::try_from_ascii_str("example.org").unwrap();
-        let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
-        let mut tcp = TcpStream::connect(addr(1982)).unwrap();
-        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+    let plaintext = |port: u16, url: &str| {
+        let mut tcp = std::net::TcpStream::connect(addr(port)).unwrap();
+        write!(tcp, "{}\r\n", url).unwrap();
+        let mut response = String::new();
+        BufReader::new(tcp).read_line(&mut response).unwrap();
+        response.trim_end().to_string()
+    };
 
-        write!(tls, "gemini://example.org/\r\n").unwrap();
+    let _server = Server::new(&[
+        "--addr",
+        "[::]:2107",
+        "--insecure-listener",
+        "127.0.0.1:2108",
+    ]);
+    assert_eq!(
+        header(addr(2107), "gemini://localhost/"),
+        "53 proxy request refused, a URL without a port is for port 1965"
+    );
+    assert_eq!(
+        header(addr(2107), "gemini://localhost:2107/"),
+        "20 text/gemini"
+    );
+    assert_eq!(
+        header(addr(2107), "gemini://localhost:1965/"),
+        "53 proxy request refused"
+    );
+    assert_eq!(plaintext(2108, "gemini://localhost/"), "20 text/gemini");
+    assert_eq!(
+        plaintext(2108, "gemini://localhost:1965/"),
+        "20 text/gemini"
+    );
 
-        let mut buf = [0; 10];
-        let _ = tls.read(&mut buf);
+    let _server = Server::new(&[
+        "--addr",
+        "[::]:2109",
+        "--insecure-listener",
+        "127.0.0.1:2110",
+        "--public-port",
+        "11965",
+    ]);
+    assert!(plaintext(2110, "gemini://localhost/").starts_with("53 "));
+    assert_eq!(
+        plaintext(2110, "gemini://localhost:11965/"),
+        "20 text/gemini"
+    );
+    assert_eq!(
+        header(addr(2109), "gemini://localhost:11965/"),
+        "20 text/gemini"
+    );
+    assert_eq!(
+        header(addr(2109), "gemini://localhost:2109/"),
+        "53 proxy request refused"
+    );
 
-        server.stop().unwrap();
-    }
+    let _server = Server::new(&["--addr", "[::]:2120", "--public-port", "1965"]);
+    assert_eq!(header(addr(2120), "gemini://localhost/"), "20 text/gemini");
+    assert_eq!(
+        header(addr(2120), "gemini://localhost:1965/"),
+        "20 text/gemini"
+    );
+    assert_eq!(
+        header(addr(2120), "gemini://localhost:2120/"),
+        "53 proxy request refused"
+    );
+
+    let _server = Server::new(&["--addr", "[::]:2111", "--loose-ports"]);
+    assert_eq!(header(addr(2111), "gemini://localhost/"), "20 text/gemini");
+    assert_eq!(
+        header(addr(2111), "gemini://localhost:2112/"),
+        "53 proxy request refused"
+    );
+}
+
+#[test]
+/// - files missing locally are fetched from the mirrored capsule and saved
+/// - other responses of the mirrored capsule are relayed but not saved
+fn mirror() {
+    let content = std::env::temp_dir().join("agate-test-mirror");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+
+    let _upstream = Server::new(&["--addr", "[::]:1992"]);
+    let page = get(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:1993",
+            "--content",
+            content.to_str().unwrap(),
+            "--mirror",
+            "gemini://localhost:1992",
+            // fingerprint of tests/data/.certificates/cert.der
+            "--mirror-fingerprint",
+            "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+        ],
+        addr(1993),
+        "gemini://localhost/test.gmi",
+    )
+    .expect("could not get page");
+
+    assert_eq!(
+        page.header,
+        Header {
+            status: Status::Success,
+            meta: "text/gemini;lang=en ;charset=us-ascii".to_string(),
+        }
+    );
+    let original = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/content/test.gmi"
+    ))
+    .unwrap();
+    assert_eq!(page.body, Some(original.clone()));
+    assert_eq!(
+        std::fs::read_to_string(content.join("test.gmi")).unwrap(),
+        original
+    );
+
+    let page = get(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:1993",
+            "--content",
+            content.to_str().unwrap(),
+            "--mirror",
+            "gemini://localhost:1992",
+            "--mirror-fingerprint",
+            "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+        ],
+        addr(1993),
+        "gemini://localhost/gone.txt",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Gone);
+    assert!(!content.join("gone.txt").exists());
+}
+
+#[test]
+/// - requests with a query are relayed from the mirrored capsule for each query
+/// - responses to queries are not saved
+fn mirror_query() {
+    let content = std::env::temp_dir().join("agate-test-mirror-query");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+
+    let _upstream = Server::new(&["--addr", "[::]:2118", "--search", "/search"]);
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2119",
+        "--content",
+        content.to_str().unwrap(),
+        "--mirror",
+        "gemini://localhost:2118",
+        "--mirror-fingerprint",
+        "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+    ]);
+    let fetch = |query: &str| {
+        let url = Url::parse(&format!("gemini://localhost/search{}", query)).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2119), None))
+            .expect("could not get page")
+    };
+
+    assert_eq!(
+        fetch("?zebrafish%20zebra").body.as_deref(),
+        Some("# Search results for zebrafish zebra\n\n=> /searchable/zebra.gmi Zebras\n")
+    );
+    assert_eq!(
+        fetch("?quagga").body.as_deref(),
+        Some("# Search results for quagga\n\nNo matching pages found.\n")
+    );
+    assert!(!content.join("search").exists());
+    assert_eq!(fetch("").header.status, Status::Input);
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - requests waiting for a response that was relayed but not saved are told
+///   to retry instead of fetching it again one after another
+fn mirror_not_saved() {
+    let upstream_content = std::env::temp_dir().join("agate-test-mirror-not-saved-upstream");
+    let content = std::env::temp_dir().join("agate-test-mirror-not-saved");
+    for dir in [&upstream_content, &content] {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+    }
+    // large enough not to fit into the buffers of the connections
+    std::fs::write(upstream_content.join("large.txt"), vec![b'x'; 16 << 20]).unwrap();
+
+    let mut upstream = Server::new(&[
+        "--addr",
+        "[::]:2121",
+        "--content",
+        upstream_content.to_str().unwrap(),
+    ]);
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2122",
+        "--content",
+        content.to_str().unwrap(),
+        "--mirror",
+        "gemini://localhost:2121",
+        "--mirror-fingerprint",
+        "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+    ]);
+
+    // responses to queries are never saved
+    let url = "gemini://localhost/large.txt?query";
+    let mut first = request(addr(2122), url);
+    let mut line = String::new();
+    first.read_line(&mut line).unwrap();
+    assert_eq!(line, "20 text/plain\r\n");
+
+    let waiting = std::thread::spawn(move || header(addr(2122), url));
+    server.wait_for_log("waiting for another request fetching");
+    assert_eq!(read_to_end(first).len(), 16 << 20);
+    assert_eq!(waiting.join().unwrap(), "44 2");
+
+    upstream.wait_for_log("\"gemini://localhost:2121/large.txt?query\" 20 ");
+    let log = upstream.stop_and_read_log();
+    let received = "received request \"gemini://localhost:2121/large.txt?query\"";
+    assert_eq!(log.matches(received).count(), 1, "{}", log);
+    server.stop().unwrap();
+}
+
+#[test]
+/// - a client certificate for the mirrored capsule is loaded at startup
+/// - a broken one is refused at startup
+fn mirror_identity() {
+    let output = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args([
+            "--addr",
+            "[::]:2028",
+            "--mirror",
+            "gemini://localhost:2029",
+            "--mirror-fingerprint",
+            "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+            "--mirror-identity",
+            "key_missing",
+        ])
+        .output()
+        .expect("failed to start binary");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Could not load --mirror-identity: The key file for key_missing is missing."));
+
+    let content = std::env::temp_dir().join("agate-test-mirror-identity");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+
+    let _upstream = Server::new(&["--addr", "[::]:2029"]);
+    let page = get(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:2028",
+            "--content",
+            content.to_str().unwrap(),
+            "--mirror",
+            "gemini://localhost:2029",
+            "--mirror-fingerprint",
+            "fa60e5f97f48fbf66ed8cf170e08bed3fd02f91a058b018c0c3545a5b197a16a",
+            "--mirror-identity",
+            ".certificates",
+        ],
+        addr(2028),
+        "gemini://localhost/test.gmi",
+    )
+    .expect("could not get page");
+    assert_eq!(page.header.status, Status::Success);
+}
+
+#[test]
+#[cfg(unix)]
+/// - file names cannot inject lines into directory listings
+fn directory_listing_injection() {
+    let content = std::env::temp_dir().join("agate-test-listing");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    for name in [
+        ".directory-listing-ok",
+        // file names cannot contain slashes, but a scheme is enough for a link
+        "foo\n=> gemini:evil.example PayPal login",
+        "tab\there",
+        "# heading",
+        "=> link",
+    ] {
+        std::fs::write(content.join(name), "").unwrap();
+    }
+
+    let page = get(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:1999",
+            "--content",
+            content.to_str().unwrap(),
+        ],
+        addr(1999),
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Success);
+    let body = page.body.unwrap();
+    assert_eq!(body.lines().count(), 4);
+    for line in body.lines() {
+        assert!(line.starts_with("=> "), "{:?}", line);
+    }
+    assert!(body.contains("=> foo%0A=%3E%20gemini:evil.example%20PayPal%20login foo\u{FFFD}=> "));
+    assert!(body.contains("=> %23%20heading heading\n"));
+}
+
+#[test]
+#[cfg(unix)]
+/// - files with non-UTF-8 names are listed with a percent-encoded URL
+/// - such files can be requested with that URL
+fn non_unicode_file_name() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let content = std::env::temp_dir().join("agate-test-non-unicode");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join(".directory-listing-ok"), "").unwrap();
+    std::fs::write(
+        content.join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt")),
+        "hello",
+    )
+    .unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2000",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+    let fetch = |url: &str| {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(
+                &Url::parse(url).unwrap(),
+                addr(2000),
+                None,
+            ))
+            .expect("could not get page")
+    };
+
+    let listing = fetch("gemini://localhost/");
+    assert_eq!(
+        listing.body.as_deref(),
+        Some("=> caf%E9.txt caf\u{FFFD}.txt\n")
+    );
+
+    let page = fetch("gemini://localhost/caf%E9.txt");
+    assert_eq!(page.header.status, Status::Success);
+    assert_eq!(page.body.as_deref(), Some("hello"));
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - entries with a description in .descriptions use it as the link label
+/// - malformed lines and the sidecar file itself do not show up
+fn directory_listing_descriptions() {
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:2026"],
+        addr(2026),
+        "gemini://localhost/described/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Success);
+    assert_eq!(
+        page.body.as_deref(),
+        Some("=> plain.txt\n=> report.pdf Quarterly report, March 2024\n=> sub/ Subdirectory\n")
+    );
+}
+
+#[test]
+/// - the compressed companion of a file is served for the configured query
+/// - the plain file is served if there is no companion
+/// - directory listings point out files with a companion
+fn precompressed() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2036", "--precompressed"]);
+
+    assert_eq!(
+        header(addr(2036), "gemini://localhost/precompressed/data.txt?gz"),
+        "20 application/gzip"
+    );
+    assert_eq!(
+        header(addr(2036), "gemini://localhost/precompressed/data.txt.gz"),
+        "20 application/gzip"
+    );
+    assert_eq!(
+        header(addr(2036), "gemini://localhost/precompressed/data.txt"),
+        "20 text/plain"
+    );
+    assert_eq!(
+        header(addr(2036), "gemini://localhost/precompressed/other.txt?gz"),
+        "20 text/plain"
+    );
+
+    let url = Url::parse("gemini://localhost/precompressed/").unwrap();
+    let page = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(Page::fetch_from(&url, addr(2036), None))
+        .expect("could not get page");
+    assert_eq!(
+        page.body.as_deref(),
+        Some("=> data.txt data.txt (also gzip compressed)\n=> data.txt.gz\n=> other.txt\n")
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// - symlinks to directories are listed as directories
+/// - dangling symlinks are not listed
+fn directory_listing_symlinks() {
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:2001"],
+        addr(2001),
+        "gemini://localhost/symlinks/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Success);
+    assert_eq!(page.body.as_deref(), Some("=> dir/\n=> file.gmi\n"));
+}
+
+#[test]
+/// - requests over the rate limit are answered with status 44
+/// - the rule with the longest matching path applies
+/// - rules apply to the decoded path, without empty segments
+fn rate_limit() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2002",
+        "--rate-limit",
+        "2/1h",
+        "--rate-limit",
+        "/test.gmi=1/1h",
+    ]);
+
+    assert!(header(addr(2002), "gemini://localhost/test.gmi").starts_with("20 "));
+    assert_eq!(header(addr(2002), "gemini://localhost/test.gmi"), "44 3600");
+    assert_eq!(
+        header(addr(2002), "gemini://localhost/t%65st.gmi"),
+        "44 3600"
+    );
+    assert_eq!(
+        header(addr(2002), "gemini://localhost//test.gmi"),
+        "44 3600"
+    );
+    assert!(header(addr(2002), "gemini://localhost/").starts_with("20 "));
+    assert!(header(addr(2002), "gemini://localhost/").starts_with("20 "));
+    assert_eq!(header(addr(2002), "gemini://localhost/"), "44 3600");
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - when the table of rate limit windows is full, the oldest one is forgotten
+/// - windows of limited clients are kept in its place
+fn rate_limit_full() {
+    let mut server = Server::with_env(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:2117",
+            "--rate-limit",
+            "/a=2/1h",
+            "--rate-limit",
+            "/b=2/1h",
+            "--rate-limit",
+            "/c=2/1h",
+        ],
+        &[("AGATE_TEST_MAX_TRACKED", "2")],
+    );
+    let limited =
+        |path: &str| header(addr(2117), &format!("gemini://localhost{}", path)) == "44 3600";
+
+    assert!(!limited("/a"));
+    assert!(!limited("/a"));
+    assert!(limited("/a"));
+    // fills the table
+    assert!(!limited("/b"));
+    // forgets /b, but not the limited /a
+    assert!(!limited("/c"));
+    assert!(limited("/a"));
+    // forgets /c, after which this is again the first request for /b
+    assert!(!limited("/b"));
+    assert!(!limited("/b"));
+    assert!(limited("/a"));
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - with --redact-queries, queries are not logged, also not in the URLs of
+///   redirects or in debug messages
+fn redact_queries() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2003",
+        "--redact-queries",
+        "--byte-ranges",
+    ]);
+
+    assert!(header(addr(2003), "gemini://localhost/test.gmi?secret").starts_with("20 "));
+    server.wait_for_log("\" 20 ");
+    // malformed requests are redacted too
+    assert!(header(addr(2003), "gemini://localhost/test.gmi#?secret").starts_with("59 "));
+
+    server.wait_for_log("\" 59 ");
+    // so is the query in the URL of a redirect
+    assert_eq!(
+        header(addr(2003), "gemini://localhost/testdir?secret"),
+        "31 gemini://localhost/testdir/?secret"
+    );
+    server.wait_for_log("\" 31 ");
+    // and in an invalid byte range
+    assert!(header(addr(2003), "gemini://localhost/test.gmi?bytes=secret").starts_with("59 "));
+    server.wait_for_log("\" 59 ");
+    let log = server.stop_and_read_log();
+
+    assert!(log.contains("\"gemini://localhost/test.gmi?\u{2026}\" 20"));
+    assert!(
+        log.contains(
+            "\"gemini://localhost/testdir?\u{2026}\" 31 \"gemini://localhost/testdir/?\u{2026}\""
+        ),
+        "{}",
+        log
+    );
+    assert!(log.contains("invalid byte range \"\u{2026}\""), "{}", log);
+    assert!(!log.contains("secret"), "{}", log);
+}
+
+#[test]
+/// - with --sniff-mime, the MIME type of files without extension is guessed
+///   from their content
+/// - the sniffed bytes are still sent
+/// - an entry in the configuration file takes precedence
+fn sniff_mime() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2004", "--sniff-mime"]);
+    // only the headers are read, the bodies of the binary files are not UTF-8
+    let meta = |path: &str| header(addr(2004), &format!("gemini://localhost/sniff/{}", path));
+
+    assert_eq!(meta("image"), "20 image/png");
+    assert_eq!(meta("binary"), "20 application/octet-stream");
+    assert_eq!(meta("explicit"), "20 text/x-custom");
+    assert_eq!(
+        read_to_end(request(addr(2004), "gemini://localhost/sniff/notes")),
+        b"20 text/plain\r\nJust some notes.\n"
+    );
+
+    server.stop().unwrap();
+
+    let page = get(
+        &["--loose-ports", "--addr", "[::]:2004"],
+        addr(2004),
+        "gemini://localhost/sniff/notes",
+    )
+    .expect("could not get page");
+    assert_eq!(page.header.meta, "application/octet-stream");
+}
+
+#[test]
+/// - host names are read from the hostnames file
+/// - invalid lines are reported as an argument error
+fn hostnames_file() {
+    let dir = std::env::temp_dir().join("agate-test-hostnames-file");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let file = dir.join("hostnames");
+
+    std::fs::write(&file, "example.com\nnot a host name\n").unwrap();
+    let status = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args([
+            "--addr",
+            "[::]:2007",
+            "--certs",
+            "multicert",
+            "--hostnames-file",
+        ])
+        .arg(&file)
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to start binary");
+    assert_eq!(status.code(), Some(2));
+
+    std::fs::write(
+        &file,
+        "# vhosts\nexample.com\n\nexample.org # second vhost\n",
+    )
+    .unwrap();
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2007",
+        "--certs",
+        "multicert",
+        "--hostnames-file",
+        file.to_str().unwrap(),
+    ]);
+
+    // both host names are used as virtual hosts
+    assert!(header(addr(2007), "gemini://example.com/").starts_with("20 "));
+    assert!(header(addr(2007), "gemini://example.org/").starts_with("20 "));
+
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+/// - host names added to the hostnames file are used after SIGHUP
+/// - certificates are generated for them
+fn hostnames_file_reload() {
+    let dir = std::env::temp_dir().join("agate-test-hostnames-reload");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let file = dir.join("hostnames");
+    let certs = dir.join("certs");
+    std::fs::write(&file, "example.com\n").unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2008",
+        "--certs",
+        certs.to_str().unwrap(),
+        "--hostnames-file",
+        file.to_str().unwrap(),
+    ]);
+    assert!(certs.join("example.com/cert.der").exists());
+    assert!(!certs.join("example.org").exists());
+
+    std::fs::write(&file, "example.com\nexample.org\n").unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("Reloaded host names and certificates.");
+
+    assert!(certs.join("example.org/cert.der").exists());
+    assert!(header(addr(2008), "gemini://example.org/").starts_with("20 "));
+
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+/// - generated keys can only be read by the owner
+/// - keys readable by others are warned about
+fn key_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let certs = std::env::temp_dir().join("agate-test-key-permissions");
+    let _ = std::fs::remove_dir_all(&certs);
+    let args = [
+        "--addr",
+        "[::]:2019",
+        "--certs",
+        certs.to_str().unwrap(),
+        "--hostname",
+        "example.com",
+    ];
+    let mode = |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let mut server = Server::new(&args);
+    server.stop().unwrap();
+    assert_eq!(mode(certs.join("example.com")), 0o700);
+    assert_eq!(mode(certs.join("example.com/key.der")), 0o600);
+
+    let key = certs.join("example.com/key.der");
+    std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+    // the certificates are loaded before the address is bound, so this fails
+    // after the warning was logged
+    let _server = Server::new(&args);
+    let output = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(args)
+        .output()
+        .expect("failed to start binary");
+    assert_eq!(output.status.code(), Some(4));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(log.contains("is readable by other users"), "{}", log);
+}
+
+#[test]
+/// - the health check succeeds for a 2x response
+/// - the health check fails for other responses and unreachable servers
+fn ping() {
+    let ping = |args: &[&str]| {
+        Command::new(BINARY_PATH)
+            .arg("ping")
+            .args(args)
+            .status()
+            .expect("failed to start binary")
+            .code()
+    };
+
+    let mut server = Server::new(&["--addr", "[::]:1994"]);
+
+    assert_eq!(ping(&["--addr", "127.0.0.1:1994"]), Some(0));
+    assert_eq!(
+        ping(&["--addr", "127.0.0.1:1994", "--path", "/gone.txt"]),
+        Some(1)
+    );
+
+    server.stop().unwrap();
+
+    assert_eq!(
+        ping(&["--addr", "127.0.0.1:1994", "--timeout", "1"]),
+        Some(1)
+    );
+}
+
+#[test]
+/// - the conformance checks pass against agate serving the test content
+/// - failed checks are reported and make the exit code non-zero
+fn conformance() {
+    let conformance = |port: &str, args: &[&str]| {
+        let output = Command::new(BINARY_PATH)
+            .args(["conformance", "--addr", &format!("127.0.0.1:{}", port)])
+            .args(args)
+            .output()
+            .expect("failed to start binary");
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2105",
+        "--hostname",
+        "localhost",
+    ]);
+    let (code, report) = conformance("2105", &["--directory", "/testdir"]);
+    assert_eq!(code, Some(0), "{}", report);
+    assert!(!report.contains("FAIL"), "{}", report);
+    assert!(
+        report.contains("PASS directory-redirect: 31 gemini://localhost:2105/testdir/"),
+        "{}",
+        report
+    );
+    assert!(
+        report.ends_with("15 passed, 0 failed, 0 skipped\n"),
+        "{}",
+        report
+    );
+
+    // without --hostname, agate serves requests for any host name
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2106"]);
+    let (code, report) = conformance("2106", &[]);
+    assert_eq!(code, Some(1), "{}", report);
+    assert!(
+        report.contains("FAIL foreign-host: expected status 53"),
+        "{}",
+        report
+    );
+    assert!(report.contains("SKIP directory-redirect"), "{}", report);
+}
+
+#[test]
+/// - startup failures are reported with distinct exit codes
+fn exit_codes() {
+    let agate = |args: &[&str]| {
+        Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(args)
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to start binary")
+            .code()
+    };
+
+    assert_eq!(agate(&["--no-such-option"]), Some(2));
+    assert_eq!(
+        agate(&["--addr", "[::]:1995", "--certs", "cert_missing"]),
+        Some(3)
+    );
+    assert_eq!(
+        agate(&["--addr", "[::]:1995", "--content", "no_such_dir"]),
+        Some(5)
+    );
+
+    let mut server = Server::new(&["--addr", "[::]:1995"]);
+    assert_eq!(agate(&["--addr", "[::]:1995"]), Some(4));
+    server.stop().unwrap();
+}
+
+#[test]
+/// - a missing content directory is created with a placeholder index
+/// - parent directories are only created with `--create-content=recursive`
+fn create_content() {
+    let root = std::env::temp_dir().join("agate-test-create-content");
+    let _ = std::fs::remove_dir_all(&root);
+    let content = root.join("content");
+
+    let status = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(["--addr", "[::]:1996", "--create-content", "--content"])
+        .arg(&content)
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to start binary");
+    assert_eq!(status.code(), Some(5));
+    assert!(!root.exists());
+
+    let content = content.to_str().unwrap();
+    let page = get(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:1996",
+            "--create-content=recursive",
+            "--content",
+            content,
+        ],
+        addr(1996),
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.header.status, Status::Success);
+    assert!(page.body.unwrap().starts_with("# It works!"));
+}
+
+mod multicert {
+    use super::*;
+
+    #[test]
+    #[should_panic]
+    fn cert_missing() {
+        let mut server = Server::new(&["--addr", "[::]:1979", "--certs", "cert_missing"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    #[should_panic]
+    fn key_missing() {
+        let mut server = Server::new(&["--addr", "[::]:1980", "--certs", "key_missing"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
+    #[test]
+    /// - a key that does not belong to its certificate is refused at startup
+    /// - the check can be skipped
+    fn key_mismatch() {
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", "[::]:2020", "--certs", "key_mismatch"])
+            .output()
+            .expect("failed to start binary");
+        assert_eq!(output.status.code(), Some(3));
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("The key file for example.com does not belong to the certificate file"));
+
+        let mut server = Server::new(&[
+            "--addr",
+            "[::]:2020",
+            "--certs",
+            "key_mismatch",
+            "--skip-cert-validation",
+        ]);
+        server.stop().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// - broken certificate directories are skipped, the others are served
+    /// - a broken certificate for a host name prevents starting, unless
+    ///   --ignore-broken-certs is used
+    /// - when reloading, the old certificate of a domain that broke is kept
+    /// - a directory name that can not be a domain name does not stop reloads
+    fn broken_certs() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let certs = std::env::temp_dir().join("agate-test-broken-certs");
+        let _ = std::fs::remove_dir_all(&certs);
+        let data = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/multicert");
+        for domain in ["example.com", "example.org"] {
+            std::fs::create_dir_all(certs.join(domain)).unwrap();
+            for file in ["cert.der", "key.der"] {
+                std::fs::copy(
+                    format!("{}/{}/{}", data, domain, file),
+                    certs.join(domain).join(file),
+                )
+                .unwrap();
+            }
+        }
+        std::fs::create_dir(certs.join("example.net")).unwrap();
+        std::fs::write(certs.join("example.net/key.der"), "junk").unwrap();
+        let certs_arg = certs.to_str().unwrap();
+
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", "[::]:2021", "--certs", certs_arg])
+            .args(["--hostname", "example.net"])
+            .output()
+            .expect("failed to start binary");
+        assert_eq!(output.status.code(), Some(3));
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2021",
+            "--certs",
+            certs_arg,
+            "--hostname",
+            "example.net",
+            "--ignore-broken-certs",
+        ]);
+        server.stop().unwrap();
+
+        let mut server =
+            Server::new(&["--loose-ports", "--addr", "[::]:2021", "--certs", certs_arg]);
+        assert!(header(addr(2021), "gemini://example.org/").starts_with("20 "));
+
+        std::fs::write(certs.join("example.org/key.der"), "truncated").unwrap();
+        std::fs::create_dir(certs.join(std::ffi::OsStr::from_bytes(b"junk\xff"))).unwrap();
+        // the second reload would fail if the first one had panicked
+        for _ in 0..2 {
+            let status = Command::new("kill")
+                .args(["-HUP", &server.server.id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+            server.wait_for_log("Reloaded host names and certificates.");
+            assert!(header(addr(2021), "gemini://example.org/").starts_with("20 "));
+        }
+
+        let log = server.stop_and_read_log();
+        assert!(
+            log.contains("Certificates: kept because of errors example.org"),
+            "{}",
+            log
+        );
+        assert!(
+            log.contains("The domain name junk\u{FFFD} cannot be processed"),
+            "{}",
+            log
+        );
+        assert!(!log.contains("panicked"), "{}", log);
+    }
+
+    #[test]
+    /// - certificates and keys can be put directly into the certificate
+    ///   directory, named after their domain
+    /// - a directory for the same domain is preferred
+    fn flat_layout() {
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2022",
+            "--certs",
+            "flatcert",
+        ]);
+        assert!(header(addr(2022), "gemini://example.com/").starts_with("20 "));
+        assert!(header(addr(2022), "gemini://example.org/").starts_with("20 "));
+        server.stop().unwrap();
+
+        let certs = std::env::temp_dir().join("agate-test-flat-layout");
+        let _ = std::fs::remove_dir_all(&certs);
+        std::fs::create_dir_all(certs.join("example.org")).unwrap();
+        let data = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/multicert/example.org"
+        );
+        for file in ["cert.der", "key.der"] {
+            std::fs::copy(
+                format!("{}/{}", data, file),
+                certs.join("example.org").join(file),
+            )
+            .unwrap();
+        }
+        std::fs::write(certs.join("example.org.pem"), "junk").unwrap();
+        let args = [
+            "--loose-ports",
+            "--addr",
+            "[::]:2022",
+            "--certs",
+            certs.to_str().unwrap(),
+        ];
+        let mut server = Server::new(&args);
+        assert!(header(addr(2022), "gemini://example.org/").starts_with("20 "));
+
+        // binding the address fails after the certificates were loaded
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(args)
+            .output()
+            .expect("failed to start binary");
+        assert_eq!(output.status.code(), Some(4));
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("There is a directory and files named after example.org"));
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - with --single-cert, one certificate is generated for all host names
+    /// - it is generated again with the same key for a new host name
+    fn single_cert() {
+        let certs = std::env::temp_dir().join("agate-test-single-cert");
+        let _ = std::fs::remove_dir_all(&certs);
+        let certs_arg = certs.to_str().unwrap();
+        let dir = certs.join("_all-hostnames");
+
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2023",
+            "--certs",
+            certs_arg,
+            "--single-cert",
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "localhost",
+        ]);
+        assert!(header(addr(2023), "gemini://example.com/").starts_with("20 "));
+        server.stop().unwrap();
+        assert!(!certs.join("example.com").exists());
+        let cert = std::fs::read(dir.join("cert.der")).unwrap();
+        let key = std::fs::read(dir.join("key.der")).unwrap();
+
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2023",
+            "--certs",
+            certs_arg,
+            "--single-cert",
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "localhost",
+            "--hostname",
+            "example.org",
+        ]);
+        assert!(header(addr(2023), "gemini://example.org/").starts_with("20 "));
+        server.stop().unwrap();
+        assert_ne!(std::fs::read(dir.join("cert.der")).unwrap(), cert);
+        assert_eq!(std::fs::read(dir.join("key.der")).unwrap(), key);
+    }
+
+    /// Creates a certificate directory with generated certificates for
+    /// `count` domains, by starting agate with them as host names once.
+    fn many_certs(name: &str, count: usize, port: u16) -> PathBuf {
+        let certs = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&certs);
+        let hostnames = certs.with_extension("hostnames");
+        let names = (0..count)
+            .map(|i| format!("domain{}.example\n", i))
+            .collect::<String>();
+        std::fs::write(&hostnames, names).unwrap();
+
+        let addr = format!("[::]:{}", port);
+        let mut server = Server::new(&[
+            "--addr",
+            &addr,
+            "--certs",
+            certs.to_str().unwrap(),
+            "--hostnames-file",
+            hostnames.to_str().unwrap(),
+        ]);
+        server.stop().unwrap();
+        certs
+    }
+
+    /// Starts agate with a certificate directory while `port` is already in
+    /// use, so it exits after loading the certificates, and returns its log.
+    fn certs_log(certs: &std::path::Path, port: u16) -> String {
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", &format!("[::]:{}", port)])
+            .args(["--certs", certs.to_str().unwrap()])
+            .output()
+            .expect("failed to start binary");
+        assert_eq!(output.status.code(), Some(4));
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    #[test]
+    /// - the certificates of many domains are all loaded
+    /// - the number of loaded domains is logged
+    /// - broken certificates are reported in the order of their domains
+    fn many_domains() {
+        let certs = many_certs("agate-test-many-domains", 40, 2087);
+        for domain in ["broken-a.example", "broken-b.example", "broken-c.example"] {
+            std::fs::create_dir(certs.join(domain)).unwrap();
+            std::fs::write(certs.join(domain).join("key.der"), "junk").unwrap();
+        }
+
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2087",
+            "--certs",
+            certs.to_str().unwrap(),
+        ]);
+        assert!(header(addr(2087), "gemini://domain17.example/").starts_with("20 "));
+        let log = certs_log(&certs, 2087);
+        server.stop().unwrap();
+
+        assert!(
+            log.contains("Loaded certificates for 40 of 43 domains in "),
+            "{}",
+            log
+        );
+        let broken = log
+            .lines()
+            .filter(|line| line.contains("Skipping a certificate"))
+            .collect::<Vec<_>>();
+        assert_eq!(broken.len(), 3, "{}", log);
+        for (line, domain) in
+            broken
+                .iter()
+                .zip(["broken-a.example", "broken-b.example", "broken-c.example"])
+        {
+            assert!(line.contains(domain), "{}", log);
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --ignored --nocapture"]
+    /// - prints how long loading the certificates of 500 domains takes
+    fn many_domains_load_time() {
+        let certs = many_certs("agate-test-many-domains-load-time", 500, 2088);
+        let _server = Server::new(&["--addr", "[::]:2088", "--certs", certs.to_str().unwrap()]);
+        let log = certs_log(&certs, 2088);
+        let loaded = log
+            .lines()
+            .find(|line| line.contains("Loaded certificates for"))
+            .expect("load time was not logged");
+        println!("{}", loaded);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// - failed TLS handshakes are logged and counted by their cause
+    fn handshake_failures() {
+        use rustls::ClientSession;
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let mut server = Server::new(&["--addr", "[::]:2025", "--certs", "multicert"]);
+
+        let handshake = |host: &str, sni: bool| {
+            let mut config = rustls::ClientConfig::new();
+            config.enable_sni = sni;
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str(host).unwrap();
+            let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+            let mut tcp = TcpStream::connect(addr(2025)).unwrap();
+            let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+            assert!(write!(tls, "gemini://{}/\r\n", host).is_err());
+        };
+        handshake("example.com", false);
+        server.wait_for_log("\"\" 00 \"TLS error: no SNI\"");
+        handshake("example.net", true);
+        server.wait_for_log("\"\" 00 \"TLS error: unknown SNI\"");
+        // the client goes away without a handshake
+        drop(TcpStream::connect(addr(2025)).unwrap());
+
+        server.wait_for_log("\"\" 00 \"TLS error: peer reset\"");
+        let status = Command::new("kill")
+            .args(["-USR2", &server.server.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        server.wait_for_log("statistics:");
+        let log = server.stop_and_read_log();
+
+        assert!(log.contains("\"\" 00 \"TLS error: no SNI\""), "{}", log);
+        assert!(
+            log.contains("\"\" 00 \"TLS error: unknown SNI\""),
+            "{}",
+            log
+        );
+        assert!(
+            log.contains("TLS handshake failures: 0 unsupported protocol version, 0 no cipher overlap, 1 no SNI, 1 unknown SNI, 1 peer reset, 0 other"),
+            "{}",
+            log
+        );
+    }
+
+    #[test]
+    fn example_com() {
+        use rustls::{Certificate, ClientSession};
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:1981",
+            "--certs",
+            "multicert",
+        ]);
+
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&Certificate(
+                include_bytes!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/data/multicert/example.com/cert.der"
+                ))
+                .to_vec(),
+            ))
+            .unwrap();
+
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap();
+        let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+        let mut tcp = TcpStream::connect(addr(1981)).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://example.com/\r\n").unwrap();
+
+        let mut buf = [0; 10];
+        let _ = tls.read(&mut buf);
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn example_org() {
+        use rustls::{Certificate, ClientSession};
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:1982",
+            "--certs",
+            "multicert",
+        ]);
+
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&Certificate(
+                include_bytes!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/data/multicert/example.org/cert.der"
+                ))
+                .to_vec(),
+            ))
+            .unwrap();
+
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("example.org").unwrap();
+        let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+        let mut tcp = TcpStream::connect(addr(1982)).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://example.org/\r\n").unwrap();
+
+        let mut buf = [0; 10];
+        let _ = tls.read(&mut buf);
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - with --strict-sni, requests for another host than the SNI are refused
+    fn strict_sni() {
+        use rustls::{Certificate, ClientSession};
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:1997",
+            "--certs",
+            "multicert",
+            "--hostname",
+            "example.com",
+            "--hostname",
+            "example.org",
+            "--strict-sni",
+        ]);
+
+        let mut config = rustls::ClientConfig::new();
+        config
+            .root_store
+            .add(&Certificate(
+                include_bytes!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/data/multicert/example.org/cert.der"
+                ))
+                .to_vec(),
+            ))
+            .unwrap();
+        let config = std::sync::Arc::new(config);
+
+        let request = |url: &str| {
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str("example.org").unwrap();
+            let mut session = ClientSession::new(&config, dns_name);
+            let mut tcp = TcpStream::connect(addr(1997)).unwrap();
+            let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+            write!(tls, "{}\r\n", url).unwrap();
+
+            let mut buf = [0; 2];
+            tls.read_exact(&mut buf).unwrap();
+            buf
+        };
+
+        assert_eq!(&request("gemini://example.com/"), b"53");
+        assert_ne!(&request("gemini://example.org/"), b"53");
+
+        server.stop().unwrap();
+    }
+}
+
+#[test]
+/// - content is served from a zip archive
+/// - index files, hidden files, sidecar files and directory listings work
+///   with the directories in the archive
+/// - files larger than a decompressed chunk are sent completely
+fn content_archive() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2009",
+        "--content-archive",
+        "content.zip",
+    ]);
+    let fetch = |path: &str| {
+        let url = Url::parse(&format!("gemini://localhost{}", path)).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2009), None))
+            .expect("could not get page")
+    };
+
+    let index = fetch("/");
+    assert_eq!(index.header.status, Status::Success);
+    assert_eq!(
+        index.body.as_deref(),
+        Some("# Archive\n\nThis capsule is served from a zip file.\n")
+    );
+
+    assert_eq!(fetch("/.secret.gmi").header.status, Status::Gone);
+    let gone = fetch("/gone.gmi");
+    assert_eq!(gone.header.meta, "Gone from the archive");
+
+    assert_eq!(fetch("/listed").header.meta, "gemini://localhost/listed/");
+    assert_eq!(
+        fetch("/listed/").body.as_deref(),
+        Some("=> page.gmi\n=> sub/\n")
+    );
+    assert_eq!(
+        fetch("/listed/sub/notes.txt").body.as_deref(),
+        Some("notes\n")
+    );
+    assert_eq!(fetch("/unlisted/").header.meta, "Directory index disabled.");
+    assert_eq!(fetch("/missing.gmi").header.status, Status::NotFound);
+
+    let large = fetch("/large.txt");
+    let expected: String = (0..30000)
+        .map(|i| format!("line {:03}\n", i % 1000))
+        .collect();
+    assert_eq!(large.body.as_deref(), Some(expected.as_str()));
+
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+/// - requests for other schemes are refused with a meta string naming the scheme
+/// - they are counted in the statistics
+fn refused_schemes() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2011"]);
+
+    assert_eq!(
+        header(addr(2011), "https://localhost/"),
+        "53 Proxying https URLs is not supported"
+    );
+    assert_eq!(
+        header(addr(2011), "titan://localhost/upload;size=1"),
+        "53 Proxying titan URLs is not supported"
+    );
+    assert_eq!(
+        header(addr(2011), "foo://localhost/"),
+        "53 Unsupported URL scheme foo"
+    );
+    assert!(header(addr(2011), "gemini://localhost/").starts_with("20 "));
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    assert!(log.contains("3 requests for other URL schemes refused"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// - IPv6 addresses with a zone can be listened on
+/// - invalid zones and addresses are refused at startup
+fn addr_zone() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::1%lo]:2032"]);
+    assert!(header("[::1]:2032".parse().unwrap(), "gemini://localhost/").starts_with("20 "));
+
+    for addr in [
+        "[::1%no-such-if]:2033",
+        "::1%lo:2033",
+        "[::1%lo]",
+        "[::1%]:2033",
+    ] {
+        let status = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", addr])
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to start binary");
+        assert_eq!(status.code(), Some(2), "{}", addr);
+    }
+}
+
+#[test]
+/// - IPv6 host names match with and without brackets
+/// - host names with a zone are refused at startup
+fn ipv6_hostname() {
+    for hostname in ["::1", "[::1]", "[0:0::1]"] {
+        let _server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2034",
+            "--hostname",
+            hostname,
+        ]);
+        assert!(
+            header(addr(2034), "gemini://[::1]/").starts_with("20 "),
+            "{}",
+            hostname
+        );
+        assert_eq!(
+            header(addr(2034), "gemini://[::2]/"),
+            "53 Proxy request refused",
+            "{}",
+            hostname
+        );
+    }
+
+    for hostname in ["[fe80::1%eth0]", "fe80::1%1"] {
+        let status = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", "[::]:2034", "--hostname", hostname])
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to start binary");
+        assert_eq!(status.code(), Some(2), "{}", hostname);
+    }
+}
+
+#[test]
+/// - messy URLs are redirected to the canonical URL in one step
+/// - the query and percent-encoding are kept
+/// - canonical URLs are not redirected
+fn canonical_urls() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2031", "--canonical-urls"]);
+
+    for (messy, canonical) in [
+        ("gemini://localhost", "gemini://localhost/"),
+        (
+            "gemini://localhost//test.gmi",
+            "gemini://localhost/test.gmi",
+        ),
+        (
+            "gemini://localhost/./test.gmi?q=a%2F.",
+            "gemini://localhost/test.gmi?q=a%2F.",
+        ),
+        (
+            "gemini://localhost/%2e%2E/testdir/../test.gmi",
+            "gemini://localhost/test.gmi",
+        ),
+        (
+            "gemini://localhost/test.gmi/",
+            "gemini://localhost/test.gmi",
+        ),
+        ("gemini://localhost/index.gmi", "gemini://localhost/"),
+        (
+            "gemini://localhost/example.com/index.gmi",
+            "gemini://localhost/example.com/",
+        ),
+        (
+            "gemini://localhost//example.com",
+            "gemini://localhost/example.com/",
+        ),
+        (
+            "gemini://localhost/example.com//index.gmi/",
+            "gemini://localhost/example.com/",
+        ),
+        (
+            "gemini://localhost/%2E/testdir//a%20b.gmi",
+            "gemini://localhost/testdir/a%20b.gmi",
+        ),
+    ] {
+        assert_eq!(
+            header(addr(2031), messy),
+            format!("31 {}", canonical),
+            "{}",
+            messy
+        );
+        assert!(
+            !header(addr(2031), canonical).starts_with('3'),
+            "{} is redirected again",
+            canonical
+        );
+    }
+
+    for canonical in [
+        "gemini://localhost/",
+        "gemini://localhost/test.gmi?q",
+        "gemini://localhost/testdir/a.gmi",
+        "gemini://localhost/example.com/",
+    ] {
+        assert!(
+            header(addr(2031), canonical).starts_with("20 "),
+            "{}",
+            canonical
+        );
+    }
+}
+
+#[test]
+#[cfg(unix)]
+/// - requests for unknown hosts are refused with the configured meta string
+/// - they are counted in the statistics
+fn unknown_host_meta() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2030",
+        "--hostname",
+        "example.org",
+        "--unknown-host-meta",
+        "This server hosts example.org only",
+    ]);
+
+    assert_eq!(
+        header(addr(2030), "gemini://example.com/"),
+        "53 This server hosts example.org only"
+    );
+    assert!(header(addr(2030), "gemini://example.org/").starts_with("20 "));
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    assert!(log.contains("1 requests for unknown hosts refused"));
+
+    let status = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(["--addr", "[::]:2030", "--unknown-host-meta", "two\r\nlines"])
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to start binary");
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+/// - lines logged for a connection are prefixed with its request ID
+/// - also if the TLS handshake fails
+fn request_ids() {
+    let mut server = Server::with_env(&["--addr", "[::]:2037"], &[("RUST_LOG", "agate=info")]);
+
+    for _ in 0..2 {
+        assert!(header(addr(2037), "gemini://localhost:2037/").starts_with("20 "));
+        server.wait_for_log("\"gemini://localhost:2037/\" 20 ");
+    }
+    drop(std::net::TcpStream::connect(addr(2037)).unwrap());
+    server.wait_for_log(" 00 \"TLS error: ");
+    let log = server.stop_and_read_log();
+
+    let ids = log
+        .lines()
+        .map(|line| {
+            let id = line
+                .split("] conn{id=")
+                .nth(1)
+                .and_then(|rest| Some(rest.split_once("}: ")?.0))
+                .unwrap_or_else(|| panic!("no request ID in {:?}", line));
+            assert!(
+                id.len() == 8 && id.chars().all(|c| c.is_ascii_hexdigit()),
+                "{}",
+                line
+            );
+            id
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids.len(), 3, "{}", log);
+    assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+    assert!(log.contains(" 00 \"TLS error: "));
+}
+
+#[test]
+/// - identical failures are logged once, and then how often they were repeated
+/// - successful requests are always logged
+fn log_dedup() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2035", "--log-dedup=1"]);
+
+    let url = Url::parse("gemini://localhost/").unwrap();
+    for _ in 0..3 {
+        assert!(header(addr(2035), "gemini://localhost/#fragment").starts_with("59 "));
+        let page = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2035), None))
+            .expect("could not get page");
+        assert_eq!(page.header.status, Status::Success);
+    }
+    server.wait_for_log("Last message repeated");
+    let log = server.stop_and_read_log();
+
+    let failures = log
+        .lines()
+        .filter(|line| line.contains("#fragment\" 59 "))
+        .collect::<Vec<_>>();
+    assert_eq!(failures.len(), 2, "{}", log);
+    assert!(failures[1].contains("Last message repeated 2 times: "));
+    assert_eq!(
+        log.lines()
+            .filter(|line| line.contains("\"gemini://localhost/\" 20"))
+            .count(),
+        3
+    );
+}
+
+#[test]
+/// - the search endpoint asks for a query
+/// - files containing all words of the query are listed with their heading
+/// - hidden files are not found
+fn search() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2012",
+        "--search",
+        "/search",
+    ]);
+    let fetch = |query: &str| {
+        let url = Url::parse(&format!("gemini://localhost/search{}", query)).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2012), None))
+            .expect("could not get page")
+    };
+
+    let prompt = fetch("");
+    assert_eq!(prompt.header.status, Status::Input);
+    assert_eq!(prompt.header.meta, "Search this capsule");
+
+    assert_eq!(
+        fetch("?Zebrafish").body.as_deref(),
+        Some(
+            "# Search results for Zebrafish\n\n\
+             => /searchable/fish.txt searchable/fish.txt\n\
+             => /searchable/zebra.gmi Zebras\n"
+        )
+    );
+    assert_eq!(
+        fetch("?zebrafish%20zebra").body.as_deref(),
+        Some("# Search results for zebrafish zebra\n\n=> /searchable/zebra.gmi Zebras\n")
+    );
+    assert_eq!(
+        fetch("?quagga").body.as_deref(),
+        Some("# Search results for quagga\n\nNo matching pages found.\n")
+    );
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - the feed lists gemtext files, the most recently modified first
+/// - hidden files are left out
+/// - the feed is not regenerated for every request
+fn feed() {
+    use std::time::{Duration, SystemTime};
+
+    let dir = std::env::temp_dir().join("agate-test-feed");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let write = |name: &str, text: &str, age: u64| {
+        std::fs::write(dir.join(name), text).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join(name))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 - age))
+            .unwrap();
+    };
+    write("old.gmi", "# Old\n", 60);
+    write("new.gmi", "text\n# New & <improved>\n", 0);
+    write("sub/untitled.gmi", "no heading\n", 30);
+    write("notes.txt", "# Not gemtext\n", 0);
+    write(".hidden.gmi", "# Hidden\n", 0);
+
+    let content = dir.to_str().unwrap();
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2013",
+        "--content",
+        content,
+        "--feed",
+        "/recent.xml",
+    ]);
+    let fetch = || {
+        let url = Url::parse("gemini://localhost/recent.xml").unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2013), None))
+            .expect("could not get page")
+    };
+
+    let page = fetch();
+    assert_eq!(page.header.meta, "application/atom+xml");
+    let feed = page.body.unwrap();
+    assert!(feed.contains("<updated>2023-11-14T22:13:20Z</updated>"));
+    let titles = feed
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("<title>"))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        titles,
+        [
+            "Recent changes on localhost</title>",
+            "New &amp; &lt;improved&gt;</title>",
+            "sub/untitled.gmi</title>",
+            "Old</title>",
+        ]
+    );
+    assert!(feed.contains("<link href=\"gemini://localhost/sub/untitled.gmi\"/>"));
+
+    // changes are only picked up after a while
+    write("newer.gmi", "# Newer\n", 0);
+    assert_eq!(fetch().body.unwrap(), feed);
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - a directory with a .gemlog file gets a generated index of its posts
+/// - files without a date are listed separately
+/// - the index is generated again when the directory changes
+/// - a real index.gmi is preferred
+fn gemlog_index() {
+    let dir = std::env::temp_dir().join("agate-test-gemlog");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("log")).unwrap();
+    let write = |name: &str, text: &str| std::fs::write(dir.join("log").join(name), text).unwrap();
+    write(".gemlog", "# My gemlog\nThoughts and such.\n");
+    write("2024-05-01-first.gmi", "# First post\n");
+    write("2024-06-10-second.gmi", "intro\n## Second post\n");
+    write("2024-13-01-bogus.gmi", "# Not a date\n");
+    write("about.gmi", "# About\n");
+    write("photo.jpg", "");
+
+    let content = dir.to_str().unwrap();
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2027", "--content", content]);
+    let fetch = || {
+        let url = Url::parse("gemini://localhost/log/").unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(Page::fetch_from(&url, addr(2027), None))
+            .expect("could not get page")
+    };
+
+    let page = fetch();
+    assert_eq!(page.header.status, Status::Success);
+    assert_eq!(page.header.meta, "text/gemini");
+    assert_eq!(
+        page.body.as_deref(),
+        Some(
+            "# My gemlog\nThoughts and such.\n\n\
+             => 2024-06-10-second.gmi 2024-06-10 \u{2014} Second post\n\
+             => 2024-05-01-first.gmi 2024-05-01 \u{2014} First post\n\
+             \n## Other pages\n\n\
+             => 2024-13-01-bogus.gmi Not a date\n\
+             => about.gmi About\n\
+             => photo.jpg\n"
+        )
+    );
+
+    write("2024-07-04-third.gmi", "# Third post\n");
+    assert!(fetch()
+        .body
+        .unwrap()
+        .contains("\n\n=> 2024-07-04-third.gmi 2024-07-04 \u{2014} Third post\n=> 2024-06-10"));
+
+    write("index.gmi", "hand-written\n");
+    assert_eq!(fetch().body.as_deref(), Some("hand-written\n"));
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - requests for other host names are redirected to the canonical host name
+/// - requests for the canonical host name in another case or with a trailing
+///   dot are not redirected
+fn canonical_host() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2014",
+        "--canonical-host",
+        "example.com",
+    ]);
+
+    assert_eq!(
+        header(addr(2014), "gemini://localhost/test.gmi?query"),
+        "31 gemini://example.com/test.gmi?query"
+    );
+    assert_eq!(
+        header(addr(2014), "gemini://alias.example:2014/"),
+        "31 gemini://example.com:2014/"
+    );
+    assert!(header(addr(2014), "gemini://EXAMPLE.com/").starts_with("20 "));
+    assert!(header(addr(2014), "gemini://example.com./").starts_with("20 "));
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - requests for a redirected host name are redirected to the same path and
+///   query on the new host, without looking at the content
+/// - percent-encoding is kept as it was requested
+/// - the port of the request is dropped unless the new host includes one
+fn redirect_host() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2060",
+        "--redirect-host",
+        "example.org=example.com",
+        "--redirect-host",
+        "localhost=example.com:1966",
+    ]);
+
+    assert_eq!(
+        header(addr(2060), "gemini://example.org/a%20b/c?q%2F=%C3%A4"),
+        "31 gemini://example.com/a%20b/c?q%2F=%C3%A4"
+    );
+    assert_eq!(
+        header(addr(2060), "gemini://EXAMPLE.org.:2060/does-not-exist"),
+        "31 gemini://example.com/does-not-exist"
+    );
+    assert_eq!(
+        header(addr(2060), "gemini://localhost:2060/test.gmi?query"),
+        "31 gemini://example.com:1966/test.gmi?query"
+    );
+    assert!(header(addr(2060), "gemini://example.com/").starts_with("20 "));
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - with --max-generated, concurrent directory listings wait for each other
+///   instead of being refused
+fn max_generated() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2061",
+        "--max-generated",
+        "1",
+    ]);
+
+    let requests = (0..4)
+        .map(|_| std::thread::spawn(|| header(addr(2061), "gemini://localhost/described/")))
+        .collect::<Vec<_>>();
+    for request in requests {
+        assert_eq!(request.join().unwrap(), "20 text/gemini");
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - an empty content directory is answered with a welcome page
+/// - the welcome page disappears once there is an index file
+/// - it is not served with --no-welcome
+fn welcome_page() {
+    let content = std::env::temp_dir().join("agate-test-welcome");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join(".meta"), "").unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2062",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+    let response =
+        String::from_utf8(read_to_end(request(addr(2062), "gemini://localhost/"))).unwrap();
+    assert!(response.starts_with("20 text/gemini\r\n# Welcome to Agate\n"));
+    assert!(response.contains(content.to_str().unwrap()));
+    assert_eq!(
+        header(addr(2062), "gemini://localhost/other"),
+        "51 Not found, sorry."
+    );
+
+    std::fs::write(content.join("index.gmi"), "# My capsule\n").unwrap();
+    let response =
+        String::from_utf8(read_to_end(request(addr(2062), "gemini://localhost/"))).unwrap();
+    assert_eq!(response, "20 text/gemini\r\n# My capsule\n");
+    server.stop().unwrap();
+
+    std::fs::remove_file(content.join("index.gmi")).unwrap();
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2063",
+        "--content",
+        content.to_str().unwrap(),
+        "--no-welcome",
+    ]);
+    assert_eq!(
+        header(addr(2063), "gemini://localhost/"),
+        "51 Directory index disabled."
+    );
+    server.stop().unwrap();
+}
+
+#[test]
+/// - the debug endpoint asks for input without a query
+/// - with a query, it shows what was received, quoted so it can not add lines
+/// - the query is not logged with --redact-queries
+fn debug_endpoint() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2064",
+        "--debug-endpoint",
+        "/echo",
+        "--redact-queries",
+    ]);
+
+    assert_eq!(
+        header(addr(2064), "gemini://localhost/echo"),
+        "10 Type something"
+    );
+    let response = read_to_end(request(
+        addr(2064),
+        "gemini://localhost/echo?s%C3%A4cret%0A=>",
+    ));
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("20 text/gemini\r\n# Received request\n"));
+    assert!(
+        response.contains("* Query: \"s%C3%A4cret%0A=%3E\"\n"),
+        "{}",
+        response
+    );
+    assert!(
+        response.contains("* Decoded query: \"s\u{e4}cret\\n=>\"\n"),
+        "{}",
+        response
+    );
+    assert!(response.contains("* Parsed URL: \"gemini://localhost/echo?s%C3%A4cret%0A=%3E\"\n"));
+    assert!(response.contains("* SNI: \"localhost\"\n"));
+    assert!(response.contains("* TLS version: TLS 1."));
+    assert!(response.contains("* Client certificate: none\n"));
+
+    server.wait_for_log("\"gemini://localhost/echo?\u{2026}\" 20");
+    let log = server.stop_and_read_log();
+    assert!(
+        log.contains("\"gemini://localhost/echo?\u{2026}\" 20"),
+        "{}",
+        log
+    );
+    assert!(!log.contains("cret"), "{}", log);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// - a file that was cached as missing is served right after it is created,
+///   also in a new directory, because the content directory is watched
+fn watched_changes() {
+    let content = std::env::temp_dir().join("agate-test-watched-changes");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2065",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+
+    // well within the few seconds missing files are cached for otherwise
+    let eventually = |url: &str, expected: &str| {
+        let start = std::time::Instant::now();
+        while header(addr(2065), url) != expected {
+            assert!(start.elapsed().as_secs() < 2, "{} was not noticed", url);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    };
+
+    let url = "gemini://localhost/new.gmi";
+    assert_eq!(header(addr(2065), url), "51 Not found, sorry.");
+    std::fs::write(content.join("new.gmi"), "# New\n").unwrap();
+    eventually(url, "20 text/gemini");
+
+    let url = "gemini://localhost/dir/new.gmi";
+    assert_eq!(header(addr(2065), url), "51 Not found, sorry.");
+    std::fs::create_dir(content.join("dir")).unwrap();
+    std::fs::write(content.join("dir/new.gmi"), "# New\n").unwrap();
+    eventually(url, "20 text/gemini");
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - requests for an IP address are refused if it is not a host name
+/// - with --ip-requests redirect, they are redirected to the host name with
+///   the same path, query and port, after the port was checked
+/// - with --ip-requests serve, they get the content of the first host name
+fn ip_requests() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2066",
+        "--hostname",
+        "example.com",
+    ]);
+    assert_eq!(
+        header(addr(2066), "gemini://127.0.0.1/"),
+        "53 Proxy request refused"
+    );
+    server.stop().unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2067",
+        "--hostname",
+        "example.com",
+        "--ip-requests",
+        "redirect",
+    ]);
+    assert_eq!(
+        header(addr(2067), "gemini://127.0.0.1/test.gmi?a%20b"),
+        "31 gemini://example.com/test.gmi?a%20b"
+    );
+    assert_eq!(
+        header(addr(2067), "gemini://[::1]:2067/"),
+        "31 gemini://example.com:2067/"
+    );
+    assert_eq!(
+        header(addr(2067), "gemini://[::1]:1965/"),
+        "53 proxy request refused"
+    );
+    assert!(header(addr(2067), "gemini://example.com/").starts_with("20 "));
+    server.stop().unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2068",
+        "--hostname",
+        "example.com",
+        "--hostname",
+        "example.org",
+        "--ip-requests",
+        "serve",
+    ]);
+    let index = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/data/content/example.com/index.gmi"
+    ))
+    .unwrap();
+    for url in ["gemini://127.0.0.1/", "gemini://[::1]/"] {
+        let response = read_to_end(request(addr(2068), url));
+        assert!(response.starts_with(b"20 "), "{}", url);
+        assert!(response.ends_with(&index), "{}", url);
+    }
+    server.stop().unwrap();
+}
+
+#[test]
+/// - with --log-time, timestamps have millisecond precision and the offset of
+///   the time zone
+fn log_time() {
+    for (port, zone) in [(2015, "utc"), (2016, "local")] {
+        let mut server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            &format!("[::]:{}", port),
+            "--log-time",
+            zone,
+        ]);
+        assert!(header(addr(port), "gemini://localhost/").starts_with("20 "));
+
+        server.wait_for_log("\"gemini://localhost/\" 20");
+        let log = server.stop_and_read_log();
+
+        let line = log
+            .lines()
+            .find(|line| line.contains("\"gemini://localhost/\" 20"))
+            .expect("request was not logged");
+        let timestamp = line[1..].split(' ').next().unwrap();
+        // e.g. 2025-01-01T12:00:00.123Z or 2025-01-01T13:00:00.123+01:00
+        assert_eq!(&timestamp[19..20], ".", "{}", timestamp);
+        assert!(timestamp[20..23].bytes().all(|b| b.is_ascii_digit()));
+        let offset = &timestamp[23..];
+        if zone == "utc" {
+            assert_eq!(offset, "Z");
+        } else {
+            assert!(
+                offset.starts_with(['+', '-']) && offset.len() == 6,
+                "{}",
+                offset
+            );
+        }
+    }
+}
+
+#[test]
+/// - files larger than --max-response-size are refused, files exactly that
+///   large are served
+/// - a sidecar file can lift the limit for a file
+/// - directory listings are limited too
+fn max_response_size() {
+    let dir = std::env::temp_dir().join("agate-test-max-response-size");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("listed")).unwrap();
+    std::fs::write(dir.join("index.gmi"), "1234567890").unwrap();
+    std::fs::write(dir.join("exact.gmi"), "0123456789").unwrap();
+    std::fs::write(dir.join("large.gmi"), "01234567890").unwrap();
+    std::fs::write(dir.join("allowed.gmi"), "01234567890").unwrap();
+    std::fs::write(dir.join(".meta"), "allowed.gmi: max-size unlimited\n").unwrap();
+    std::fs::write(dir.join("listed/.directory-listing-ok"), "").unwrap();
+    std::fs::write(dir.join("listed/a-file-with-a-long-name.gmi"), "").unwrap();
+
+    let content = dir.to_str().unwrap();
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2017",
+        "--content",
+        content,
+        "--max-response-size",
+        "10",
+    ]);
+
+    assert!(header(addr(2017), "gemini://localhost/exact.gmi").starts_with("20 "));
+    assert_eq!(
+        header(addr(2017), "gemini://localhost/large.gmi"),
+        "59 Resource too large"
+    );
+    assert!(header(addr(2017), "gemini://localhost/allowed.gmi").starts_with("20 "));
+    assert_eq!(
+        header(addr(2017), "gemini://localhost/listed/"),
+        "59 Resource too large"
+    );
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - with --stats-file, successful responses are counted per file
+/// - counts from a previous run are loaded and the file is written when
+///   terminating
+fn stats_file() {
+    let dir = std::env::temp_dir().join("agate-test-stats-file");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("hits.tsv");
+    std::fs::write(&file, "2\tindex.gmi\n").unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2018",
+        "--stats-file",
+        file.to_str().unwrap(),
+    ]);
+    for url in [
+        "gemini://localhost/",
+        "gemini://localhost/index.gmi",
+        "gemini://localhost/test.gmi",
+    ] {
+        assert!(header(addr(2018), url).starts_with("20 "));
+    }
+    assert!(header(addr(2018), "gemini://localhost/missing.gmi").starts_with("51 "));
+
+    // files are counted before their header is sent
+    let status = Command::new("kill")
+        .args(["-TERM", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(server.server.wait().unwrap().success());
+    server.stop_and_read_log();
+
+    assert_eq!(
+        std::fs::read_to_string(&file).unwrap(),
+        "4\tindex.gmi\n1\ttest.gmi\n"
+    );
+}
+
+#[test]
+/// - default MIME parameters are added by the most specific pattern
+/// - parameters from a .meta file replace them
+/// - invalid rules are refused at startup
+fn mime_params() {
+    for rule in ["text=;charset=utf-8", "text/*=charset=utf-8", "*=;a=\nb"] {
+        let status = Command::new(BINARY_PATH)
+            .current_dir("tests/data")
+            .args(["--addr", "[::]:2038", "--mime-params", rule])
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to start binary");
+        assert_eq!(status.code(), Some(2), "{:?}", rule);
+    }
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2038",
+        "--mime-params",
+        "text/*=;charset=utf-8",
+        "--mime-params",
+        "*=;source=capsule",
+        "--mime-params",
+        "text/plain=;charset=us-ascii",
+    ]);
+
+    assert_eq!(
+        header(addr(2038), "gemini://localhost/"),
+        "20 text/gemini;charset=utf-8"
+    );
+    assert_eq!(
+        header(addr(2038), "gemini://localhost/precompressed/other.txt"),
+        "20 text/plain;charset=us-ascii"
+    );
+    assert_eq!(
+        header(addr(2038), "gemini://localhost/described/report.pdf"),
+        "20 application/pdf;source=capsule"
+    );
+    assert_eq!(
+        header(addr(2038), "gemini://localhost/test.gmi"),
+        "20 text/gemini;lang=en ;charset=us-ascii"
+    );
+}
+
+#[test]
+/// - files are sent completely with a small buffer and short reads
+/// - empty files are sent with an empty body
+/// - of files that grow while being sent, only the length they had when
+///   they were opened is sent, with a warning
+/// - files that shrink while being sent are cut short, the connection is not
+///   closed cleanly and an error is logged
+fn io_buffer() {
+    let status = Command::new(BINARY_PATH)
+        .current_dir("tests/data")
+        .args(["--addr", "[::]:2039", "--io-buffer", "0"])
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to start binary");
+    assert_eq!(status.code(), Some(2));
+
+    let content = std::env::temp_dir().join("agate-test-io-buffer");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    let data = (0..100_003u32).map(|i| i as u8).collect::<Vec<_>>();
+    std::fs::write(content.join("data.bin"), &data).unwrap();
+    std::fs::write(content.join("empty.txt"), "").unwrap();
+    // larger than what fits into the socket buffers, so the server is still
+    // reading the file while the client waits
+    let large = vec![b'a'; 24 * 1024 * 1024];
+    std::fs::write(content.join("large.txt"), &large).unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2039",
+        "--content",
+        content.to_str().unwrap(),
+        "--io-buffer",
+        "1000",
+    ]);
+
+    let response = read_to_end(request(addr(2039), "gemini://localhost/data.bin"));
+    assert_eq!(&response[..27], b"20 application/octet-stream");
+    assert_eq!(response[29..], data[..]);
+
+    let response = read_to_end(request(addr(2039), "gemini://localhost/empty.txt"));
+    assert_eq!(response, b"20 text/plain\r\n");
+
+    let mut connection = request(addr(2039), "gemini://localhost/large.txt");
+    let mut header = String::new();
+    connection.read_line(&mut header).unwrap();
+    assert_eq!(header, "20 text/plain\r\n");
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(content.join("large.txt"))
+        .unwrap();
+    std::io::Write::write_all(&mut file, b"grown").unwrap();
+    let body = read_to_end(connection);
+    assert_eq!(body, large);
+    server.wait_for_log("\"gemini://localhost/large.txt\" 20 ");
+
+    let mut connection = request(addr(2039), "gemini://localhost/large.txt");
+    let mut header = String::new();
+    connection.read_line(&mut header).unwrap();
+    assert_eq!(header, "20 text/plain\r\n");
+    file.set_len(1024).unwrap();
+    let mut body = vec![];
+    // rustls reports the close_notify alert of a clean close as an error
+    assert!(connection.read_to_end(&mut body).is_ok());
+    assert!(body.len() < large.len(), "{}", body.len());
+
+    // the connection is closed before the access line is logged
+    server.wait_for_log("\"gemini://localhost/large.txt\" 20 ");
+    let log = server.stop_and_read_log();
+    assert!(
+        log.contains(&format!(
+            "large.txt\" grew during transfer, only sent the {} bytes",
+            large.len()
+        )),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("large.txt\" was truncated during transfer, sent "),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("\"gemini://localhost/large.txt\" 20 \"text/plain\" error:file truncated during transfer, sent "),
+        "{}",
+        log
+    );
+    std::fs::remove_dir_all(&content).unwrap();
+}
+
+#[test]
+/// - plaintext requests are accepted on the offload listener
+/// - the port in URLs is checked against the public port
+/// - such requests are marked in the access log
+/// - the offload listener is refused on other than loopback addresses
+fn insecure_listener() {
+    use std::io::Write;
+
+    let status = Command::new(BINARY_PATH)
+        .current_dir("tests/data")
+        .args(["--addr", "[::]:2040", "--insecure-listener", "0.0.0.0:2041"])
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to start binary");
+    assert_eq!(status.code(), Some(2));
+
+    let mut server = Server::new(&[
+        "--addr",
+        "[::]:2040",
+        "--insecure-listener",
+        "127.0.0.1:2041",
+        "--public-port",
+        "1965",
+    ]);
+
+    let plaintext = |url: &str| {
+        let mut tcp = std::net::TcpStream::connect(addr(2041)).unwrap();
+        write!(tcp, "{}\r\n", url).unwrap();
+        let mut response = String::new();
+        tcp.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = plaintext("gemini://localhost/");
+    assert!(response.starts_with("20 text/gemini\r\n"), "{:?}", response);
+    server.wait_for_log("offload:127.0.0.1:2041 - \"gemini://localhost/\" 20 ");
+    assert!(plaintext("gemini://localhost:1965/").starts_with("20 "));
+    assert!(plaintext("gemini://localhost:2041/").starts_with("53 "));
+    // TLS is still used on the other addresses, with the same public port
+    assert_eq!(
+        header(addr(2040), "gemini://localhost:1965/"),
+        "20 text/gemini"
+    );
+
+    server.wait_for_log("[::ffff:127.0.0.1]:2040 - \"gemini://localhost:1965/\" 20 ");
+    let log = server.stop_and_read_log();
+    assert!(
+        log.contains("offload:127.0.0.1:2041 - \"gemini://localhost/\" 20 \"text/gemini\""),
+        "{}",
+        log
+    );
+
+    #[cfg(unix)]
+    {
+        let socket = std::env::temp_dir().join("agate-test-offload.sock");
+        let _server = Server::new(&[
+            "--loose-ports",
+            "--addr",
+            "[::]:2040",
+            "--insecure-listener",
+            &format!("unix:{}", socket.display()),
+        ]);
+        let mut unix = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+        write!(unix, "gemini://localhost/\r\n").unwrap();
+        let mut response = String::new();
+        unix.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("20 text/gemini\r\n"), "{:?}", response);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+/// - requests without CRLF within the length limit are refused
+/// - request bytes and too long requests are counted in the statistics
+fn received_bytes() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2042"]);
+
+    let url = format!("gemini://localhost/{}", "a".repeat(1100));
+    assert_eq!(header(addr(2042), &url), "59 Request too long");
+    let response = read_to_end(request(addr(2042), "gemini://localhost/"));
+    assert!(response.starts_with(b"20 "));
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    // the whole buffer and the second request including its CRLF
+    let received = 1026 + "gemini://localhost/\r\n".len();
+    assert!(
+        log.contains(&format!(
+            "{} request bytes received, 1 requests too long",
+            received
+        )),
+        "{}",
+        log
+    );
+}
+
+#[test]
+/// - a URL without a path serves the index of the content root like "/"
+/// - also with a query, with vhosts and with a directory listing
+/// - with --canonical-urls, the empty path is redirected to "/"
+fn empty_path() {
+    let content = std::env::temp_dir().join("agate-test-empty-path");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join(".directory-listing-ok"), "").unwrap();
+    std::fs::write(content.join("file.gmi"), "").unwrap();
+
+    let body = |url: &str| {
+        let response = read_to_end(request(addr(2045), url));
+        String::from_utf8(response).unwrap()
+    };
+
+    let server = Server::new(&["--loose-ports", "--addr", "[::]:2045"]);
+    let root = body("gemini://localhost/");
+    assert!(root.starts_with("20 text/gemini\r\n"));
+    assert_eq!(body("gemini://localhost"), root);
+    assert_eq!(body("gemini://localhost?query"), root);
+    drop(server);
+
+    let server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2045",
+        "--hostname",
+        "example.com",
+        "--hostname",
+        "example.org",
+    ]);
+    let root = body("gemini://example.com/");
+    assert!(root.starts_with("20 text/gemini;lang=en-US\r\n"));
+    assert_eq!(body("gemini://example.com"), root);
+    assert_eq!(body("gemini://example.com?query"), root);
+    drop(server);
+
+    let server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2045",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+    assert_eq!(
+        body("gemini://localhost"),
+        "20 text/gemini\r\n=> file.gmi\n"
+    );
+    assert_eq!(
+        body("gemini://localhost?query"),
+        "20 text/gemini\r\n=> file.gmi\n"
+    );
+    drop(server);
+
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2045", "--canonical-urls"]);
+    assert_eq!(
+        header(addr(2045), "gemini://localhost"),
+        "31 gemini://localhost/"
+    );
+    assert_eq!(
+        header(addr(2045), "gemini://localhost?query"),
+        "31 gemini://localhost/?query"
+    );
+    assert!(header(addr(2045), "gemini://localhost/").starts_with("20 "));
+}
+
+#[test]
+/// - problems in the content directory are printed and fail the check
+/// - a clean content directory passes without starting the server
+fn validate_content() {
+    let content = std::env::temp_dir().join("agate-test-validate-content");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir_all(content.join("sub")).unwrap();
+    std::fs::write(content.join("index.gmi"), "# Home\n").unwrap();
+    std::fs::write(content.join("sub/index.txt"), "not an index\n").unwrap();
+    std::fs::write(content.join(".meta"), "missing.gmi: text/plain\n").unwrap();
+    std::os::unix::fs::symlink("nowhere.gmi", content.join("dangling.gmi")).unwrap();
+    std::os::unix::fs::symlink("..", content.join("sub/loop")).unwrap();
+
+    let validate = |content: &std::path::Path| {
+        Command::new(BINARY_PATH)
+            .current_dir("tests/data")
+            .args(["--addr", "[::]:2046", "--validate-content", "--content"])
+            .arg(content)
+            .stderr(Stdio::null())
+            .output()
+            .expect("failed to start binary")
+    };
+
+    let output = validate(&content);
+    assert_eq!(output.status.code(), Some(5));
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        report.contains("dangling.gmi\": dangling symlink"),
+        "{}",
+        report
+    );
+    assert!(report.contains("index.txt\": is not used as directory index"));
+    assert!(report.contains("entry \"missing.gmi\" does not match any file"));
+    assert!(report.contains("loop\": symlink loop to"));
+    assert!(report.contains("1 errors, 3 warnings in"));
+
+    std::fs::remove_file(content.join("dangling.gmi")).unwrap();
+    std::fs::remove_file(content.join(".meta")).unwrap();
+    let output = validate(&content);
+    assert_eq!(output.status.code(), Some(0));
+
+    std::fs::remove_dir_all(content.join("sub")).unwrap();
+    let output = validate(&content);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("0 errors, 0 warnings in"));
+}
+
+#[test]
+#[cfg(unix)]
+/// - repeated requests for a missing file are answered from a cache
+/// - a .meta entry for the file bypasses the cache
+/// - cached misses are counted in the statistics
+fn cached_misses() {
+    let content = std::env::temp_dir().join("agate-test-cached-misses");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join("index.gmi"), "# Home\n").unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2047",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+
+    let url = "gemini://localhost/missing.gmi";
+    assert_eq!(header(addr(2047), url), "51 Not found, sorry.");
+    assert_eq!(header(addr(2047), url), "51 Not found, sorry.");
+    assert_eq!(header(addr(2047), url), "51 Not found, sorry.");
+    std::fs::write(content.join("missing.gmi"), "# Found\n").unwrap();
+    std::fs::write(content.join(".meta"), "missing.gmi: text/plain\n").unwrap();
+    assert_eq!(header(addr(2047), url), "20 text/plain");
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    assert!(
+        log.contains("2 requests for missing files answered from cache"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+#[cfg(feature = "http-gateway")]
+/// - gemtext is rendered as HTML with a link to the Gemini URL
+/// - other files are sent with their MIME type
+/// - .meta entries and hidden files are handled like for Gemini requests
+/// - requests for other hosts and other methods are refused
+fn http_gateway() {
+    use std::{io::Write, net::TcpStream};
+
+    let _server = Server::new(&[
+        "--addr",
+        "[::]:2048",
+        "--http-gateway",
+        "127.0.0.1:2049",
+        "--hostname",
+        "localhost",
+    ]);
+
+    let get = |request: &str| {
+        let mut stream = TcpStream::connect("127.0.0.1:2049").unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = get("GET / HTTP/1.1\r\nHost: localhost:2049\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    assert!(response.contains("<a href=\"gemini://localhost:2048/\">"));
+    assert!(response.contains("<p>This is a test index file.</p>"));
+
+    let response = get("GET /test HTTP/1.0\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/html\r\n"));
+
+    let response = get("HEAD /test.gmi HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n"));
+
+    let response = get("GET /testdir HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+    assert!(response.contains("Location: /testdir/\r\n"));
+
+    let response = get("GET /gone.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 410 Gone\r\n"),
+        "{}",
+        response
+    );
+    let response = get("GET /.meta HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 410 Gone\r\n"),
+        "{}",
+        response
+    );
+    let response = get("GET /missing.gmi HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    let response = get("GET http://example.org/ HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    let response = get("GET / HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"));
+    let response = get("POST / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
+
+#[test]
+#[cfg(feature = "http-gateway")]
+/// - gemtext pages that are too large to render are answered with 502
+fn http_gateway_large_page() {
+    use std::{io::Write, net::TcpStream};
+
+    let content = std::env::temp_dir().join("agate-test-http-gateway-large-page-2114");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join("small.gmi"), "# Small\n").unwrap();
+    std::fs::write(content.join("large.gmi"), "a\n".repeat(1024 * 1024)).unwrap();
+
+    let _server = Server::new(&[
+        "--addr",
+        "[::]:2114",
+        "--content",
+        content.to_str().unwrap(),
+        "--http-gateway",
+        "127.0.0.1:2115",
+    ]);
+
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect("127.0.0.1:2115").unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = get("/small.gmi");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let response = get("/large.gmi");
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+/// - a query like ?lang=de selects a language variant like page.de.gmi
+/// - the only variant of a missing file is served instead of it
+/// - names with dots that are not language tags are not variants
+/// - directory listings link variants after their base file
+fn lang_variants() {
+    let content = std::env::temp_dir().join("agate-test-lang-variants");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    for (name, text) in [
+        (".directory-listing-ok", ""),
+        ("page.gmi", "base\n"),
+        ("page.de.gmi", "deutsch\n"),
+        ("page.pt-BR.gmi", "português\n"),
+        ("solo.fr.gmi", "français\n"),
+        ("multi.de.gmi", "deutsch\n"),
+        ("multi.fr.gmi", "français\n"),
+        ("notes.old.gmi", "old\n"),
+    ] {
+        std::fs::write(content.join(name), text).unwrap();
+    }
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2050",
+        "--content",
+        content.to_str().unwrap(),
+        "--lang",
+        "en",
+        "--lang-variants",
+    ]);
+    let get = |path: &str| {
+        let url = format!("gemini://localhost/{}", path);
+        String::from_utf8(read_to_end(request(addr(2050), &url))).unwrap()
+    };
+
+    assert_eq!(
+        get("page.gmi?lang=de"),
+        "20 text/gemini;lang=de\r\ndeutsch\n"
+    );
+    assert_eq!(
+        get("page.gmi?lang=pt-BR"),
+        "20 text/gemini;lang=pt-BR\r\nportuguês\n"
+    );
+    assert_eq!(get("page.gmi?lang=it"), "20 text/gemini;lang=en\r\nbase\n");
+    assert_eq!(
+        get("page.gmi?lang=../x"),
+        "20 text/gemini;lang=en\r\nbase\n"
+    );
+    assert_eq!(get("page.gmi"), "20 text/gemini;lang=en\r\nbase\n");
+    assert_eq!(get("solo.gmi"), "20 text/gemini;lang=fr\r\nfrançais\n");
+    assert_eq!(get("multi.gmi"), "51 Not found, sorry.\r\n");
+    assert_eq!(
+        get("multi.gmi?lang=fr"),
+        "20 text/gemini;lang=fr\r\nfrançais\n"
+    );
+    assert_eq!(get("notes.gmi"), "51 Not found, sorry.\r\n");
+
+    assert_eq!(
+        get(""),
+        "20 text/gemini\r\n\
+        => multi.gmi?lang=de multi.gmi (de)\n\
+        => multi.gmi?lang=fr multi.gmi (fr)\n\
+        => notes.old.gmi\n\
+        => page.gmi\n\
+        => page.gmi?lang=de page.gmi (de)\n\
+        => page.gmi?lang=pt-BR page.gmi (pt-BR)\n\
+        => solo.gmi?lang=fr solo.gmi (fr)\n"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// - transfers in progress are logged on SIGUSR2
+/// - with --shutdown-grace, they are logged while waiting for them to finish
+///   after SIGTERM, and the server exits once they did
+fn shutdown_grace() {
+    let content = std::env::temp_dir().join("agate-test-shutdown-grace");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    // larger than what fits into the socket buffers
+    let large = vec![b'a'; 24 * 1024 * 1024];
+    std::fs::write(content.join("large.txt"), &large).unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2051",
+        "--content",
+        content.to_str().unwrap(),
+        "--shutdown-grace",
+        "30",
+    ]);
+
+    let mut connection = request(addr(2051), "gemini://localhost/large.txt");
+    let mut header = String::new();
+    connection.read_line(&mut header).unwrap();
+    assert_eq!(header, "20 text/plain\r\n");
+
+    let pid = server.server.id().to_string();
+    let status = Command::new("kill").args(["-USR2", &pid]).status().unwrap();
+    assert!(status.success());
+    server.wait_for_log("1 transfers in progress");
+    let status = Command::new("kill").args(["-TERM", &pid]).status().unwrap();
+    assert!(status.success());
+    // right away and again after a few seconds
+    server.wait_for_log("1 transfers in progress");
+    server.wait_for_log("1 transfers in progress");
+
+    let body = read_to_end(connection);
+    assert_eq!(body.len(), large.len());
+    let status = server.server.wait().unwrap();
+    assert!(status.success());
+    let log = server.stop_and_read_log();
+
+    // on SIGUSR2, and twice while shutting down
+    assert!(
+        log.matches("1 transfers in progress").count() >= 3,
+        "{}",
+        log
+    );
+    assert!(log.contains("in progress: \"large.txt\" "));
+    assert!(log.contains(" of 25165824 bytes sent, "));
+    assert!(log.contains("Shutting down, waiting up to 30s for transfers to finish..."));
+    assert!(log.contains("All transfers finished"));
+    std::fs::remove_dir_all(&content).unwrap();
+}
+
+#[test]
+/// - a download line in a .meta file makes .gmi files application/octet-stream
+///   without the language parameter
+/// - directory listings mark these files
+fn meta_download() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2053", "--lang", "en"]);
+    let get = |path: &str| {
+        let url = format!("gemini://localhost/download/{}", path);
+        String::from_utf8(read_to_end(request(addr(2053), &url))).unwrap()
+    };
+
+    assert_eq!(
+        get("template.gmi"),
+        "20 application/octet-stream\r\n# Template\n"
+    );
+    assert_eq!(get("notes.txt"), "20 text/plain\r\nnotes\n");
+    assert_eq!(
+        get(""),
+        "20 text/gemini\r\n\
+        => notes.txt\n\
+        => template.gmi template.gmi (download)\n"
+    );
+}
+
+#[test]
+/// - responses are sent the same way for headers, files and generated bodies
+fn response_bytes() {
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2054",
+        "--search",
+        "/search",
+    ]);
+    let get = |url: &str| String::from_utf8(read_to_end(request(addr(2054), url))).unwrap();
+    let too_deep = format!("gemini://localhost/{}", "a/".repeat(40));
+    let too_long = format!("gemini://localhost/{}", "a".repeat(1024));
+
+    for (url, expected) in [
+        (
+            "gemini://localhost/",
+            "20 text/gemini\r\nThis is a test index file.\n",
+        ),
+        ("gemini://localhost/test", "20 text/html\r\n"),
+        (
+            "gemini://localhost/test.gmi",
+            "20 text/gemini;lang=en ;charset=us-ascii\r\nThis is a test in the root\nSuppe\n",
+        ),
+        (
+            "gemini://localhost/gone.txt",
+            "52 This file is no longer available.\r\n",
+        ),
+        (
+            "gemini://localhost/testdir",
+            "31 gemini://localhost/testdir/\r\n",
+        ),
+        (
+            "gemini://localhost/testdir/",
+            "51 Directory index disabled.\r\n",
+        ),
+        ("gemini://localhost/missing.gmi", "51 Not found, sorry.\r\n"),
+        ("gemini://localhost/a/b/%2F", "51 Not found, sorry.\r\n"),
+        (
+            "gemini://localhost/searchable/.hidden.gmi",
+            "52 If I told you, it would not be a secret.\r\n",
+        ),
+        (
+            "gemini://localhost/described/",
+            "20 text/gemini\r\n\
+            => plain.txt\n\
+            => report.pdf Quarterly report, March 2024\n\
+            => sub/ Subdirectory\n",
+        ),
+        (
+            "gemini://localhost/download/template.gmi",
+            "20 application/octet-stream\r\n# Template\n",
+        ),
+        ("gemini://localhost/search", "10 Search this capsule\r\n"),
+        (
+            "gemini://localhost/search?zebra",
+            "20 text/gemini\r\n\
+            # Search results for zebra\n\n\
+            => /searchable/zebra.gmi Zebras\n",
+        ),
+        (&too_deep, "59 Path has too many segments\r\n"),
+        (&too_long, "59 Request too long\r\n"),
+        (
+            "http://localhost/",
+            "53 Proxying http URLs is not supported\r\n",
+        ),
+    ] {
+        assert_eq!(get(url), expected, "{}", url);
+    }
+}
+
+#[test]
+/// - redirects that would make the header too long are made relative
+fn long_redirects() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2055"]);
+
+    // empty segments are ignored when looking for the file, but kept in the
+    // URL, so the redirect to add a trailing slash is one byte longer
+    let prefix = "gemini://localhost";
+    let nested = format!("{}{}/testdir", prefix, "/".repeat(1024 - prefix.len() - 8));
+    assert_eq!(nested.len(), 1024);
+    assert_eq!(header(addr(2055), &nested), "31 testdir/");
+
+    let short = format!("{}{}/testdir", prefix, "/".repeat(900));
+    assert_eq!(header(addr(2055), &short), format!("31 {}/", short));
+
+    let query = "a".repeat(1024 - "gemini://localhost/testdir?".len());
+    let url = format!("gemini://localhost/testdir?{}", query);
+    assert_eq!(header(addr(2055), &url), format!("31 testdir/?{}", query));
+}
+
+#[test]
+/// - directories are served the same way when requested repeatedly
+fn directory_index_repeated() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2056"]);
+
+    for _ in 0..2 {
+        for (url, expected) in [
+            ("gemini://localhost/", "20 text/gemini"),
+            ("gemini://localhost", "20 text/gemini"),
+            (
+                "gemini://localhost/described",
+                "31 gemini://localhost/described/",
+            ),
+            ("gemini://localhost/described/", "20 text/gemini"),
+            (
+                "gemini://localhost/testdir/",
+                "51 Directory index disabled.",
+            ),
+            (
+                "gemini://localhost/symlinks/dir/",
+                "51 Directory index disabled.",
+            ),
+            (
+                "gemini://localhost/test.gmi/",
+                "20 text/gemini;lang=en ;charset=us-ascii",
+            ),
+            ("gemini://localhost/missing/", "51 Not found, sorry."),
+        ] {
+            assert_eq!(header(addr(2056), url), expected, "{}", url);
+        }
+    }
+}
+
+#[test]
+/// - the status page is only shown with an allowed client certificate
+/// - the status page is shown even if the content directory is gone
+fn status_page() {
+    let identity = |name: &str| {
+        let cert = rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+        (
+            cert.serialize_der().unwrap(),
+            cert.serialize_private_key_der(),
+        )
+    };
+    let admin = identity("admin");
+    let other = identity("other");
+
+    let dir = std::env::temp_dir().join("agate-test-status-page");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("index.gmi"), "hello\n").unwrap();
+
+    let admin_fpr = ring::digest::digest(&ring::digest::SHA256, &admin.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2057",
+        "--content",
+        dir.to_str().unwrap(),
+        "--status-page",
+        "/.server-status",
+        "--admin-cert",
+        &admin_fpr,
+    ]);
+    let url = "gemini://localhost/.server-status";
+    let get =
+        |cert| String::from_utf8(read_to_end(request_with_cert(addr(2057), url, cert))).unwrap();
+
+    // other requests are not affected by asking for client certificates
+    assert_eq!(
+        read_to_end(request(addr(2057), "gemini://localhost/")),
+        b"20 text/gemini\r\nhello\n"
+    );
+    assert_eq!(get(None), "60 Client certificate required\r\n");
+    assert_eq!(get(Some(&other)), "61 Certificate not authorized\r\n");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    let page = get(Some(&admin));
+    assert!(
+        page.starts_with("20 text/gemini\r\n# Server status\n"),
+        "{}",
+        page
+    );
+    assert!(page.contains("\n* 1 with status 20\n"), "{}", page);
+    assert!(page.contains("\n* 1 with status 60\n"), "{}", page);
+    assert!(
+        page.contains("\n* localhost: 3 completed, 0 aborted, 6 body bytes sent, 91 request bytes received, statuses 20=1 60=1 61=1\n"),
+        "{}",
+        page
+    );
+    assert!(
+        page.contains("\n## Certificates\n\n* other domains: valid until "),
+        "{}",
+        page
+    );
+}
+
+/// Sends a request in parts with a pause after each, so the server reads
+/// them separately, and returns the response. If `close` is set, the
+/// connection is closed for writing after the last part.
+fn request_parts(addr: SocketAddr, parts: &[&[u8]], close: bool) -> String {
+    use std::io::Write;
+
+    let mut tls = connect(addr, "localhost", None);
+    for part in parts {
+        tls.write_all(part).unwrap();
+        tls.flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    if close {
+        rustls::Session::send_close_notify(&mut tls.sess);
+        tls.flush().unwrap();
+    }
+    String::from_utf8(read_to_end(tls)).unwrap()
+}
+
+#[test]
+/// - requests have to end with CRLF by default, also when it is split
+fn strict_termination() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2058"]);
+    let url: &[u8] = b"gemini://localhost/";
+    let ok = "20 text/gemini\r\nThis is a test index file.\n";
+    let unexpected = "59 Request ended unexpectedly\r\n";
+
+    assert_eq!(request_parts(addr(2058), &[url, b"\r", b"\n"], false), ok);
+    assert_eq!(request_parts(addr(2058), &[url, b"\r\n"], false), ok);
+    assert_eq!(request_parts(addr(2058), &[url, b"\n"], true), unexpected);
+    assert_eq!(request_parts(addr(2058), &[url, b"\r"], true), unexpected);
+}
+
+#[test]
+/// - requests may end with a bare LF with --lenient-termination
+/// - a bare CR still does not end a request
+/// - the URL may still not be longer than 1024 bytes
+fn lenient_termination() {
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2059",
+        "--lenient-termination",
+    ]);
+    let url: &[u8] = b"gemini://localhost/";
+    let ok = "20 text/gemini\r\nThis is a test index file.\n";
+
+    assert_eq!(request_parts(addr(2059), &[url, b"\n"], false), ok);
+    assert_eq!(request_parts(addr(2059), &[url, b"\r", b"\n"], false), ok);
+    assert_eq!(
+        request_parts(addr(2059), &[url, b"\r"], true),
+        "59 Request ended unexpectedly\r\n"
+    );
+
+    let long = format!("gemini://localhost/?{}", "a".repeat(1024 - 20));
+    assert_eq!(
+        request_parts(addr(2059), &[long.as_bytes(), b"\n"], false),
+        ok
+    );
+    let long = format!("{}a", long);
+    assert_eq!(
+        request_parts(addr(2059), &[long.as_bytes(), b"\n"], false),
+        "59 Request too long\r\n"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// - connections are dropped after too many failed TLS handshakes
+/// - addresses with a successful handshake are not blocked
+/// - dropped connections are counted
+fn handshake_limit() {
+    use rustls::ClientSession;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2069",
+        "--handshake-limit",
+        "2/1m",
+    ]);
+    let ipv6: SocketAddr = "[::1]:2069".parse().unwrap();
+
+    let fail_handshake = |addr: SocketAddr| {
+        let mut config = rustls::ClientConfig::new();
+        config.enable_sni = false;
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+        let mut tcp = TcpStream::connect(addr).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        assert!(write!(tls, "gemini://localhost/\r\n").is_err());
+        // give the server time to count the failure
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    };
+
+    assert!(header(ipv6, "gemini://localhost/").starts_with("20 "));
+    for _ in 0..3 {
+        fail_handshake(ipv6);
+    }
+    assert!(header(ipv6, "gemini://localhost/").starts_with("20 "));
+
+    fail_handshake(addr(2069));
+    fail_handshake(addr(2069));
+    let mut tcp = TcpStream::connect(addr(2069)).unwrap();
+    tcp.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    // the server closes the connection without waiting for a handshake
+    assert!(matches!(tcp.read(&mut [0; 16]), Ok(0)));
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    assert!(
+        log.contains("Dropping connections from a client for 10m after 2 failed TLS handshakes"),
+        "{}",
+        log
+    );
+    assert!(log.contains("1 connections dropped"), "{}", log);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// - when the table of clients is full, the oldest client is no longer tracked
+/// - blocked clients are kept tracked in its place
+fn handshake_limit_full() {
+    use rustls::ClientSession;
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    let _server = Server::with_env(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:2116",
+            "--handshake-limit",
+            "2/1m",
+        ],
+        &[("AGATE_TEST_MAX_TRACKED", "2")],
+    );
+    let ipv6: SocketAddr = "[::1]:2116".parse().unwrap();
+
+    // connects from another address than 127.0.0.1
+    let connect = |from: SocketAddr, to: SocketAddr| -> TcpStream {
+        let tcp = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async {
+                let socket = match from {
+                    SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
+                    SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
+                }?;
+                socket.bind(from)?;
+                socket.connect(to).await?.into_std()
+            })
+            .unwrap();
+        tcp.set_nonblocking(false).unwrap();
+        tcp
+    };
+    let fail_handshake = |mut tcp: TcpStream| {
+        let mut config = rustls::ClientConfig::new();
+        config.enable_sni = false;
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        assert!(write!(tls, "gemini://localhost/\r\n").is_err());
+        // give the server time to count the failure
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    };
+    let other: SocketAddr = "127.0.0.2:0".parse().unwrap();
+    let is_dropped = || {
+        let mut tcp = TcpStream::connect(addr(2116)).unwrap();
+        tcp.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        matches!(tcp.read(&mut [0; 16]), Ok(0))
+    };
+
+    fail_handshake(TcpStream::connect(addr(2116)).unwrap());
+    fail_handshake(TcpStream::connect(addr(2116)).unwrap());
+    assert!(is_dropped());
+
+    // fills the table
+    fail_handshake(TcpStream::connect(ipv6).unwrap());
+    // evicts [::1], but not the blocked 127.0.0.1
+    fail_handshake(connect(other, addr(2116)));
+    assert!(is_dropped());
+    // evicts 127.0.0.2, after which this is again the first failure of [::1]
+    fail_handshake(TcpStream::connect(ipv6).unwrap());
+    assert!(header(ipv6, "gemini://localhost/").starts_with("20 "));
+    assert!(is_dropped());
+}
+
+#[test]
+/// - configuration files are refused even with `--serve-secret`, also when
+///   served from a content archive
+/// - other secret files are still served
+/// - `--serve-config-files` serves them like other secret files
+fn config_files() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2070", "--serve-secret"]);
+    for path in [
+        "/.meta",
+        "/testdir/.meta",
+        "/schedule/.directory-listing-ok",
+        "/described/.descriptions",
+        "/described/%2Edescriptions",
+    ] {
+        let url = format!("gemini://localhost{}", path);
+        assert!(header(addr(2070), &url).starts_with("52 "), "{}", path);
+    }
+    assert!(header(addr(2070), "gemini://localhost/searchable/.hidden.gmi").starts_with("20 "));
+    server.stop().unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2071",
+        "--serve-secret",
+        "--content-archive",
+        "content.zip",
+    ]);
+    assert!(header(addr(2071), "gemini://localhost/.meta").starts_with("52 "));
+    assert!(header(
+        addr(2071),
+        "gemini://localhost/listed/.directory-listing-ok"
+    )
+    .starts_with("52 "));
+    let url = Url::parse("gemini://localhost/listed/").unwrap();
+    let listing = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(Page::fetch_from(&url, addr(2071), None))
+        .expect("could not get page");
+    assert_eq!(listing.body.as_deref(), Some("=> page.gmi\n=> sub/\n"));
+    server.stop().unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2072",
+        "--serve-secret",
+        "--serve-config-files",
+    ]);
+    assert!(header(addr(2072), "gemini://localhost/.meta").starts_with("20 "));
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+/// - connections are closed after `--max-connection-time`, whatever they
+///   are doing
+/// - the client is told to retry if no header was sent yet
+/// - the stage the connection was in is logged
+fn max_connection_time() {
+    use std::net::TcpStream;
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2073",
+        "--max-connection-time",
+        "1s",
+    ]);
+
+    let started = std::time::Instant::now();
+    let response = request_parts(addr(2073), &[b"gemini://localhost/"], false);
+    assert_eq!(response, "41 Connection took too long.\r\n");
+    assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    server.wait_for_log("while reading the request");
+
+    // a client that never starts the TLS handshake
+    let mut tcp = TcpStream::connect(addr(2073)).unwrap();
+    tcp.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    assert!(matches!(tcp.read(&mut [0; 16]), Ok(0) | Err(_)));
+    server.wait_for_log("during the TLS handshake");
+    assert!(header(addr(2073), "gemini://localhost/").starts_with("20 "));
+
+    server.wait_for_log("\"gemini://localhost/\" 20 ");
+    let log = server.stop_and_read_log();
+
+    assert!(
+        log.contains("connection time exceeded while reading the request, received 19 bytes, sent 0 body bytes"),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("connection time exceeded during the TLS handshake"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// - the statistics summary is broken down by host name
+/// - requests refused before their host name was checked and requests for
+///   other host names are counted as unknown
+fn stats_by_host() {
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2074",
+        "--hostname",
+        "localhost",
+    ]);
+
+    assert!(header(addr(2074), "gemini://localhost/").starts_with("20 "));
+    assert!(header(addr(2074), "gemini://example.org/").starts_with("53 "));
+    assert!(header(addr(2074), "http://localhost/").starts_with("53 "));
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    assert!(
+        log.contains("; unknown: 2 completed, 0 aborted, 0 body bytes sent, 42 request bytes received, statuses 53=2; localhost: 1 completed, 0 aborted, 27 body bytes sent, 21 request bytes received, statuses 20=1"),
+        "{}",
+        log
+    );
+}
+
+#[test]
+/// - `agate resolve` shows the steps of resolving a URL and the response
+/// - the `.meta` entry that applies is shown with its line number
+/// - redirects and refused paths are shown
+fn resolve_subcommand() {
+    let resolve = |url: &str| {
+        let output = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["resolve", url, "--hostname", "localhost"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    assert_eq!(
+        resolve("gemini://localhost/"),
+        "host name localhost accepted\n\
+         content root \"content\"\n\
+         directory \"content\", serving the index file\n\
+         file \"content/index.gmi\"\n\
+         response: 20 text/gemini\n\
+         body: file \"content/index.gmi\"\n"
+    );
+    let gone = resolve("gemini://localhost/gone.txt");
+    assert!(
+        gone.contains("\nentry in \"content/.meta\" line 8: "),
+        "{}",
+        gone
+    );
+    assert!(
+        gone.ends_with("\nresponse: 52 This file is no longer available.\n"),
+        "{}",
+        gone
+    );
+    assert!(resolve("gemini://localhost/testdir")
+        .ends_with("\nredirect to gemini://localhost/testdir/\n"));
+    assert!(resolve("gemini://localhost/test%2Fx").ends_with(
+        "\nsegment \"test%2Fx\" decoded to \"test/x\"\n\
+         segment contains a path separator\n\
+         response: 51 Not found, sorry.\n"
+    ));
+    assert_eq!(
+        resolve("gemini://example.org/"),
+        "host name example.org is not one of the --hostname\n\
+         response: 53 Proxy request refused\n"
+    );
+}
+
+#[test]
+/// - the server starts with `--warm-up`, also with an invalid `.meta` file
+/// - preloaded `.meta` entries apply
+fn warm_up() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2075", "--warm-up"]);
+    assert_eq!(
+        header(addr(2075), "gemini://localhost/gone.txt"),
+        "52 This file is no longer available."
+    );
+    assert!(header(addr(2075), "gemini://localhost/test.gmi")
+        .starts_with("20 text/gemini;lang=en ;charset=us-ascii"));
+}
+
+#[test]
+/// - redirects keep the percent-encoding of the path and query as requested,
+///   including lower case escapes and encoded slashes
+/// - paths with spaces, non-ASCII and `+` stay valid URIs
+/// - the port is kept when the host name is replaced
+fn redirect_encoding() {
+    let content = std::env::temp_dir().join("agate-test-redirect-encoding");
+    let _ = std::fs::remove_dir_all(&content);
+    for dir in ["with space", "\u{1F600}", "a+b"] {
+        std::fs::create_dir_all(content.join(dir)).unwrap();
+    }
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2076",
+        "--content",
+        content.to_str().unwrap(),
+        "--canonical-host",
+        "example.com",
+    ]);
+
+    for (request, target) in [
+        (
+            "gemini://example.com/with%20space?q=1+2",
+            "gemini://example.com/with%20space/?q=1+2",
+        ),
+        (
+            "gemini://example.com/with space",
+            "gemini://example.com/with%20space/",
+        ),
+        (
+            "gemini://example.com/%f0%9f%98%80?a%2Fb",
+            "gemini://example.com/%f0%9f%98%80/?a%2Fb",
+        ),
+        (
+            "gemini://example.com/\u{1F600}",
+            "gemini://example.com/%F0%9F%98%80/",
+        ),
+        ("gemini://example.com/a+b", "gemini://example.com/a+b/"),
+        ("gemini://example.com/a%2bb", "gemini://example.com/a%2bb/"),
+        (
+            "gemini://localhost:2076/with%20space/a%2Fb?q=%20",
+            "gemini://example.com:2076/with%20space/a%2Fb?q=%20",
+        ),
+    ] {
+        assert_eq!(
+            header(addr(2076), request),
+            format!("31 {}", target),
+            "{}",
+            request
+        );
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - a client certificate is recorded the first time it is seen, with a
+///   petname from the query
+/// - it is known in later requests and after a restart
+/// - requests without a client certificate are not affected
+fn client_registry() {
+    let cert = rcgen::generate_simple_self_signed(vec!["client".into()]).unwrap();
+    let identity = (
+        cert.serialize_der().unwrap(),
+        cert.serialize_private_key_der(),
+    );
+    let fingerprint = ring::digest::digest(&ring::digest::SHA256, &identity.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let dir = std::env::temp_dir().join("agate-test-client-registry");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let registry = dir.join("clients.tsv");
+    let args = [
+        "--loose-ports",
+        "--addr",
+        "[::]:2077",
+        "--debug-endpoint",
+        "/echo",
+        "--client-registry",
+        registry.to_str().unwrap(),
+    ];
+    let echo = |query: &str| {
+        let url = format!("gemini://localhost/echo?{}", query);
+        String::from_utf8(read_to_end(request_with_cert(
+            addr(2077),
+            &url,
+            Some(&identity),
+        )))
+        .unwrap()
+    };
+
+    let mut server = Server::new(&args);
+    let page = echo("petname=Alice%20B");
+    assert!(page.contains("\n* Known client: no\n"), "{}", page);
+    assert!(page.contains("\n* Petname: \"Alice B\"\n"), "{}", page);
+    let page = echo("petname=Mallory");
+    assert!(page.contains("\n* Known client: yes\n"), "{}", page);
+    assert!(page.contains("\n* Petname: \"Alice B\"\n"), "{}", page);
+    let page = String::from_utf8(read_to_end(request(
+        addr(2077),
+        "gemini://localhost/echo?x",
+    )))
+    .unwrap();
+    assert!(!page.contains("Known client"), "{}", page);
+    server.stop().unwrap();
+
+    let saved = std::fs::read_to_string(&registry).unwrap();
+    let fields = saved.trim_end().split('\t').collect::<Vec<_>>();
+    assert_eq!(fields.len(), 3, "{:?}", saved);
+    assert_eq!(fields[0], fingerprint);
+    assert_eq!(fields[2], "Alice B");
+
+    let mut server = Server::new(&args);
+    let page = echo("q");
+    assert!(page.contains("\n* Known client: yes\n"), "{}", page);
+    assert!(
+        page.contains(&format!("\n* First seen: {}\n", fields[1])),
+        "{}",
+        page
+    );
+    server.stop().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+/// - with `--access-log-target file:PATH`, access log lines are appended to
+///   the file in order and not written to stderr
+/// - other messages are still written to stderr
+/// - invalid targets are refused
+fn access_log_target() {
+    let dir = std::env::temp_dir().join("agate-test-access-log-target");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("access.log");
+    std::fs::write(&file, "earlier line\n").unwrap();
+    let target = format!("file:{}", file.to_str().unwrap());
+
+    // the lines are written after the connections were closed
+    let wait_for_lines = |count: usize| {
+        let started = std::time::Instant::now();
+        while std::fs::read_to_string(&file).unwrap().lines().count() < count {
+            assert!(started.elapsed() < LOG_TIMEOUT, "request was not logged");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2078",
+        "--access-log-target",
+        &target,
+    ]);
+    assert!(header(addr(2078), "gemini://localhost/").starts_with("20 "));
+    wait_for_lines(2);
+    assert_eq!(
+        header(addr(2078), "gemini://localhost/missing"),
+        "51 Not found, sorry."
+    );
+    wait_for_lines(3);
+    let log = server.stop_and_read_log();
+    assert!(log.contains("TLS handshake completed"), "{}", log);
+    assert!(!log.contains("\"gemini://localhost/\" 20 "), "{}", log);
+    assert!(
+        !log.contains("\"gemini://localhost/missing\" 51 "),
+        "{}",
+        log
+    );
+
+    let access = std::fs::read_to_string(&file).unwrap();
+    let lines = access.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3, "{}", access);
+    assert_eq!(lines[0], "earlier line");
+    assert!(
+        lines[1].contains("\"gemini://localhost/\" 20 \"text/gemini\""),
+        "{}",
+        access
+    );
+    assert!(
+        lines[2].contains("\"gemini://localhost/missing\" 51 "),
+        "{}",
+        access
+    );
+
+    for target in [
+        "file:",
+        "syslog",
+        &format!("file:{}", dir.join("no/such/dir").display()),
+    ] {
+        let status = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", "[::]:2078", "--access-log-target", target])
+            .stderr(Stdio::null())
+            .status()
+            .expect("failed to start binary");
+        assert_eq!(status.code(), Some(2), "{}", target);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+/// - certificates not used for any host name are logged with
+///   `--prune-unused-certs`, certificates for parent domains are in use
+/// - with `--prune-delete`, they are moved to the retired directory when
+///   reloading
+/// - nothing is pruned without host names
+fn prune_unused_certs() {
+    let dir = std::env::temp_dir().join("agate-test-prune-certs");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let file = dir.join("hostnames");
+    let certs = dir.join("certs");
+    let args = |extra: &[&'static str]| {
+        let mut args = vec![
+            "--loose-ports".to_string(),
+            "--addr".to_string(),
+            "[::]:2079".to_string(),
+            "--certs".to_string(),
+            certs.to_str().unwrap().to_string(),
+            "--hostnames-file".to_string(),
+            file.to_str().unwrap().to_string(),
+        ];
+        args.extend(extra.iter().map(|arg| arg.to_string()));
+        args
+    };
+    let start = |args: &[String]| Server::new(&args.iter().map(String::as_str).collect::<Vec<_>>());
+
+    std::fs::write(&file, "a.example\nb.example\nc.example\n").unwrap();
+    start(&args(&[])).stop().unwrap();
+
+    // the address is in use, so this fails after checking the certificates
+    std::fs::write(&file, "a.example\ngemini.c.example\n").unwrap();
+    let mut server = start(&args(&[]));
+    let output = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(args(&["--prune-unused-certs"]))
+        .output()
+        .expect("failed to start binary");
+    assert_eq!(output.status.code(), Some(4));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        log.contains("The certificate for b.example is not used for any host name."),
+        "{}",
+        log
+    );
+    assert!(!log.contains("for a.example is not used"), "{}", log);
+    assert!(!log.contains("for c.example is not used"), "{}", log);
+    assert!(certs.join("b.example/cert.der").exists());
+    server.stop().unwrap();
+
+    std::fs::write(&file, "a.example\nb.example\ngemini.c.example\n").unwrap();
+    let mut server = start(&args(&["--prune-unused-certs", "--prune-delete"]));
+    assert!(certs.join("b.example/cert.der").exists());
+    std::fs::write(&file, "a.example\ngemini.c.example\n").unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("Reloaded host names and certificates.");
+    assert!(!certs.join("b.example").exists());
+    assert!(certs.join("retired/b.example/cert.der").exists());
+    assert!(certs.join("c.example/cert.der").exists());
+    // the certificates still work, but there is no content for these hosts
+    for url in ["gemini://a.example/", "gemini://gemini.c.example/"] {
+        assert_eq!(header(addr(2079), url), "51 Not found, sorry.", "{}", url);
+    }
+    server.stop().unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2079",
+        "--certs",
+        certs.to_str().unwrap(),
+        "--prune-unused-certs",
+        "--prune-delete",
+    ]);
+    assert!(certs.join("a.example/cert.der").exists());
+    assert!(certs.join("c.example/cert.der").exists());
+    server.stop().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+/// - requests in other protocols are refused as soon as they can be recognized,
+///   without waiting for the end of the request
+/// - Gemini requests and URLs with similar schemes are not affected, also if
+///   they are received in parts
+fn other_protocols() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2080"]);
+    let refused = "59 This is a Gemini server\r\n";
+
+    for parts in [
+        &[b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".as_slice()][..],
+        &[b"GE", b"T / HTTP/1.1"],
+        &[b"POST /login HTTP/1.1"],
+        &[b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"],
+        &[b"S", b"SH", b"-2.0-OpenSSH_9.6"],
+        &[b"\x16", b"\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"],
+    ] {
+        assert_eq!(
+            request_parts(addr(2080), parts, false),
+            refused,
+            "{:?}",
+            parts
+        );
+    }
+
+    assert!(request_parts(
+        addr(2080),
+        &[b"G", b"ET", b"gemini://localhost/\r\n"],
+        false
+    )
+    .starts_with("53 Unsupported URL scheme getgemini\r\n"));
+    assert!(request_parts(addr(2080), &[b"GET://localhost/\r\n"], false)
+        .starts_with("53 Unsupported URL scheme get\r\n"));
+    assert!(
+        request_parts(addr(2080), &[b"ge", b"mini://localhost/\r\n"], false)
+            .starts_with("20 text/gemini\r\n")
+    );
+}
+
+#[test]
+/// - directories are answered with the variant of the index file for the
+///   requested language, then for the default language, then with the index
+///   file itself, then with the first other variant
+/// - the language parameter is the one of the variant that is served
+/// - a directory with only one variant of the index file serves that variant
+fn lang_index_variants() {
+    let content = std::env::temp_dir().join("agate-test-lang-index-variants");
+    let _ = std::fs::remove_dir_all(&content);
+    for (name, text) in [
+        ("index.gmi", "base\n"),
+        ("index.de.gmi", "deutsch\n"),
+        ("index.en.gmi", "english\n"),
+        ("plain/index.gmi", "plain\n"),
+        ("plain/index.fr.gmi", "français\n"),
+        ("only/index.fr.gmi", "français\n"),
+        ("others/index.it.gmi", "italiano\n"),
+        ("others/index.fr.gmi", "français\n"),
+    ] {
+        let path = content.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, text).unwrap();
+    }
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2081",
+        "--content",
+        content.to_str().unwrap(),
+        "--lang",
+        "en",
+        "--lang-variants",
+    ]);
+    let get = |path: &str| {
+        let url = format!("gemini://localhost/{}", path);
+        String::from_utf8(read_to_end(request(addr(2081), &url))).unwrap()
+    };
+
+    assert_eq!(get(""), "20 text/gemini;lang=en\r\nenglish\n");
+    assert_eq!(get("?lang=de"), "20 text/gemini;lang=de\r\ndeutsch\n");
+    assert_eq!(get("?lang=it"), "20 text/gemini;lang=en\r\nenglish\n");
+    assert_eq!(get("index.gmi"), "20 text/gemini;lang=en\r\nbase\n");
+    assert_eq!(get("plain/"), "20 text/gemini;lang=en\r\nplain\n");
+    assert_eq!(
+        get("plain/?lang=fr"),
+        "20 text/gemini;lang=fr\r\nfrançais\n"
+    );
+    assert_eq!(get("only/"), "20 text/gemini;lang=fr\r\nfrançais\n");
+    assert_eq!(get("others/"), "20 text/gemini;lang=fr\r\nfrançais\n");
+    assert_eq!(
+        get("others/?lang=it"),
+        "20 text/gemini;lang=it\r\nitaliano\n"
+    );
+}
+
+#[test]
+#[cfg(unix)]
+/// - the TLS configuration is replaced when reloading
+/// - a connection established before the reload is still answered
+/// - new connections use the configuration built again from the options,
+///   e.g. TLS 1.2 is still refused with `--only-tls13`
+fn reload_tls_config() {
+    use rustls::{ClientSession, ProtocolVersion, TLSError};
+    use std::io::{Read, Write};
+
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2082", "--only-tls13"]);
+
+    let mut old = connect(addr(2082), "localhost", None);
+    old.write_all(b"gemini://localhost/").unwrap();
+    old.flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let status = Command::new("kill")
+        .args(["-HUP", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("Reloaded host names and certificates.");
+
+    assert!(header(addr(2082), "gemini://localhost/").starts_with("20 "));
+    let mut config = rustls::ClientConfig::new();
+    config.versions = vec![ProtocolVersion::TLSv1_2];
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut session = ClientSession::new(&std::sync::Arc::new(config), dns_name);
+    let mut tcp = std::net::TcpStream::connect(addr(2082)).unwrap();
+    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+    assert_eq!(
+        *tls.read(&mut [0; 10])
+            .unwrap_err()
+            .into_inner()
+            .unwrap()
+            .downcast::<TLSError>()
+            .unwrap(),
+        TLSError::AlertReceived(rustls::internal::msgs::enums::AlertDescription::ProtocolVersion)
+    );
+
+    old.write_all(b"test.gmi\r\n").unwrap();
+    old.flush().unwrap();
+    let response = String::from_utf8(read_to_end(old)).unwrap();
+    assert!(response.starts_with("20 text/gemini"), "{}", response);
+    server.stop().unwrap();
+}
+
+#[test]
+/// - quotes, backslashes and control characters in requests are escaped in
+///   the access log line
+/// - requests that are not valid UTF-8 are logged decoded with replacement
+///   characters and still refused
+fn log_escaped_request() {
+    let mut server = Server::new(&["--loose-ports", "--addr", "[::]:2083"]);
+
+    assert_eq!(
+        request_parts(addr(2083), &[b"gemini://localhost/\xff\"\xfe\r\n"], false),
+        "59 Non-UTF-8 request\r\n"
+    );
+    server.wait_for_log("\" 59 ");
+    for request in [
+        b"gemini://localhost/a\"b\\c\r\n".as_slice(),
+        b"gemini://localhost/x\ny\tz\x1b[31m\r\n",
+    ] {
+        assert!(request_parts(addr(2083), &[request], false).starts_with("5"));
+        server.wait_for_log("\" 5");
+    }
+
+    let log = server.stop_and_read_log();
+
+    for logged in [
+        "\"gemini://localhost/\u{FFFD}\\\"\u{FFFD}\" 59 \"Non-UTF-8 request\"",
+        "\"gemini://localhost/a\\\"b\\\\c\" 5",
+        "\"gemini://localhost/x\\x0ay\\x09z\\x1b[31m\" 5",
+    ] {
+        assert!(log.contains(logged), "{}\n{}", logged, log);
+    }
+}
+
+/// Creates a content directory with the given empty files.
+fn empty_files_content(name: &str, files: &[&str]) -> std::path::PathBuf {
+    let content = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&content);
+    for file in files {
+        let path = content.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    content
+}
+
+#[test]
+/// - an empty gemtext file is answered with only the header
+/// - the connection is closed cleanly, so clients see the end of the body
+fn empty_gmi() {
+    let content = empty_files_content("agate-test-empty-gmi", &["empty.gmi"]);
+    let args = [
+        "--loose-ports",
+        "--addr",
+        "[::]:2084",
+        "--content",
+        content.to_str().unwrap(),
+    ];
+
+    let page = get(&args, addr(2084), "gemini://localhost/empty.gmi").expect("could not get page");
+    assert_eq!(
+        page.header,
+        Header {
+            status: Status::Success,
+            meta: "text/gemini".to_string(),
+        }
+    );
+    // gemini-fetch reports an empty body as no body
+    assert_eq!(page.body, None);
+
+    let _server = Server::new(&args);
+    let response = read_to_end(request(addr(2084), "gemini://localhost/empty.gmi"));
+    assert_eq!(response, b"20 text/gemini\r\n");
+}
+
+#[test]
+/// - an empty binary file is answered with only the header
+fn empty_binary() {
+    let content = empty_files_content("agate-test-empty-binary", &["empty.bin"]);
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2085",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+
+    let response = read_to_end(request(addr(2085), "gemini://localhost/empty.bin"));
+    assert_eq!(response, b"20 application/octet-stream\r\n");
+}
+
+#[test]
+/// - an empty index file is served for its directory with only the header
+fn empty_index() {
+    let content = empty_files_content("agate-test-empty-index", &["index.gmi", "dir/index.gmi"]);
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2086",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+
+    for url in ["gemini://localhost/", "gemini://localhost/dir/"] {
+        let response = read_to_end(request(addr(2086), url));
+        assert_eq!(response, b"20 text/gemini\r\n", "{}", url);
+    }
+}
+
+#[test]
+/// - directory listings are sorted by the bytes of the file names, so
+///   upper case comes before lower case and multi-byte characters after ASCII
+/// - the order is the same for every request
+fn listing_order() {
+    let content = std::env::temp_dir().join("agate-test-listing-order");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir_all(content.join("dir")).unwrap();
+    for name in [
+        ".directory-listing-ok",
+        "b.txt",
+        "é.txt",
+        "B.txt",
+        "Ω.txt",
+        "a.txt",
+        "e.txt",
+        "A.txt",
+        "z.txt",
+    ] {
+        std::fs::write(content.join("dir").join(name), "").unwrap();
+    }
+    std::fs::create_dir(content.join("dir/é")).unwrap();
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2091",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+    let listing = || read_to_end(request(addr(2091), "gemini://localhost/dir/"));
+    let first = listing();
+    assert_eq!(
+        String::from_utf8(first.clone()).unwrap(),
+        "20 text/gemini\r\n\
+         => A.txt\n\
+         => B.txt\n\
+         => a.txt\n\
+         => b.txt\n\
+         => e.txt\n\
+         => z.txt\n\
+         => %C3%A9/ é/\n\
+         => %C3%A9.txt é.txt\n\
+         => %CE%A9.txt Ω.txt\n"
+    );
+    assert_eq!(listing(), first);
+}
+
+#[test]
+// the panic can only be triggered in debug builds
+#[cfg(all(unix, debug_assertions))]
+/// - a panic while handling a request only ends that connection
+/// - the client gets status 42 if no header was sent yet
+/// - the panic is logged with the request and counted in the statistics
+fn panic_isolation() {
+    let mut server = Server::with_env(
+        &["--loose-ports", "--addr", "[::]:2092"],
+        &[("AGATE_TEST_PANIC", "/panic")],
+    );
+
+    assert_eq!(
+        read_to_end(request(addr(2092), "gemini://localhost/panic")),
+        b"42 Internal server error.\r\n"
+    );
+    assert!(header(addr(2092), "gemini://localhost/").starts_with("20 "));
+
+    let status = Command::new("kill")
+        .args(["-USR2", &server.server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    server.wait_for_log("statistics:");
+    let log = server.stop_and_read_log();
+
+    for expected in [
+        "Panic while handling request \"gemini://localhost/panic\": test panic for gemini://localhost/panic",
+        "\"gemini://localhost/panic\" 42 \"Internal server error.\" error:panicked: test panic for gemini://localhost/panic",
+        "1 ended by a panic",
+    ] {
+        assert!(log.contains(expected), "{}\n{}", expected, log);
+    }
+}
+
+#[test]
+/// - a `bytes=START-END` query serves only that slice with the usual MIME type
+/// - an empty end serves the rest of the file
+/// - malformed and out of bounds ranges are answered with status 59
+/// - without `--byte-ranges` the query is ignored and the whole file is sent
+fn byte_ranges() {
+    let content = std::env::temp_dir().join("agate-test-byte-ranges");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir_all(&content).unwrap();
+    std::fs::write(content.join("digits.txt"), "0123456789").unwrap();
+    let content = content.to_str().unwrap();
+
+    let mut server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2093",
+        "--content",
+        content,
+        "--byte-ranges",
+    ]);
+    let cases: [(&str, &[u8]); 7] = [
+        ("bytes=2-5", b"20 text/plain\r\n2345"),
+        ("bytes=9-9", b"20 text/plain\r\n9"),
+        ("bytes=7-", b"20 text/plain\r\n789"),
+        (
+            "bytes=5-2",
+            b"59 Invalid byte range, expected bytes=START-END\r\n",
+        ),
+        (
+            "bytes=-3",
+            b"59 Invalid byte range, expected bytes=START-END\r\n",
+        ),
+        (
+            "bytes=5-10",
+            b"59 Byte range outside of the file, which has 10 bytes\r\n",
+        ),
+        (
+            "bytes=10-",
+            b"59 Byte range outside of the file, which has 10 bytes\r\n",
+        ),
+    ];
+    for (query, expected) in cases {
+        let url = format!("gemini://localhost/digits.txt?{}", query);
+        let response = read_to_end(request(addr(2093), &url));
+        assert_eq!(response, expected, "{}", query);
+        server.wait_for_log(&format!("?{}\" ", query));
+    }
+
+    let log = server.stop_and_read_log();
+    assert!(
+        log.contains("?bytes=2-5\" 20 \"text/plain\" range:2-5"),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("?bytes=7-\" 20 \"text/plain\" range:7-9"),
+        "{}",
+        log
+    );
+
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2094", "--content", content]);
+    let response = read_to_end(request(
+        addr(2094),
+        "gemini://localhost/digits.txt?bytes=2-5",
+    ));
+    assert_eq!(response, b"20 text/plain\r\n0123456789");
+}
+
+#[test]
+/// - with `--self-test`, agate only starts if it can answer a request itself
+/// - a failed self-test ends agate with exit code 6 before it is listening
+/// - with `--self-test-warn`, a failed self-test is only logged
+fn self_test() {
+    let _server = Server::new(&["--loose-ports", "--addr", "[::]:2095", "--self-test"]);
+
+    let output = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(["--addr", "[::]:2096", "--self-test=/missing"])
+        .output()
+        .expect("failed to start binary");
+    assert_eq!(output.status.code(), Some(6));
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        log.contains("Self-test failed: gemini://localhost:2096/missing responded with status 51"),
+        "{}",
+        log
+    );
+    assert!(!log.contains("Listening"), "{}", log);
+
+    let _server = Server::new(&[
+        "--loose-ports",
+        "--addr",
+        "[::]:2097",
+        "--self-test=/missing",
+        "--self-test-warn",
+    ]);
+    assert!(header(addr(2097), "gemini://localhost/").starts_with("20 "));
+}
+
+#[test]
+/// - requests matching `--debug-path` are logged at debug level, including
+///   the steps of resolving them
+/// - other requests only get the access log line
+fn debug_path() {
+    let mut server = Server::with_env(
+        &[
+            "--loose-ports",
+            "--addr",
+            "[::]:2098",
+            "--debug-path",
+            "/testdir",
+        ],
+        &[("RUST_LOG", "agate=info")],
+    );
+    assert!(header(addr(2098), "gemini://localhost/").starts_with("20 "));
+    server.wait_for_log("\"gemini://localhost/\" 20 ");
+    assert!(header(addr(2098), "gemini://localhost/testdir/").starts_with("51 "));
+
+    server.wait_for_log("\"gemini://localhost/testdir/\" 51 ");
+    let log = server.stop_and_read_log();
+
+    // the request ID is in the connection span that prefixes each line
+    let id = |request: &str| {
+        let line = log.lines().find(|line| line.contains(request)).unwrap();
+        line[line.find("conn{").unwrap()..line.find("}:").unwrap()].to_string()
+    };
+    let debugged = id("\"gemini://localhost/testdir/\" 51");
+    assert_ne!(id("\"gemini://localhost/\" 20"), debugged);
+    let debug_lines = log
+        .lines()
+        .filter(|line| line.contains(" DEBUG "))
+        .collect::<Vec<_>>();
+    assert!(debug_lines.len() > 1, "{}", log);
+    assert!(
+        debug_lines.iter().all(|line| line.contains(&debugged)),
+        "{}",
+        log
+    );
+    assert!(log.contains("host name localhost accepted"), "{}", log);
+    assert!(
+        log.contains("segment \"testdir\" decoded to \"testdir\""),
+        "{}",
+        log
+    );
+    assert!(
+        log.contains("\"gemini://localhost/\" 20 \"text/gemini\""),
+        "{}",
+        log
+    );
 }